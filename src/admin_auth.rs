@@ -0,0 +1,139 @@
+//! Authentication for the admin listener, configured separately from the
+//! data-plane auth.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use cel_interpreter::Value;
+use tracing::{debug, warn};
+
+use crate::{cel_functions, context_headers, context_schema, KeyStore, NonUtf8Headers};
+
+/// How requests to the admin listener are authenticated.
+#[derive(Clone)]
+pub enum AdminAuth {
+    /// Requests need to carry this static bearer token.
+    Token(Arc<str>),
+    /// Requests need to carry a JWT verifiable by the key store, for which
+    /// the CEL program returns true.
+    Policy {
+        key_store: KeyStore,
+        program: Arc<cel_interpreter::Program>,
+    },
+}
+
+impl AdminAuth {
+    /// Read the bearer token from a file, stripping surrounding whitespace.
+    pub fn token_from_file(path: &std::path::Path) -> std::io::Result<Self> {
+        let token = std::fs::read_to_string(path)?;
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "admin token file is empty",
+            ));
+        }
+        Ok(Self::Token(token.into()))
+    }
+
+    /// Compile the CEL policy. It has the `jwt_claims` and `request_headers`
+    /// variables available, same as for the /auth endpoint.
+    pub fn policy(key_store: KeyStore, cel_str: &str) -> Result<Self, cel_interpreter::ParseError> {
+        Ok(Self::Policy {
+            key_store,
+            program: Arc::new(cel_interpreter::Program::compile(cel_str)?),
+        })
+    }
+
+    async fn check(&self, token: &str, headers: HeaderMap) -> bool {
+        match self {
+            AdminAuth::Token(expected) => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+            AdminAuth::Policy { key_store, program } => {
+                let jwt_claims = match key_store
+                    .verify::<serde_json::Map<String, serde_json::Value>>(token, None, None)
+                    .await
+                {
+                    Ok(claims) => claims,
+                    Err(e) => {
+                        debug!(err=%e, "invalid admin token");
+                        return false;
+                    }
+                };
+
+                let mut context = cel_interpreter::Context::default();
+                cel_functions::register(&mut context);
+                context
+                    .add_variable(
+                        context_schema::REQUEST_HEADERS.name,
+                        context_headers::parse_headers(headers, NonUtf8Headers::Bytes),
+                    )
+                    .expect("add request_headers must not fail");
+                context
+                    .add_variable(context_schema::JWT_CLAIMS.name, jwt_claims)
+                    .expect("add jwt_claims must not fail");
+
+                match program.execute(&context) {
+                    Ok(Value::Bool(allowed)) => allowed,
+                    Ok(_) => {
+                        warn!("admin policy didn't return boolean, denying");
+                        false
+                    }
+                    Err(e) => {
+                        warn!(err=%e, "failed to execute admin policy");
+                        false
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Compare two byte strings in constant time (for equal lengths).
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware rejecting requests not authenticated as configured.
+pub async fn require(
+    State(admin_auth): State<AdminAuth>,
+    maybe_auth_header: Option<TypedHeader<Authorization<Bearer>>>,
+    rq: Request,
+    next: Next,
+) -> Response {
+    let allowed = match &maybe_auth_header {
+        Some(TypedHeader(auth)) => admin_auth.check(auth.token(), rq.headers().clone()).await,
+        None => false,
+    };
+
+    if !allowed {
+        debug!(path = %rq.uri().path(), "rejecting unauthenticated admin request");
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response();
+    }
+
+    next.run(rq).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::constant_time_eq;
+
+    #[test]
+    fn eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
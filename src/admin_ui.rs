@@ -0,0 +1,182 @@
+//! A minimal admin UI, showing decision counters, recent denials, the loaded
+//! keys and the configured policies, for operational visibility without
+//! setting up dashboards.
+//!
+//! The page is static and holds no data, so it's served without
+//! authentication, allowing browsers to load it. It fetches the status from
+//! /-/ui/status, which requires admin authentication like all other admin
+//! endpoints, passing the bearer token entered in the page.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::IpAddr,
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Html,
+    Json,
+};
+use parking_lot::Mutex;
+
+use crate::{
+    context_request::X_FORWARDED_URI,
+    key_store::{self, SourceInfo},
+    metrics::METRICS,
+    AppState,
+};
+
+/// The number of recent denials kept.
+const MAX_DENIALS: usize = 50;
+
+static RECENT_DENIALS: LazyLock<Mutex<VecDeque<Denial>>> = LazyLock::new(Default::default);
+
+/// A request denied by the /auth endpoint.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Denial {
+    /// When the request was denied, in seconds since the epoch.
+    pub at: u64,
+    pub status: u16,
+    /// The name of the policy, if configured on the server.
+    pub policy: Option<String>,
+    /// The original URI, from the X-Forwarded-Uri header.
+    pub uri: Option<String>,
+    pub client_ip: Option<String>,
+}
+
+/// Remember a denied request, forgetting the oldest one if
+/// [MAX_DENIALS] are kept already.
+pub(crate) fn record_denial(
+    status: StatusCode,
+    policy: Option<&str>,
+    headers: &HeaderMap,
+    client_ip: Option<IpAddr>,
+) {
+    let denial = Denial {
+        at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        status: status.as_u16(),
+        policy: policy.map(str::to_owned),
+        uri: headers
+            .get(X_FORWARDED_URI)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned),
+        client_ip: client_ip.map(|ip| ip.to_string()),
+    };
+
+    let mut denials = RECENT_DENIALS.lock();
+    if denials.len() >= MAX_DENIALS {
+        denials.pop_front();
+    }
+    denials.push_back(denial);
+}
+
+/// A policy configured on the server.
+#[derive(Debug, serde::Serialize)]
+pub struct PolicyInfo {
+    pub name: String,
+    pub source: Option<String>,
+    pub cel: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Status {
+    pub version: &'static str,
+    pub key_set_generation: u64,
+    /// Decisions taken by the /auth endpoint, by outcome.
+    pub decisions: BTreeMap<String, u64>,
+    /// The most recent denials, newest first.
+    pub denials: Vec<Denial>,
+    pub key_sources: Vec<SourceInfo>,
+    /// Named policies by name, followed by the default policy, if any.
+    pub policies: Vec<PolicyInfo>,
+}
+
+/// Collect the current status.
+pub fn status(state: &AppState) -> Status {
+    let reloadable = state.reloadable.load();
+
+    let mut policies = reloadable
+        .policies
+        .iter()
+        .map(|(name, policy)| (name.clone(), policy))
+        .collect::<Vec<_>>();
+    policies.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let policies = policies
+        .into_iter()
+        .chain(
+            reloadable
+                .default_policy
+                .iter()
+                .map(|policy| ("default".to_string(), policy)),
+        )
+        .map(|(name, policy)| PolicyInfo {
+            name,
+            source: policy.source.as_deref().map(str::to_owned),
+            cel: policy.cel.clone(),
+        })
+        .collect();
+
+    Status {
+        version: clap::crate_version!(),
+        key_set_generation: key_store::generation(),
+        decisions: METRICS
+            .decisions
+            .entries()
+            .into_iter()
+            .map(|(labels, counter)| (labels.join(","), counter.get()))
+            .collect(),
+        denials: RECENT_DENIALS.lock().iter().rev().cloned().collect(),
+        key_sources: reloadable
+            .key_store
+            .sources()
+            .iter()
+            .map(|source| source.describe())
+            .collect(),
+        policies,
+    }
+}
+
+/// Serve the page.
+pub async fn page() -> Html<&'static str> {
+    Html(include_str!("admin_ui.html"))
+}
+
+/// Serve the status as JSON.
+pub async fn status_handler(State(state): State<AppState>) -> Json<Status> {
+    Json(status(&state))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue, StatusCode};
+
+    use super::{record_denial, MAX_DENIALS, RECENT_DENIALS};
+
+    #[test]
+    fn recent_denials() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-uri", HeaderValue::from_static("/admin"));
+
+        for _ in 0..MAX_DENIALS + 5 {
+            record_denial(
+                StatusCode::UNAUTHORIZED,
+                Some("admin"),
+                &headers,
+                Some([192, 0, 2, 1].into()),
+            );
+        }
+
+        let denials = RECENT_DENIALS.lock();
+        assert_eq!(MAX_DENIALS, denials.len());
+        let denial = denials.back().unwrap();
+        assert_eq!(401, denial.status);
+        assert_eq!(Some("admin"), denial.policy.as_deref());
+        assert_eq!(Some("/admin"), denial.uri.as_deref());
+        assert_eq!(Some("192.0.2.1"), denial.client_ip.as_deref());
+    }
+}
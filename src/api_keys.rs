@@ -0,0 +1,107 @@
+//! API keys, as a further identity next to tokens and client certificates.
+//!
+//! Keys are loaded from a file (TOML, YAML or JSON), mapping a name to the
+//! hex-encoded SHA-256 hash of the key, so the file doesn't hold the keys
+//! themselves:
+//!
+//! ```toml
+//! billing-batch = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+//! ```
+use std::{collections::HashMap, fmt, path::Path};
+
+use sha2::{Digest, Sha256};
+
+/// The header API keys are sent in.
+pub const X_API_KEY: &str = "x-api-key";
+
+#[derive(Debug)]
+pub struct ApiKeysError(String);
+
+impl fmt::Display for ApiKeysError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ApiKeysError {}
+
+fn err(msg: String) -> ApiKeysError {
+    ApiKeysError(msg)
+}
+
+/// The known API keys, by hash.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ApiKeys(HashMap<[u8; 32], String>);
+
+fn decode_hash(hash: &str) -> Option<[u8; 32]> {
+    if hash.len() != 64 || !hash.is_ascii() {
+        return None;
+    }
+    let mut out = [0; 32];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+impl ApiKeys {
+    /// Build the keys from the hex-encoded hashes, by name.
+    pub fn from_hashes(hashes: HashMap<String, String>) -> Result<Self, ApiKeysError> {
+        hashes
+            .into_iter()
+            .map(|(name, hash)| match decode_hash(hash.trim()) {
+                Some(hash) => Ok((hash, name)),
+                None => Err(err(format!(
+                    "hash of key {} is not a hex-encoded SHA-256 hash",
+                    name
+                ))),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// Load the keys from a file, in TOML, YAML or JSON format depending on
+    /// its extension.
+    pub fn load(path: &Path) -> Result<Self, ApiKeysError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| err(format!("unable to read {}: {}", path.display(), e)))?;
+
+        let hashes: HashMap<String, String> = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| e.to_string()),
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+            Some("json") => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+            _ => Err("unknown format, expected a .toml, .yaml, .yml or .json file".to_string()),
+        }
+        .map_err(|e| err(format!("{}: {}", path.display(), e)))?;
+
+        Self::from_hashes(hashes).map_err(|e| err(format!("{}: {}", path.display(), e)))
+    }
+
+    /// The name of the key, if it's known.
+    pub fn verify(&self, key: &str) -> Option<&str> {
+        let hash: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+        self.0.get(&hash).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::ApiKeys;
+
+    #[test]
+    fn verify() {
+        let keys = ApiKeys::from_hashes(HashMap::from([(
+            "billing".to_string(),
+            // sha256("test")
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".to_string(),
+        )]))
+        .expect("must parse");
+        assert_eq!(Some("billing"), keys.verify("test"));
+        assert_eq!(None, keys.verify("Test"));
+
+        let invalid = HashMap::from([("billing".to_string(), "test".to_string())]);
+        assert!(ApiKeys::from_hashes(invalid).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Signed identity assertions, returned on allow as `X-Auth-Assertion`.
+//!
+//! Plain identity headers are only as trustworthy as the network between
+//! the proxy and the upstream. The assertion is a short-lived JWT (ES256),
+//! signed with a key configured on the server, carrying the subject and
+//! the headers returned along with it, so upstreams can verify them, see
+//! [crate::client]. The public key is served as JWKS at [WELL_KNOWN_KEYS].
+//! With local keys, they're rotated, see [crate::local_keys].
+use std::{collections::BTreeMap, fmt, path::Path, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use axum::{
+    extract::State,
+    http::{HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use jwt_simple::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::AppState;
+
+/// The header the assertion is returned in.
+pub const X_AUTH_ASSERTION: &str = "x-auth-assertion";
+
+/// The path the keys to verify assertions with are served at.
+pub const WELL_KNOWN_KEYS: &str = "/.well-known/cellulose-keys";
+
+/// The issuer of assertions.
+pub const ISSUER: &str = "cellulose";
+
+#[derive(Debug)]
+pub struct AssertionError(String);
+
+impl fmt::Display for AssertionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AssertionError {}
+
+fn err(msg: String) -> AssertionError {
+    AssertionError(msg)
+}
+
+/// The claims of an assertion, next to the registered ones.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AssertionClaims {
+    /// The headers returned along with the assertion, by lowercase name.
+    pub headers: BTreeMap<String, String>,
+}
+
+/// The keys assertions are signed with, and the ones published.
+struct SigningKeys {
+    /// All published keys, including the active one, and ones about to be
+    /// used or retired.
+    published: Vec<ES256KeyPair>,
+    /// The index of the key to sign with.
+    active: usize,
+}
+
+impl SigningKeys {
+    fn active(&self) -> &ES256KeyPair {
+        &self.published[self.active]
+    }
+}
+
+/// Signs assertions.
+pub struct Signer {
+    keys: ArcSwap<SigningKeys>,
+    /// How long assertions are valid for.
+    ttl: Duration,
+}
+
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signer")
+            .field("kid", self.keys.load().active().key_id())
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl Signer {
+    /// Use the key pair, with a key id derived from its public key, so
+    /// rotated keys get distinct ones.
+    pub fn new(key_pair: ES256KeyPair, ttl: Duration) -> Self {
+        let point = key_pair.public_key().public_key().to_bytes_uncompressed();
+        let kid = Sha256::digest(point)[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        Self {
+            keys: ArcSwap::from_pointee(SigningKeys {
+                published: vec![key_pair.with_key_id(&kid)],
+                active: 0,
+            }),
+            ttl,
+        }
+    }
+
+    /// Replace the keys, publishing [published], and signing with the one
+    /// at index [active] from now on. The key pairs need to carry their key
+    /// id.
+    pub(crate) fn set_keys(&self, published: Vec<ES256KeyPair>, active: usize) {
+        assert!(active < published.len(), "active key must be published");
+        self.keys.store(Arc::new(SigningKeys { published, active }));
+    }
+
+    /// Use a random key pair, valid until restarted, or replaced by local
+    /// keys, see [crate::local_keys].
+    pub fn generate(ttl: Duration) -> Self {
+        Self::new(ES256KeyPair::generate(), ttl)
+    }
+
+    /// Load the ES256 private key (PEM) from [path].
+    pub fn load(path: &Path, ttl: Duration) -> Result<Self, AssertionError> {
+        let key_pair = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| ES256KeyPair::from_pem(&pem).map_err(|e| e.to_string()))
+            .map_err(|e| err(format!("invalid ES256 key {}: {}", path.display(), e)))?;
+        Ok(Self::new(key_pair, ttl))
+    }
+
+    /// Sign an assertion of the subject and headers, for the audience
+    /// (usually the host of the original request), if known.
+    pub fn sign(
+        &self,
+        subject: Option<&str>,
+        audience: Option<&str>,
+        headers: &[(HeaderName, String)],
+    ) -> Result<String, AssertionError> {
+        let custom = AssertionClaims {
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+        };
+        let mut claims = Claims::with_custom_claims(custom, self.ttl.into()).with_issuer(ISSUER);
+        if let Some(subject) = subject {
+            claims = claims.with_subject(subject);
+        }
+        if let Some(audience) = audience {
+            claims = claims.with_audience(audience);
+        }
+        self.keys
+            .load()
+            .active()
+            .sign(claims)
+            .map_err(|e| err(format!("signing assertion: {}", e)))
+    }
+
+    /// The JWKS with the public keys.
+    pub fn jwks(&self) -> serde_json::Value {
+        let keys: Vec<_> = (self.keys.load().published.iter())
+            .map(crate::fixture::jwk)
+            .collect();
+        serde_json::json!({ "keys": keys })
+    }
+}
+
+/// Serve the keys assertions are signed with, if configured.
+pub async fn keys_handler(State(state): State<AppState>) -> Response {
+    match &state.assertion_signer {
+        Some(signer) => Json(signer.jwks()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
@@ -0,0 +1,437 @@
+//! Audit records of decisions, one JSON object per line and /auth request,
+//! written to a file or stdout, apart from the diagnostic logs (on stderr)
+//! and unaffected by their filter.
+//!
+//! Records carry the outcome and status, the subject, issuer and audience of
+//! the verified token, the policy (by name and hash of its program), the
+//! client address, the forwarded request and the latency. Health checks
+//! aren't recorded.
+//!
+//! Each record carries the hash of the line before as `prev`, so records
+//! removed or altered afterwards break the chain, see [verify]. Files are
+//! rotated once they exceed a size, the chain continues across them.
+//!
+//! If enabled, denials get a random id, recorded as `decision_id` and handed
+//! to the user, see [crate::decision_id].
+use std::{
+    cell::RefCell,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Encoder};
+use parking_lot::Mutex;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::{
+    context_request, decision_id::DecisionIds, health_checks::HealthCheck, metrics, CustomClaims,
+    Policy,
+};
+
+/// How much of the end of an existing file is read to continue its chain.
+const TAIL_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug)]
+pub struct AuditError(String);
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+fn err(msg: String) -> AuditError {
+    AuditError(msg)
+}
+
+/// The hash of a line, as referenced by the next one.
+fn hash(line: &str) -> String {
+    Base64UrlSafeNoPadding::encode_to_string(Sha256::digest(line)).expect("must encode")
+}
+
+/// The identity a decision was taken for, from the verified claims.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub struct Identity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// A string or list of strings, as in the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<Value>,
+}
+
+impl Identity {
+    pub(crate) fn from_claims(claims: &CustomClaims) -> Self {
+        let string = |name| claims.get(name).and_then(Value::as_str).map(str::to_owned);
+        Self {
+            sub: string("sub"),
+            iss: string("iss"),
+            aud: claims.get("aud").cloned(),
+        }
+    }
+}
+
+/// Details of a decision, noted while taking it, see [note].
+#[derive(Default)]
+pub(crate) struct Details {
+    pub identity: Identity,
+    pub policy: Option<Arc<str>>,
+    pub policy_hash: Option<String>,
+    pub client_ip: Option<IpAddr>,
+}
+
+impl Details {
+    pub fn set_policy(&mut self, policy: &Policy) {
+        self.policy = policy.name.clone();
+        self.policy_hash = Some(hash(&policy.cel));
+    }
+}
+
+tokio::task_local! {
+    /// The details of the decision being recorded by [record].
+    static DETAILS: RefCell<Details>;
+}
+
+/// Note details of the decision being recorded, if audit records are
+/// written.
+pub(crate) fn note(f: impl FnOnce(&mut Details)) {
+    let _ = DETAILS.try_with(|details| f(&mut details.borrow_mut()));
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Record<'a> {
+    time: String,
+    outcome: &'static str,
+    status: u16,
+    #[serde(flatten)]
+    identity: &'a Identity,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy_hash: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<&'a str>,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    decision_id: Option<String>,
+    /// The hash of the previous record, empty for the first one.
+    prev: String,
+}
+
+enum Sink {
+    Stdout,
+    File {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        max_bytes: u64,
+        keep: usize,
+    },
+}
+
+struct Inner {
+    sink: Sink,
+    /// The hash of the last line written.
+    prev: String,
+}
+
+/// Where audit records are written to.
+pub struct AuditLog {
+    inner: Mutex<Inner>,
+    decision_ids: Option<DecisionIds>,
+}
+
+/// The path of the [i]th rotated file.
+fn rotated(path: &Path, i: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", i));
+    path.into()
+}
+
+/// The hash of the last line of the file, to continue the chain with.
+fn last_hash(file: &mut File) -> io::Result<String> {
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL_SIZE)))?;
+    let mut tail = String::new();
+    file.read_to_string(&mut tail)?;
+    Ok(tail.lines().last().map(hash).unwrap_or_default())
+}
+
+impl AuditLog {
+    /// Write records to stdout.
+    pub fn stdout() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                sink: Sink::Stdout,
+                prev: String::new(),
+            }),
+            decision_ids: None,
+        }
+    }
+
+    /// Append records to the file at [path], continuing the chain of the
+    /// records in it, if any. Once it exceeds [max_bytes], it's renamed to
+    /// `<path>.1` (and so on, up to [keep] files), and a new one started.
+    pub fn open(path: PathBuf, max_bytes: u64, keep: usize) -> Result<Self, AuditError> {
+        let open = || {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(&path)?;
+            let prev = last_hash(&mut file)?;
+            Ok::<_, io::Error>((file.metadata()?.len(), file, prev))
+        };
+        let (size, file, prev) =
+            open().map_err(|e| err(format!("failed to open {}: {}", path.display(), e)))?;
+        Ok(Self {
+            inner: Mutex::new(Inner {
+                sink: Sink::File {
+                    path,
+                    file,
+                    size,
+                    max_bytes,
+                    keep,
+                },
+                prev,
+            }),
+            decision_ids: None,
+        })
+    }
+
+    /// Record an id for every denial, and hand it to the user.
+    pub fn with_decision_ids(mut self, decision_ids: DecisionIds) -> Self {
+        self.decision_ids = Some(decision_ids);
+        self
+    }
+
+    /// Append the record, with the hash of the previous one.
+    fn append(&self, mut record: Record) -> Result<(), AuditError> {
+        let mut inner = self.inner.lock();
+        record.prev = inner.prev.clone();
+        let line = serde_json::to_string(&record).expect("must serialize");
+        match &mut inner.sink {
+            Sink::Stdout => {
+                let mut stdout = io::stdout().lock();
+                writeln!(stdout, "{}", line).and_then(|_| stdout.flush())
+            }
+            Sink::File {
+                path,
+                file,
+                size,
+                max_bytes,
+                keep,
+            } => {
+                if *size > 0 && *size + line.len() as u64 >= *max_bytes {
+                    let rotate = || {
+                        for i in (1..*keep).rev() {
+                            match fs::rename(rotated(path, i), rotated(path, i + 1)) {
+                                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                                _ => {}
+                            }
+                        }
+                        match keep {
+                            0 => fs::remove_file(&*path)?,
+                            _ => fs::rename(&*path, rotated(path, 1))?,
+                        }
+                        File::create(&*path)
+                    };
+                    *file = rotate()
+                        .map_err(|e| err(format!("failed to rotate {}: {}", path.display(), e)))?;
+                    *size = 0;
+                }
+                *size += line.len() as u64 + 1;
+                writeln!(file, "{}", line)
+            }
+        }
+        .map_err(|e| err(format!("failed to write audit record: {}", e)))?;
+        inner.prev = hash(&line);
+        Ok(())
+    }
+}
+
+/// Check the chain of the records in the files, in the order they were
+/// written (oldest rotated file first). The first record may reference one
+/// that's no longer around. Returns the number of records.
+pub fn verify(paths: &[PathBuf]) -> Result<usize, AuditError> {
+    let mut prev: Option<String> = None;
+    let mut count = 0;
+    for path in paths {
+        let file = File::open(path)
+            .map_err(|e| err(format!("failed to open {}: {}", path.display(), e)))?;
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let at = || format!("{}:{}", path.display(), i + 1);
+            let line = line.map_err(|e| err(format!("failed to read {}: {}", at(), e)))?;
+            let record: Value = serde_json::from_str(&line)
+                .map_err(|e| err(format!("invalid record at {}: {}", at(), e)))?;
+            let referenced = record.get("prev").and_then(Value::as_str);
+            if let Some(prev) = &prev {
+                if referenced != Some(prev.as_str()) {
+                    return Err(err(format!("broken chain at {}", at())));
+                }
+            }
+            prev = Some(hash(&line));
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Middleware writing an audit record of each decision.
+pub async fn record(
+    State(audit_log): State<Option<Arc<AuditLog>>>,
+    rq: Request,
+    next: Next,
+) -> Response {
+    let Some(audit_log) = audit_log else {
+        return next.run(rq).await;
+    };
+    if rq.extensions().get::<HealthCheck>().is_some() {
+        return next.run(rq).await;
+    }
+    let (method, host, uri, https) = {
+        let header = |name| {
+            rq.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        (
+            header(context_request::X_FORWARDED_METHOD),
+            header(context_request::X_FORWARDED_HOST),
+            header(context_request::X_FORWARDED_URI),
+            header(context_request::X_FORWARDED_PROTO)
+                .is_some_and(|proto| proto.eq_ignore_ascii_case("https")),
+        )
+    };
+
+    let start = Instant::now();
+    let (mut response, details) = DETAILS
+        .scope(RefCell::new(Details::default()), async {
+            let response = next.run(rq).await;
+            (response, DETAILS.with(RefCell::take))
+        })
+        .await;
+
+    // handed out for denials only, users have no reason to ask about others.
+    let decision_id = audit_log
+        .decision_ids
+        .as_ref()
+        .filter(|_| !response.status().is_success())
+        .map(|decision_ids| {
+            let (id, value) = decision_ids.issue();
+            decision_ids.attach(https, &mut response, &value);
+            id
+        });
+
+    let record = Record {
+        time: DateTime::<Utc>::from(SystemTime::now()).to_rfc3339_opts(SecondsFormat::Millis, true),
+        outcome: metrics::outcome(response.status()),
+        status: response.status().as_u16(),
+        identity: &details.identity,
+        policy: details.policy.as_deref(),
+        policy_hash: details.policy_hash.as_deref(),
+        client_ip: details.client_ip,
+        method: method.as_deref(),
+        host: host.as_deref(),
+        uri: uri.as_deref(),
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+        decision_id,
+        prev: String::new(),
+    };
+    if let Err(e) = audit_log.append(record) {
+        error!(err = %e, "failed to write audit record");
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rotated, verify, AuditLog, Identity, Record};
+
+    fn record(identity: &Identity) -> Record<'_> {
+        Record {
+            time: "2024-01-01T00:00:00.000Z".to_string(),
+            outcome: "allowed",
+            status: 200,
+            identity,
+            policy: Some("admins"),
+            policy_hash: Some("abc"),
+            client_ip: Some([192, 0, 2, 1].into()),
+            method: Some("GET"),
+            host: Some("example.com"),
+            uri: Some("/admin"),
+            latency_ms: 1.5,
+            decision_id: None,
+            prev: String::new(),
+        }
+    }
+
+    #[test]
+    fn chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let claims = serde_json::json!({ "sub": "alice", "aud": ["a", "b"], "groups": [] });
+        let identity = Identity::from_claims(claims.as_object().unwrap());
+
+        // rotated after two records
+        let audit_log = AuditLog::open(path.clone(), 700, 2).expect("must open");
+        for _ in 0..3 {
+            audit_log.append(record(&identity)).expect("must append");
+        }
+        drop(audit_log);
+        // continued after reopening
+        let audit_log = AuditLog::open(path.clone(), 700, 2).expect("must open");
+        audit_log.append(record(&identity)).expect("must append");
+        assert!(!rotated(&path, 2).exists());
+
+        let first = std::fs::read_to_string(rotated(&path, 1)).unwrap();
+        let line: serde_json::Value = serde_json::from_str(first.lines().next().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "time": "2024-01-01T00:00:00.000Z",
+                "outcome": "allowed",
+                "status": 200,
+                "sub": "alice",
+                "aud": ["a", "b"],
+                "policy": "admins",
+                "policy_hash": "abc",
+                "client_ip": "192.0.2.1",
+                "method": "GET",
+                "host": "example.com",
+                "uri": "/admin",
+                "latency_ms": 1.5,
+                "prev": "",
+            }),
+            line
+        );
+        let files = [rotated(&path, 1), path.clone()];
+        assert_eq!(4, verify(&files).expect("must verify"));
+
+        // altering a record breaks the chain
+        std::fs::write(rotated(&path, 1), first.replacen("alice", "mallory", 1)).unwrap();
+        assert!(verify(&files).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Projection of claims into W3C Baggage, so services behind the proxy (and
+//! their traces) carry authorization context without parsing the token.
+use serde_json::Value;
+
+/// The header carrying baggage.
+pub const BAGGAGE: &str = "baggage";
+
+/// Limits from the W3C Baggage specification.
+const MAX_MEMBERS: usize = 64;
+const MAX_LENGTH: usize = 8192;
+
+/// Percent-encode everything that isn't a baggage-octet, as well as `%`
+/// itself.
+fn encode_value(v: &str) -> String {
+    let mut out = String::with_capacity(v.len());
+    for b in v.bytes() {
+        match b {
+            b'!' | b'#'..=b'+' | b'-'..=b':' | b'<'..=b'[' | b']'..=b'~' if b != b'%' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Whether the claim name can be used as baggage key, which needs to be an
+/// RFC 7230 token.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Render the baggage header value for the selected claims, keeping members
+/// of the incoming baggage that aren't overridden.
+/// Claims that aren't strings, numbers or bools are skipped.
+/// Returns None if there's no member.
+pub fn render(
+    claims: &serde_json::Map<String, Value>,
+    selected: &[String],
+    incoming: Option<&str>,
+) -> Option<String> {
+    let mut members = selected
+        .iter()
+        .filter(|name| is_token(name))
+        .filter_map(|name| {
+            let value = match claims.get(name)? {
+                Value::String(s) => encode_value(s),
+                v @ (Value::Number(_) | Value::Bool(_)) => v.to_string(),
+                _ => return None,
+            };
+            Some(format!("{}={}", name, value))
+        })
+        .collect::<Vec<_>>();
+
+    // Incoming members for the selected claims are dropped, so clients can't
+    // spoof them.
+    let incoming = incoming
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|member| {
+            let key = member.split(['=', ';']).next().unwrap_or_default().trim();
+            !key.is_empty() && !selected.iter().any(|name| name == key)
+        })
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    members.extend(incoming);
+
+    let mut out = String::new();
+    for member in members.iter().take(MAX_MEMBERS) {
+        if out.len() + member.len() + 1 > MAX_LENGTH {
+            break;
+        }
+        if !out.is_empty() {
+            out.push(',');
+        }
+        out.push_str(member);
+    }
+
+    (!out.is_empty()).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn baggage() {
+        let claims = serde_json::json!({
+            "tenant": "acme corp",
+            "tier": 3,
+            "groups": ["admin"],
+        });
+        let claims = claims.as_object().unwrap();
+        let selected = ["tenant", "tier", "groups", "missing"].map(String::from);
+
+        assert_eq!(
+            Some("tenant=acme%20corp,tier=3".to_string()),
+            render(claims, &selected, None)
+        );
+        assert_eq!(
+            Some("tenant=acme%20corp,tier=3,trace=1;p=x".to_string()),
+            render(claims, &selected, Some("tenant=spoofed, trace=1;p=x"))
+        );
+        assert_eq!(None, render(claims, &[], None));
+    }
+}
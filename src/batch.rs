@@ -0,0 +1,368 @@
+//! Batch decision API, evaluating many (hypothetical) requests at once.
+//!
+//! Entries are decided like requests to /auth, with the same policy
+//! selection, and refused altogether while the clock is skewed. Entries
+//! with invalid signatures count towards the [crate::lockout] of the client,
+//! and once banned, its batches and their remaining entries are answered
+//! with a 429. Each entry takes a token from the [crate::rate_limit] buckets
+//! of the client and its subject, entries exceeding them are denied with a
+//! 429 too. As nothing
+//! is granted by them, they aren't recorded as decisions: not in the
+//! metrics, the [crate::audit] log, nor sent to the
+//! [crate::denial_webhook]. [crate::chaos] isn't injected either, it's
+//! meant to exercise the proxies relying on /auth.
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{debug, warn};
+
+use crate::{decide, resolve_policy, AppState, DecisionMetadata, Denial, VerificationConfig};
+
+/// The maximum number of entries accepted in a single batch.
+pub const MAX_BATCH_SIZE: usize = 1000;
+
+/// A single header value, or multiple values for the same header.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum HeaderValues {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+/// A single request to decide on.
+#[derive(serde::Deserialize)]
+pub struct BatchEntry {
+    /// Request headers, made available to the policy as `request_headers`.
+    #[serde(default)]
+    headers: HashMap<String, HeaderValues>,
+
+    /// The bearer token. Other identities (like API keys) are taken from the
+    /// headers.
+    token: Option<String>,
+
+    /// A CEL expression that returns true if access should be granted, or
+    /// false if not, like the `cel_str` query parameter of /auth.
+    cel_str: Option<String>,
+
+    /// The name of a configured policy to evaluate, like the `policy` query
+    /// parameter of /auth. Without this nor [cel_str], the default one
+    /// configured on the server is used.
+    policy: Option<String>,
+
+    /// Scopes relevant for this policy. If set, the ones granted to the
+    /// token are returned in the decision on allow.
+    scopes: Option<Vec<String>>,
+
+    /// Signature algorithms to accept.
+    allowed_algs: Option<Vec<String>>,
+
+    /// Additional checks on the token, same as the query parameters of /auth.
+    #[serde(default)]
+    verification: VerificationConfig,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct BatchDecision {
+    allowed: bool,
+    /// The status code /auth would have responded with.
+    status: u16,
+    /// The scopes relevant for the policy granted to the token, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scopes: Option<Vec<String>>,
+    /// Selected claims in W3C baggage format, if configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    baggage: Option<String>,
+    /// Identity headers, and the ones returned by the policy, by
+    /// (lowercase) name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    /// Why access was denied, if the policy returned a reason.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+fn to_header_map(headers: HashMap<String, HeaderValues>) -> Result<HeaderMap, StatusCode> {
+    let mut header_map = HeaderMap::new();
+    for (name, values) in headers {
+        let name = HeaderName::try_from(name).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let values = match values {
+            HeaderValues::Single(v) => vec![v],
+            HeaderValues::Multiple(vs) => vs,
+        };
+        for value in values {
+            header_map.append(
+                name.clone(),
+                HeaderValue::try_from(value).map_err(|_| StatusCode::BAD_REQUEST)?,
+            );
+        }
+    }
+    Ok(header_map)
+}
+
+/// Decide on a single entry, returning the metadata on allow.
+async fn decide_entry(state: &AppState, entry: BatchEntry) -> Result<DecisionMetadata, Denial> {
+    let headers = to_header_map(entry.headers)?;
+    let decision = decide(
+        state,
+        entry.token.as_deref(),
+        resolve_policy(state, entry.cel_str, entry.policy)?,
+        &entry.verification,
+        entry.allowed_algs.as_deref(),
+        headers,
+    )
+    .await?;
+
+    Ok(decision.metadata(entry.scopes.as_deref()))
+}
+
+/// Decide on all entries, returning a decision per entry, in the same order.
+pub async fn handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(entries): Json<Vec<BatchEntry>>,
+) -> Result<Response, StatusCode> {
+    let client_ip = state.forwarded_for.client_ip(&headers);
+    let banned = |ip| {
+        let lockout = state.lockout.as_ref()?;
+        lockout.banned(ip?, Instant::now())
+    };
+    if let Some(retry_after) = banned(client_ip) {
+        debug!(?client_ip, "client banned");
+        return Ok(Denial {
+            retry_after: Some(retry_after),
+            ..StatusCode::TOO_MANY_REQUESTS.into()
+        }
+        .into_response());
+    }
+
+    if entries.len() > MAX_BATCH_SIZE {
+        debug!(len = entries.len(), "batch too large");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    if state.clock_check.as_ref().is_some_and(|c| c.refusing()) {
+        warn!("refusing batch decision, the system clock is skewed");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let mut decisions = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let rate_limited = |token: Option<&str>| {
+            let rate_limiter = state.rate_limiter.as_ref();
+            rate_limiter.is_some_and(|r| r.check(client_ip, token).is_err())
+        };
+        let decision = if banned(client_ip).is_some() || rate_limited(entry.token.as_deref()) {
+            Err(StatusCode::TOO_MANY_REQUESTS.into())
+        } else {
+            decide_entry(&state, entry).await
+        };
+        if let (Err(denial), Some(lockout), Some(ip)) = (&decision, &state.lockout, client_ip) {
+            if denial.counts_as_guess {
+                lockout.record_failure(ip, Instant::now());
+            }
+        }
+        decisions.push(match decision {
+            Ok(DecisionMetadata {
+                scopes,
+                baggage,
+                headers,
+            }) => BatchDecision {
+                allowed: true,
+                status: StatusCode::OK.as_u16(),
+                scopes,
+                baggage,
+                headers: headers
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value))
+                    .collect(),
+                reason: None,
+            },
+            Err(Denial { status, reason, .. }) => BatchDecision {
+                allowed: false,
+                status: status.as_u16(),
+                scopes: None,
+                baggage: None,
+                headers: BTreeMap::new(),
+                reason,
+            },
+        });
+    }
+
+    Ok(Json(decisions).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+        response::Response,
+        Router,
+    };
+    use jwt_simple::prelude::{Claims, HS256Key, MACLike};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::{to_header_map, HeaderValues, MAX_BATCH_SIZE};
+    use crate::{
+        lockout::Lockout,
+        rate_limit::{Limit, RateLimiter},
+        tests::{router, state, token},
+        AppState,
+    };
+
+    /// Post [entries] to the batch endpoint of [router].
+    async fn batch(router: &Router, entries: &Value) -> Response {
+        let rq = Request::post("/auth/batch")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-forwarded-for", "192.0.2.1")
+            .body(Body::from(entries.to_string()))
+            .unwrap();
+        router.clone().oneshot(rq).await.expect("infallible")
+    }
+
+    /// The decisions on [entries] by [router], as allowed and status.
+    async fn decisions(router: &Router, entries: Value) -> Vec<(bool, u64)> {
+        let response = batch(router, &entries).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let decisions: Vec<Value> = serde_json::from_slice(&body).expect("must be decisions");
+        decisions
+            .iter()
+            .map(|d| {
+                (
+                    d["allowed"].as_bool().unwrap(),
+                    d["status"].as_u64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn handler() {
+        let router = router(state(r#"jwt_claims.sub == "alice""#));
+        let statuses = decisions(
+            &router,
+            json!([
+                {"token": token("alice")},
+                {"token": token("bob")},
+                {},
+                {"token": token("admin"), "policy": "admins"},
+                {"token": token("bob"), "cel_str": r#"jwt_claims.sub == "bob""#},
+                {"token": token("bob"), "policy": "unknown"},
+                {"token": token("bob"), "policy": "admins", "cel_str": "true"},
+            ]),
+        )
+        .await;
+        assert_eq!(
+            vec![
+                (true, 200),
+                (false, 403),
+                (false, 401),
+                (true, 200),
+                (true, 200),
+                (false, 500),
+                (false, 400),
+            ],
+            statuses
+        );
+
+        let entries = Value::Array(vec![json!({}); MAX_BATCH_SIZE + 1]);
+        let response = batch(&router, &entries).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+    }
+
+    #[tokio::test]
+    async fn lockout() {
+        let state = AppState {
+            lockout: Some(Arc::new(Lockout::new(
+                2,
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+            ))),
+            ..state(r#"jwt_claims.sub == "alice""#)
+        };
+        let router = router(state);
+        let forged = HS256Key::generate()
+            .authenticate(Claims::create(jwt_simple::prelude::Duration::from_mins(5)))
+            .unwrap();
+
+        // invalid signatures count, and ban for the rest of the batch
+        let entries = json!([
+            {"token": token("bob")},
+            {"token": forged},
+            {"token": forged},
+            {"token": token("alice")},
+        ]);
+        assert_eq!(
+            vec![(false, 403), (false, 401), (false, 401), (false, 429)],
+            decisions(&router, entries).await
+        );
+
+        // and for further batches
+        let response = batch(&router, &json!([{"token": token("alice")}])).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let limit = Limit {
+            per_second: 0.001,
+            burst: 2,
+        };
+        let state = AppState {
+            rate_limiter: Some(Arc::new(RateLimiter::new(None, Some(limit)))),
+            ..state("true")
+        };
+        let router = router(state);
+
+        // per entry, by subject
+        let entries = json!([
+            {"token": token("alice")},
+            {"token": token("alice")},
+            {"token": token("alice")},
+            {"token": token("bob")},
+        ]);
+        assert_eq!(
+            vec![(true, 200), (true, 200), (false, 429), (true, 200)],
+            decisions(&router, entries).await
+        );
+        // and across batches
+        assert_eq!(
+            vec![(false, 429)],
+            decisions(&router, json!([{"token": token("alice")}])).await
+        );
+    }
+
+    #[test]
+    fn header_map() {
+        let headers = HashMap::from([
+            (
+                "Host".to_string(),
+                HeaderValues::Single("example.com".into()),
+            ),
+            (
+                "x-foo".to_string(),
+                HeaderValues::Multiple(vec!["a".into(), "b".into()]),
+            ),
+        ]);
+        let header_map = to_header_map(headers).expect("must convert");
+        assert_eq!("example.com", header_map["host"]);
+        assert_eq!(2, header_map.get_all("x-foo").iter().count());
+
+        let headers = HashMap::from([("in valid".to_string(), HeaderValues::Single("".into()))]);
+        assert_eq!(Err(StatusCode::BAD_REQUEST), to_header_map(headers));
+    }
+}
@@ -0,0 +1,336 @@
+//! Session cookies set after verifying a bearer token, so following checks
+//! of the same token skip parsing and verifying it against the key store.
+//!
+//! The cookie carries the claims, encrypted and authenticated (AES-256-GCM)
+//! with a key only cellulose knows, and is bound to the token and the
+//! verification options it was verified with: it's only used along with
+//! the very same token, so it never outlives the token in the client, and
+//! a check with stricter options verifies the token again. It expires with
+//! the token, or after the configured lifetime, whichever comes first.
+//!
+//! The proxy needs to return the Set-Cookie header of the auth response to
+//! the client.
+//!
+//! With local keys, they're rotated, see [crate::local_keys], and cookies
+//! carry the id of the key they're encrypted with.
+use std::{
+    fmt,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, OsRng},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use arc_swap::ArcSwap;
+use axum::http::{HeaderMap, HeaderValue};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    context_request::X_FORWARDED_PROTO, login::cookie, verification::VerificationConfig,
+    CustomClaims,
+};
+
+/// The name of the cookie.
+pub const COOKIE: &str = "cellulose_bearer_session";
+
+/// Browsers drop larger cookies, tokens with that many claims are verified
+/// every time.
+const MAX_COOKIE_SIZE: usize = 4000;
+
+const NONCE_SIZE: usize = 12;
+
+#[derive(Debug)]
+pub struct BearerSessionError(String);
+
+impl fmt::Display for BearerSessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for BearerSessionError {}
+
+fn err(msg: String) -> BearerSessionError {
+    BearerSessionError(msg)
+}
+
+/// The content of the cookie.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Session {
+    claims: CustomClaims,
+    /// When the session expires, in seconds since the epoch.
+    exp: u64,
+    /// What the session is bound to, see [binding].
+    bound: String,
+}
+
+/// The binding of a session to the token and how it was verified, see
+/// [VerificationConfig::canonical].
+pub(crate) fn binding(
+    token: &str,
+    options: &VerificationConfig,
+    allowed_algs: Option<&[String]>,
+) -> String {
+    let digest = Sha256::new()
+        .chain_update(token)
+        .chain_update([0])
+        .chain_update(options.canonical())
+        .chain_update([0])
+        .chain_update(format!("{:?}", allowed_algs))
+        .finalize();
+    Base64UrlSafeNoPadding::encode_to_string(digest).expect("must encode")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The keys sessions are encrypted with.
+struct SessionKeys {
+    /// The id of the key new sessions are encrypted with, if keys have ids.
+    /// It's prefixed to the cookie value, as `<kid>.`.
+    active: Option<String>,
+    /// The keys sessions are accepted with, by id.
+    ciphers: Vec<(Option<String>, Aes256Gcm)>,
+}
+
+impl SessionKeys {
+    fn cipher(&self, kid: Option<&str>) -> Option<&Aes256Gcm> {
+        self.ciphers
+            .iter()
+            .find(|(id, _)| id.as_deref() == kid)
+            .map(|(_, cipher)| cipher)
+    }
+}
+
+/// Issues and reads session cookies.
+#[derive(Clone)]
+pub struct BearerSessions {
+    keys: Arc<ArcSwap<SessionKeys>>,
+    /// How long sessions are valid for, at most.
+    ttl: Duration,
+    /// The domain to set the cookie for, if not only the host of the
+    /// original request.
+    cookie_domain: Option<String>,
+}
+
+impl BearerSessions {
+    /// Use a random key, so sessions are only valid for this instance, until
+    /// it restarts.
+    pub fn new(ttl: Duration, cookie_domain: Option<String>) -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self::with_key(&key, ttl, cookie_domain)
+    }
+
+    /// Derive the key from the secret in the file at [path], so sessions are
+    /// valid for all instances sharing it.
+    pub fn load(
+        path: &Path,
+        ttl: Duration,
+        cookie_domain: Option<String>,
+    ) -> Result<Self, BearerSessionError> {
+        let secret = std::fs::read(path)
+            .map_err(|e| err(format!("failed to read {}: {}", path.display(), e)))?;
+        let secret = secret.trim_ascii();
+        if secret.len() < 32 {
+            return Err(err(format!(
+                "secret in {} too short, needs at least 32 bytes",
+                path.display()
+            )));
+        }
+        Ok(Self::with_key(&Sha256::digest(secret), ttl, cookie_domain))
+    }
+
+    fn with_key(key: &[u8], ttl: Duration, cookie_domain: Option<String>) -> Self {
+        let cipher = Aes256Gcm::new_from_slice(key).expect("key must be 32 bytes");
+        Self {
+            keys: Arc::new(ArcSwap::from_pointee(SessionKeys {
+                active: None,
+                ciphers: vec![(None, cipher)],
+            })),
+            ttl,
+            cookie_domain,
+        }
+    }
+
+    /// Replace the keys, by id, encrypting new sessions with the one with
+    /// the [active] id. Keys need to be 32 bytes.
+    pub(crate) fn set_keys(&self, keys: &[(String, Vec<u8>)], active: &str) {
+        let ciphers = keys
+            .iter()
+            .filter_map(|(kid, key)| {
+                Some((Some(kid.clone()), Aes256Gcm::new_from_slice(key).ok()?))
+            })
+            .collect();
+        self.keys.store(Arc::new(SessionKeys {
+            active: Some(active.to_string()),
+            ciphers,
+        }));
+    }
+
+    /// The claims of the session the request carries, if it's valid and
+    /// bound to the token and options.
+    pub(crate) fn claims(
+        &self,
+        headers: &HeaderMap,
+        token: &str,
+        options: &VerificationConfig,
+        allowed_algs: Option<&[String]>,
+    ) -> Option<CustomClaims> {
+        let value = cookie(headers, COOKIE)?;
+        let (kid, value) = match value.split_once('.') {
+            Some((kid, value)) => (Some(kid), value),
+            None => (None, value),
+        };
+        let sealed = Base64UrlSafeNoPadding::decode_to_vec(value, None).ok()?;
+        if sealed.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let plaintext = self
+            .keys
+            .load()
+            .cipher(kid)?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        let session: Session = serde_json::from_slice(&plaintext).ok()?;
+        (session.exp > now() && session.bound == binding(token, options, allowed_algs))
+            .then_some(session.claims)
+    }
+
+    /// The Set-Cookie header establishing a session for the verified token,
+    /// if it fits into a cookie.
+    pub(crate) fn issue(
+        &self,
+        headers: &HeaderMap,
+        token: &str,
+        options: &VerificationConfig,
+        allowed_algs: Option<&[String]>,
+        claims: &CustomClaims,
+    ) -> Option<HeaderValue> {
+        let now = now();
+        let mut exp = now + self.ttl.as_secs();
+        if let Some(token_exp) = claims.get("exp").and_then(|exp| exp.as_f64()) {
+            exp = exp.min(token_exp as u64);
+        }
+        if exp <= now {
+            return None;
+        }
+
+        let session = Session {
+            claims: claims.clone(),
+            exp,
+            bound: binding(token, options, allowed_algs),
+        };
+        let plaintext = serde_json::to_vec(&session).ok()?;
+        let mut nonce = [0; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let keys = self.keys.load();
+        let ciphertext = keys
+            .cipher(keys.active.as_deref())?
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .ok()?;
+        let mut value =
+            Base64UrlSafeNoPadding::encode_to_string([&nonce[..], &ciphertext].concat()).ok()?;
+        if let Some(kid) = &keys.active {
+            value = format!("{}.{}", kid, value);
+        }
+
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            COOKIE,
+            value,
+            exp - now
+        );
+        let https = headers
+            .get(X_FORWARDED_PROTO)
+            .is_some_and(|proto| proto.as_bytes().eq_ignore_ascii_case(b"https"));
+        if https {
+            cookie.push_str("; Secure");
+        }
+        if let Some(domain) = &self.cookie_domain {
+            cookie.push_str(&format!("; Domain={}", domain));
+        }
+        if cookie.len() > MAX_COOKIE_SIZE {
+            return None;
+        }
+        HeaderValue::try_from(cookie).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use jwt_simple::prelude::HashSet;
+
+    use super::BearerSessions;
+    use crate::verification::VerificationConfig;
+
+    #[test]
+    fn issue_and_read() {
+        let sessions = BearerSessions::new(Duration::from_secs(60), None);
+        let claims = serde_json::json!({ "sub": "alice", "exp": 4_000_000_000u64 });
+        let claims = claims.as_object().unwrap();
+        let options = VerificationConfig {
+            allowed_audiences: Some(HashSet::from_iter(["a".to_string(), "b".to_string()])),
+            ..Default::default()
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        let set_cookie = sessions
+            .issue(&headers, "a.b.c", &options, None, claims)
+            .expect("must issue");
+        let set_cookie = set_cookie.to_str().unwrap();
+        assert!(set_cookie.starts_with("cellulose_bearer_session="));
+        assert!(set_cookie.ends_with("; Path=/; Max-Age=60; HttpOnly; SameSite=Lax; Secure"));
+
+        let mut headers = HeaderMap::new();
+        let cookie = set_cookie.split(';').next().unwrap();
+        headers.insert(header::COOKIE, HeaderValue::from_str(cookie).unwrap());
+        assert_eq!(
+            Some(claims),
+            sessions.claims(&headers, "a.b.c", &options, None).as_ref()
+        );
+
+        // bound to the token and options
+        assert_eq!(None, sessions.claims(&headers, "d.e.f", &options, None));
+        let stricter = VerificationConfig {
+            required_subject: Some("alice".to_string()),
+            ..options.clone()
+        };
+        assert_eq!(None, sessions.claims(&headers, "a.b.c", &stricter, None));
+        let algs = ["ES256".to_string()];
+        assert_eq!(
+            None,
+            sessions.claims(&headers, "a.b.c", &options, Some(&algs))
+        );
+
+        // issued by another instance
+        let other = BearerSessions::new(Duration::from_secs(60), None);
+        assert_eq!(None, other.claims(&headers, "a.b.c", &options, None));
+
+        // not beyond the expiry of the token
+        let claims = serde_json::json!({ "sub": "alice", "exp": 1 });
+        assert_eq!(
+            None,
+            sessions.issue(
+                &headers,
+                "a.b.c",
+                &options,
+                None,
+                claims.as_object().unwrap()
+            )
+        );
+    }
+}
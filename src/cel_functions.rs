@@ -0,0 +1,375 @@
+//! Custom functions registered in the CEL context, in addition to the ones
+//! the interpreter provides.
+use std::{collections::HashMap, net::IpAddr, sync::Arc, sync::LazyLock, time::SystemTime};
+
+use cel_interpreter::{
+    extractors::{Arguments, This},
+    objects::Key,
+    Context, ExecutionError, FunctionContext, Value,
+};
+use chrono::{DateTime, Datelike, FixedOffset, Timelike, Utc};
+use jwt_simple::reexports::ct_codecs::{Base64NoPadding, Base64UrlSafeNoPadding, Decoder};
+use parking_lot::RwLock;
+use regex::{Regex, RegexBuilder};
+
+use crate::{context_schema, forwarded_for::Cidr};
+
+/// The maximum number of compiled regular expressions kept. Patterns are
+/// part of the (possibly request-supplied) programs, so the cache is
+/// cleared once full, rather than growing forever.
+const REGEX_CACHE_SIZE: usize = 1000;
+
+/// The maximum size of a compiled regular expression, in bytes.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+static REGEX_CACHE: LazyLock<RwLock<HashMap<String, Regex>>> = LazyLock::new(Default::default);
+
+/// Register all custom functions.
+pub fn register(context: &mut Context) {
+    context.add_function(context_schema::MATCHES.name, matches);
+    context.add_function(context_schema::IP_IN_CIDR.name, ip_in_cidr);
+    context.add_function(context_schema::BASE64_DECODE.name, base64_decode);
+    context.add_function(context_schema::PARSE_JSON.name, parse_json);
+    context.add_function(context_schema::IMPERSONATED_BY.name, impersonated_by);
+    for accessor in context_schema::TIMESTAMP_ACCESSORS {
+        context.add_function(accessor.name, timestamp_accessor);
+    }
+}
+
+/// The current time, as CEL timestamp in UTC.
+pub fn now() -> Value {
+    Value::Timestamp(DateTime::<Utc>::from(SystemTime::now()).fixed_offset())
+}
+
+/// Returns the compiled regular expression, compiling and caching it if
+/// not seen yet.
+fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    if let Some(regex) = REGEX_CACHE.read().get(pattern) {
+        return Ok(regex.clone());
+    }
+
+    let regex = RegexBuilder::new(pattern)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()?;
+
+    let mut cache = REGEX_CACHE.write();
+    if cache.len() >= REGEX_CACHE_SIZE {
+        cache.clear();
+    }
+    cache.insert(pattern.to_owned(), regex.clone());
+    Ok(regex)
+}
+
+/// Whether the subject matches the regular expression. Replaces the
+/// interpreter's builtin, which compiles the pattern on every call.
+/// Both `subject.matches(pattern)` and `matches(subject, pattern)` work.
+fn matches(
+    ftx: &FunctionContext,
+    This(subject): This<Arc<String>>,
+    pattern: Arc<String>,
+) -> Result<bool, ExecutionError> {
+    let regex = compile(&pattern)
+        .map_err(|e| ftx.error(format!("'{}' not a valid regex: {}", pattern, e)))?;
+    Ok(regex.is_match(&subject))
+}
+
+/// Whether the address is in the CIDR range. IPv4-mapped IPv6 addresses
+/// are treated as IPv4 ones, addresses of the other family never match.
+/// A null address (like `client_ip` without X-Forwarded-For header) never
+/// matches either, as the interpreter doesn't short-circuit `&&`, so it
+/// can't be guarded against.
+fn ip_in_cidr(
+    ftx: &FunctionContext,
+    This(ip): This<Value>,
+    cidr: Arc<String>,
+) -> Result<bool, ExecutionError> {
+    let range = cidr
+        .parse::<Cidr>()
+        .map_err(|e| ftx.error(format!("'{}' not a valid CIDR: {}", cidr, e)))?;
+    let ip = match ip {
+        Value::String(ip) => ip
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|e| ftx.error(format!("'{}' not a valid IP address: {}", ip, e)))?,
+        Value::Null => return Ok(false),
+        v => return Err(ftx.error(format!("expected string or null, got {}", v.type_of()))),
+    };
+
+    Ok(range.contains(ip))
+}
+
+/// Decode base64, in either the standard or URL-safe alphabet, with or
+/// without padding.
+fn base64_decode(
+    ftx: &FunctionContext,
+    This(encoded): This<Arc<String>>,
+) -> Result<Value, ExecutionError> {
+    let trimmed = encoded.trim().trim_end_matches('=');
+    let decoded = if trimmed.contains(['-', '_']) {
+        Base64UrlSafeNoPadding::decode_to_vec(trimmed, None)
+    } else {
+        Base64NoPadding::decode_to_vec(trimmed, None)
+    };
+    decoded
+        .map(|decoded| Value::Bytes(Arc::new(decoded)))
+        .map_err(|e| ftx.error(format!("not valid base64: {}", e)))
+}
+
+/// Parse a JSON document, from a string or bytes, into the corresponding
+/// CEL value.
+fn parse_json(ftx: &FunctionContext, This(json): This<Value>) -> Result<Value, ExecutionError> {
+    let parsed = match &json {
+        Value::String(s) => serde_json::from_str::<serde_json::Value>(s),
+        Value::Bytes(b) => serde_json::from_slice::<serde_json::Value>(b),
+        v => return Err(ftx.error(format!("expected string or bytes, got {}", v.type_of()))),
+    }
+    .map_err(|e| ftx.error(format!("not valid JSON: {}", e)))?;
+
+    cel_interpreter::to_value(parsed).map_err(|e| ftx.error(e.to_string()))
+}
+
+/// Whether the subject is one of the actors in the delegation chain of the
+/// token, see [crate::context_jwt::actors].
+fn impersonated_by(ftx: &FunctionContext, subject: Arc<String>) -> Result<bool, ExecutionError> {
+    let Value::List(actors) = ftx.ptx.get_variable(context_schema::ACTORS.name)? else {
+        return Err(ftx.error(format!("{} is not a list", context_schema::ACTORS.name)));
+    };
+    let sub = Key::String(Arc::new("sub".to_string()));
+    Ok(actors.iter().any(|actor| match actor {
+        Value::Map(actor) => actor
+            .map
+            .get(&sub)
+            .is_some_and(|s| matches!(s, Value::String(s) if *s == subject)),
+        _ => false,
+    }))
+}
+
+/// Parse a time zone, either "UTC" or a fixed offset like "+01:00".
+fn parse_time_zone(tz: &str) -> Result<FixedOffset, String> {
+    if tz.eq_ignore_ascii_case("utc") || tz == "Z" {
+        return Ok(FixedOffset::east_opt(0).expect("must be valid"));
+    }
+    tz.parse::<FixedOffset>()
+        .map_err(|_| "expected UTC or an offset like +01:00".to_string())
+}
+
+/// The timestamp accessors, like `getHours`, dispatched by the name the
+/// function is called with. Both `ts.getHours(tz)` and `getHours(ts, tz)`
+/// work, the time zone defaults to UTC.
+fn timestamp_accessor(
+    ftx: &FunctionContext,
+    This(ts): This<DateTime<FixedOffset>>,
+    Arguments(args): Arguments,
+) -> Result<i64, ExecutionError> {
+    // without a target, the timestamp is the first argument
+    let tz = match args.get(if ftx.this.is_some() { 0 } else { 1 }) {
+        Some(Value::String(tz)) => parse_time_zone(tz)
+            .map_err(|e| ftx.error(format!("'{}' not a valid time zone: {}", tz, e)))?,
+        Some(v) => return Err(ftx.error(format!("expected string, got {}", v.type_of()))),
+        None => FixedOffset::east_opt(0).expect("must be valid"),
+    };
+    let ts = ts.with_timezone(&tz);
+
+    Ok(match ftx.name.as_str() {
+        "getFullYear" => ts.year() as i64,
+        "getMonth" => ts.month0() as i64,
+        "getDayOfYear" => ts.ordinal0() as i64,
+        "getDayOfMonth" => ts.day0() as i64,
+        "getDate" => ts.day() as i64,
+        "getDayOfWeek" => ts.weekday().num_days_from_sunday() as i64,
+        "getHours" => ts.hour() as i64,
+        "getMinutes" => ts.minute() as i64,
+        "getSeconds" => ts.second() as i64,
+        "getMilliseconds" => (ts.nanosecond() / 1_000_000 % 1000) as i64,
+        name => return Err(ftx.error(format!("unknown accessor {}", name))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use cel_interpreter::{Context, Program, Value};
+
+    use super::{now, register, REGEX_CACHE};
+    use crate::context_jwt::actors;
+
+    #[test]
+    fn matches() {
+        let mut context = Context::default();
+        context
+            .add_variable("uri", "/api/v2/users")
+            .expect("must add");
+        register(&mut context);
+
+        for (expr, expected) in [
+            (r#"uri.matches("^/api/v[0-9]+/")"#, true),
+            (r#"matches(uri, "^/api/v[0-9]+/")"#, true),
+            (r#"uri.matches("^/admin/")"#, false),
+        ] {
+            let program = Program::compile(expr).expect("must compile");
+            assert_eq!(
+                Value::Bool(expected),
+                program.execute(&context).expect("must execute"),
+                "{}",
+                expr
+            );
+        }
+        assert!(REGEX_CACHE.read().contains_key("^/api/v[0-9]+/"));
+
+        let program = Program::compile(r#"uri.matches("(")"#).expect("must compile");
+        assert!(program.execute(&context).is_err());
+    }
+
+    #[test]
+    fn decode() {
+        let mut context = Context::default();
+        context
+            .add_variable("header", "eyJ0ZW5hbnQiOiJhY21lIiwiaWRzIjpbMSwyXX0")
+            .expect("must add");
+        register(&mut context);
+
+        for (expr, expected) in [
+            (r#"base64_decode("aGk=") == b"hi""#, true),
+            (r#"base64_decode("aGk") == b"hi""#, true),
+            (r#"size(base64_decode("-_8")) == 2"#, true),
+            (
+                r#"parse_json(base64_decode(header)).tenant == "acme""#,
+                true,
+            ),
+            (r#"2 in parse_json(base64_decode(header)).ids"#, true),
+            (r#"parse_json("{\"a\": {\"b\": true}}").a.b"#, true),
+        ] {
+            let program = Program::compile(expr).expect("must compile");
+            assert_eq!(
+                Value::Bool(expected),
+                program.execute(&context).expect("must execute"),
+                "{}",
+                expr
+            );
+        }
+
+        for expr in [
+            r#"base64_decode("!!")"#,
+            r#"parse_json("{")"#,
+            "parse_json(1)",
+        ] {
+            let program = Program::compile(expr).expect("must compile");
+            assert!(program.execute(&context).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn timestamp_accessors() {
+        let mut context = Context::default();
+        let ts = chrono::DateTime::parse_from_rfc3339("2024-03-10T23:30:15.250Z").unwrap();
+        context.add_variable_from_value("ts", Value::Timestamp(ts));
+        context.add_variable_from_value("now", now());
+        register(&mut context);
+
+        for expr in [
+            "ts.getFullYear() == 2024",
+            "ts.getMonth() == 2",
+            "ts.getDate() == 10 && ts.getDayOfMonth() == 9",
+            "ts.getDayOfYear() == 69",
+            // a Sunday in UTC, Monday morning further east
+            "ts.getDayOfWeek() == 0",
+            r#"ts.getDayOfWeek("+02:00") == 1 && ts.getHours("+02:00") == 1"#,
+            r#"getHours(ts, "-05:30") == 18 && getMinutes(ts, "-05:30") == 0"#,
+            r#"ts.getHours("UTC") == 23 && ts.getSeconds() == 15"#,
+            "ts.getMilliseconds() == 250",
+            "now > ts && now.getFullYear() >= 2024",
+        ] {
+            let program = Program::compile(expr).expect("must compile");
+            assert_eq!(
+                Value::Bool(true),
+                program.execute(&context).expect("must execute"),
+                "{}",
+                expr
+            );
+        }
+
+        for expr in [r#"ts.getHours("Europe/Berlin")"#, "ts.getHours(1)"] {
+            let program = Program::compile(expr).expect("must compile");
+            assert!(program.execute(&context).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn impersonated_by() {
+        let claims = serde_json::json!({
+            "sub": "alice",
+            "act": {
+                "sub": "gateway-svc",
+                "client_id": "gw",
+                "act": { "sub": "batch-svc" },
+            },
+        });
+        let mut context = Context::default();
+        context.add_variable_from_value(
+            "actors",
+            actors(claims.as_object().unwrap(), Default::default()),
+        );
+        register(&mut context);
+
+        for (expr, expected) in [
+            (r#"impersonatedBy("gateway-svc")"#, true),
+            (r#"impersonatedBy("batch-svc")"#, true),
+            (r#"impersonatedBy("alice")"#, false),
+            (
+                r#"actors[0].sub == "gateway-svc" && actors[0].client_id == "gw""#,
+                true,
+            ),
+            (r#"size(actors) == 2 && !has(actors[0].act)"#, true),
+        ] {
+            let program = Program::compile(expr).expect("must compile");
+            assert_eq!(
+                Value::Bool(expected),
+                program.execute(&context).expect("must execute"),
+                "{}",
+                expr
+            );
+        }
+
+        // tokens without act claim have no actors
+        let mut context = Context::default();
+        context.add_variable_from_value("actors", actors(&Default::default(), Default::default()));
+        register(&mut context);
+        let program = Program::compile(r#"impersonatedBy("gateway-svc")"#).unwrap();
+        assert_eq!(Value::Bool(false), program.execute(&context).unwrap());
+    }
+
+    #[test]
+    fn ip_in_cidr() {
+        let mut context = Context::default();
+        register(&mut context);
+
+        for (expr, expected) in [
+            (r#"ip_in_cidr("10.1.2.3", "10.0.0.0/8")"#, true),
+            (r#""10.1.2.3".ip_in_cidr("10.0.0.0/8")"#, true),
+            (r#"ip_in_cidr("11.1.2.3", "10.0.0.0/8")"#, false),
+            (r#"ip_in_cidr("192.0.2.1", "192.0.2.1")"#, true),
+            (r#"ip_in_cidr("192.0.2.1", "0.0.0.0/0")"#, true),
+            (r#"ip_in_cidr("::ffff:10.1.2.3", "10.0.0.0/8")"#, true),
+            (r#"ip_in_cidr("10.1.2.3", "::ffff:10.0.0.0/104")"#, true),
+            (r#"ip_in_cidr("2001:db8::1", "2001:db8::/32")"#, true),
+            (r#"ip_in_cidr("2001:db9::1", "2001:db8::/32")"#, false),
+            (r#"ip_in_cidr("2001:db8::1", "10.0.0.0/8")"#, false),
+            (r#"ip_in_cidr(null, "10.0.0.0/8")"#, false),
+        ] {
+            let program = Program::compile(expr).expect("must compile");
+            assert_eq!(
+                Value::Bool(expected),
+                program.execute(&context).expect("must execute"),
+                "{}",
+                expr
+            );
+        }
+
+        for expr in [
+            r#"ip_in_cidr("10.1.2.3", "10.0.0.0/33")"#,
+            r#"ip_in_cidr("unknown", "10.0.0.0/8")"#,
+        ] {
+            let program = Program::compile(expr).expect("must compile");
+            assert!(program.execute(&context).is_err(), "{}", expr);
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! Reusable CEL snippets, defined once in the config file and expanded into
+//! programs before compiling them.
+//!
+//! Macros are either plain names, or take parameters, which are substituted
+//! with the (parenthesized) arguments in the body:
+//!
+//! ```toml
+//! [cel.macros]
+//! "is_internal(ip)" = 'ip_in_cidr(ip, "10.0.0.0/8") || ip_in_cidr(ip, "192.168.0.0/16")'
+//! is_admin = 'jwt_claims.groups.exists(g, g in ADMIN_GROUPS)'
+//! ```
+//!
+//! Expansion is textual, but aware of string literals, comments and member
+//! accesses, so `"is_admin"` or `x.is_admin` are left alone.
+use std::{borrow::Cow, collections::BTreeMap, fmt, ops::Range};
+
+#[derive(Debug)]
+pub struct MacroError(String);
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for MacroError {}
+
+fn err(msg: String) -> MacroError {
+    MacroError(msg)
+}
+
+/// How often macros are expanded within each other, before giving up on
+/// (likely recursive) definitions.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Macro {
+    /// The parameter names, if the macro is called like a function.
+    params: Option<Vec<String>>,
+    body: String,
+}
+
+/// All macros, by name.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(try_from = "BTreeMap<String, String>")]
+pub struct Macros(BTreeMap<String, Macro>);
+
+/// Whether the name is a valid CEL identifier.
+pub fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl TryFrom<BTreeMap<String, String>> for Macros {
+    type Error = String;
+
+    fn try_from(definitions: BTreeMap<String, String>) -> Result<Self, Self::Error> {
+        let mut macros = BTreeMap::new();
+        for (signature, body) in definitions {
+            let (name, params) = match signature.split_once('(') {
+                Some((name, params)) => {
+                    let params = params
+                        .strip_suffix(')')
+                        .ok_or_else(|| format!("macro {}: missing closing parenthesis", signature))?
+                        .split(',')
+                        .map(|p| p.trim().to_owned())
+                        .filter(|p| !p.is_empty())
+                        .collect::<Vec<_>>();
+                    (name.trim(), Some(params))
+                }
+                None => (signature.trim(), None),
+            };
+
+            if !is_ident(name) || !params.iter().flatten().all(|p| is_ident(p)) {
+                return Err(format!("macro {}: invalid name or parameters", signature));
+            }
+            if macros
+                .insert(name.to_owned(), Macro { params, body })
+                .is_some()
+            {
+                return Err(format!("macro {} defined twice", name));
+            }
+        }
+        Ok(Self(macros))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Ident,
+    /// String and bytes literals, and comments, which are never expanded.
+    Opaque,
+    Space,
+    Punct(char),
+}
+
+/// Split a CEL program into tokens, as far as needed for expansion.
+fn tokenize(src: &str) -> Vec<(Kind, Range<usize>)> {
+    let bytes = src.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let kind = match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                Kind::Opaque
+            }
+            b'\'' | b'"' => {
+                i = skip_string(bytes, i, false);
+                Kind::Opaque
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                // string prefixes, like r"..." or b'...'
+                let prefix = &src[start..i];
+                if matches!(bytes.get(i), Some(b'\'' | b'"'))
+                    && prefix.len() <= 2
+                    && prefix.chars().all(|c| "rRbB".contains(c))
+                {
+                    i = skip_string(bytes, i, prefix.contains(['r', 'R']));
+                    Kind::Opaque
+                } else {
+                    Kind::Ident
+                }
+            }
+            b if b.is_ascii_whitespace() => {
+                while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                Kind::Space
+            }
+            _ => {
+                let c = src[i..].chars().next().expect("must not be at the end");
+                i += c.len_utf8();
+                Kind::Punct(c)
+            }
+        };
+        tokens.push((kind, start..i));
+    }
+    tokens
+}
+
+/// Returns the position after the string literal starting at [start].
+/// Unterminated literals extend to the end.
+fn skip_string(bytes: &[u8], start: usize, raw: bool) -> usize {
+    let quote = bytes[start];
+    let triple = bytes.get(start..start + 3) == Some(&[quote; 3]);
+    let mut i = start + if triple { 3 } else { 1 };
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && !raw {
+            i += 2;
+        } else if triple && bytes.get(i..i + 3) == Some(&[quote; 3]) {
+            return i + 3;
+        } else if !triple && bytes[i] == quote {
+            return i + 1;
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// The index of the next token that isn't whitespace, starting at [i].
+fn skip_space(tokens: &[(Kind, Range<usize>)], mut i: usize) -> usize {
+    while tokens.get(i).is_some_and(|(kind, _)| *kind == Kind::Space) {
+        i += 1;
+    }
+    i
+}
+
+/// Replace all identifiers (that aren't member names) for which [f] returns
+/// a replacement.
+fn replace_idents(src: &str, mut f: impl FnMut(&str) -> Option<String>) -> String {
+    let tokens = tokenize(src);
+    let mut out = String::with_capacity(src.len());
+    let mut prev = None;
+    for (kind, range) in tokens {
+        let replacement = match kind {
+            Kind::Ident if prev != Some(Kind::Punct('.')) => f(&src[range.clone()]),
+            _ => None,
+        };
+        out.push_str(replacement.as_deref().unwrap_or(&src[range]));
+        if kind != Kind::Space {
+            prev = Some(kind);
+        }
+    }
+    out
+}
+
+impl Macros {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Expand all macros in the program, including ones used by other
+    /// macros.
+    pub fn expand<'a>(&self, src: &'a str) -> Result<Cow<'a, str>, MacroError> {
+        if self.is_empty() {
+            return Ok(Cow::Borrowed(src));
+        }
+
+        let mut src = Cow::Borrowed(src);
+        for _ in 0..MAX_DEPTH {
+            match self.expand_once(&src)? {
+                Some(expanded) => src = Cow::Owned(expanded),
+                None => return Ok(src),
+            }
+        }
+        Err(err(format!(
+            "macros nested more than {} levels deep, recursive definition?",
+            MAX_DEPTH
+        )))
+    }
+
+    /// Expand the macros used directly in the program.
+    /// Returns None if there's none.
+    fn expand_once(&self, src: &str) -> Result<Option<String>, MacroError> {
+        let tokens = tokenize(src);
+        let mut out = String::with_capacity(src.len());
+        let mut expanded = false;
+        let mut prev = None;
+        let mut i = 0;
+        while i < tokens.len() {
+            let (kind, range) = &tokens[i];
+            let m = match kind {
+                Kind::Ident if prev != Some(Kind::Punct('.')) => self.0.get(&src[range.clone()]),
+                _ => None,
+            };
+            if *kind != Kind::Space {
+                prev = Some(*kind);
+            }
+            i += 1;
+
+            let Some(m) = m else {
+                out.push_str(&src[range.clone()]);
+                continue;
+            };
+            let name = &src[range.clone()];
+            expanded = true;
+
+            let Some(params) = &m.params else {
+                out.push_str(&format!("({})", m.body));
+                continue;
+            };
+
+            // collect the arguments, up to the matching parenthesis
+            let open = skip_space(&tokens, i);
+            if tokens.get(open).map(|(kind, _)| *kind) != Some(Kind::Punct('(')) {
+                return Err(err(format!("macro {} used without arguments", name)));
+            }
+            let mut args = Vec::new();
+            let mut arg_start = tokens[open].1.end;
+            let mut depth = 0;
+            i = open + 1;
+            loop {
+                let Some((kind, range)) = tokens.get(i) else {
+                    return Err(err(format!("macro {}: unclosed argument list", name)));
+                };
+                i += 1;
+                match kind {
+                    Kind::Punct('(' | '[' | '{') => depth += 1,
+                    Kind::Punct(')') if depth == 0 => {
+                        args.push(src[arg_start..range.start].trim());
+                        break;
+                    }
+                    Kind::Punct(')' | ']' | '}') => depth -= 1,
+                    Kind::Punct(',') if depth == 0 => {
+                        args.push(src[arg_start..range.start].trim());
+                        arg_start = range.end;
+                    }
+                    _ => {}
+                }
+            }
+            if args == [""] {
+                args.clear();
+            }
+            if args.len() != params.len() {
+                return Err(err(format!(
+                    "macro {} takes {} arguments, got {}",
+                    name,
+                    params.len(),
+                    args.len()
+                )));
+            }
+
+            let body = replace_idents(&m.body, |ident| {
+                params
+                    .iter()
+                    .position(|p| p == ident)
+                    .map(|pos| format!("({})", args[pos]))
+            });
+            out.push_str(&format!("({})", body));
+        }
+
+        Ok(expanded.then_some(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::Macros;
+
+    fn macros(definitions: &[(&str, &str)]) -> Macros {
+        definitions
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<BTreeMap<_, _>>()
+            .try_into()
+            .expect("must be valid")
+    }
+
+    #[test]
+    fn expand() {
+        let m = macros(&[
+            ("is_internal(ip)", r#"ip_in_cidr(ip, "10.0.0.0/8")"#),
+            ("is_admin", r#""admin" in jwt_claims.groups"#),
+            ("either(a, b)", "a || b"),
+        ]);
+
+        assert_eq!(
+            r#"(ip_in_cidr((client_ip), "10.0.0.0/8"))"#,
+            m.expand("is_internal(client_ip)").unwrap()
+        );
+        assert_eq!(
+            r#"((("admin" in jwt_claims.groups)) || ((ip_in_cidr((f(1, 2)), "10.0.0.0/8"))))"#,
+            m.expand("either(is_admin, is_internal(f(1, 2)))").unwrap()
+        );
+        // strings, comments and members are left alone
+        let src = "x.is_admin && 'is_admin' == r\"is_admin\" // is_admin";
+        assert_eq!(src, m.expand(src).unwrap());
+
+        assert!(m.expand("is_internal").is_err());
+        assert!(m.expand("either(1)").is_err());
+        assert!(macros(&[("a", "b"), ("b", "a")]).expand("a").is_err());
+        assert!(Macros::try_from(BTreeMap::from([("f(x".to_string(), "x".to_string())])).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Failure injection for /auth, to verify how proxies are configured to
+//! handle cellulose misbehaving (fail open or closed, timeouts, retries)
+//! against a real instance. Only enabled via hidden flags, never meant for
+//! production use.
+use std::time::Duration;
+
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::time;
+use tracing::debug;
+
+use crate::{Denial, REFRESH_CHECK_INTERVAL};
+
+#[derive(Clone, Debug, Default)]
+pub struct Chaos {
+    /// Delay every response by this much.
+    pub delay: Duration,
+    /// Answer this percentage of requests with 500.
+    pub error_percent: f64,
+    /// Answer all requests as if the keys expired before they could be
+    /// refreshed.
+    pub expired_keys: bool,
+}
+
+impl Chaos {
+    /// Whether any failure is injected at all.
+    pub fn enabled(&self) -> bool {
+        !self.delay.is_zero() || self.error_percent > 0.0 || self.expired_keys
+    }
+
+    /// The failure to respond with instead of deciding, if any, with [roll]
+    /// uniformly distributed in [0, 100).
+    fn failure(&self, roll: f64) -> Option<Denial> {
+        if self.expired_keys {
+            return Some(Denial {
+                retry_after: Some(REFRESH_CHECK_INTERVAL),
+                ..StatusCode::INTERNAL_SERVER_ERROR.into()
+            });
+        }
+        (roll < self.error_percent).then(|| StatusCode::INTERNAL_SERVER_ERROR.into())
+    }
+}
+
+/// Middleware injecting the configured failures.
+pub async fn apply(State(chaos): State<Chaos>, rq: Request, next: Next) -> Response {
+    if !chaos.enabled() {
+        return next.run(rq).await;
+    }
+    time::sleep(chaos.delay).await;
+    let roll = f64::from(OsRng.next_u32()) / (f64::from(u32::MAX) + 1.0) * 100.0;
+    match chaos.failure(roll) {
+        Some(denial) => {
+            debug!(status = %denial.status, "injecting failure");
+            denial.into_response()
+        }
+        None => next.run(rq).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::Chaos;
+
+    #[test]
+    fn failure() {
+        let chaos = Chaos {
+            error_percent: 25.0,
+            ..Default::default()
+        };
+        assert!(chaos.enabled());
+        assert_eq!(
+            Some(StatusCode::INTERNAL_SERVER_ERROR),
+            chaos.failure(24.9).map(|d| d.status)
+        );
+        assert_eq!(None, chaos.failure(25.0));
+
+        let chaos = Chaos {
+            expired_keys: true,
+            ..Default::default()
+        };
+        assert!(chaos.failure(99.0).unwrap().retry_after.is_some());
+        assert!(!Chaos::default().enabled());
+    }
+}
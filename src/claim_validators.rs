@@ -0,0 +1,255 @@
+//! Validation of the claims of tokens, once their signature is verified (or
+//! they're introspected), before the policy is evaluated.
+//!
+//! Validators run in the order they were added, and the first one rejecting
+//! the claims decides: the request is denied without running the others, or
+//! the policy. Built-in ones check the audience, issuer and authentication
+//! context class of tokens, and whether they're revoked. Embedders can add
+//! their own, like asking an internal service whether the subject is still
+//! active, by implementing [ClaimValidator].
+//!
+//! They apply to all tokens, unlike the verification options, which are
+//! only checked for JWTs, and run for positive decisions served from the
+//! [crate::DecisionCache] too, with the cached claims.
+use std::{collections::HashSet, fmt, sync::Arc};
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use tracing::{debug, warn};
+
+use crate::{denylist::Denylist, Denial};
+
+/// The claims of a verified token.
+pub type Claims = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Debug)]
+pub enum ClaimError {
+    /// The claims aren't acceptable. Answered with a 401, with the
+    /// description in the `WWW-Authenticate` challenge.
+    Invalid(&'static str),
+    /// The claims couldn't be validated, like when a service asked is
+    /// unavailable. Answered with a 500.
+    Failed(String),
+}
+
+impl fmt::Display for ClaimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClaimError::Invalid(description) => write!(f, "{}", description),
+            ClaimError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ClaimError {}
+
+/// Validates the claims of verified tokens, see the module docs.
+#[async_trait]
+pub trait ClaimValidator: Send + Sync {
+    /// The name of the validator, for logs.
+    fn name(&self) -> &str;
+
+    /// Accept or reject the [claims] of the [token].
+    async fn validate(&self, token: &str, claims: &Claims) -> Result<(), ClaimError>;
+}
+
+/// Validators run in order, see the module docs.
+#[derive(Clone, Default)]
+pub struct ClaimValidators {
+    validators: Arc<[Arc<dyn ClaimValidator>]>,
+}
+
+impl ClaimValidators {
+    /// Run [validator] after the ones added before.
+    pub fn with(self, validator: impl ClaimValidator + 'static) -> Self {
+        let mut validators = self.validators.to_vec();
+        validators.push(Arc::new(validator));
+        Self {
+            validators: validators.into(),
+        }
+    }
+
+    /// Run the validators, returning the denial to respond with for the
+    /// first one rejecting the claims.
+    pub(crate) async fn validate(&self, token: &str, claims: &Claims) -> Result<(), Denial> {
+        for validator in self.validators.iter() {
+            validator
+                .validate(token, claims)
+                .await
+                .map_err(|e| match e {
+                    ClaimError::Invalid(description) => {
+                        debug!(validator = validator.name(), %description, "rejecting claims");
+                        Denial::invalid_token(description)
+                    }
+                    e @ ClaimError::Failed(_) => {
+                        warn!(validator = validator.name(), err = %e, "failed to validate claims");
+                        StatusCode::INTERNAL_SERVER_ERROR.into()
+                    }
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the string claim [name], or any of the list of strings, is in
+/// [allowed].
+fn any_allowed(claims: &Claims, name: &str, allowed: &HashSet<String>) -> bool {
+    match claims.get(name) {
+        Some(serde_json::Value::String(value)) => allowed.contains(value),
+        Some(serde_json::Value::Array(values)) => values
+            .iter()
+            .filter_map(|v| v.as_str())
+            .any(|v| allowed.contains(v)),
+        _ => false,
+    }
+}
+
+/// Requires the `aud` claim to contain one of the audiences.
+pub struct Audience(pub HashSet<String>);
+
+#[async_trait]
+impl ClaimValidator for Audience {
+    fn name(&self) -> &str {
+        "audience"
+    }
+
+    async fn validate(&self, _token: &str, claims: &Claims) -> Result<(), ClaimError> {
+        if !any_allowed(claims, "aud", &self.0) {
+            return Err(ClaimError::Invalid(
+                "The access token is meant for another audience",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Requires the `iss` claim to be one of the issuers.
+pub struct Issuer(pub HashSet<String>);
+
+#[async_trait]
+impl ClaimValidator for Issuer {
+    fn name(&self) -> &str {
+        "issuer"
+    }
+
+    async fn validate(&self, _token: &str, claims: &Claims) -> Result<(), ClaimError> {
+        match claims.get("iss").and_then(|v| v.as_str()) {
+            Some(iss) if self.0.contains(iss) => Ok(()),
+            _ => Err(ClaimError::Invalid(
+                "The access token was issued by an unknown issuer",
+            )),
+        }
+    }
+}
+
+/// Requires the `acr` claim (the authentication context class, like
+/// whether a second factor was used) to be one of the values.
+pub struct Acr(pub HashSet<String>);
+
+#[async_trait]
+impl ClaimValidator for Acr {
+    fn name(&self) -> &str {
+        "acr"
+    }
+
+    async fn validate(&self, _token: &str, claims: &Claims) -> Result<(), ClaimError> {
+        match claims.get("acr").and_then(|v| v.as_str()) {
+            Some(acr) if self.0.contains(acr) => Ok(()),
+            _ => Err(ClaimError::Invalid(
+                "The authentication level of the access token is insufficient",
+            )),
+        }
+    }
+}
+
+/// Rejects tokens on the [Denylist].
+pub struct Revocation(pub Arc<Denylist>);
+
+#[async_trait]
+impl ClaimValidator for Revocation {
+    fn name(&self) -> &str {
+        "revocation"
+    }
+
+    async fn validate(&self, token: &str, claims: &Claims) -> Result<(), ClaimError> {
+        if self.0.is_revoked(token, claims) {
+            return Err(ClaimError::Invalid("The access token is revoked"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use async_trait::async_trait;
+    use axum::http::StatusCode;
+
+    use super::{Acr, Audience, ClaimError, ClaimValidator, ClaimValidators, Claims, Issuer};
+
+    struct Unavailable;
+
+    #[async_trait]
+    impl ClaimValidator for Unavailable {
+        fn name(&self) -> &str {
+            "unavailable"
+        }
+
+        async fn validate(&self, _token: &str, _claims: &Claims) -> Result<(), ClaimError> {
+            Err(ClaimError::Failed("connection refused".to_string()))
+        }
+    }
+
+    fn set(values: &[&str]) -> HashSet<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn chain() {
+        let claims = serde_json::json!({
+            "iss": "https://idp.example.com",
+            "aud": ["api", "web"],
+            "acr": "mfa",
+        });
+        let claims = claims.as_object().unwrap();
+
+        let validators = ClaimValidators::default()
+            .with(Issuer(set(&["https://idp.example.com"])))
+            .with(Audience(set(&["web"])))
+            .with(Acr(set(&["mfa", "hwk"])));
+        assert!(validators.validate("a.b.c", claims).await.is_ok());
+        assert!(ClaimValidators::default()
+            .validate("a.b.c", &Default::default())
+            .await
+            .is_ok());
+
+        // the first rejection wins
+        let denial = validators
+            .clone()
+            .with(Unavailable)
+            .with(Audience(set(&["admin"])))
+            .validate("a.b.c", claims)
+            .await
+            .unwrap_err();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, denial.status);
+        let denial = validators
+            .with(Audience(set(&["admin"])))
+            .with(Unavailable)
+            .validate("a.b.c", claims)
+            .await
+            .unwrap_err();
+        assert_eq!(StatusCode::UNAUTHORIZED, denial.status);
+        assert_eq!(
+            Some("The access token is meant for another audience"),
+            denial.token_error
+        );
+
+        // missing claims are rejected
+        let validators = ClaimValidators::default().with(Acr(set(&["mfa"])));
+        assert!(validators
+            .validate("a.b.c", &Default::default())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! For upstream services behind cellulose: verifying the signed
+//! `X-Auth-Assertion` returned on allow, and the identity headers it
+//! covers.
+//!
+//! The keys are fetched from cellulose (see [crate::assertion::WELL_KNOWN_KEYS]),
+//! or loaded from a JWKS document distributed otherwise:
+//!
+//! ```no_run
+//! # async fn example(headers: axum::http::HeaderMap) -> Result<(), Box<dyn std::error::Error>> {
+//! use cellulose::client::Verifier;
+//!
+//! let verifier = Verifier::fetch("http://cellulose:9000")
+//!     .await?
+//!     .with_audience("app.example.com");
+//! let assertion = verifier.verify_headers(&headers).await?;
+//! println!("{:?} {:?}", assertion.subject, assertion.header("x-auth-request-email"));
+//! # Ok(())
+//! # }
+//! ```
+use std::{collections::BTreeMap, fmt};
+
+use axum::http::HeaderMap;
+use jwt_simple::prelude::{HashSet, VerificationOptions};
+
+use crate::{
+    assertion::{AssertionClaims, ISSUER, WELL_KNOWN_KEYS, X_AUTH_ASSERTION},
+    key_set::KeySet,
+};
+
+#[derive(Debug)]
+pub struct ClientError(String);
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+fn err(msg: String) -> ClientError {
+    ClientError(msg)
+}
+
+/// A verified assertion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assertion {
+    /// The subject of the token the request was allowed with, if any.
+    pub subject: Option<String>,
+    /// The headers cellulose returned, by lowercase name.
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Assertion {
+    /// The value of the header, if cellulose returned it.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+}
+
+/// Verifies assertions against the keys of a cellulose instance.
+pub struct Verifier {
+    keys: KeySet,
+    audience: Option<String>,
+}
+
+impl Verifier {
+    /// Use the keys in the JWKS document.
+    pub fn from_jwks(json: &[u8]) -> Result<Self, ClientError> {
+        let keys = KeySet::from_jwk_json(json).map_err(|e| err(format!("invalid keys: {}", e)))?;
+        Ok(Self {
+            keys,
+            audience: None,
+        })
+    }
+
+    /// Fetch the keys from the cellulose instance at [base_url].
+    pub async fn fetch(base_url: &str) -> Result<Self, ClientError> {
+        let url = format!("{}{}", base_url.trim_end_matches('/'), WELL_KNOWN_KEYS);
+        let body = reqwest::get(&url)
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| err(format!("fetching {}: {}", url, e)))?
+            .bytes()
+            .await
+            .map_err(|e| err(format!("fetching {}: {}", url, e)))?;
+        Self::from_jwks(&body)
+    }
+
+    /// Only accept assertions for this audience, the host of the original
+    /// request.
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Verify the assertion.
+    pub async fn verify(&self, assertion: &str) -> Result<Assertion, ClientError> {
+        let options = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_iter([ISSUER.to_string()])),
+            allowed_audiences: self
+                .audience
+                .as_ref()
+                .map(|audience| HashSet::from_iter([audience.clone()])),
+            ..Default::default()
+        };
+        let claims = self
+            .keys
+            .verify::<AssertionClaims>(assertion, Some(options))
+            .await
+            .map_err(|e| err(format!("invalid assertion: {}", e)))?;
+        Ok(Assertion {
+            subject: claims.subject,
+            headers: claims.custom.headers,
+        })
+    }
+
+    /// Verify the assertion in the request headers, and that the identity
+    /// headers it covers weren't tampered with on the way.
+    pub async fn verify_headers(&self, headers: &HeaderMap) -> Result<Assertion, ClientError> {
+        let assertion = headers
+            .get(X_AUTH_ASSERTION)
+            .ok_or_else(|| err(format!("no {} header", X_AUTH_ASSERTION)))?
+            .to_str()
+            .map_err(|e| err(format!("invalid {} header: {}", X_AUTH_ASSERTION, e)))?;
+        let assertion = self.verify(assertion).await?;
+
+        for (name, value) in &assertion.headers {
+            let sent = headers.get_all(name.as_str()).iter().collect::<Vec<_>>();
+            if sent.iter().any(|v| v.as_bytes() != value.as_bytes()) {
+                return Err(err(format!("{} header doesn't match the assertion", name)));
+            }
+        }
+        Ok(assertion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::{HeaderMap, HeaderName};
+    use jwt_simple::prelude::ES256KeyPair;
+
+    use super::Verifier;
+    use crate::assertion::{Signer, X_AUTH_ASSERTION};
+
+    #[tokio::test]
+    async fn verify_assertion() {
+        let signer = Signer::new(ES256KeyPair::generate(), Duration::from_secs(60));
+        let email = HeaderName::from_static("x-auth-request-email");
+        let assertion = signer
+            .sign(
+                Some("alice"),
+                Some("app.example.com"),
+                &[(email.clone(), "alice@example.com".to_string())],
+            )
+            .expect("must sign");
+
+        let verifier = Verifier::from_jwks(signer.jwks().to_string().as_bytes())
+            .expect("must parse")
+            .with_audience("app.example.com");
+        let verified = verifier.verify(&assertion).await.expect("must verify");
+        assert_eq!(Some("alice".to_string()), verified.subject);
+        assert_eq!(
+            Some("alice@example.com"),
+            verified.header("X-Auth-Request-Email")
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(X_AUTH_ASSERTION, assertion.parse().unwrap());
+        headers.insert(email.clone(), "alice@example.com".parse().unwrap());
+        assert_eq!(verified, verifier.verify_headers(&headers).await.unwrap());
+
+        // tampered with
+        headers.insert(email, "mallory@example.com".parse().unwrap());
+        assert!(verifier.verify_headers(&headers).await.is_err());
+
+        // for another upstream
+        let other = Verifier::from_jwks(signer.jwks().to_string().as_bytes())
+            .unwrap()
+            .with_audience("admin.example.com");
+        assert!(other.verify(&assertion).await.is_err());
+
+        // signed with another key
+        let other = Signer::new(ES256KeyPair::generate(), Duration::from_secs(60));
+        let assertion = other.sign(Some("alice"), None, &[]).unwrap();
+        assert!(verifier.verify(&assertion).await.is_err());
+    }
+}
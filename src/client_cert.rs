@@ -0,0 +1,525 @@
+//! Client certificates forwarded by the proxy terminating mTLS, exposed to
+//! CEL programs as `client_cert`.
+//!
+//! Each proxy forwards them differently:
+//!
+//!  - Envoy sets `X-Forwarded-Client-Cert` (XFCC), with `Hash`, `Subject`,
+//!    `URI` and `DNS` fields, and optionally the URL-encoded PEM as `Cert`.
+//!    `By` is the identity of the proxy itself.
+//!  - Traefik sets `X-Forwarded-Tls-Client-Cert` to the URL-encoded PEM
+//!    body, without delimiters, followed by the chain (comma-separated).
+//!  - nginx forwards `$ssl_client_escaped_cert`, the URL-encoded PEM,
+//!    usually as `X-SSL-Client-Cert`.
+//!  - Caddy forwards `{http.request.tls.client.certificate_der_base64}`,
+//!    usually as `X-Client-Cert`.
+//!
+//! All of these are normalized into the same fields. The header is only
+//! trustworthy if the proxy always overwrites it, so it's only read if the
+//! format is configured explicitly.
+use std::{collections::HashMap, fmt::Write};
+
+use axum::http::{HeaderMap, HeaderName};
+use cel_interpreter::Value;
+use jwt_simple::reexports::ct_codecs::{Base64, Decoder};
+use sha2::{Digest, Sha256};
+
+use crate::context_request::percent_decode;
+
+/// The proxy (and with it, the header format) client certificates are
+/// forwarded by.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Envoy,
+    Traefik,
+    Nginx,
+    Caddy,
+}
+
+impl Format {
+    /// The header the proxy forwards certificates in by default.
+    pub fn default_header(self) -> HeaderName {
+        HeaderName::from_static(match self {
+            Format::Envoy => "x-forwarded-client-cert",
+            Format::Traefik => "x-forwarded-tls-client-cert",
+            Format::Nginx => "x-ssl-client-cert",
+            Format::Caddy => "x-client-cert",
+        })
+    }
+}
+
+/// Where to read the client certificate from.
+#[derive(Clone, Debug)]
+pub struct ClientCertSource {
+    pub format: Format,
+    pub header: HeaderName,
+}
+
+/// The details of a client certificate.
+#[derive(Debug, Default, PartialEq)]
+pub struct ClientCert {
+    /// The SHA-256 hash of the DER-encoded certificate, hex-encoded.
+    pub hash: Option<String>,
+    /// The subject, as RFC 4514 string, like `CN=web,O=Example`.
+    pub subject: Option<String>,
+    /// The issuer, as RFC 4514 string.
+    pub issuer: Option<String>,
+    /// The serial number, hex-encoded.
+    pub serial: Option<String>,
+    /// URI subject alternative names, like SPIFFE IDs.
+    pub uris: Vec<String>,
+    /// DNS subject alternative names.
+    pub dns: Vec<String>,
+    /// The URI SAN of the proxy that verified the certificate, from the
+    /// `By` field of the XFCC header.
+    pub by: Option<String>,
+}
+
+impl ClientCert {
+    /// The SPIFFE ID, the first URI SAN with the spiffe scheme.
+    pub fn spiffe_id(&self) -> Option<&str> {
+        self.uris
+            .iter()
+            .find(|uri| uri.starts_with("spiffe://"))
+            .map(String::as_str)
+    }
+
+    /// The certificate as CEL map, with absent fields as null.
+    pub fn to_value(&self) -> Value {
+        let list = |v: &[String]| {
+            Value::from(
+                v.iter()
+                    .map(|s| Value::from(s.as_str()))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        HashMap::from([
+            ("hash", Value::from(self.hash.clone())),
+            ("subject", Value::from(self.subject.clone())),
+            ("issuer", Value::from(self.issuer.clone())),
+            ("serial", Value::from(self.serial.clone())),
+            ("uris", list(&self.uris)),
+            ("dns", list(&self.dns)),
+            (
+                "spiffe_id",
+                Value::from(self.spiffe_id().map(str::to_owned)),
+            ),
+            ("by", Value::from(self.by.clone())),
+        ])
+        .into()
+    }
+}
+
+impl ClientCertSource {
+    /// Extract the client certificate from the request headers.
+    /// Returns None if the header is absent or can't be parsed.
+    pub fn extract(&self, headers: &HeaderMap) -> Option<ClientCert> {
+        let value = headers.get(&self.header)?.to_str().ok()?;
+        match self.format {
+            Format::Envoy => parse_xfcc(value),
+            // the leaf certificate comes first, followed by the chain
+            Format::Traefik => parse_certificate(&decode_cert(value.split(',').next()?)?),
+            Format::Nginx | Format::Caddy => parse_certificate(&decode_cert(value)?),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, b| {
+        let _ = write!(out, "{:02x}", b);
+        out
+    })
+}
+
+/// Decode a certificate forwarded in a header, to DER. Accepts PEM (with or
+/// without delimiters) or base64-encoded DER, optionally URL-encoded.
+fn decode_cert(value: &str) -> Option<Vec<u8>> {
+    let value = percent_decode(value.trim(), false);
+    let body = value
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect::<String>();
+    Base64::decode_to_vec(body.trim(), Some(b" \t\r\n")).ok()
+}
+
+/// Split at the separator, except within double quotes.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Remove surrounding quotes, and the escaping within.
+fn unquote(s: &str) -> String {
+    match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => out.extend(chars.next()),
+                    c => out.push(c),
+                }
+            }
+            out
+        }
+        None => s.to_owned(),
+    }
+}
+
+/// Parse an Envoy XFCC header. Each proxy appends an element, the last one
+/// describes the client connected to the closest proxy.
+/// Fields set explicitly take precedence over the ones from `Cert`.
+fn parse_xfcc(value: &str) -> Option<ClientCert> {
+    let element = split_unquoted(value, ',').pop()?;
+    let fields = split_unquoted(element, ';')
+        .into_iter()
+        .filter_map(|pair| {
+            let (k, v) = pair.split_once('=')?;
+            Some((k.trim().to_ascii_lowercase(), unquote(v.trim())))
+        })
+        .collect::<Vec<_>>();
+    let field = |name: &'static str| {
+        fields
+            .iter()
+            .filter(move |(k, _)| k == name)
+            .map(|(_, v)| v.clone())
+    };
+
+    let mut cert = field("cert")
+        .next()
+        .and_then(|pem| parse_certificate(&decode_cert(&pem)?))
+        .unwrap_or_default();
+    if let Some(hash) = field("hash").next() {
+        cert.hash = Some(hash.to_ascii_lowercase());
+    }
+    if let Some(subject) = field("subject").next() {
+        cert.subject = Some(subject);
+    }
+    let uris = field("uri").collect::<Vec<_>>();
+    if !uris.is_empty() {
+        cert.uris = uris;
+    }
+    let dns = field("dns").collect::<Vec<_>>();
+    if !dns.is_empty() {
+        cert.dns = dns;
+    }
+
+    // the proxy's own identity alone doesn't make a client certificate
+    if cert == ClientCert::default() {
+        return None;
+    }
+    cert.by = field("by").next();
+    Some(cert)
+}
+
+/// Read a DER TLV, returning the tag and contents.
+fn read_tlv<'a>(input: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0, |acc, b| acc << 8 | *b as usize);
+        rest = &rest[n..];
+        len
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    *input = rest;
+    Some((tag, contents))
+}
+
+/// Read a DER TLV, expecting the given tag.
+fn expect_tlv<'a>(input: &mut &'a [u8], tag: u8) -> Option<&'a [u8]> {
+    read_tlv(input).filter(|(t, _)| *t == tag).map(|(_, c)| c)
+}
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+const INTEGER: u8 = 0x02;
+const BOOLEAN: u8 = 0x01;
+const OCTET_STRING: u8 = 0x04;
+
+/// The subjectAltName extension, 2.5.29.17.
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Render an OID in dotted notation.
+fn oid_to_string(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut value = 0u64;
+    for b in oid {
+        value = value << 7 | (b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            // the first subidentifier encodes the first two arcs
+            if arcs.is_empty() {
+                let first = (value / 40).min(2);
+                arcs.extend([first, value - first * 40]);
+            } else {
+                arcs.push(value);
+            }
+            value = 0;
+        }
+    }
+    arcs.iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// The short name of well-known attribute types.
+fn attribute_name(oid: &[u8]) -> Option<&'static str> {
+    Some(match oid {
+        [0x55, 0x04, 0x03] => "CN",
+        [0x55, 0x04, 0x06] => "C",
+        [0x55, 0x04, 0x07] => "L",
+        [0x55, 0x04, 0x08] => "ST",
+        [0x55, 0x04, 0x09] => "STREET",
+        [0x55, 0x04, 0x0a] => "O",
+        [0x55, 0x04, 0x0b] => "OU",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID",
+        _ => return None,
+    })
+}
+
+/// Escape an attribute value, as described in RFC 4514.
+fn escape_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && matches!(c, '#' | ' '))
+            || (i == last && c == ' ');
+        if special {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Render a Name as RFC 4514 string, which lists the RDNs in reverse order.
+fn name_to_string(mut name: &[u8]) -> Option<String> {
+    let mut rdns = Vec::new();
+    while !name.is_empty() {
+        let mut set = expect_tlv(&mut name, SET)?;
+        let mut attributes = Vec::new();
+        while !set.is_empty() {
+            let mut attribute = expect_tlv(&mut set, SEQUENCE)?;
+            let oid = expect_tlv(&mut attribute, OID)?;
+            let (_, value) = read_tlv(&mut attribute)?;
+            let name = attribute_name(oid)
+                .map(str::to_owned)
+                .unwrap_or_else(|| oid_to_string(oid));
+            attributes.push(format!(
+                "{}={}",
+                name,
+                escape_value(&String::from_utf8_lossy(value))
+            ));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+/// Parse the fields of interest from a DER-encoded X.509 certificate.
+fn parse_certificate(der: &[u8]) -> Option<ClientCert> {
+    let mut input = der;
+    let mut certificate = expect_tlv(&mut input, SEQUENCE)?;
+    let mut tbs = expect_tlv(&mut certificate, SEQUENCE)?;
+
+    // the version is optional, and explicitly tagged
+    if tbs.first() == Some(&0xa0) {
+        read_tlv(&mut tbs)?;
+    }
+    let serial = expect_tlv(&mut tbs, INTEGER)?;
+    let serial = match serial {
+        [0, rest @ ..] if !rest.is_empty() => rest,
+        serial => serial,
+    };
+    expect_tlv(&mut tbs, SEQUENCE)?; // signature algorithm
+    let issuer = name_to_string(expect_tlv(&mut tbs, SEQUENCE)?)?;
+    expect_tlv(&mut tbs, SEQUENCE)?; // validity
+    let subject = name_to_string(expect_tlv(&mut tbs, SEQUENCE)?)?;
+    expect_tlv(&mut tbs, SEQUENCE)?; // subject public key info
+
+    let mut cert = ClientCert {
+        hash: Some(hex(&Sha256::digest(der))),
+        subject: Some(subject),
+        issuer: Some(issuer),
+        serial: Some(hex(serial)),
+        ..Default::default()
+    };
+
+    // skip the unique identifiers, up to the extensions
+    while let Some((tag, contents)) = read_tlv(&mut tbs) {
+        if tag != 0xa3 {
+            continue;
+        }
+        let mut contents = contents;
+        let mut extensions = expect_tlv(&mut contents, SEQUENCE)?;
+        while !extensions.is_empty() {
+            let mut extension = expect_tlv(&mut extensions, SEQUENCE)?;
+            if expect_tlv(&mut extension, OID)? != OID_SUBJECT_ALT_NAME {
+                continue;
+            }
+            if extension.first() == Some(&BOOLEAN) {
+                read_tlv(&mut extension)?;
+            }
+            let mut value = expect_tlv(&mut extension, OCTET_STRING)?;
+            let mut names = expect_tlv(&mut value, SEQUENCE)?;
+            while let Some((tag, name)) = read_tlv(&mut names) {
+                let name = String::from_utf8_lossy(name).into_owned();
+                match tag {
+                    0x82 => cert.dns.push(name),
+                    0x86 => cert.uris.push(name),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Some(cert)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{ClientCert, ClientCertSource, Format};
+
+    /// Self-signed, with subject and issuer `CN=web,O=Example`, serial 0x1234,
+    /// and the SANs `spiffe://example.org/ns/default/sa/web` and
+    /// `web.example.org`.
+    const PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIByDCCAW+gAwIBAgICEjQwCgYIKoZIzj0EAwIwIDEQMA4GA1UECgwHRXhhbXBs
+ZTEMMAoGA1UEAwwDd2ViMB4XDTI2MTAxNjEzMjQ1N1oXDTM2MTAxMzEzMjQ1N1ow
+IDEQMA4GA1UECgwHRXhhbXBsZTEMMAoGA1UEAwwDd2ViMFkwEwYHKoZIzj0CAQYI
+KoZIzj0DAQcDQgAE2SCpiJ9o/ccOStr8BhEBgZ6KTqdFFfoCuMfQTw7HGCPg/Fc3
+l/hfNIoILVIOwzjXFEAMLwARtUlkLrV+FtUvmKOBmDCBlTAdBgNVHQ4EFgQUYgYT
+JN+FKUiAIVYdKIQBRtJ9sSYwHwYDVR0jBBgwFoAUYgYTJN+FKUiAIVYdKIQBRtJ9
+sSYwDwYDVR0TAQH/BAUwAwEB/zBCBgNVHREEOzA5hiZzcGlmZmU6Ly9leGFtcGxl
+Lm9yZy9ucy9kZWZhdWx0L3NhL3dlYoIPd2ViLmV4YW1wbGUub3JnMAoGCCqGSM49
+BAMCA0cAMEQCIG1irEKYxO8EQwHwT/Ohub9c9eM8SkEdAHTiOAPaJJe5AiANwOkH
+2n6HxXhyhbr0HHDBvpwhEXjWCODLGU1OfMPKNQ==
+-----END CERTIFICATE-----
+";
+
+    const HASH: &str = "cca043f2a2e60571f8fbdba02f2dc895995725fd3a2ba9ab4707de03a35ea038";
+
+    fn expected() -> ClientCert {
+        ClientCert {
+            hash: Some(HASH.to_string()),
+            subject: Some("CN=web,O=Example".to_string()),
+            issuer: Some("CN=web,O=Example".to_string()),
+            serial: Some("1234".to_string()),
+            uris: vec!["spiffe://example.org/ns/default/sa/web".to_string()],
+            dns: vec!["web.example.org".to_string()],
+            by: None,
+        }
+    }
+
+    /// URL-encode everything but unreserved characters.
+    fn url_encode(s: &str) -> String {
+        s.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (b as char).to_string()
+                }
+                b => format!("%{:02X}", b),
+            })
+            .collect()
+    }
+
+    fn extract(format: Format, value: &str) -> Option<ClientCert> {
+        let source = ClientCertSource {
+            format,
+            header: format.default_header(),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            source.header.clone(),
+            HeaderValue::from_str(value).expect("must be a valid header value"),
+        );
+        source.extract(&headers)
+    }
+
+    #[test]
+    fn formats() {
+        let body = PEM
+            .lines()
+            .filter(|l| !l.starts_with("-----"))
+            .collect::<String>();
+
+        // nginx: $ssl_client_escaped_cert
+        assert_eq!(Some(expected()), extract(Format::Nginx, &url_encode(PEM)));
+        // Traefik: the escaped body, followed by the chain
+        assert_eq!(
+            Some(expected()),
+            extract(
+                Format::Traefik,
+                &format!("{},{}", url_encode(&body), "Y2hhaW4=")
+            )
+        );
+        // Caddy: the base64-encoded DER
+        assert_eq!(Some(expected()), extract(Format::Caddy, &body));
+
+        // Envoy, with the certificate
+        let xfcc = format!(
+            r#"By=spiffe://example.org/ns/default/sa/edge;Hash={};Cert="{}";Subject="CN=web,O=Example";URI=spiffe://example.org/ns/default/sa/web;DNS=web.example.org"#,
+            HASH,
+            url_encode(PEM)
+        );
+        let cert = extract(Format::Envoy, &xfcc).expect("must parse");
+        assert_eq!(
+            ClientCert {
+                by: Some("spiffe://example.org/ns/default/sa/edge".to_string()),
+                ..expected()
+            },
+            cert
+        );
+        assert_eq!(
+            Some("spiffe://example.org/ns/default/sa/web"),
+            cert.spiffe_id()
+        );
+
+        // Envoy, with fields only, the last element is used
+        let xfcc = format!(
+            r#"Hash=00;URI=spiffe://other,By=x;Hash={};Subject="CN=web,O=Example";URI=spiffe://example.org/ns/default/sa/web;DNS=web.example.org"#,
+            HASH.to_ascii_uppercase()
+        );
+        assert_eq!(
+            Some(ClientCert {
+                issuer: None,
+                serial: None,
+                by: Some("x".to_string()),
+                ..expected()
+            }),
+            extract(Format::Envoy, &xfcc)
+        );
+
+        assert_eq!(None, extract(Format::Nginx, "garbage"));
+        assert_eq!(None, extract(Format::Envoy, "By=x"));
+    }
+}
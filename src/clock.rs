@@ -0,0 +1,149 @@
+//! Sanity checking of the system clock.
+//!
+//! A skewed clock silently breaks the validation of exp and nbf, with tokens
+//! rejected as expired or not yet valid right after being issued. To make
+//! this obvious, the local time is compared against the Date header of JWKS
+//! responses, which are fetched at startup and refreshed periodically.
+//! Sources without HTTP requests (key files, HMAC secrets) don't allow
+//! observing the skew.
+use std::{
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::DateTime;
+use parking_lot::Mutex;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::metrics::METRICS;
+
+/// How often the last observed skew is checked, to warn about it.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+static LAST: LazyLock<Mutex<Option<Observation>>> = LazyLock::new(Default::default);
+
+/// A comparison of the local time against a remote one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Observation {
+    /// Seconds the local clock is ahead of the remote one, negative if it's
+    /// behind. The Date header has a resolution of a second, and is set
+    /// before the response is sent, so this is only accurate to a second
+    /// plus the time the request took.
+    pub skew: f64,
+    /// The URL the remote time was taken from.
+    pub source: String,
+}
+
+/// The skew of the local clock at [now] against an HTTP date, if valid.
+fn skew(date: &str, now: SystemTime) -> Option<f64> {
+    let remote = DateTime::parse_from_rfc2822(date).ok()?;
+    let now = now.duration_since(UNIX_EPOCH).ok()?.as_secs_f64();
+    Some(now - remote.timestamp() as f64)
+}
+
+/// Record the skew against the Date header of a response from [source], if
+/// it has one.
+pub(crate) fn observe(source: &str, headers: &reqwest::header::HeaderMap) {
+    let Some(skew) = headers
+        .get(reqwest::header::DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|date| skew(date, SystemTime::now()))
+    else {
+        return;
+    };
+
+    debug!(source, skew, "observed clock skew");
+    METRICS.clock_skew.with_labels(&[]).set(skew);
+    *LAST.lock() = Some(Observation {
+        skew,
+        source: source.to_owned(),
+    });
+}
+
+/// The last observed skew, if any.
+pub fn last() -> Option<Observation> {
+    LAST.lock().clone()
+}
+
+/// The maximum skew tolerated, and what to do if it's exceeded.
+#[derive(Clone, Debug)]
+pub struct ClockCheck {
+    pub max_skew: Duration,
+    /// Refuse to start, and answer decisions with 503 Service Unavailable,
+    /// while the skew is exceeded, instead of only warning.
+    pub refuse: bool,
+}
+
+impl ClockCheck {
+    fn exceeded_by(&self, observation: &Observation) -> bool {
+        observation.skew.abs() > self.max_skew.as_secs_f64()
+    }
+
+    /// The last observation, if it exceeds the maximum skew.
+    pub fn exceeded(&self) -> Option<Observation> {
+        last().filter(|o| self.exceeded_by(o))
+    }
+
+    /// Whether decisions should be refused, due to the skew.
+    pub fn refusing(&self) -> bool {
+        self.refuse && self.exceeded().is_some()
+    }
+
+    /// Periodically warn while the skew is exceeded.
+    pub async fn run(self) {
+        let mut interval = time::interval(CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+        let mut was_exceeded = false;
+        loop {
+            interval.tick().await;
+            match self.exceeded() {
+                Some(o) => {
+                    warn!(
+                        skew = o.skew,
+                        source = o.source,
+                        max_skew = ?self.max_skew,
+                        refusing = self.refuse,
+                        "system clock is skewed, tokens will be wrongly rejected or accepted"
+                    );
+                    was_exceeded = true;
+                }
+                None if was_exceeded => {
+                    info!("system clock skew is back within bounds");
+                    was_exceeded = false;
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{skew, ClockCheck, Observation};
+
+    #[test]
+    fn skew_from_date() {
+        // Tue, 14 Nov 2023 22:13:20 GMT
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(Some(0.0), skew("Tue, 14 Nov 2023 22:13:20 GMT", now));
+        assert_eq!(Some(-90.0), skew("Tue, 14 Nov 2023 22:14:50 GMT", now));
+        assert_eq!(Some(3600.0), skew("Tue, 14 Nov 2023 21:13:20 GMT", now));
+        assert_eq!(None, skew("yesterday", now));
+
+        let check = ClockCheck {
+            max_skew: Duration::from_secs(60),
+            refuse: false,
+        };
+        let observation = |skew| Observation {
+            skew,
+            source: "https://idp.example.com/jwks".to_string(),
+        };
+        assert!(!check.exceeded_by(&observation(59.5)));
+        assert!(check.exceeded_by(&observation(-90.0)));
+        assert!(check.exceeded_by(&observation(3600.0)));
+    }
+}
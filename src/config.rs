@@ -0,0 +1,342 @@
+//! Configuration files (TOML or YAML).
+//!
+//! Top-level keys correspond to command line flags (without the leading
+//! dashes), and are applied unless the flag is passed on the command line or
+//! via its environment variable. Additionally, config files can define
+//! default verification options, named policies, and CEL constants and
+//! macros, which have no command line equivalent.
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use clap::{parser::ValueSource, ArgAction, ArgMatches, Command};
+use serde_json::Value;
+
+use crate::{cel_macros, context_schema, Macros, Policy, VerificationConfig};
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn err(msg: String) -> ConfigError {
+    ConfigError(msg)
+}
+
+/// The contents of a config file.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// Verification options applied to all tokens, unless overridden by the
+    /// request.
+    #[serde(default)]
+    pub verification: VerificationConfig,
+
+    /// Policies by name, referenced as `?policy=<name>`. Either the CEL
+    /// program, or a table with the program (`cel`) and further settings,
+    /// possibly inherited from the policy named as `extends`.
+    #[serde(default, deserialize_with = "crate::policy::deserialize_named")]
+    pub policies: HashMap<String, Policy>,
+
+    /// Constants and macros available to all CEL programs.
+    #[serde(default)]
+    pub cel: CelConfig,
+
+    /// Values of command line flags.
+    #[serde(flatten)]
+    flags: serde_json::Map<String, Value>,
+}
+
+/// The `cel` table of config files.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct CelConfig {
+    /// Values added to the context of every CEL program, by variable name.
+    #[serde(default)]
+    pub constants: BTreeMap<String, Value>,
+
+    /// Snippets expanded into every CEL program before compiling it.
+    #[serde(default)]
+    pub macros: Macros,
+}
+
+impl CelConfig {
+    /// The constants, converted to CEL values.
+    /// Names need to be valid identifiers, and must not shadow the
+    /// variables provided by cellulose itself.
+    pub fn constants(&self) -> Result<HashMap<String, cel_interpreter::Value>, ConfigError> {
+        self.constants
+            .iter()
+            .map(|(name, value)| {
+                if !cel_macros::is_ident(name) || context_schema::is_reserved(name) {
+                    return Err(err(format!("cel.constants: invalid name {}", name)));
+                }
+                let value = cel_interpreter::to_value(value)
+                    .map_err(|e| err(format!("cel.constants.{}: {}", name, e)))?;
+                Ok((name.clone(), value))
+            })
+            .collect()
+    }
+}
+
+/// Render a scalar config value as command line argument value.
+fn scalar(key: &str, value: &Value) -> Result<String, ConfigError> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(err(format!("{}: expected a string, number or bool", key))),
+    }
+}
+
+impl ConfigFile {
+    /// Load a config file, in TOML or YAML format depending on its extension.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| err(format!("unable to read {}: {}", path.display(), e)))?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| e.to_string()),
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+            _ => Err("unknown format, expected a .toml, .yaml or .yml file".to_string()),
+        }
+        .map_err(|e| err(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Render the flags from the config file as command line arguments, to
+    /// be parsed in front of the actual ones.
+    /// Flags set in [matches] from the command line or environment are
+    /// skipped, so these take precedence.
+    pub fn to_args(
+        &self,
+        command: &Command,
+        matches: &ArgMatches,
+    ) -> Result<Vec<OsString>, ConfigError> {
+        let mut positionals = Vec::new();
+        let mut flags = Vec::new();
+
+        for (key, value) in &self.flags {
+            // keys are the long flag names, or the ids of positional
+            // arguments.
+            let arg = command
+                .get_arguments()
+                .find(|arg| match arg.get_long() {
+                    Some(long) => long == key,
+                    None => arg.get_id() == key.replace('-', "_").as_str(),
+                })
+                .filter(|arg| arg.get_id() != "config")
+                .ok_or_else(|| err(format!("unknown config key {}", key)))?;
+
+            if matches!(
+                matches.value_source(arg.get_id().as_str()),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            ) {
+                continue;
+            }
+
+            // Lists become repeated flags, tables KEY=VALUE pairs.
+            let values = match value {
+                Value::Array(values) => values
+                    .iter()
+                    .map(|v| scalar(key, v))
+                    .collect::<Result<Vec<_>, _>>()?,
+                Value::Object(entries) => entries
+                    .iter()
+                    .map(|(k, v)| Ok(format!("{}={}", k, scalar(key, v)?)))
+                    .collect::<Result<Vec<_>, _>>()?,
+                v => vec![scalar(key, v)?],
+            };
+
+            match (arg.get_long(), arg.get_action()) {
+                (None, _) => positionals.extend(values),
+                (Some(long), ArgAction::SetTrue) => match value {
+                    Value::Bool(true) => flags.push(format!("--{}", long)),
+                    Value::Bool(false) => {}
+                    _ => return Err(err(format!("{}: expected a bool", key))),
+                },
+                (Some(long), _) => flags.extend(values.iter().map(|v| format!("--{}={}", long, v))),
+            }
+        }
+
+        Ok(positionals
+            .into_iter()
+            .chain(flags)
+            .map(OsString::from)
+            .collect())
+    }
+}
+
+/// Load named policies from the `*.cel` files in a directory, named by their
+/// file name without extension. Other files are ignored.
+pub fn load_policy_dir(dir: &Path) -> Result<HashMap<String, Policy>, ConfigError> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| err(format!("unable to read {}: {}", dir.display(), e)))?;
+
+    let mut policies = HashMap::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| err(format!("unable to read {}: {}", dir.display(), e)))?
+            .path();
+        if path.extension().and_then(|e| e.to_str()) != Some("cel") || !path.is_file() {
+            continue;
+        }
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| err(format!("{}: invalid policy name", path.display())))?;
+        let cel_str = std::fs::read_to_string(&path)
+            .map_err(|e| err(format!("unable to read {}: {}", path.display(), e)))?;
+        let policy = Policy {
+            source: Some(path.display().to_string().into()),
+            ..Policy::from(cel_str.trim().to_owned())
+        };
+        policies.insert(name.to_owned(), policy);
+    }
+
+    Ok(policies)
+}
+
+/// Parse the command line arguments, applying the config file passed via
+/// the argument with id [config_id], if any.
+/// Returns the matches along with the config file.
+pub fn parse_args(
+    command: Command,
+    config_id: &str,
+) -> Result<(ArgMatches, ConfigFile), ConfigError> {
+    let matches = command.clone().get_matches();
+    let Some(path) = matches.get_one::<PathBuf>(config_id) else {
+        return Ok((matches, ConfigFile::default()));
+    };
+
+    let config = ConfigFile::load(path)?;
+    let mut args = std::env::args_os();
+    let args = args
+        .next()
+        .into_iter()
+        .chain(config.to_args(&command, &matches)?)
+        .chain(args)
+        .collect::<Vec<_>>();
+
+    Ok((command.get_matches_from(args), config))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, Parser};
+
+    use super::{load_policy_dir, ConfigFile};
+
+    #[derive(Parser)]
+    struct Cli {
+        uris: Vec<String>,
+        #[clap(long)]
+        issuer: Vec<String>,
+        #[clap(long, value_parser = parse_pair)]
+        pair: Vec<(String, String)>,
+        #[clap(long, default_value_t = 0)]
+        grace_secs: u64,
+        #[clap(long)]
+        watch: bool,
+        #[clap(long, env = "CELLULOSE_TEST_LISTEN")]
+        listen: Option<String>,
+    }
+
+    fn parse_pair(s: &str) -> Result<(String, String), String> {
+        s.split_once('=')
+            .map(|(a, b)| (a.to_owned(), b.to_owned()))
+            .ok_or_else(|| "expected A=B".to_owned())
+    }
+
+    #[test]
+    fn flags() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            uris = ["https://a/jwks"]
+            issuer = ["https://b"]
+            grace-secs = 30
+            watch = true
+            listen = "[::]:1234"
+
+            [pair]
+            "https://c" = "https://c/jwks"
+
+            [verification]
+            allowed_audiences = ["aud"]
+
+            [policies]
+            admin = "'admin' in jwt_claims.groups"
+            senior = { cel = "jwt_claims.level > 2", coerce = { level = "int" } }
+
+            [cel.constants]
+            ADMIN_GROUPS = ["admins", "sre"]
+
+            [cel.macros]
+            is_admin = "jwt_claims.groups.exists(g, g in ADMIN_GROUPS)"
+            "#,
+        )
+        .expect("must parse");
+        assert_eq!(2, config.policies.len());
+        assert_eq!(1, config.policies["senior"].coerce.len());
+        assert_eq!(1, config.cel.constants().expect("must convert").len());
+        assert!(!config.cel.macros.is_empty());
+        assert!(config.verification.allowed_audiences.is_some());
+
+        let cli_args = ["cli", "--issuer", "https://cli"];
+        let matches = Cli::command().get_matches_from(cli_args);
+        let args = config
+            .to_args(&Cli::command(), &matches)
+            .expect("must render");
+        let cli = Cli::parse_from(
+            std::iter::once("cli".into())
+                .chain(args)
+                .chain(cli_args[1..].iter().map(Into::into)),
+        );
+
+        assert_eq!(vec!["https://a/jwks"], cli.uris);
+        // overridden on the command line
+        assert_eq!(vec!["https://cli"], cli.issuer);
+        assert_eq!(
+            vec![("https://c".to_string(), "https://c/jwks".to_string())],
+            cli.pair
+        );
+        assert_eq!(30, cli.grace_secs);
+        assert!(cli.watch);
+        assert_eq!(Some("[::]:1234".to_string()), cli.listen);
+
+        let config: ConfigFile =
+            serde_yaml::from_str("cel: {constants: {jwt_claims: 1}}").expect("must parse");
+        assert!(config.cel.constants().is_err());
+
+        let config: ConfigFile = serde_yaml::from_str("unknown: 1").expect("must parse");
+        assert!(config.to_args(&Cli::command(), &matches).is_err());
+    }
+
+    #[test]
+    fn policy_dir() {
+        let dir = tempfile::tempdir().expect("must create tempdir");
+        std::fs::write(
+            dir.path().join("admin-only.cel"),
+            "'admin' in jwt_claims.groups\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("README.md"), "not a policy").unwrap();
+
+        let policies = load_policy_dir(dir.path()).expect("must load");
+        assert_eq!(1, policies.len());
+        assert_eq!("'admin' in jwt_claims.groups", policies["admin-only"].cel);
+        assert!(policies["admin-only"]
+            .source
+            .as_deref()
+            .is_some_and(|s| s.ends_with("admin-only.cel")));
+
+        assert!(load_policy_dir(&dir.path().join("missing")).is_err());
+    }
+}
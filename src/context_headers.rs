@@ -3,15 +3,81 @@ use std::{collections::HashMap, sync::Arc};
 use axum::http::{HeaderMap, HeaderName, HeaderValue};
 use cel_interpreter::Value;
 
+use crate::metrics::METRICS;
+
+/// What to do with header values that aren't valid UTF-8.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum NonUtf8Headers {
+    /// Expose them to CEL programs as bytes, like all values with
+    /// characters other than visible ASCII.
+    #[default]
+    Bytes,
+    /// Deny requests carrying them with a 400. All other values are exposed
+    /// as strings.
+    Reject,
+    /// Remove them, before the policy sees the request. All other values are
+    /// exposed as strings.
+    Strip,
+}
+
+impl NonUtf8Headers {
+    /// Count the header values that aren't valid UTF-8, and strip them, or
+    /// return an error naming the first one, depending on the mode.
+    pub fn apply(self, headers: &mut HeaderMap) -> Result<(), String> {
+        let invalid = |value: &HeaderValue| std::str::from_utf8(value.as_bytes()).is_err();
+        let count = headers.values().filter(|value| invalid(value)).count();
+        if count == 0 {
+            return Ok(());
+        }
+        METRICS
+            .non_utf8_headers
+            .with_labels(&[])
+            .inc_by(count as u64);
+
+        let names = headers
+            .iter()
+            .filter(|(_, value)| invalid(value))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        match self {
+            NonUtf8Headers::Bytes => {}
+            NonUtf8Headers::Reject => {
+                return Err(format!("value of header {} isn't valid UTF-8", names[0]));
+            }
+            NonUtf8Headers::Strip => {
+                for name in names {
+                    let valid = headers
+                        .get_all(&name)
+                        .iter()
+                        .filter(|value| !invalid(value))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    headers.remove(&name);
+                    for value in valid {
+                        headers.append(name.clone(), value);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 // Convert a `Vec<HeaderValue>` to a [Value].
 // Single-element vectors are converted to the single element.
-fn header_values_to_value(hvs: Vec<HeaderValue>) -> Value {
-    fn to_cel_value(hv: HeaderValue) -> Value {
-        match hv.to_str() {
-            Ok(s) => Value::String(Arc::new(s.to_owned())),
-            Err(_) => Value::Bytes(Arc::new(hv.as_bytes().to_vec())),
+fn header_values_to_value(hvs: Vec<HeaderValue>, mode: NonUtf8Headers) -> Value {
+    let to_cel_value = |hv: HeaderValue| {
+        let s = match mode {
+            NonUtf8Headers::Bytes => hv.to_str().ok(),
+            NonUtf8Headers::Reject | NonUtf8Headers::Strip => {
+                std::str::from_utf8(hv.as_bytes()).ok()
+            }
+        };
+        match s {
+            Some(s) => Value::String(Arc::new(s.to_owned())),
+            None => Value::Bytes(Arc::new(hv.as_bytes().to_vec())),
         }
-    }
+    };
 
     let mut vs = hvs.into_iter().map(to_cel_value).collect::<Vec<_>>();
     if vs.len() == 1 {
@@ -21,7 +87,10 @@ fn header_values_to_value(hvs: Vec<HeaderValue>) -> Value {
     }
 }
 
-pub fn parse_headers(header_map: HeaderMap<HeaderValue>) -> Value {
+/// Convert the headers to a map of CEL values. Values are strings if they
+/// only consist of visible ASCII, or, unless [mode] is
+/// [NonUtf8Headers::Bytes], are valid UTF-8, and bytes otherwise.
+pub fn parse_headers(header_map: HeaderMap<HeaderValue>, mode: NonUtf8Headers) -> Value {
     let mut out: HashMap<String, Value> = HashMap::new();
 
     let last = header_map.into_iter().fold(
@@ -32,7 +101,7 @@ pub fn parse_headers(header_map: HeaderMap<HeaderValue>) -> Value {
                 if let Some((prev_hn, prev_hvs)) = prev {
                     out.insert(
                         prev_hn.as_str().to_owned(),
-                        header_values_to_value(prev_hvs),
+                        header_values_to_value(prev_hvs, mode),
                     );
                 }
 
@@ -47,7 +116,7 @@ pub fn parse_headers(header_map: HeaderMap<HeaderValue>) -> Value {
         },
     );
     if let Some((hn, hv)) = last {
-        out.insert(hn.as_str().to_owned(), header_values_to_value(hv));
+        out.insert(hn.as_str().to_owned(), header_values_to_value(hv, mode));
     }
 
     out.into()
@@ -57,7 +126,7 @@ pub fn parse_headers(header_map: HeaderMap<HeaderValue>) -> Value {
 mod tests {
     use std::{collections::HashMap, sync::Arc};
 
-    use super::parse_headers;
+    use super::{parse_headers, NonUtf8Headers};
     use axum::http::{HeaderMap, HeaderValue};
     use cel_interpreter::{objects::Map, Value};
 
@@ -67,7 +136,7 @@ mod tests {
             Value::Map(Map {
                 map: Arc::new(HashMap::new())
             }),
-            parse_headers(HeaderMap::new())
+            parse_headers(HeaderMap::new(), NonUtf8Headers::Bytes)
         );
     }
 
@@ -94,7 +163,7 @@ mod tests {
                     )
                 ]))
             }),
-            parse_headers(hm)
+            parse_headers(hm, NonUtf8Headers::Bytes)
         );
     }
 
@@ -125,7 +194,49 @@ mod tests {
                     )
                 ]))
             }),
-            parse_headers(hm)
+            parse_headers(hm, NonUtf8Headers::Bytes)
+        );
+    }
+
+    #[test]
+    fn strict_utf8() {
+        let mut hm = HeaderMap::new();
+        hm.insert("a", HeaderValue::from_static("b"));
+        hm.insert("name", HeaderValue::from_bytes("Zoë".as_bytes()).unwrap());
+        hm.append(
+            "name",
+            HeaderValue::from_bytes(b"bar\xc5\xc4\xd6foo").unwrap(),
+        );
+
+        let err = NonUtf8Headers::Reject
+            .apply(&mut hm.clone())
+            .expect_err("must reject");
+        assert_eq!("value of header name isn't valid UTF-8", err);
+
+        let mut stripped = hm.clone();
+        NonUtf8Headers::Strip.apply(&mut stripped).unwrap();
+        assert_eq!(2, stripped.len());
+        let Value::Map(map) = parse_headers(stripped, NonUtf8Headers::Strip) else {
+            panic!("must be a map");
+        };
+        assert_eq!(
+            Some(&Value::String(Arc::new("Zoë".to_string()))),
+            map.map.get(&cel_interpreter::objects::Key::String(Arc::new(
+                "name".to_string()
+            )))
         );
+
+        // left as is, non-ASCII values stay bytes
+        let mut kept = hm.clone();
+        NonUtf8Headers::Bytes.apply(&mut kept).unwrap();
+        assert_eq!(hm, kept);
+        let Value::Map(map) = parse_headers(kept, NonUtf8Headers::Bytes) else {
+            panic!("must be a map");
+        };
+        assert!(matches!(
+            map.map
+                .get(&cel_interpreter::objects::Key::String(Arc::new("name".to_string()))),
+            Some(Value::List(values)) if matches!(values[0], Value::Bytes(_))
+        ));
     }
 }
@@ -0,0 +1,189 @@
+//! The registered claims of a token, as typed CEL values, so programs don't
+//! need to know how they're encoded in the token.
+use std::{collections::HashMap, sync::Arc};
+
+use cel_interpreter::Value;
+use chrono::DateTime;
+
+use crate::{CustomClaims, NumberMode};
+
+/// Claims holding a string.
+const STRING_CLAIMS: &[&str] = &["sub", "iss", "jti"];
+
+/// Claims holding a NumericDate (seconds since the epoch).
+const TIME_CLAIMS: &[&str] = &["exp", "nbf", "iat"];
+
+/// Convert a NumericDate, which may have a fractional part, to a timestamp.
+fn timestamp(value: &serde_json::Value) -> Option<Value> {
+    let secs = value.as_f64()?;
+    let ts = DateTime::from_timestamp(secs.trunc() as i64, (secs.fract() * 1e9) as u32)?;
+    Some(Value::Timestamp(ts.fixed_offset()))
+}
+
+/// The largest integer doubles represent exactly, 2^53.
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Convert an integral number to an int, or a string if it doesn't fit one,
+/// see [NumberMode::Int]. Others stay doubles.
+fn int(n: &serde_json::Number) -> Value {
+    if let Some(i) = n.as_i64() {
+        return Value::Int(i);
+    }
+    if let Some(u) = n.as_u64() {
+        return Value::String(Arc::new(u.to_string()));
+    }
+    let f = n.as_f64().unwrap_or_default();
+    if f.fract() != 0.0 {
+        Value::Float(f)
+    } else if f.abs() <= MAX_EXACT_INTEGER {
+        Value::Int(f as i64)
+    } else {
+        Value::String(Arc::new(format!("{:.0}", f)))
+    }
+}
+
+/// Convert claims (or any JSON value) to a CEL value, with numbers
+/// converted according to [numbers].
+pub fn to_value(value: serde_json::Value, numbers: NumberMode) -> Value {
+    match (numbers, value) {
+        (NumberMode::Auto, value) => cel_interpreter::to_value(value).unwrap_or(Value::Null),
+        (NumberMode::Int, serde_json::Value::Number(n)) => int(&n),
+        (NumberMode::Int, serde_json::Value::Array(values)) => Value::List(Arc::new(
+            values.into_iter().map(|v| to_value(v, numbers)).collect(),
+        )),
+        (NumberMode::Int, serde_json::Value::Object(map)) => map
+            .into_iter()
+            .map(|(k, v)| (k, to_value(v, numbers)))
+            .collect::<HashMap<_, _>>()
+            .into(),
+        (NumberMode::Int, value) => cel_interpreter::to_value(value).unwrap_or(Value::Null),
+    }
+}
+
+/// Build the `jwt` variable from the claims: sub, iss and jti as strings,
+/// exp, nbf and iat as timestamps, and aud as a list of strings, even if the
+/// token has a single audience. Claims that are absent, or of the wrong
+/// type, are null (or an empty list for aud).
+pub fn standard_claims(claims: &CustomClaims) -> Value {
+    let mut out = HashMap::new();
+    for name in STRING_CLAIMS {
+        let value = claims.get(*name).and_then(|v| v.as_str());
+        out.insert(*name, Value::from(value.map(str::to_owned)));
+    }
+    for name in TIME_CLAIMS {
+        let value = claims.get(*name).and_then(timestamp);
+        out.insert(*name, value.unwrap_or(Value::Null));
+    }
+
+    let aud = match claims.get("aud") {
+        Some(serde_json::Value::String(aud)) => vec![aud.as_str().into()],
+        Some(serde_json::Value::Array(auds)) => auds
+            .iter()
+            .filter_map(|aud| aud.as_str())
+            .map(Value::from)
+            .collect(),
+        _ => vec![],
+    };
+    out.insert("aud", Value::List(Arc::new(aud)));
+
+    out.into()
+}
+
+/// Build the `actors` variable from the `act` claim (RFC 8693 token
+/// exchange): the delegation chain, starting with the current actor, then
+/// the ones it acted on behalf of in turn. Each actor is a map of its
+/// claims, without the nested `act`. Empty if the token has no `act` claim,
+/// the chain ends at the first one that isn't an object. Numbers are
+/// converted according to [numbers].
+pub fn actors(claims: &CustomClaims, numbers: NumberMode) -> Value {
+    let mut actors = vec![];
+    let mut act = claims.get("act");
+    while let Some(serde_json::Value::Object(actor)) = act {
+        act = actor.get("act");
+        let mut actor = actor.clone();
+        actor.remove("act");
+        actors.push(to_value(actor.into(), numbers));
+    }
+    Value::List(Arc::new(actors))
+}
+
+#[cfg(test)]
+mod tests {
+    use cel_interpreter::{Context, Program, Value};
+
+    use super::{standard_claims, to_value};
+    use crate::NumberMode;
+
+    #[test]
+    fn typed_claims() {
+        let claims = serde_json::json!({
+            "sub": "alice",
+            "iss": "https://issuer.example.com",
+            "aud": "api",
+            "exp": 1_700_003_600,
+            "iat": 1_700_000_000.5,
+            "nbf": "soon",
+        });
+        let mut context = Context::default();
+        context.add_variable_from_value("jwt", standard_claims(claims.as_object().unwrap()));
+        let claims = serde_json::json!({ "aud": ["a", "b"] });
+        context.add_variable_from_value("other", standard_claims(claims.as_object().unwrap()));
+
+        for expr in [
+            r#"jwt.sub == "alice" && jwt.iss == "https://issuer.example.com""#,
+            r#"jwt.aud == ["api"] && other.aud == ["a", "b"]"#,
+            r#"jwt.exp == timestamp("2023-11-14T23:13:20Z")"#,
+            r#"jwt.exp - jwt.iat > duration("59m") && jwt.exp - jwt.iat < duration("60m")"#,
+            "jwt.nbf == null && jwt.jti == null",
+            "other.sub == null && other.exp == null",
+        ] {
+            let program = Program::compile(expr).expect("must compile");
+            assert_eq!(
+                Value::Bool(true),
+                program.execute(&context).expect("must execute"),
+                "{}",
+                expr
+            );
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        let claims = serde_json::json!({
+            "id": 1_234_567_890_123_456_789_i64,
+            "big": u64::MAX,
+            "neg": -1,
+            "ratio": 2.5,
+            "nested": { "levels": [1, 2] },
+        });
+        let mut claims = claims.as_object().unwrap().clone();
+        for (name, json) in [("exp", "1e3"), ("huge", "1e20")] {
+            claims.insert(name.to_string(), serde_json::from_str(json).unwrap());
+        }
+        let claims = serde_json::Value::Object(claims);
+
+        let mut context = Context::default();
+        context.add_variable_from_value("auto", to_value(claims.clone(), NumberMode::Auto));
+        context.add_variable_from_value("int", to_value(claims, NumberMode::Int));
+
+        for expr in [
+            "int.id == 1234567890123456789 && int.id + 1 == 1234567890123456790",
+            r#"int.big == "18446744073709551615" && int.huge == "100000000000000000000""#,
+            "int.neg + 1 == 0 && int.ratio == 2.5 && int.exp + 1 == 1001",
+            "int.nested.levels[1] + 1 == 3",
+            "auto.id == 1234567890123456789u && auto.big == 18446744073709551615u",
+            "auto.neg == -1 && auto.ratio == 2.5 && auto.exp == 1000.0",
+        ] {
+            let program = Program::compile(expr).expect("must compile");
+            assert_eq!(
+                Value::Bool(true),
+                program.execute(&context).expect("must execute"),
+                "{}",
+                expr
+            );
+        }
+        // uints don't mix with int literals
+        let program = Program::compile("auto.id + 1 > 0").expect("must compile");
+        assert!(program.execute(&context).is_err());
+    }
+}
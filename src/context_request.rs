@@ -0,0 +1,189 @@
+//! Details about the original request, as forwarded by the proxy, parsed
+//! into structured CEL values.
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use axum::http::HeaderMap;
+use cel_interpreter::Value;
+
+/// The header carrying the path and query of the original request.
+pub const X_FORWARDED_URI: &str = "x-forwarded-uri";
+pub const X_FORWARDED_METHOD: &str = "x-forwarded-method";
+pub const X_FORWARDED_HOST: &str = "x-forwarded-host";
+pub const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Decode percent-encoded octets, and `+` as space if [plus_as_space].
+/// Invalid escapes are kept as-is, invalid UTF-8 replaced.
+pub(crate) fn percent_decode(s: &str, plus_as_space: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(b) => {
+                        out.push(b);
+                        i += 3;
+                        continue;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b'+' if plus_as_space => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parse a query string into a map of decoded parameters.
+/// Parameters occurring multiple times are a list of values, like headers.
+fn parse_query(query: &str) -> Value {
+    let mut params: HashMap<String, Vec<Value>> = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        params
+            .entry(percent_decode(k, true))
+            .or_default()
+            .push(percent_decode(v, true).into());
+    }
+
+    params
+        .into_iter()
+        .map(|(k, mut vs)| {
+            let v = match vs.len() {
+                1 => vs.pop().expect("must have one element"),
+                _ => Value::List(Arc::new(vs)),
+            };
+            (k, v)
+        })
+        .collect::<HashMap<_, _>>()
+        .into()
+}
+
+/// Parse the X-Forwarded-Uri header into a map with the `raw` value, the
+/// `path` (as sent, not decoded) and the decoded `query` parameters.
+/// Returns null if the header is absent or not valid UTF-8.
+pub fn parse_url(headers: &HeaderMap) -> Value {
+    let Some(raw) = headers.get(X_FORWARDED_URI).and_then(|v| v.to_str().ok()) else {
+        return Value::Null;
+    };
+
+    // the fragment is never sent to servers, but be lenient
+    let without_fragment = raw.split_once('#').map_or(raw, |(uri, _)| uri);
+    let (path, query) = without_fragment
+        .split_once('?')
+        .unwrap_or((without_fragment, ""));
+
+    HashMap::from([
+        ("raw", Value::from(raw)),
+        ("path", Value::from(path)),
+        ("query", parse_query(query)),
+    ])
+    .into()
+}
+
+/// The first element of the (possibly repeated, comma-separated) header,
+/// which was added by the proxy closest to the client.
+fn first_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let first = value.split(',').next()?.trim();
+    (!first.is_empty()).then(|| first.to_owned())
+}
+
+/// Collect the details of the original request from the X-Forwarded-*
+/// headers, into a map with `method` (uppercase), `host` and `scheme`
+/// (lowercase), and `source_ip`, the client address as determined by
+/// [crate::forwarded_for::ForwardedFor::client_ip], which are null if unknown.
+pub fn parse_request(headers: &HeaderMap, source_ip: Option<IpAddr>) -> Value {
+    let method = first_value(headers, X_FORWARDED_METHOD).map(|m| m.to_ascii_uppercase());
+    let host = first_value(headers, X_FORWARDED_HOST).map(|h| h.to_ascii_lowercase());
+    let scheme = first_value(headers, X_FORWARDED_PROTO).map(|p| p.to_ascii_lowercase());
+    let source_ip = source_ip.map(|ip| ip.to_string());
+
+    HashMap::from([
+        ("method", Value::from(method)),
+        ("host", Value::from(host)),
+        ("scheme", Value::from(scheme)),
+        ("source_ip", Value::from(source_ip)),
+    ])
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue};
+    use cel_interpreter::{Context, Program, Value};
+
+    use super::{parse_request, parse_url, X_FORWARDED_URI};
+
+    fn assert_all(context: &Context, exprs: &[&str]) {
+        for expr in exprs {
+            let program = Program::compile(expr).expect("must compile");
+            assert_eq!(
+                Value::Bool(true),
+                program.execute(context).expect("must execute"),
+                "{}",
+                expr
+            );
+        }
+    }
+
+    #[test]
+    fn request() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-method", HeaderValue::from_static("get"));
+        headers.insert(
+            "x-forwarded-host",
+            HeaderValue::from_static("API.example.com"),
+        );
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https, http"));
+
+        let mut context = Context::default();
+        context.add_variable_from_value(
+            "request",
+            parse_request(&headers, Some([10, 0, 0, 1].into())),
+        );
+        context.add_variable_from_value("empty", parse_request(&HeaderMap::new(), None));
+        assert_all(
+            &context,
+            &[
+                r#"request.method == "GET""#,
+                r#"request.host == "api.example.com""#,
+                r#"request.scheme == "https""#,
+                r#"request.source_ip == "10.0.0.1""#,
+                "empty.method == null && empty.source_ip == null",
+            ],
+        );
+    }
+
+    #[test]
+    fn url() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Value::Null, parse_url(&headers));
+
+        headers.insert(
+            X_FORWARDED_URI,
+            HeaderValue::from_static("/api/v1/users?id=1&tag=a%20b&tag=c+d&flag&bad=%zz"),
+        );
+        let mut context = Context::default();
+        context.add_variable_from_value("url", parse_url(&headers));
+
+        assert_all(
+            &context,
+            &[
+                r#"url.raw == "/api/v1/users?id=1&tag=a%20b&tag=c+d&flag&bad=%zz""#,
+                r#"url.path == "/api/v1/users""#,
+                r#"url.query.id == "1""#,
+                r#"url.query.tag == ["a b", "c d"]"#,
+                r#"url.query.flag == """#,
+                r#"url.query.bad == "%zz""#,
+            ],
+        );
+    }
+}
@@ -0,0 +1,394 @@
+//! Description of the variables and functions available to CEL programs.
+//!
+//! Variables are added to the CEL context via the definitions here, so
+//! the description served at /-/context-schema can't drift from what's
+//! actually available.
+use axum::{extract::State, Json};
+
+use crate::AppState;
+
+/// A variable available to CEL programs.
+#[derive(Debug, serde::Serialize)]
+pub struct Variable {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub typ: &'static str,
+    pub description: &'static str,
+}
+
+/// A function available to CEL programs.
+#[derive(Debug, serde::Serialize)]
+pub struct Function {
+    pub name: &'static str,
+    pub signature: &'static str,
+    pub description: &'static str,
+}
+
+pub static REQUEST_HEADERS: Variable = Variable {
+    name: "request_headers",
+    typ: "map(string, string | bytes | list(string | bytes))",
+    description: "Headers of the incoming request, with lowercase names. \
+                  Headers occurring multiple times are a list of values, \
+                  values that aren't valid UTF-8 are bytes, unless \
+                  rejected or stripped per --non-utf8-headers. Credentials \
+                  (Authorization, Cookie, Proxy-Authorization, X-Api-Key \
+                  and --sensitive-header) are omitted.",
+};
+
+pub static JWT_CLAIMS: Variable = Variable {
+    name: "jwt_claims",
+    typ: "map(string, dyn)",
+    description: "Claims of the verified JWT. Registered claims (iss, sub, \
+                  aud, exp, nbf, iat, jti) as well as custom ones are present \
+                  if set in the token. For opaque tokens validated via token \
+                  introspection, the introspection response.",
+};
+
+pub static JWT: Variable = Variable {
+    name: "jwt",
+    typ: "map(string, dyn)",
+    description: "The registered claims of the token, typed: sub, iss and \
+                  jti as string, exp, nbf and iat as timestamp, and aud as \
+                  list(string), even for a single audience. Absent claims \
+                  are null (aud an empty list).",
+};
+
+pub static ACTORS: Variable = Variable {
+    name: "actors",
+    typ: "list(map(string, dyn))",
+    description: "The delegation chain from the act claim of the token \
+                  (token exchange): the current actor first, then the ones \
+                  it acted on behalf of. Each with the actor's claims, like \
+                  sub. Empty if the token has no act claim.",
+};
+
+pub static REQUEST: Variable = Variable {
+    name: "request",
+    typ: "map(string, string | null)",
+    description: "The original request, from the X-Forwarded-* headers: \
+                  method (uppercase), host and scheme (lowercase), and \
+                  source_ip (the same as client_ip). \
+                  Fields are null if the header is absent.",
+};
+
+pub static URL: Variable = Variable {
+    name: "url",
+    typ: "map(string, dyn) | null",
+    description: "The X-Forwarded-Uri header, with the value as raw, the \
+                  path (not decoded) as path, and the decoded query \
+                  parameters as query, a map(string, string | \
+                  list(string)). Null if the header is absent.",
+};
+
+pub static CLIENT_IP: Variable = Variable {
+    name: "client_ip",
+    typ: "string | null",
+    description: "The X-Forwarded-For address added by the outermost \
+                  proxy, the rightmost one unless --xff-depth is set. Null \
+                  if the header is absent or has too few entries.",
+};
+
+pub static GEO: Variable = Variable {
+    name: "geo",
+    typ: "map(string, dyn) | null",
+    description: "The client address looked up in the GeoIP databases: \
+                  country (ISO 3166-1 alpha-2 code, like \"DE\"), asn (int) \
+                  and as_org, each null if not contained in any database. \
+                  Null if the client address is unknown, only present if \
+                  GeoIP databases are configured.",
+};
+
+pub static CLIENT_CERT: Variable = Variable {
+    name: "client_cert",
+    typ: "map(string, dyn) | null",
+    description: "The client certificate forwarded by the proxy, with \
+                  hash (hex SHA-256), subject and issuer (RFC 4514), serial \
+                  (hex), uris and dns (lists of SANs), spiffe_id (the \
+                  spiffe:// URI SAN) and by (the proxy's identity, from \
+                  the XFCC header). Fields the proxy doesn't forward are \
+                  null. Null if absent, only present if \
+                  a client certificate format is configured.",
+};
+
+pub static IDENTITIES: Variable = Variable {
+    name: "identities",
+    typ: "map(string, map(string, dyn))",
+    description: "The identities of the request, by kind: jwt, and \
+                  client_cert and api_key if configured. Each with present \
+                  and verified (bools), and name (the API key's, else \
+                  null). Depending on the combiner, policies may be \
+                  evaluated with only some of them verified, with \
+                  jwt_claims empty if the token isn't.",
+};
+
+pub static NOW: Variable = Variable {
+    name: "now",
+    typ: "timestamp",
+    description: "The time of the decision, in UTC. The same for the whole \
+                  evaluation of the program.",
+};
+
+pub static FLAGS: Variable = Variable {
+    name: "flags",
+    typ: "map(string, bool)",
+    description: "Feature flags from the flags file, by name. Flags past \
+                  their expiry are false. Only present if a flags file is \
+                  configured.",
+};
+
+pub static MATCHES: Function = Function {
+    name: "matches",
+    signature: "string.matches(string) -> bool, matches(string, string) -> bool",
+    description: "Whether the string matches the regular expression. \
+                  Compiled expressions are cached.",
+};
+
+pub static IP_IN_CIDR: Function = Function {
+    name: "ip_in_cidr",
+    signature: "ip_in_cidr(string | null, string) -> bool",
+    description: "Whether the IP address is in the CIDR range, like \
+                  \"10.0.0.0/8\". IPv4-mapped IPv6 addresses match IPv4 \
+                  ranges, null never matches.",
+};
+
+pub static BASE64_DECODE: Function = Function {
+    name: "base64_decode",
+    signature: "base64_decode(string) -> bytes",
+    description: "Decode base64, in the standard or URL-safe alphabet, with \
+                  or without padding.",
+};
+
+pub static PARSE_JSON: Function = Function {
+    name: "parse_json",
+    signature: "parse_json(string | bytes) -> dyn",
+    description: "Parse a JSON document, like \
+                  parse_json(base64_decode(request_headers[\"x-claims\"])).",
+};
+
+pub static IMPERSONATED_BY: Function = Function {
+    name: "impersonatedBy",
+    signature: "impersonatedBy(string) -> bool",
+    description: "Whether the subject is one of the actors, like \
+                  impersonatedBy(\"gateway-svc\").",
+};
+
+/// Accessors for the fields of timestamps, like in the CEL spec. They take
+/// an optional time zone, as a fixed offset like "+01:00" or "UTC" (the
+/// default). Time zone names aren't supported.
+pub static TIMESTAMP_ACCESSORS: &[Function] = &[
+    Function {
+        name: "getFullYear",
+        signature: "timestamp.getFullYear([string]) -> int",
+        description: "The year.",
+    },
+    Function {
+        name: "getMonth",
+        signature: "timestamp.getMonth([string]) -> int",
+        description: "The month, from 0 (January) to 11.",
+    },
+    Function {
+        name: "getDayOfYear",
+        signature: "timestamp.getDayOfYear([string]) -> int",
+        description: "The day of the year, from 0 to 365.",
+    },
+    Function {
+        name: "getDayOfMonth",
+        signature: "timestamp.getDayOfMonth([string]) -> int",
+        description: "The day of the month, from 0 to 30.",
+    },
+    Function {
+        name: "getDate",
+        signature: "timestamp.getDate([string]) -> int",
+        description: "The day of the month, from 1 to 31.",
+    },
+    Function {
+        name: "getDayOfWeek",
+        signature: "timestamp.getDayOfWeek([string]) -> int",
+        description: "The day of the week, from 0 (Sunday) to 6.",
+    },
+    Function {
+        name: "getHours",
+        signature: "timestamp.getHours([string]) -> int",
+        description: "The hour, from 0 to 23.",
+    },
+    Function {
+        name: "getMinutes",
+        signature: "timestamp.getMinutes([string]) -> int",
+        description: "The minute, from 0 to 59.",
+    },
+    Function {
+        name: "getSeconds",
+        signature: "timestamp.getSeconds([string]) -> int",
+        description: "The second, from 0 to 59.",
+    },
+    Function {
+        name: "getMilliseconds",
+        signature: "timestamp.getMilliseconds([string]) -> int",
+        description: "The millisecond, from 0 to 999.",
+    },
+];
+
+/// Functions registered by the CEL interpreter by default.
+static BUILTIN_FUNCTIONS: &[Function] = &[
+    Function {
+        name: "contains",
+        signature: "(string | list | map).contains(dyn) -> bool",
+        description: "Whether the string contains the substring, or the list \
+                      or map the element or key.",
+    },
+    Function {
+        name: "size",
+        signature: "size(string | bytes | list | map) -> int",
+        description: "Length of the value.",
+    },
+    Function {
+        name: "has",
+        signature: "has(map.field) -> bool",
+        description: "Whether the field is present.",
+    },
+    Function {
+        name: "map",
+        signature: "list.map(x, expr) -> list",
+        description: "Transform each element.",
+    },
+    Function {
+        name: "filter",
+        signature: "list.filter(x, expr) -> list",
+        description: "Elements for which expr is true.",
+    },
+    Function {
+        name: "all",
+        signature: "list.all(x, expr) -> bool",
+        description: "Whether expr is true for all elements.",
+    },
+    Function {
+        name: "exists",
+        signature: "list.exists(x, expr) -> bool",
+        description: "Whether expr is true for any element.",
+    },
+    Function {
+        name: "exists_one",
+        signature: "list.exists_one(x, expr) -> bool",
+        description: "Whether expr is true for exactly one element.",
+    },
+    Function {
+        name: "max",
+        signature: "max(number...) -> number",
+        description: "The largest of the arguments.",
+    },
+    Function {
+        name: "startsWith",
+        signature: "string.startsWith(string) -> bool",
+        description: "Whether the string starts with the prefix.",
+    },
+    Function {
+        name: "endsWith",
+        signature: "string.endsWith(string) -> bool",
+        description: "Whether the string ends with the suffix.",
+    },
+    Function {
+        name: "duration",
+        signature: "duration(string) -> duration",
+        description: "Parse a duration, like \"1h30m\".",
+    },
+    Function {
+        name: "timestamp",
+        signature: "timestamp(string) -> timestamp",
+        description: "Parse an RFC 3339 timestamp.",
+    },
+    Function {
+        name: "string",
+        signature: "string(dyn) -> string",
+        description: "Convert to string.",
+    },
+    Function {
+        name: "bytes",
+        signature: "bytes(string) -> bytes",
+        description: "Convert to bytes.",
+    },
+    Function {
+        name: "double",
+        signature: "double(dyn) -> double",
+        description: "Convert to double.",
+    },
+    Function {
+        name: "int",
+        signature: "int(dyn) -> int",
+        description: "Convert to int.",
+    },
+    Function {
+        name: "uint",
+        signature: "uint(dyn) -> uint",
+        description: "Convert to uint.",
+    },
+];
+
+/// Whether the name is used by one of the variables provided by cellulose.
+pub fn is_reserved(name: &str) -> bool {
+    [
+        &REQUEST_HEADERS,
+        &REQUEST,
+        &URL,
+        &CLIENT_IP,
+        &GEO,
+        &CLIENT_CERT,
+        &JWT_CLAIMS,
+        &JWT,
+        &ACTORS,
+        &IDENTITIES,
+        &NOW,
+        &FLAGS,
+    ]
+    .iter()
+    .any(|v| v.name == name)
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Schema {
+    pub variables: Vec<&'static Variable>,
+    pub functions: Vec<&'static Function>,
+}
+
+/// Returns the schema of the CEL context, as configured for this instance.
+pub fn schema(state: &AppState) -> Schema {
+    let mut variables = vec![
+        &REQUEST_HEADERS,
+        &REQUEST,
+        &URL,
+        &CLIENT_IP,
+        &JWT_CLAIMS,
+        &JWT,
+        &ACTORS,
+        &IDENTITIES,
+        &NOW,
+    ];
+    if state.reloadable.load().geoip.is_some() {
+        variables.push(&GEO);
+    }
+    if state.client_cert.is_some() {
+        variables.push(&CLIENT_CERT);
+    }
+    if state.reloadable.load().flags.is_some() {
+        variables.push(&FLAGS);
+    }
+
+    Schema {
+        variables,
+        functions: BUILTIN_FUNCTIONS
+            .iter()
+            .chain([
+                &MATCHES,
+                &IP_IN_CIDR,
+                &BASE64_DECODE,
+                &PARSE_JSON,
+                &IMPERSONATED_BY,
+            ])
+            .chain(TIMESTAMP_ACCESSORS)
+            .collect(),
+    }
+}
+
+/// Serve the schema of the CEL context as JSON.
+pub async fn handler(State(state): State<AppState>) -> Json<Schema> {
+    Json(schema(&state))
+}
@@ -0,0 +1,345 @@
+//! Metadata about positive decisions, passed on to the upstream, and the
+//! details of negative ones.
+//!
+//! Each frontend returns it the way its protocol supports: as response
+//! headers for forward_auth, as fields in the batch API, and as variables
+//! for HAProxy SPOE.
+use std::time::Duration;
+
+use axum::{
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use cel_interpreter::{
+    objects::{Key, Map},
+    Value,
+};
+
+use crate::{baggage, scopes};
+
+/// Metadata about a positive decision.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DecisionMetadata {
+    /// The scopes relevant for the policy granted to the token, if requested.
+    pub scopes: Option<Vec<String>>,
+
+    /// Selected claims in W3C baggage format, if configured.
+    pub baggage: Option<String>,
+
+    /// Further headers: identity headers rendered from claims, if
+    /// configured, and the ones returned by the policy.
+    pub headers: Vec<(HeaderName, String)>,
+}
+
+impl DecisionMetadata {
+    /// The metadata as (name, value) pairs, named like the HTTP headers
+    /// they're sent as.
+    pub fn entries(&self) -> Vec<(&str, String)> {
+        let mut entries = Vec::new();
+        if let Some(scopes) = &self.scopes {
+            entries.push((scopes::X_AUTH_SCOPES, scopes.join(" ")));
+        }
+        if let Some(baggage) = &self.baggage {
+            entries.push((baggage::BAGGAGE, baggage.clone()));
+        }
+        for (name, value) in &self.headers {
+            entries.push((name.as_str(), value.clone()));
+        }
+        entries
+    }
+
+    /// Render the metadata as HTTP headers.
+    /// Values not allowed in headers are dropped. Scopes are restricted to
+    /// printable ASCII by RFC 6749, and baggage is percent-encoded, so this
+    /// only happens for further headers with control characters.
+    pub fn to_headers(&self) -> HeaderMap {
+        self.entries()
+            .into_iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    HeaderName::try_from(name).ok()?,
+                    HeaderValue::try_from(value).ok()?,
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Render a scalar CEL value, or None for other types.
+fn scalar(value: &Value) -> Option<String> {
+    Some(match value {
+        Value::String(s) => s.to_string(),
+        Value::Int(i) => i.to_string(),
+        Value::UInt(u) => u.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Bool(b) => b.to_string(),
+        _ => return None,
+    })
+}
+
+/// The headers returned by a policy as map, by name, sorted. Lists are
+/// joined with commas, null values are omitted.
+pub(crate) fn policy_headers(map: &Map) -> Result<Vec<(HeaderName, String)>, String> {
+    let mut headers = Vec::with_capacity(map.map.len());
+    for (key, value) in map.map.iter() {
+        let Key::String(name) = key else {
+            return Err(format!("header name {:?} isn't a string", key));
+        };
+        let name = HeaderName::try_from(name.as_str())
+            .map_err(|e| format!("invalid header name {}: {}", name, e))?;
+        let value = match value {
+            Value::Null => continue,
+            Value::List(values) => values
+                .iter()
+                .map(scalar)
+                .collect::<Option<Vec<_>>>()
+                .map(|values| values.join(",")),
+            value => scalar(value),
+        }
+        .ok_or_else(|| format!("value of header {} isn't a scalar or list", name))?;
+        headers.push((name, value));
+    }
+    headers.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+    Ok(headers)
+}
+
+/// The header carrying the upstream to route to, see [crate::Policy::backend].
+pub const X_AUTH_BACKEND: &str = "x-auth-backend";
+
+/// The upstream returned by the backend expression of a policy, or None
+/// for no hint. Names are restricted to letters, digits, `-`, `_` and `.`,
+/// so proxies can use them in routing rules as is.
+pub(crate) fn backend(value: &Value) -> Result<Option<String>, String> {
+    match value {
+        Value::Null => Ok(None),
+        Value::String(name)
+            if !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.')) =>
+        {
+            Ok(Some(name.to_string()))
+        }
+        Value::String(name) => Err(format!("invalid backend name {:?}", name)),
+        _ => Err("backend isn't a string or null".to_string()),
+    }
+}
+
+/// A negative decision.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Denial {
+    pub status: StatusCode,
+    /// Why access was denied, if the policy returned a reason.
+    pub reason: Option<String>,
+    /// Why the token was rejected, if it was, sent as `error_description`
+    /// of the challenge.
+    pub token_error: Option<&'static str>,
+    /// When to retry, if the denial is temporary, sent as `Retry-After`.
+    pub retry_after: Option<Duration>,
+    /// Whether the token failed verification against the keys, like when
+    /// guessing signatures, rather than being rejected despite being
+    /// properly signed. Counted by [crate::lockout].
+    pub counts_as_guess: bool,
+}
+
+impl From<StatusCode> for Denial {
+    fn from(status: StatusCode) -> Self {
+        Self {
+            status,
+            reason: None,
+            token_error: None,
+            retry_after: None,
+            counts_as_guess: false,
+        }
+    }
+}
+
+/// Quote a string for use in an auth-param (RFC 9110).
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Denial {
+    /// A denial due to an invalid token, for the reason given.
+    pub(crate) fn invalid_token(description: &'static str) -> Self {
+        Self {
+            token_error: Some(description),
+            ..StatusCode::UNAUTHORIZED.into()
+        }
+    }
+
+    /// The `WWW-Authenticate` challenge (RFC 6750) for 401 responses, in the
+    /// realm: `invalid_token` if a token was rejected, and without error
+    /// code if none was presented (or it was fine, but insufficient).
+    pub fn challenge(&self, realm: &str) -> Option<HeaderValue> {
+        if self.status != StatusCode::UNAUTHORIZED {
+            return None;
+        }
+        let mut challenge = format!("Bearer realm={}", quote(realm));
+        if let Some(description) = self.token_error {
+            challenge.push_str(&format!(
+                ", error=\"invalid_token\", error_description={}",
+                quote(description)
+            ));
+        }
+        HeaderValue::try_from(challenge).ok()
+    }
+}
+
+impl IntoResponse for Denial {
+    /// The status, with the reason as body, if any. The denial is attached
+    /// as extension, for [crate::error_pages] to render it differently.
+    fn into_response(self) -> Response {
+        let mut response = match &self.reason {
+            Some(reason) => (
+                self.status,
+                [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+                reason.clone(),
+            )
+                .into_response(),
+            None => self.status.into_response(),
+        };
+        if let Some(retry_after) = self.retry_after {
+            // in whole seconds, rounded up.
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        http::{header, HeaderName, HeaderValue, StatusCode},
+        response::IntoResponse,
+    };
+    use cel_interpreter::{Context, Program, Value};
+
+    use super::{policy_headers, DecisionMetadata, Denial};
+
+    #[test]
+    fn headers() {
+        assert!(DecisionMetadata::default().to_headers().is_empty());
+
+        let metadata = DecisionMetadata {
+            scopes: Some(vec!["read".into(), "write".into()]),
+            baggage: Some("tenant=acme".into()),
+            headers: vec![
+                (
+                    HeaderName::from_static("x-auth-request-user"),
+                    "alice".into(),
+                ),
+                (
+                    HeaderName::from_static("x-auth-request-email"),
+                    "a\nb".into(),
+                ),
+            ],
+        };
+        let headers = metadata.to_headers();
+        assert_eq!("read write", headers["x-auth-scopes"]);
+        assert_eq!("tenant=acme", headers["baggage"]);
+        assert_eq!("alice", headers["x-auth-request-user"]);
+        assert!(!headers.contains_key("x-auth-request-email"));
+
+        // requested, but none granted
+        let metadata = DecisionMetadata {
+            scopes: Some(vec![]),
+            ..Default::default()
+        };
+        assert_eq!("", metadata.to_headers()["x-auth-scopes"]);
+    }
+
+    #[test]
+    fn headers_from_policy() {
+        let eval = |expr: &str| match Program::compile(expr)
+            .expect("must compile")
+            .execute(&Context::default())
+            .expect("must execute")
+        {
+            Value::Map(map) => policy_headers(&map),
+            v => panic!("expected map, got {:?}", v),
+        };
+
+        let headers =
+            eval(r#"{"X-User": "alice", "x-tier": 2, "x-roles": ["a", "b"], "x-none": null}"#)
+                .expect("must convert")
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("x-roles".to_string(), "a,b".to_string()),
+                ("x-tier".into(), "2".into()),
+                ("x-user".into(), "alice".into()),
+            ],
+            headers
+        );
+
+        for expr in [
+            r#"{"in valid": "x"}"#,
+            r#"{"x-map": {"a": 1}}"#,
+            r#"{1: "x"}"#,
+        ] {
+            assert!(eval(expr).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn backend() {
+        let eval = |expr: &str| {
+            super::backend(
+                &Program::compile(expr)
+                    .expect("must compile")
+                    .execute(&Context::default())
+                    .expect("must execute"),
+            )
+        };
+        assert_eq!(
+            Ok(Some("premium-eu.1".to_string())),
+            eval(r#"2 > 1 ? "premium-eu.1" : "default""#)
+        );
+        assert_eq!(Ok(None), eval("null"));
+        for expr in [r#""""#, r#""a b""#, r#""a\nb""#, "1", "true"] {
+            assert!(eval(expr).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn challenge() {
+        let missing = Denial::from(StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            Some(HeaderValue::from_static(r#"Bearer realm="cellulose""#)),
+            missing.challenge("cellulose")
+        );
+        let expired = Denial::invalid_token("The access token expired");
+        assert_eq!(
+            Some(HeaderValue::from_static(
+                r#"Bearer realm="my \"app\"", error="invalid_token", error_description="The access token expired""#
+            )),
+            expired.challenge(r#"my "app""#)
+        );
+        assert_eq!(
+            None,
+            Denial::from(StatusCode::FORBIDDEN).challenge("cellulose")
+        );
+    }
+
+    #[test]
+    fn retry_after() {
+        let response = Denial {
+            retry_after: Some(Duration::from_millis(41_500)),
+            ..StatusCode::INTERNAL_SERVER_ERROR.into()
+        }
+        .into_response();
+        assert_eq!("42", response.headers()[header::RETRY_AFTER]);
+
+        let response = Denial::from(StatusCode::INTERNAL_SERVER_ERROR).into_response();
+        assert!(!response.headers().contains_key(header::RETRY_AFTER));
+    }
+}
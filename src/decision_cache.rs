@@ -0,0 +1,412 @@
+//! Caching of positive decisions, so repeated requests with the same bearer
+//! token skip verifying it and executing the policy. With forward_auth, the
+//! same token is checked for every asset of a page.
+//!
+//! Decisions are cached by a hash of the token, the policy (its name,
+//! program, backend expression and verification options) and the
+//! verification options in effect, until the token expires, or for the configured
+//! time at most. Only decisions depending on nothing but the token are
+//! cached: for policies referencing other variables than the claims and
+//! constants (the request, the time, flags), tokens bound to a key (DPoP)
+//! or introspected, and with other identities, baggage or assertions
+//! configured, the decision is taken every time. The
+//! [crate::claim_validators] run on hits too, with the cached claims, so
+//! revoked tokens are denied right away.
+//!
+//! Hits are the fast path of forward_auth: looking a decision up doesn't
+//! allocate, see [DecisionCache::key] and [DecisionCache::get], and neither
+//! compiles nor evaluates the policy. That's asserted by
+//! `tests/decision_cache.rs`, and its latency tracked by the `policy`
+//! benchmark. Only the claim validators and rendering the response follow.
+//!
+//! The cache is cleared on reload. The key set generation is part of the
+//! key, so decisions are taken again after the keys were (re)loaded, and
+//! removed keys don't keep tokens signed with them allowed.
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::HeaderName;
+use cel_interpreter::Program;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    audit, context_schema, key_store,
+    metrics::{Counter, METRICS},
+    verification::VerificationConfig,
+    CustomClaims, Policy,
+};
+
+/// The maximum number of decisions cached. Once reached, expired ones are
+/// dropped, and if there are none, the ones expiring first.
+const MAX_ENTRIES: usize = 100_000;
+
+/// The variables derived from the token alone.
+const TOKEN_VARIABLES: &[&str] = &[
+    context_schema::JWT_CLAIMS.name,
+    context_schema::JWT.name,
+    context_schema::ACTORS.name,
+];
+
+/// The parts of a positive decision depending on the token and policy only.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedDecision {
+    /// The claims of the token, for the claim validators, which run on hits
+    /// too.
+    pub claims: CustomClaims,
+    pub token_scopes: Vec<String>,
+    /// Identity headers, and the ones returned by the policy.
+    pub headers: Vec<(HeaderName, String)>,
+    /// The tenant to account the request to, if accounting is enabled.
+    pub tenant: Option<String>,
+    /// The principal to log, if configured.
+    pub principal: Option<String>,
+    /// The identity to record in the audit log.
+    pub identity: audit::Identity,
+}
+
+struct Entry {
+    decision: Arc<CachedDecision>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<[u8; 32], Entry>,
+    /// The keys ordered by expiry, to drop the expired ones (or the ones
+    /// expiring first) without going through all entries.
+    by_expiry: BTreeSet<(Instant, [u8; 32])>,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &[u8; 32]) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.by_expiry.remove(&(entry.expires, *key));
+        }
+    }
+
+    /// Drop the entry expiring first, if it expired by [now], or [force]d.
+    fn pop_first(&mut self, now: Instant, force: bool) -> bool {
+        match self.by_expiry.first() {
+            Some(&(expires, key)) if force || expires <= now => {
+                self.by_expiry.pop_first();
+                self.by_key.remove(&key);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct DecisionCache {
+    entries: Mutex<Entries>,
+    max_entries: usize,
+    /// The counters of hits and misses, looked up once rather than per
+    /// request, which would allocate.
+    hits: Arc<Counter>,
+    misses: Arc<Counter>,
+    /// How long decisions are cached, at most.
+    max_ttl: Duration,
+}
+
+/// Feed [s] to [hasher], prefixed with its length, so consecutive strings
+/// can't be confused.
+fn hash_str(hasher: &mut Sha256, s: &str) {
+    hasher.update((s.len() as u64).to_be_bytes());
+    hasher.update(s);
+}
+
+fn hash_option<T>(hasher: &mut Sha256, value: Option<T>, f: impl FnOnce(&mut Sha256, T)) {
+    match value {
+        Some(value) => {
+            hasher.update([1]);
+            f(hasher, value);
+        }
+        None => hasher.update([0]),
+    }
+}
+
+/// Feed the verification options to [hasher]. The elements of sets are
+/// hashed separately and combined by XOR, as their order is random, and
+/// sorting them would allocate.
+fn hash_options(hasher: &mut Sha256, options: &VerificationConfig) {
+    let set = |hasher: &mut Sha256, set: &HashSet<String>| {
+        let mut combined = [0; 32];
+        for element in set {
+            for (c, d) in combined.iter_mut().zip(Sha256::digest(element)) {
+                *c ^= d;
+            }
+        }
+        hasher.update((set.len() as u64).to_be_bytes());
+        hasher.update(combined);
+    };
+    let number = |hasher: &mut Sha256, n: u64| hasher.update(n.to_be_bytes());
+
+    hash_option(hasher, options.allowed_audiences.as_ref(), set);
+    hash_option(hasher, options.allowed_issuers.as_ref(), set);
+    hash_option(hasher, options.required_subject.as_deref(), hash_str);
+    hash_option(hasher, options.required_nonce.as_deref(), hash_str);
+    hash_option(hasher, options.required_key_id.as_deref(), hash_str);
+    hash_option(hasher, options.reject_before, number);
+    hash_option(hasher, options.accept_future, |hasher, b| {
+        hasher.update([u8::from(b)])
+    });
+    hash_option(hasher, options.time_tolerance, number);
+    hash_option(hasher, options.max_validity, number);
+}
+
+/// Whether decisions of the program only depend on the token, as it
+/// references no other variables than the claims and [constants].
+pub(crate) fn cacheable<V>(program: &Program, constants: &HashMap<String, V>) -> bool {
+    program
+        .references()
+        .variables()
+        .into_iter()
+        .all(|name| TOKEN_VARIABLES.contains(&name) || constants.contains_key(name))
+}
+
+impl DecisionCache {
+    pub fn new(max_ttl: Duration) -> Self {
+        Self {
+            entries: Default::default(),
+            max_entries: MAX_ENTRIES,
+            hits: METRICS.decision_cache.with_labels(&["hit"]),
+            misses: METRICS.decision_cache.with_labels(&["miss"]),
+            max_ttl,
+        }
+    }
+
+    /// The key of the decision for the token and policy, with the current
+    /// key set generation. Other settings of the policy aren't hashed: only
+    /// configured policies have them, which are told apart by name, and the
+    /// cache is cleared on reload. Everything is fed to the hash as is, to
+    /// not allocate.
+    pub fn key(
+        token: &str,
+        policy: &Policy,
+        options: &VerificationConfig,
+        allowed_algs: Option<&[String]>,
+    ) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hash_str(&mut hasher, token);
+        hash_option(&mut hasher, policy.name.as_deref(), hash_str);
+        hash_str(&mut hasher, &policy.cel);
+        hash_option(&mut hasher, policy.backend.as_deref(), hash_str);
+        hash_options(&mut hasher, &policy.verification);
+        hash_options(&mut hasher, options);
+        hash_option(&mut hasher, allowed_algs, |hasher, algs| {
+            hasher.update((algs.len() as u64).to_be_bytes());
+            for alg in algs {
+                hash_str(hasher, alg);
+            }
+        });
+        hasher.update(key_store::generation().to_be_bytes());
+        hasher.finalize().into()
+    }
+
+    /// The decision cached under the key, unless it expired.
+    pub fn get(&self, key: &[u8; 32]) -> Option<Arc<CachedDecision>> {
+        let mut entries = self.entries.lock();
+        let decision = match entries.by_key.get(key) {
+            Some(entry) if Instant::now() < entry.expires => Some(entry.decision.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        match decision {
+            Some(_) => self.hits.inc(),
+            None => self.misses.inc(),
+        }
+        decision
+    }
+
+    /// Cache the decision, until the token expires at [exp], or for the
+    /// configured time, whichever comes first.
+    pub fn insert(&self, key: [u8; 32], exp: Option<f64>, decision: CachedDecision) {
+        let mut ttl = self.max_ttl;
+        if let Some(exp) = exp {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            ttl = ttl.min(Duration::try_from_secs_f64(exp - now).unwrap_or_default());
+        }
+        if ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let expires = now + ttl;
+        let mut entries = self.entries.lock();
+        entries.remove(&key);
+        while entries.pop_first(now, false) {}
+        while entries.by_key.len() >= self.max_entries && entries.pop_first(now, true) {}
+        entries.by_expiry.insert((expires, key));
+        entries.by_key.insert(
+            key,
+            Entry {
+                decision: Arc::new(decision),
+                expires,
+            },
+        );
+    }
+
+    /// The number of decisions cached, including expired ones not dropped
+    /// yet.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().by_key.len()
+    }
+
+    /// Drop all cached decisions, like after the configuration changed.
+    pub fn clear(&self) {
+        *self.entries.lock() = Default::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use cel_interpreter::Program;
+
+    use super::{cacheable, CachedDecision, DecisionCache};
+    use crate::{verification::VerificationConfig, Policy};
+
+    #[test]
+    fn cache() {
+        let cache = DecisionCache::new(Duration::from_secs(60));
+        let policy = Policy::from("true".to_string());
+        let options = VerificationConfig::default();
+        let key = DecisionCache::key("a.b.c", &policy, &options, None);
+        let decision = CachedDecision {
+            claims: Default::default(),
+            token_scopes: vec!["read".to_string()],
+            headers: vec![],
+            tenant: None,
+            principal: Some("alice".to_string()),
+            identity: Default::default(),
+        };
+
+        assert_eq!(None, cache.get(&key));
+        cache.insert(key, Some(4_000_000_000.0), decision.clone());
+        assert_eq!(Some(&decision), cache.get(&key).as_deref());
+
+        // by token, policy and options
+        let other_policy = Policy::from("false".to_string());
+        for other_policy in [
+            Policy {
+                name: Some("admin".into()),
+                ..policy.clone()
+            },
+            Policy {
+                backend: Some("'blue'".to_string()),
+                ..policy.clone()
+            },
+            Policy {
+                verification: VerificationConfig {
+                    max_validity: Some(60),
+                    ..Default::default()
+                },
+                ..policy.clone()
+            },
+        ] {
+            assert_ne!(
+                key,
+                DecisionCache::key("a.b.c", &other_policy, &options, None)
+            );
+        }
+        assert_ne!(key, DecisionCache::key("a.b.d", &policy, &options, None));
+        let audiences = |auds: &[&str]| VerificationConfig {
+            allowed_audiences: Some(auds.iter().map(|aud| aud.to_string()).collect()),
+            ..Default::default()
+        };
+        let (ab, ba) = (audiences(&["a", "b"]), audiences(&["b", "a"]));
+        assert_ne!(key, DecisionCache::key("a.b.c", &policy, &ab, None));
+        assert_eq!(
+            DecisionCache::key("a.b.c", &policy, &ab, None),
+            DecisionCache::key("a.b.c", &policy, &ba, None)
+        );
+        assert_ne!(
+            DecisionCache::key("a.b.c", &policy, &ab, None),
+            DecisionCache::key("a.b.c", &policy, &audiences(&["a"]), None)
+        );
+        assert_ne!(
+            key,
+            DecisionCache::key("a.b.c", &other_policy, &options, None)
+        );
+        let algs = ["ES256".to_string()];
+        assert_ne!(
+            key,
+            DecisionCache::key("a.b.c", &policy, &options, Some(&algs))
+        );
+
+        // not beyond the expiry of the token
+        let key = DecisionCache::key("d.e.f", &policy, &options, None);
+        cache.insert(key, Some(1.0), decision);
+        assert_eq!(None, cache.get(&key));
+
+        // nor the key set generation
+        let key = DecisionCache::key("a.b.c", &policy, &options, None);
+        crate::key_store::next_generation();
+        assert_ne!(key, DecisionCache::key("a.b.c", &policy, &options, None));
+
+        cache.clear();
+        assert_eq!(0, cache.entries.lock().by_key.len());
+    }
+
+    #[test]
+    fn bounded() {
+        let cache = DecisionCache {
+            max_entries: 2,
+            ..DecisionCache::new(Duration::from_secs(60))
+        };
+        let decision = CachedDecision {
+            claims: Default::default(),
+            token_scopes: vec![],
+            headers: vec![],
+            tenant: None,
+            principal: None,
+            identity: Default::default(),
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+
+        cache.insert([1; 32], Some(now + 30.0), decision.clone());
+        cache.insert([2; 32], Some(now + 10.0), decision.clone());
+        cache.insert([3; 32], Some(now + 20.0), decision.clone());
+        // the one expiring first made room
+        assert!(cache.get(&[1; 32]).is_some());
+        assert!(cache.get(&[2; 32]).is_none());
+        assert!(cache.get(&[3; 32]).is_some());
+
+        // replacing an entry doesn't evict another one
+        cache.insert([3; 32], Some(now + 40.0), decision.clone());
+        assert!(cache.get(&[1; 32]).is_some());
+        let entries = cache.entries.lock();
+        assert_eq!(2, entries.by_key.len());
+        assert_eq!(2, entries.by_expiry.len());
+    }
+
+    #[test]
+    fn only_token_dependent() {
+        let constants = HashMap::from([("admins".to_string(), ())]);
+        for (cel, expected) in [
+            ("jwt_claims.sub in admins", true),
+            (r#"jwt.sub == "alice" && impersonatedBy("bob")"#, true),
+            (r#"url.path.startsWith("/admin")"#, false),
+            ("jwt_claims.exp > now", false),
+            ("flags.beta", false),
+        ] {
+            let program = Program::compile(cel).unwrap();
+            assert_eq!(expected, cacheable(&program, &constants), "{}", cel);
+        }
+    }
+}
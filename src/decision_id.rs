@@ -0,0 +1,165 @@
+//! Ids of denied decisions handed to end users, as a cookie or header, for
+//! support staff to ask for and look up the audit record of the decision
+//! with, see [crate::audit].
+//!
+//! The value is a random id, followed by an HMAC of it, so users can't make
+//! up ids of decisions of others: `<id>.<mac>`. Records carry the id only.
+//! Nothing else about the decision is revealed to the user.
+use std::{fmt, path::Path, time::Duration};
+
+use axum::{
+    http::{HeaderName, HeaderValue},
+    response::Response,
+};
+use hmac::{Hmac, Mac};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder};
+use sha2::Sha256;
+
+use crate::util::random_id;
+
+/// The name of the cookie carrying the decision id.
+pub const COOKIE: &str = "cellulose_decision_id";
+
+/// The header carrying the decision id.
+pub const X_DECISION_ID: HeaderName = HeaderName::from_static("x-decision-id");
+
+#[derive(Debug)]
+pub struct DecisionIdError(String);
+
+impl fmt::Display for DecisionIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for DecisionIdError {}
+
+fn err(msg: String) -> DecisionIdError {
+    DecisionIdError(msg)
+}
+
+/// How decision ids are handed to users.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Delivery {
+    /// As short-lived `cellulose_decision_id` cookie.
+    Cookie,
+    /// As `X-Decision-Id` response header.
+    Header,
+}
+
+/// Issues and verifies decision ids, see the module docs.
+#[derive(Clone)]
+pub struct DecisionIds {
+    key: Vec<u8>,
+    delivery: Delivery,
+    /// How long cookies are kept.
+    ttl: Duration,
+}
+
+impl DecisionIds {
+    pub fn new(key: Vec<u8>, delivery: Delivery, ttl: Duration) -> Self {
+        Self { key, delivery, ttl }
+    }
+
+    /// Read the key from [path], with surrounding whitespace removed.
+    pub fn load_key(path: &Path) -> Result<Vec<u8>, DecisionIdError> {
+        let key = std::fs::read(path)
+            .map_err(|e| err(format!("failed to read {}: {}", path.display(), e)))?;
+        let key = key.trim_ascii();
+        if key.len() < 32 {
+            return Err(err(format!(
+                "key in {} must be at least 32 bytes",
+                path.display()
+            )));
+        }
+        Ok(key.to_vec())
+    }
+
+    fn mac(&self, id: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(id.as_bytes());
+        mac
+    }
+
+    /// A new decision id, and the value to hand to the user.
+    pub fn issue(&self) -> (String, String) {
+        let id = random_id()[..22].to_owned();
+        let mac = self.mac(&id).finalize().into_bytes();
+        let value = format!(
+            "{}.{}",
+            id,
+            Base64UrlSafeNoPadding::encode_to_string(mac).expect("must encode")
+        );
+        (id, value)
+    }
+
+    /// The decision id of a value handed to a user, if it was issued with
+    /// this key.
+    pub fn verify<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let (id, mac) = value.trim().split_once('.')?;
+        let mac = Base64UrlSafeNoPadding::decode_to_vec(mac, None).ok()?;
+        self.mac(id).verify_slice(&mac).ok()?;
+        Some(id)
+    }
+
+    /// Hand [value] to the user with the [response], with cookies limited to
+    /// HTTPS if the request was sent via [https].
+    pub fn attach(&self, https: bool, response: &mut Response, value: &str) {
+        let (name, value) = match self.delivery {
+            Delivery::Header => (X_DECISION_ID, value.to_owned()),
+            Delivery::Cookie => {
+                let mut cookie = format!(
+                    "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                    COOKIE,
+                    value,
+                    self.ttl.as_secs()
+                );
+                if https {
+                    cookie.push_str("; Secure");
+                }
+                (axum::http::header::SET_COOKIE, cookie)
+            }
+        };
+        let value = HeaderValue::try_from(value).expect("ids must be valid header values");
+        response.headers_mut().append(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{http::header, response::IntoResponse};
+
+    use super::{DecisionIds, Delivery, X_DECISION_ID};
+
+    #[test]
+    fn issue_and_verify() {
+        let ids = DecisionIds::new(vec![1; 32], Delivery::Cookie, Duration::from_secs(3600));
+        let (id, value) = ids.issue();
+        assert_eq!(22, id.len());
+        assert_eq!(Some(id.as_str()), ids.verify(&value));
+
+        // made up, or issued with another key
+        assert_eq!(None, ids.verify(&format!("{}x", value)));
+        assert_eq!(None, ids.verify(&id));
+        let other = DecisionIds::new(vec![2; 32], Delivery::Cookie, Duration::from_secs(3600));
+        assert_eq!(None, other.verify(&value));
+
+        let mut response = ().into_response();
+        ids.attach(true, &mut response, &value);
+        assert_eq!(
+            format!(
+                "cellulose_decision_id={}; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax; Secure",
+                value
+            ),
+            response.headers()[header::SET_COOKIE]
+        );
+
+        let ids = DecisionIds::new(vec![1; 32], Delivery::Header, Duration::from_secs(3600));
+        let mut response = ().into_response();
+        ids.attach(false, &mut response, &value);
+        assert_eq!(value, response.headers()[X_DECISION_ID]);
+    }
+}
@@ -0,0 +1,279 @@
+//! Notifications about requests denied for token-validity reasons (invalid
+//! signatures, expired, revoked or inactive tokens, invalid DPoP proofs),
+//! POSTed to a webhook, so SOC tooling can alert on spikes of them against
+//! specific hosts.
+//!
+//! Denials are queued and sent in batches, as a JSON object with a
+//! `denials` list, once the batch is full or the flush interval elapsed.
+//! Failed deliveries are retried with exponential backoff, then dropped.
+//! If the webhook can't keep up, the queue is bounded and further denials
+//! are dropped, so it can't hold up or exhaust the memory of the decisions.
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use parking_lot::Mutex;
+use tokio::{sync::Notify, time};
+use tokio_retry::Retry;
+use tracing::{debug, warn};
+
+use crate::{
+    context_request, forwarded_for::ForwardedFor, health_checks::HealthCheck, metrics::METRICS,
+    util::HTTP_CLIENT, Denial, RetryStrategy,
+};
+
+/// How failed deliveries are retried: after 100ms, 200ms, … up to 6.4s.
+const RETRY: RetryStrategy = RetryStrategy {
+    retries: 7,
+    initial_delay: Duration::from_millis(100),
+    factor: 2,
+};
+
+/// A denied request, as sent to the webhook.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Event {
+    pub time: String,
+    pub status: u16,
+    /// Why the token was rejected, like in the `WWW-Authenticate` challenge.
+    pub error: &'static str,
+    pub client_ip: Option<IpAddr>,
+    pub method: Option<String>,
+    pub host: Option<String>,
+    pub uri: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct Batch<'a> {
+    denials: &'a [Event],
+}
+
+/// Queues denials and sends them to the webhook, see the module docs.
+#[derive(Clone)]
+pub struct DenialWebhook {
+    url: Arc<str>,
+    forwarded_for: ForwardedFor,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_queued: usize,
+    queue: Arc<Mutex<Vec<Event>>>,
+    /// Notified once a batch is full.
+    full: Arc<Notify>,
+}
+
+impl DenialWebhook {
+    /// Send batches of up to [batch_size] denials to [url], at least every
+    /// [flush_interval] while there are some. The client address is
+    /// determined like for policies, via [forwarded_for].
+    pub fn new(
+        url: String,
+        forwarded_for: ForwardedFor,
+        batch_size: usize,
+        flush_interval: Duration,
+    ) -> Self {
+        let batch_size = batch_size.max(1);
+        Self {
+            url: url.into(),
+            forwarded_for,
+            batch_size,
+            flush_interval,
+            max_queued: batch_size * 100,
+            queue: Default::default(),
+            full: Default::default(),
+        }
+    }
+
+    /// Queue a denial, dropping it if the queue is full.
+    pub fn push(&self, event: Event) {
+        let mut queue = self.queue.lock();
+        if queue.len() >= self.max_queued {
+            METRICS
+                .denial_webhook_events
+                .with_labels(&["dropped"])
+                .inc();
+            return;
+        }
+        queue.push(event);
+        if queue.len() >= self.batch_size {
+            self.full.notify_one();
+        }
+    }
+
+    /// Take the next batch off the queue, oldest denials first.
+    fn take_batch(&self) -> Vec<Event> {
+        let mut queue = self.queue.lock();
+        let n = queue.len().min(self.batch_size);
+        queue.drain(..n).collect()
+    }
+
+    /// POST the batch, retrying failed deliveries.
+    async fn send(&self, batch: &[Event]) -> Result<(), reqwest::Error> {
+        let action = || async {
+            HTTP_CLIENT
+                .post(&*self.url)
+                .json(&Batch { denials: batch })
+                .send()
+                .await?
+                .error_for_status()
+                .map(drop)
+        };
+        Retry::spawn(RETRY.delays(), action).await
+    }
+
+    /// Send queued denials, whenever a batch is full or the flush interval
+    /// elapsed.
+    pub async fn run(self) {
+        let mut interval = time::interval(self.flush_interval);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.full.notified() => {}
+            }
+            loop {
+                let batch = self.take_batch();
+                if batch.is_empty() {
+                    break;
+                }
+                let full = batch.len() == self.batch_size;
+                match self.send(&batch).await {
+                    Ok(()) => {
+                        debug!(denials = batch.len(), "sent denials to webhook");
+                        METRICS
+                            .denial_webhook_events
+                            .with_labels(&["sent"])
+                            .inc_by(batch.len() as u64);
+                    }
+                    Err(e) => {
+                        warn!(err = %e, denials = batch.len(), "failed to send denials to webhook, dropping them");
+                        METRICS
+                            .denial_webhook_events
+                            .with_labels(&["failed"])
+                            .inc_by(batch.len() as u64);
+                    }
+                }
+                // partial batches wait for the next tick.
+                if !full {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Middleware queueing requests denied for token-validity reasons.
+pub async fn record(
+    State(webhook): State<Option<DenialWebhook>>,
+    rq: Request,
+    next: Next,
+) -> Response {
+    let Some(webhook) = webhook else {
+        return next.run(rq).await;
+    };
+    if rq.extensions().get::<HealthCheck>().is_some() {
+        return next.run(rq).await;
+    }
+    let (client_ip, method, host, uri) = {
+        let header = |name| {
+            rq.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        (
+            webhook.forwarded_for.client_ip(rq.headers()),
+            header(context_request::X_FORWARDED_METHOD),
+            header(context_request::X_FORWARDED_HOST),
+            header(context_request::X_FORWARDED_URI),
+        )
+    };
+
+    let response = next.run(rq).await;
+    if let Some(error) = response
+        .extensions()
+        .get::<Denial>()
+        .and_then(|denial| denial.token_error)
+    {
+        webhook.push(Event {
+            time: DateTime::<Utc>::from(SystemTime::now())
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            status: response.status().as_u16(),
+            error,
+            client_ip,
+            method,
+            host,
+            uri,
+        });
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Batch, DenialWebhook, Event};
+    use crate::forwarded_for::{ForwardedFor, OnViolation};
+
+    #[test]
+    fn batches() {
+        let forwarded_for = ForwardedFor {
+            max_entries: 10,
+            on_violation: OnViolation::Strip,
+            depth: 1,
+        };
+        let webhook = DenialWebhook::new(
+            "http://localhost/hook".to_string(),
+            forwarded_for,
+            2,
+            Duration::from_secs(1),
+        );
+        let event = |host: &str| Event {
+            time: "2024-01-01T00:00:00.000Z".to_string(),
+            status: 401,
+            error: "The access token is invalid",
+            client_ip: Some([192, 0, 2, 1].into()),
+            method: Some("GET".to_string()),
+            host: Some(host.to_string()),
+            uri: Some("/".to_string()),
+        };
+        for i in 0..201 {
+            webhook.push(event(&format!("{}.example.com", i)));
+        }
+        // the queue is bounded
+        assert_eq!(200, webhook.queue.lock().len());
+
+        let batch = webhook.take_batch();
+        assert_eq!(vec![event("0.example.com"), event("1.example.com")], batch);
+        assert_eq!(
+            serde_json::json!({
+                "denials": [{
+                    "time": "2024-01-01T00:00:00.000Z",
+                    "status": 401,
+                    "error": "The access token is invalid",
+                    "client_ip": "192.0.2.1",
+                    "method": "GET",
+                    "host": "0.example.com",
+                    "uri": "/",
+                }, {
+                    "time": "2024-01-01T00:00:00.000Z",
+                    "status": 401,
+                    "error": "The access token is invalid",
+                    "client_ip": "192.0.2.1",
+                    "method": "GET",
+                    "host": "1.example.com",
+                    "uri": "/",
+                }],
+            }),
+            serde_json::to_value(Batch { denials: &batch }).unwrap()
+        );
+        assert_eq!(198, webhook.queue.lock().len());
+    }
+}
@@ -0,0 +1,361 @@
+//! Revoked tokens, rejected even though their signature is valid, so leaked
+//! tokens can be killed before they expire.
+//!
+//! Tokens are listed by their `jti` claim (as `jti:<jti>`), or by the
+//! SHA-256 hash of the whole token (as `sha256:<hash>`, base64url-encoded),
+//! for ones without. Entries are added and removed via the admin API, and
+//! expire along with the token, if known, as it's rejected by then anyway.
+//! With a file configured, the list is persisted there, so it survives
+//! restarts.
+//!
+//! Additionally, a list of revoked `jti`s and `sub`s can be polled from a
+//! URL, so a central system can revoke tokens on many instances. It
+//! replaces the previously fetched one, and doesn't affect the entries
+//! added via the admin API.
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    path::{Path as FsPath, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder};
+use parking_lot::RwLock;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::{util::HTTP_CLIENT, AppState, CustomClaims};
+
+#[derive(Debug)]
+pub struct DenylistError(String);
+
+impl fmt::Display for DenylistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for DenylistError {}
+
+fn err(msg: String) -> DenylistError {
+    DenylistError(msg)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The entry for the token by its hash.
+fn token_entry(token: &str) -> String {
+    let hash =
+        Base64UrlSafeNoPadding::encode_to_string(Sha256::digest(token)).expect("must encode");
+    format!("sha256:{}", hash)
+}
+
+/// The entry for the token by its jti.
+fn jti_entry(jti: &str) -> String {
+    format!("jti:{}", jti)
+}
+
+/// The entry for tokens of the subject, only published at the revocation
+/// URL.
+fn sub_entry(sub: &str) -> String {
+    format!("sub:{}", sub)
+}
+
+/// The expiry of the token, from its payload, without verifying it.
+fn token_exp(token: &str) -> Option<u64> {
+    let payload = token.split('.').nth(1)?;
+    let payload = Base64UrlSafeNoPadding::decode_to_vec(payload, None).ok()?;
+    let claims: CustomClaims = serde_json::from_slice(&payload).ok()?;
+    claims.get("exp")?.as_f64().map(|exp| exp as u64)
+}
+
+#[derive(Default)]
+pub struct Denylist {
+    /// The entries, with when they expire, in seconds since the epoch.
+    entries: RwLock<BTreeMap<String, Option<u64>>>,
+    /// Where the list is persisted, if anywhere.
+    path: Option<PathBuf>,
+    /// The entries last fetched from the revocation URL, if configured.
+    remote: RwLock<HashSet<String>>,
+}
+
+/// The document at the revocation URL.
+#[derive(Default, serde::Deserialize)]
+struct Revocations {
+    #[serde(default)]
+    jti: Vec<String>,
+    #[serde(default)]
+    sub: Vec<String>,
+}
+
+/// Fetch the revocations published at [url], like
+/// `{"jti": ["..."], "sub": ["..."]}`, as entries, along with the ETag of the
+/// response. None if they didn't change since the response with [etag].
+pub async fn fetch_revocations(
+    url: &str,
+    etag: Option<&str>,
+) -> Result<Option<(HashSet<String>, Option<String>)>, DenylistError> {
+    let mut request = HTTP_CLIENT.get(url);
+    if let Some(etag) = etag {
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    let response = request
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| err(format!("failed to fetch {}: {}", url, e)))?;
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let etag = response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let revocations: Revocations = response
+        .json()
+        .await
+        .map_err(|e| err(format!("failed to parse {}: {}", url, e)))?;
+    let entries = (revocations.jti.iter().map(|jti| jti_entry(jti)))
+        .chain(revocations.sub.iter().map(|sub| sub_entry(sub)))
+        .collect();
+    Ok(Some((entries, etag)))
+}
+
+impl Denylist {
+    /// Load the list from the file at [path], if it exists, and persist it
+    /// there on changes.
+    pub fn load(path: PathBuf) -> Result<Self, DenylistError> {
+        let entries: BTreeMap<String, Option<u64>> = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| err(format!("failed to parse {}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(err(format!("failed to read {}: {}", path.display(), e))),
+        };
+        Ok(Self {
+            entries: RwLock::new(entries),
+            path: Some(path),
+            remote: Default::default(),
+        })
+    }
+
+    /// Whether the token with the (verified) claims is revoked.
+    pub(crate) fn is_revoked(&self, token: &str, claims: &CustomClaims) -> bool {
+        let entries = self.entries.read();
+        let remote = self.remote.read();
+        if entries.is_empty() && remote.is_empty() {
+            return false;
+        }
+        let claim = |name| claims.get(name).and_then(|v| v.as_str());
+        let jti = claim("jti").map(jti_entry);
+        let sub = claim("sub").map(sub_entry);
+        if jti.iter().chain(&sub).any(|entry| remote.contains(entry)) {
+            return true;
+        }
+        let now = now();
+        jti.into_iter()
+            .chain([token_entry(token)])
+            .filter_map(|entry| entries.get(&entry))
+            .any(|expires_at| expires_at.is_none_or(|expires_at| now < expires_at))
+    }
+
+    /// Replace the entries fetched from the revocation URL, returning whether
+    /// they changed.
+    pub fn set_remote(&self, entries: HashSet<String>) -> bool {
+        let mut remote = self.remote.write();
+        if *remote == entries {
+            return false;
+        }
+        debug!(entries = entries.len(), "updated revocations");
+        *remote = entries;
+        true
+    }
+
+    /// Add the entry, until [expires_at], if set, dropping expired ones.
+    pub fn add(&self, entry: String, expires_at: Option<u64>) -> Result<(), DenylistError> {
+        let mut entries = self.entries.write();
+        let now = now();
+        entries.retain(|_, expires_at| expires_at.is_none_or(|expires_at| now < expires_at));
+        entries.insert(entry, expires_at);
+        self.persist(&entries)
+    }
+
+    /// Remove the entry, returning whether it was listed.
+    pub fn remove(&self, entry: &str) -> Result<bool, DenylistError> {
+        let mut entries = self.entries.write();
+        if entries.remove(entry).is_none() {
+            return Ok(false);
+        }
+        self.persist(&entries).map(|()| true)
+    }
+
+    /// All entries added via the admin API, with when they expire.
+    pub fn entries(&self) -> BTreeMap<String, Option<u64>> {
+        self.entries.read().clone()
+    }
+
+    /// Write the entries to the file, if configured, via a temporary file,
+    /// so a crash doesn't leave a truncated list behind.
+    fn persist(&self, entries: &BTreeMap<String, Option<u64>>) -> Result<(), DenylistError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let write = |path: &FsPath| {
+            let tmp_path = path.with_extension("tmp");
+            std::fs::write(&tmp_path, serde_json::to_vec(entries)?)?;
+            std::fs::rename(tmp_path, path)
+        };
+        write(path).map_err(|e| err(format!("failed to write {}: {}", path.display(), e)))
+    }
+}
+
+/// A token to revoke, by jti or as a whole.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Revocation {
+    jti: Option<String>,
+    token: Option<String>,
+    /// When the entry expires, in seconds since the epoch. Defaults to the
+    /// expiry of the token, if passed.
+    expires_at: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct Revoked {
+    entry: String,
+    expires_at: Option<u64>,
+}
+
+impl Revocation {
+    fn entry(self) -> Result<Revoked, String> {
+        match (self.jti, self.token) {
+            (Some(jti), None) => Ok(Revoked {
+                entry: jti_entry(&jti),
+                expires_at: self.expires_at,
+            }),
+            (None, Some(token)) => Ok(Revoked {
+                expires_at: self.expires_at.or_else(|| token_exp(&token)),
+                entry: token_entry(&token),
+            }),
+            _ => Err("either jti or token must be set".to_string()),
+        }
+    }
+}
+
+/// List the entries added via the admin API.
+pub async fn list(State(state): State<AppState>) -> Json<BTreeMap<String, Option<u64>>> {
+    Json(state.denylist.entries())
+}
+
+/// Revoke a token, by `jti` or `token` in the JSON body.
+pub async fn add(
+    State(state): State<AppState>,
+    Json(revocation): Json<Revocation>,
+) -> Result<(StatusCode, Json<Revoked>), (StatusCode, String)> {
+    let revoked = revocation
+        .entry()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    state
+        .denylist
+        .add(revoked.entry.clone(), revoked.expires_at)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    // decisions for the token may be cached.
+    if let Some(decision_cache) = &state.decision_cache {
+        decision_cache.clear();
+    }
+    info!(
+        entry = revoked.entry,
+        expires_at = revoked.expires_at,
+        "revoked token"
+    );
+    Ok((StatusCode::CREATED, Json(revoked)))
+}
+
+/// Remove an entry, like `jti:<jti>`.
+pub async fn delete(
+    State(state): State<AppState>,
+    Path(entry): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match state.denylist.remove(&entry) {
+        Ok(true) => {
+            info!(entry, "removed token from denylist");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err((StatusCode::NOT_FOUND, "no such entry".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{token_entry, Denylist, Revocation, Revoked};
+
+    #[test]
+    fn revoke() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("denylist.json");
+        let denylist = Denylist::load(path.clone()).expect("must load");
+        let claims = serde_json::json!({ "sub": "alice", "jti": "abc" });
+        let claims = claims.as_object().unwrap();
+        assert!(!denylist.is_revoked("a.b.c", claims));
+
+        denylist.add("jti:abc".to_string(), None).unwrap();
+        assert!(denylist.is_revoked("a.b.c", claims));
+        assert!(!denylist.is_revoked("a.b.c", &Default::default()));
+
+        // by hash, with the expiry of the token
+        // {"exp":4000000000}
+        let token = "e30.eyJleHAiOjQwMDAwMDAwMDB9.c2ln";
+        let revoked = serde_json::from_str::<Revocation>(&format!(r#"{{"token":"{}"}}"#, token))
+            .unwrap()
+            .entry()
+            .unwrap();
+        assert_eq!(
+            Revoked {
+                entry: token_entry(token),
+                expires_at: Some(4_000_000_000),
+            },
+            revoked
+        );
+        denylist.add(revoked.entry, revoked.expires_at).unwrap();
+        assert!(denylist.is_revoked(token, &Default::default()));
+
+        // expired entries don't count
+        denylist.add(token_entry("d.e.f"), Some(1)).unwrap();
+        assert!(!denylist.is_revoked("d.e.f", &Default::default()));
+
+        // persisted
+        let loaded = Denylist::load(path).expect("must load");
+        assert_eq!(denylist.entries(), loaded.entries());
+        assert!(loaded.remove("jti:abc").unwrap());
+        assert!(!loaded.remove("jti:abc").unwrap());
+        assert!(!loaded.is_revoked("a.b.c", claims));
+
+        // fetched from the revocation URL
+        let remote = HashSet::from(["sub:alice".to_string()]);
+        assert!(loaded.set_remote(remote.clone()));
+        assert!(!loaded.set_remote(remote));
+        assert!(loaded.is_revoked("a.b.c", claims));
+        assert!(loaded.set_remote(Default::default()));
+        assert!(!loaded.is_revoked("a.b.c", claims));
+
+        assert!(
+            serde_json::from_str::<Revocation>(r#"{"jti":"a","token":"b"}"#)
+                .unwrap()
+                .entry()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,469 @@
+//! Validation of DPoP proofs (RFC 9449), binding access tokens to a key held
+//! by the client.
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::HeaderMap;
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder};
+use parking_lot::Mutex;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// The header carrying the DPoP proof.
+pub const DPOP_HEADER: &str = "dpop";
+
+/// Clock skew tolerated for proofs issued in the future.
+const TIME_TOLERANCE: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct DpopError(pub String);
+
+impl fmt::Display for DpopError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid DPoP proof: {}", self.0)
+    }
+}
+
+impl std::error::Error for DpopError {}
+
+fn err(msg: impl Into<String>) -> DpopError {
+    DpopError(msg.into())
+}
+
+#[derive(serde::Deserialize)]
+struct ProofHeader {
+    typ: Option<String>,
+    alg: String,
+    jwk: Option<serde_json::Map<String, Value>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ProofClaims {
+    htm: String,
+    htu: String,
+    ath: Option<String>,
+    jti: Option<String>,
+    iat: Option<u64>,
+}
+
+/// The proof `jti`s seen recently, along with when they can be forgotten.
+#[derive(Default)]
+struct SeenJtis {
+    jtis: HashSet<String>,
+    by_expiry: BTreeSet<(u64, String)>,
+}
+
+impl SeenJtis {
+    /// Record the jti, returning false if it has been seen before.
+    /// Expired ones are forgotten first, as proofs carrying them are
+    /// rejected for their age anyways.
+    fn insert(&mut self, jti: &str, expires_at: u64, now: u64) -> bool {
+        while let Some((expiry, _)) = self.by_expiry.first() {
+            if *expiry >= now {
+                break;
+            }
+            if let Some((_, jti)) = self.by_expiry.pop_first() {
+                self.jtis.remove(&jti);
+            }
+        }
+
+        if !self.jtis.insert(jti.to_owned()) {
+            return false;
+        }
+        self.by_expiry.insert((expires_at, jti.to_owned()));
+        true
+    }
+}
+
+/// Validates DPoP proofs, and keeps track of the ones already used.
+#[derive(Clone)]
+pub struct DpopValidator {
+    max_age: Duration,
+    seen: Arc<Mutex<SeenJtis>>,
+}
+
+impl Default for DpopValidator {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60))
+    }
+}
+
+/// Returns the access token from an `Authorization: DPoP <token>` header.
+pub fn authorization_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers
+        .get(axum::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("DPoP")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+/// Returns the method and URL of the original request, from the
+/// X-Forwarded-* headers.
+fn original_request(headers: &HeaderMap) -> Option<(&str, String)> {
+    let get = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let method = get("x-forwarded-method")?;
+    let url = format!(
+        "{}://{}{}",
+        get("x-forwarded-proto")?,
+        get("x-forwarded-host")?,
+        get("x-forwarded-uri").unwrap_or("/")
+    );
+    Some((method, url))
+}
+
+/// Normalize a URL for comparison against `htu`: query and fragment are
+/// dropped, scheme and host are compared case-insensitively.
+fn normalize_htu(url: &str) -> String {
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            let path = if path.is_empty() { "/" } else { path };
+            format!(
+                "{}://{}{}",
+                scheme.to_ascii_lowercase(),
+                authority.to_ascii_lowercase(),
+                path
+            )
+        }
+        None => url.to_owned(),
+    }
+}
+
+fn b64(data: &[u8]) -> String {
+    Base64UrlSafeNoPadding::encode_to_string(data).expect("must encode")
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, DpopError> {
+    Base64UrlSafeNoPadding::decode_to_vec(part, None).map_err(|_| err("invalid base64"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Verify the signature of the proof with the embedded key.
+/// Supported are ES256, EdDSA (Ed25519), RS256 and PS256.
+fn verify_signature(
+    alg: &str,
+    jwk: &serde_json::Map<String, Value>,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), DpopError> {
+    let member = |name: &str| {
+        jwk.get(name)
+            .and_then(Value::as_str)
+            .ok_or_else(|| err(format!("key is missing member {}", name)))
+            .and_then(decode)
+    };
+    let crv = jwk.get("crv").and_then(Value::as_str);
+
+    let valid = match (alg, jwk.get("kty").and_then(Value::as_str)) {
+        ("ES256", Some("EC")) if crv == Some("P-256") => {
+            use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+
+            // uncompressed SEC1 point encoding
+            let mut point = vec![0x04];
+            point.extend(member("x")?);
+            point.extend(member("y")?);
+            let key = VerifyingKey::from_sec1_bytes(&point).map_err(|_| err("invalid EC key"))?;
+            let signature =
+                Signature::from_slice(signature).map_err(|_| err("invalid signature"))?;
+            key.verify(message, &signature).is_ok()
+        }
+        ("EdDSA", Some("OKP")) if crv == Some("Ed25519") => {
+            let key = ed25519_compact::PublicKey::from_slice(&member("x")?)
+                .map_err(|_| err("invalid Ed25519 key"))?;
+            let signature = ed25519_compact::Signature::from_slice(signature)
+                .map_err(|_| err("invalid signature"))?;
+            key.verify(message, &signature).is_ok()
+        }
+        (alg @ ("RS256" | "PS256"), Some("RSA")) => {
+            let key = rsa::RsaPublicKey::new(
+                rsa::BigUint::from_bytes_be(&member("n")?),
+                rsa::BigUint::from_bytes_be(&member("e")?),
+            )
+            .map_err(|_| err("invalid RSA key"))?;
+            let hashed = Sha256::digest(message);
+            if alg == "RS256" {
+                key.verify(rsa::Pkcs1v15Sign::new::<Sha256>(), &hashed, signature)
+            } else {
+                key.verify(rsa::Pss::new::<Sha256>(), &hashed, signature)
+            }
+            .is_ok()
+        }
+        (alg, kty) => {
+            return Err(err(format!(
+                "unsupported algorithm {} for key type {:?}",
+                alg, kty
+            )))
+        }
+    };
+
+    if !valid {
+        return Err(err("invalid signature"));
+    }
+    Ok(())
+}
+
+/// Compute the JWK SHA-256 thumbprint (RFC 7638) of a public key, as used in
+/// the `cnf.jkt` claim.
+pub fn thumbprint(jwk: &serde_json::Map<String, Value>) -> Result<String, DpopError> {
+    let members: &[&str] = match jwk.get("kty").and_then(Value::as_str) {
+        Some("RSA") => &["e", "kty", "n"],
+        Some("EC") => &["crv", "kty", "x", "y"],
+        Some("OKP") => &["crv", "kty", "x"],
+        kty => return Err(err(format!("unsupported key type {:?}", kty))),
+    };
+
+    // the required members, in lexicographic order, without whitespace.
+    let mut canonical = serde_json::Map::new();
+    for member in members {
+        let value = jwk
+            .get(*member)
+            .and_then(Value::as_str)
+            .ok_or_else(|| err(format!("key is missing member {}", member)))?;
+        canonical.insert(member.to_string(), Value::String(value.to_owned()));
+    }
+    let canonical = serde_json::to_vec(&canonical).expect("JWK must serialize");
+
+    Ok(b64(&Sha256::digest(canonical)))
+}
+
+impl DpopValidator {
+    /// Construct a validator accepting proofs issued at most [max_age] ago.
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            seen: Default::default(),
+        }
+    }
+
+    /// Verify the proof for the given request and access token.
+    /// Returns the thumbprint of the key the proof is signed with, and its
+    /// jti and iat, so it can be recorded once the binding is checked.
+    fn verify_proof(
+        &self,
+        proof: &str,
+        method: &str,
+        url: &str,
+        access_token: &str,
+    ) -> Result<(String, String, u64), DpopError> {
+        let [header_b64, claims_b64, signature] = proof.split('.').collect::<Vec<_>>()[..] else {
+            return Err(err("not a JWS in compact serialization"));
+        };
+        let header: ProofHeader = serde_json::from_slice(&decode(header_b64)?)
+            .map_err(|e| err(format!("invalid header: {}", e)))?;
+        if header.typ.as_deref() != Some("dpop+jwt") {
+            return Err(err("typ must be dpop+jwt"));
+        }
+        let jwk = header.jwk.ok_or_else(|| err("missing jwk"))?;
+        if jwk.contains_key("d") {
+            return Err(err("jwk must not contain a private key"));
+        }
+
+        // jwt-simple can't parse headers embedding a JWK, so the signature is
+        // checked here.
+        verify_signature(
+            &header.alg,
+            &jwk,
+            format!("{}.{}", header_b64, claims_b64).as_bytes(),
+            &decode(signature)?,
+        )?;
+
+        let claims: ProofClaims = serde_json::from_slice(&decode(claims_b64)?)
+            .map_err(|e| err(format!("invalid claims: {}", e)))?;
+        let iat = claims.iat.ok_or_else(|| err("missing iat"))?;
+        let jti = claims.jti.ok_or_else(|| err("missing jti"))?;
+        let now = now();
+        if iat > now + TIME_TOLERANCE.as_secs() {
+            return Err(err("issued in the future"));
+        }
+        if iat + self.max_age.as_secs() < now {
+            return Err(err("too old"));
+        }
+        if claims.htm != method {
+            return Err(err("htm doesn't match the request method"));
+        }
+        if normalize_htu(&claims.htu) != normalize_htu(url) {
+            return Err(err("htu doesn't match the request URL"));
+        }
+        if claims.ath.as_deref() != Some(&b64(&Sha256::digest(access_token))) {
+            return Err(err("ath doesn't match the access token"));
+        }
+
+        Ok((thumbprint(&jwk)?, jti, iat))
+    }
+
+    /// Check the DPoP proof in the request headers, if any, against the
+    /// claims of the access token.
+    /// Tokens bound to a key (via `cnf.jkt`) require a valid proof signed
+    /// with that key, proofs sent along with unbound tokens are rejected.
+    pub fn check(
+        &self,
+        headers: &HeaderMap,
+        access_token: &str,
+        claims: &serde_json::Map<String, Value>,
+    ) -> Result<(), DpopError> {
+        let jkt = claims
+            .get("cnf")
+            .and_then(|cnf| cnf.get("jkt"))
+            .and_then(Value::as_str);
+
+        let mut proofs = headers.get_all(DPOP_HEADER).iter();
+        let proof = match (proofs.next(), proofs.next()) {
+            (_, Some(_)) => return Err(err("multiple proofs sent")),
+            (proof, None) => proof,
+        };
+
+        let (proof, jkt) = match (proof, jkt) {
+            (None, None) => return Ok(()),
+            (None, Some(_)) => return Err(err("token is DPoP-bound, but no proof was sent")),
+            (Some(_), None) => return Err(err("proof sent for a token that isn't DPoP-bound")),
+            (Some(proof), Some(jkt)) => (proof, jkt),
+        };
+
+        let proof = proof.to_str().map_err(|_| err("proof is not ASCII"))?;
+        let (method, url) =
+            original_request(headers).ok_or_else(|| err("original request is unknown"))?;
+        let (thumbprint, jti, iat) = self.verify_proof(proof, method, &url, access_token)?;
+        if thumbprint != jkt {
+            return Err(err("proof is signed with a key the token isn't bound to"));
+        }
+
+        let expires_at = iat + (self.max_age + TIME_TOLERANCE).as_secs();
+        if !self.seen.lock().insert(&jti, expires_at, now()) {
+            return Err(err("proof has been used before"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue};
+    use ed25519_compact::{KeyPair, Seed};
+    use sha2::{Digest, Sha256};
+
+    use super::{
+        authorization_token, b64, normalize_htu, now, thumbprint, DpopValidator, ProofClaims,
+    };
+
+    #[test]
+    fn rfc7638_thumbprint() {
+        let jwk = serde_json::json!({
+            "kty": "RSA",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "e": "AQAB",
+            "alg": "RS256",
+            "kid": "2011-04-29"
+        });
+        assert_eq!(
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs",
+            thumbprint(jwk.as_object().unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn htu() {
+        assert_eq!(
+            "https://example.com/a",
+            normalize_htu("HTTPS://Example.COM/a?b=c#d")
+        );
+        assert_eq!("https://example.com/", normalize_htu("https://example.com"));
+    }
+
+    fn jwk(key_pair: &KeyPair) -> serde_json::Value {
+        serde_json::json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": b64(key_pair.pk.as_ref()),
+        })
+    }
+
+    fn proof(key_pair: &KeyPair, claims: ProofClaims) -> HeaderValue {
+        let header = serde_json::json!({"typ": "dpop+jwt", "alg": "EdDSA", "jwk": jwk(key_pair)});
+        let signing_input = format!(
+            "{}.{}",
+            b64(&serde_json::to_vec(&header).unwrap()),
+            b64(&serde_json::to_vec(&claims).unwrap())
+        );
+        let signature = key_pair.sk.sign(signing_input.as_bytes(), None);
+        format!("{}.{}", signing_input, b64(signature.as_ref()))
+            .try_into()
+            .unwrap()
+    }
+
+    #[test]
+    fn check() {
+        let key_pair = KeyPair::from_seed(Seed::new([1; 32]));
+        let jkt = thumbprint(jwk(&key_pair).as_object().unwrap()).unwrap();
+        let access_token = "access-token";
+        let bound = serde_json::json!({"cnf": {"jkt": jkt}});
+        let bound = bound.as_object().unwrap();
+
+        let claims = |htm: &str| ProofClaims {
+            htm: htm.to_owned(),
+            htu: "https://example.com/api".to_owned(),
+            ath: Some(b64(&Sha256::digest(access_token))),
+            jti: Some(format!("{}-jti", htm)),
+            iat: Some(now()),
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-method", HeaderValue::from_static("GET"));
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        headers.insert("x-forwarded-host", HeaderValue::from_static("example.com"));
+        headers.insert("x-forwarded-uri", HeaderValue::from_static("/api?x=1"));
+
+        let validator = DpopValidator::default();
+
+        // unbound tokens without proof are fine, bound ones aren't.
+        assert!(validator
+            .check(&headers, access_token, &Default::default())
+            .is_ok());
+        assert!(validator.check(&headers, access_token, bound).is_err());
+
+        let mut with_proof = headers.clone();
+        with_proof.insert("dpop", proof(&key_pair, claims("GET")));
+        validator
+            .check(&with_proof, access_token, bound)
+            .expect("must accept valid proof");
+        // replayed
+        assert!(validator.check(&with_proof, access_token, bound).is_err());
+
+        // wrong method
+        with_proof.insert("dpop", proof(&key_pair, claims("POST")));
+        assert!(validator.check(&with_proof, access_token, bound).is_err());
+
+        // signed with another key
+        let other = KeyPair::from_seed(Seed::new([2; 32]));
+        let mut other_claims = claims("GET");
+        other_claims.jti = Some("other-jti".to_owned());
+        with_proof.insert("dpop", proof(&other, other_claims));
+        assert!(validator.check(&with_proof, access_token, bound).is_err());
+
+        // too old
+        let mut old_claims = claims("GET");
+        old_claims.jti = Some("old-jti".to_owned());
+        old_claims.iat = Some(now() - 120);
+        with_proof.insert("dpop", proof(&key_pair, old_claims));
+        assert!(validator.check(&with_proof, access_token, bound).is_err());
+
+        let mut auth = HeaderMap::new();
+        auth.insert("authorization", HeaderValue::from_static("DPoP abc"));
+        assert_eq!(Some("abc"), authorization_token(&auth));
+    }
+}
@@ -0,0 +1,391 @@
+//! Bodies of error responses of /auth, so end users (and developers of API
+//! clients) can tell what went wrong, instead of seeing a bare status code.
+//!
+//! The format is negotiated with the Accept header of the original request,
+//! which proxies forward: a JSON document (`{"error": ..., "reason": ...}`)
+//! for API clients, or an HTML page for browsers, rendered from a template.
+//! Templates can reference `{{status}}`, `{{error}}` and `{{reason}}`, which
+//! are HTML-escaped. Translated templates are picked by the Accept-Language
+//! header.
+use std::{fmt, path::Path, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::Denial;
+
+/// The template used if none is configured.
+pub const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>{{status}} {{error}}</title></head>
+<body style="font-family: sans-serif; max-width: 40em; margin: 4em auto">
+<h1>{{status}} {{error}}</h1>
+<p>{{reason}}</p>
+</body>
+</html>
+"#;
+
+#[derive(Debug)]
+pub struct ErrorPagesError(String);
+
+impl fmt::Display for ErrorPagesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ErrorPagesError {}
+
+fn err(msg: String) -> ErrorPagesError {
+    ErrorPagesError(msg)
+}
+
+/// The body format of error responses.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    /// The reason returned by the policy as plain text, if any, and no body
+    /// otherwise.
+    Plain,
+    /// JSON or HTML, depending on the Accept header, plain text otherwise.
+    Negotiate,
+}
+
+/// A translated template, like `de=/etc/cellulose/error.de.html`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Translation {
+    /// The language tag, lowercase.
+    pub lang: String,
+    pub template: Arc<str>,
+}
+
+impl Translation {
+    /// Load a translation from its language tag and path, like
+    /// `de=/etc/cellulose/error.de.html`.
+    pub fn load(s: &str) -> Result<Self, ErrorPagesError> {
+        let (lang, path) = s
+            .split_once('=')
+            .ok_or_else(|| err(format!("expected <language>=<path>, got {}", s)))?;
+        Ok(Self {
+            lang: lang.trim().to_ascii_lowercase(),
+            template: ErrorPages::load_template(Path::new(path.trim()))?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ErrorPages {
+    pub format: Format,
+    /// The HTML template.
+    pub template: Arc<str>,
+    /// Translations of the template.
+    pub translations: Arc<[Translation]>,
+}
+
+impl Default for ErrorPages {
+    fn default() -> Self {
+        Self {
+            format: Format::Plain,
+            template: DEFAULT_TEMPLATE.into(),
+            translations: Arc::new([]),
+        }
+    }
+}
+
+/// The formats error bodies are rendered in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Plain,
+    Json,
+    Html,
+}
+
+/// A rendered error body.
+#[derive(Clone, Debug, PartialEq)]
+struct Body {
+    content_type: &'static str,
+    /// The language of the body, if translated.
+    lang: Option<String>,
+    body: String,
+}
+
+impl Body {
+    fn new(content_type: &'static str, body: String) -> Self {
+        Self {
+            content_type,
+            lang: None,
+            body,
+        }
+    }
+}
+
+/// Pick the body format from the Accept header, by the order of the media
+/// ranges. Quality values aren't weighed, browsers list text/html first, and
+/// API clients usually only send application/json.
+fn negotiate(headers: &HeaderMap) -> Kind {
+    let accept = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','));
+    for range in accept {
+        match range.split(';').next().unwrap_or_default().trim() {
+            "text/html" | "application/xhtml+xml" => return Kind::Html,
+            "application/json" | "application/problem+json" => return Kind::Json,
+            _ => {}
+        }
+    }
+    Kind::Plain
+}
+
+/// The language ranges of the Accept-Language header, by descending
+/// quality, lowercase.
+fn accepted_languages(headers: &HeaderMap) -> Vec<String> {
+    let mut ranges = headers
+        .get_all(header::ACCEPT_LANGUAGE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let lang = params.next()?.trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!lang.is_empty() && q > 0.0).then_some((lang, q))
+        })
+        .collect::<Vec<_>>();
+    // stable, so ranges of the same quality keep their order.
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    ranges.into_iter().map(|(lang, _)| lang).collect()
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+impl ErrorPages {
+    /// Load an HTML template from [path].
+    pub fn load_template(path: &Path) -> Result<Arc<str>, ErrorPagesError> {
+        std::fs::read_to_string(path)
+            .map(Into::into)
+            .map_err(|e| err(format!("unable to read {}: {}", path.display(), e)))
+    }
+
+    /// The translation for the most preferred language that has one, by
+    /// exact match first, then by primary language (`de` for `de-AT`).
+    fn translation(&self, headers: &HeaderMap) -> Option<&Translation> {
+        accepted_languages(headers).iter().find_map(|lang| {
+            let primary = lang.split('-').next().unwrap_or_default();
+            self.translations
+                .iter()
+                .find(|t| t.lang == *lang)
+                .or_else(|| self.translations.iter().find(|t| t.lang == primary))
+        })
+    }
+
+    /// Render the HTML page for the status and reason, in the preferred
+    /// language, if translated. Returns the language of the page, if not
+    /// the default one.
+    fn html(
+        &self,
+        headers: &HeaderMap,
+        status: StatusCode,
+        reason: Option<&str>,
+    ) -> (Option<&str>, String) {
+        let translation = self.translation(headers);
+        let template = translation.map_or(&self.template, |t| &t.template);
+        let html = template
+            .replace("{{status}}", status.as_str())
+            .replace(
+                "{{error}}",
+                &escape_html(status.canonical_reason().unwrap_or_default()),
+            )
+            .replace("{{reason}}", &escape_html(reason.unwrap_or_default()));
+        (translation.map(|t| t.lang.as_str()), html)
+    }
+
+    /// The body for the status and reason, in the format (and language)
+    /// requested by [headers], along with its content type and language.
+    /// None for plain text without reason.
+    fn body(&self, headers: &HeaderMap, status: StatusCode, reason: Option<&str>) -> Option<Body> {
+        let kind = match self.format {
+            Format::Plain => Kind::Plain,
+            Format::Negotiate => negotiate(headers),
+        };
+        match kind {
+            Kind::Plain => reason.map(|r| Body::new("text/plain; charset=utf-8", r.to_owned())),
+            Kind::Json => {
+                let mut doc = serde_json::json!({
+                    "error": status.canonical_reason().unwrap_or_default(),
+                });
+                if let Some(reason) = reason {
+                    doc["reason"] = reason.into();
+                }
+                Some(Body::new("application/json", doc.to_string()))
+            }
+            Kind::Html => {
+                let (lang, html) = self.html(headers, status, reason);
+                Some(Body {
+                    lang: lang.map(str::to_owned),
+                    ..Body::new("text/html; charset=utf-8", html)
+                })
+            }
+        }
+    }
+}
+
+/// Middleware rendering the bodies of error responses. Handlers attach the
+/// [Denial] to the response, for its reason.
+pub async fn apply(State(pages): State<ErrorPages>, rq: Request, next: Next) -> Response {
+    let headers = rq.headers().clone();
+    let response = next.run(rq).await;
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let reason = response
+        .extensions()
+        .get::<Denial>()
+        .and_then(|d| d.reason.clone());
+    let Some(body) = pages.body(&headers, status, reason.as_deref()) else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(body.content_type),
+    );
+    if pages.format == Format::Negotiate {
+        parts.headers.insert(
+            header::VARY,
+            HeaderValue::from_static("accept, accept-language"),
+        );
+    }
+    if let Some(lang) = body.lang.and_then(|l| HeaderValue::try_from(l).ok()) {
+        parts.headers.insert(header::CONTENT_LANGUAGE, lang);
+    }
+    (parts, body.body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+
+    use super::{accepted_languages, Body, ErrorPages, Format, Translation};
+
+    fn headers(pairs: &[(header::HeaderName, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn negotiate() {
+        let pages = ErrorPages {
+            format: Format::Negotiate,
+            ..Default::default()
+        };
+        let accept = |v: &'static str| headers(&[(header::ACCEPT, v)]);
+
+        let body = pages
+            .body(
+                &accept("application/json"),
+                StatusCode::FORBIDDEN,
+                Some("not in group admins"),
+            )
+            .unwrap();
+        assert_eq!("application/json", body.content_type);
+        assert_eq!(
+            serde_json::json!({"error": "Forbidden", "reason": "not in group admins"}),
+            serde_json::from_str::<serde_json::Value>(&body.body).unwrap()
+        );
+
+        let body = pages
+            .body(
+                &accept("text/html,application/xhtml+xml;q=0.9,*/*;q=0.8"),
+                StatusCode::UNAUTHORIZED,
+                Some("<script>"),
+            )
+            .unwrap();
+        assert_eq!("text/html; charset=utf-8", body.content_type);
+        assert!(body.body.contains("<h1>401 Unauthorized</h1>"));
+        assert!(body.body.contains("&lt;script&gt;"));
+
+        assert_eq!(
+            None,
+            pages.body(&accept("*/*"), StatusCode::UNAUTHORIZED, None)
+        );
+
+        // plain keeps bodies as before, whatever is accepted.
+        let plain = ErrorPages::default();
+        assert_eq!(
+            Some(Body::new("text/plain; charset=utf-8", "denied".to_string())),
+            plain.body(
+                &accept("application/json"),
+                StatusCode::FORBIDDEN,
+                Some("denied")
+            )
+        );
+    }
+
+    #[test]
+    fn translations() {
+        assert_eq!(
+            vec!["fr-ch", "fr", "en", "de"],
+            accepted_languages(&headers(&[(
+                header::ACCEPT_LANGUAGE,
+                "fr-CH, fr;q=0.9, de;q=0.7, en;q=0.8, *;q=0"
+            )]))
+        );
+
+        let pages = ErrorPages {
+            format: Format::Negotiate,
+            translations: vec![
+                Translation {
+                    lang: "de".to_string(),
+                    template: "<p>Zugriff verweigert ({{status}})</p>".into(),
+                },
+                Translation {
+                    lang: "pt-br".to_string(),
+                    template: "<p>Acesso negado ({{status}})</p>".into(),
+                },
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let render = |lang: &'static str| {
+            pages
+                .body(
+                    &headers(&[
+                        (header::ACCEPT, "text/html"),
+                        (header::ACCEPT_LANGUAGE, lang),
+                    ]),
+                    StatusCode::FORBIDDEN,
+                    None,
+                )
+                .unwrap()
+        };
+
+        let body = render("de-AT, en;q=0.5");
+        assert_eq!(Some("de".to_string()), body.lang);
+        assert_eq!("<p>Zugriff verweigert (403)</p>", body.body);
+        assert_eq!(Some("pt-br".to_string()), render("pt-BR").lang);
+        // the default template, for languages without translation.
+        let body = render("fr, pt;q=0.5");
+        assert_eq!(None, body.lang);
+        assert!(body.body.contains("<h1>403 Forbidden</h1>"));
+    }
+}
@@ -0,0 +1,197 @@
+//! Detection of clients stuck presenting the same expired token, a common
+//! bug in single-page apps that fail to refresh tokens, and would otherwise
+//! retry in an endless 401 loop.
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+use crate::metrics::METRICS;
+
+/// The maximum number of expired tokens tracked. Once reached, the oldest
+/// ones are forgotten first.
+const MAX_TRACKED: usize = 10_000;
+
+/// The challenge sent once a loop is detected, asking the client to refresh
+/// its token (RFC 6750).
+const CHALLENGE: &str =
+    r#"Bearer error="invalid_token", error_description="The access token expired""#;
+
+/// Occurrences of expired tokens, by token hash, along with when they can be
+/// forgotten.
+#[derive(Default)]
+struct Seen {
+    counts: HashMap<[u8; 32], u32>,
+    by_expiry: BTreeSet<(u64, [u8; 32])>,
+}
+
+impl Seen {
+    /// Record an occurrence of the token, returning how often it has been
+    /// seen within the window, including this one.
+    fn record(&mut self, hash: [u8; 32], forget_at: u64, now: u64) -> u32 {
+        while let Some((expiry, hash)) = self.by_expiry.first().copied() {
+            if expiry >= now && self.by_expiry.len() < MAX_TRACKED {
+                break;
+            }
+            self.by_expiry.pop_first();
+            self.counts.remove(&hash);
+        }
+
+        let count = self.counts.entry(hash).or_insert_with(|| {
+            self.by_expiry.insert((forget_at, hash));
+            0
+        });
+        *count += 1;
+        *count
+    }
+}
+
+/// Counts expired tokens presented repeatedly, and responds differently once
+/// a token was presented too often.
+#[derive(Clone)]
+pub struct ExpiredTokens {
+    /// Number of occurrences within the window after which a token is
+    /// considered stuck in a loop.
+    pub threshold: u32,
+    /// How long occurrences of a token are counted, from the first one.
+    pub window: Duration,
+    /// Where to redirect clients stuck in a loop to, like a login page.
+    /// Without it, they get a 401 with a challenge to refresh the token.
+    pub redirect: Option<String>,
+    seen: Arc<Mutex<Seen>>,
+}
+
+/// Peek into the payload of a token to check whether it expired, without
+/// verifying it.
+pub(crate) fn is_expired(token: &str, now: u64) -> bool {
+    #[derive(serde::Deserialize)]
+    struct Claims {
+        exp: Option<u64>,
+    }
+
+    token
+        .split('.')
+        .nth(1)
+        .and_then(|payload| Base64UrlSafeNoPadding::decode_to_vec(payload, None).ok())
+        .and_then(|payload| serde_json::from_slice::<Claims>(&payload).ok())
+        .and_then(|claims| claims.exp)
+        .is_some_and(|exp| exp <= now)
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl ExpiredTokens {
+    pub fn new(threshold: u32, window: Duration, redirect: Option<String>) -> Self {
+        Self {
+            threshold,
+            window,
+            redirect,
+            seen: Default::default(),
+        }
+    }
+
+    /// Called for rejected tokens. If the token expired and was presented
+    /// too often, returns the response prompting the client to refresh it or
+    /// log in again.
+    pub fn check(&self, token: &str) -> Option<Response> {
+        let now = now();
+        if !is_expired(token, now) {
+            return None;
+        }
+        METRICS.expired_tokens.with_labels(&[]).inc();
+
+        let hash = Sha256::digest(token.as_bytes()).into();
+        let count = self
+            .seen
+            .lock()
+            .record(hash, now + self.window.as_secs(), now);
+        if count < self.threshold {
+            return None;
+        }
+
+        Some(match &self.redirect {
+            Some(location) => {
+                METRICS.expired_token_loops.with_labels(&["redirect"]).inc();
+                (
+                    StatusCode::FOUND,
+                    [(header::LOCATION, location.as_str())],
+                    "Token expired",
+                )
+                    .into_response()
+            }
+            None => {
+                METRICS
+                    .expired_token_loops
+                    .with_labels(&["challenge"])
+                    .inc();
+                (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, CHALLENGE)],
+                    "Token expired",
+                )
+                    .into_response()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::{header, StatusCode};
+    use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Encoder};
+
+    use super::{now, ExpiredTokens};
+
+    fn token(exp: u64) -> String {
+        let payload = serde_json::json!({ "sub": "alice", "exp": exp }).to_string();
+        format!(
+            "eyJhbGciOiJSUzI1NiJ9.{}.sig",
+            Base64UrlSafeNoPadding::encode_to_string(payload).unwrap()
+        )
+    }
+
+    #[test]
+    fn loops() {
+        let expired = token(now() - 10);
+        let valid = token(now() + 3600);
+
+        let challenge = ExpiredTokens::new(3, Duration::from_secs(60), None);
+        assert!(challenge.check(&expired).is_none());
+        assert!(challenge.check(&expired).is_none());
+        let response = challenge.check(&expired).expect("must detect loop");
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+
+        // tokens rejected for other reasons aren't counted
+        for _ in 0..5 {
+            assert!(challenge.check(&valid).is_none());
+            assert!(challenge.check("opaque").is_none());
+        }
+
+        let redirect = ExpiredTokens::new(1, Duration::from_secs(60), Some("/login".into()));
+        let response = redirect.check(&expired).expect("must detect loop");
+        assert_eq!(StatusCode::FOUND, response.status());
+        assert_eq!("/login", response.headers()[header::LOCATION]);
+
+        // occurrences are forgotten after the window
+        let forgetful = ExpiredTokens::new(2, Duration::ZERO, None);
+        forgetful.seen.lock().record([0; 32], now() - 1, now());
+        assert_eq!(1, forgetful.seen.lock().record([0; 32], now(), now()));
+    }
+}
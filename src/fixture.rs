@@ -0,0 +1,236 @@
+//! Self-contained fixtures to reproduce decisions: a signed token, the JWKS
+//! to verify it with, and a curl command hitting /auth.
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use jwt_simple::prelude::*;
+
+/// Arguments of the make-fixture subcommand.
+#[derive(clap::Args)]
+pub struct FixtureArgs {
+    /// Directory to write the fixture to. Created if it doesn't exist.
+    #[clap(long, default_value = "fixture")]
+    pub out: PathBuf,
+
+    /// ES256 private key (PEM) to sign the token with.
+    /// If not set, a fresh dev key is generated and written to dev-key.pem,
+    /// so further tokens can be signed with the same key.
+    #[clap(long)]
+    pub key_file: Option<PathBuf>,
+
+    /// Subject of the token.
+    #[clap(long, default_value = "dev-user")]
+    pub subject: String,
+
+    /// Issuer of the token.
+    #[clap(long)]
+    pub issuer: Option<String>,
+
+    /// Audience of the token.
+    #[clap(long)]
+    pub audience: Option<String>,
+
+    /// Additional claims, as JSON object.
+    #[clap(long, default_value = "{}")]
+    pub claims: String,
+
+    /// How long the token is valid for, in seconds.
+    #[clap(long, default_value_t = 3600)]
+    pub valid_for_secs: u64,
+
+    /// Header to send along, as "Name: value". Can be passed multiple times.
+    #[clap(long = "header")]
+    pub headers: Vec<String>,
+
+    /// The CEL program to send as cel_str.
+    #[clap(long)]
+    pub policy: Option<String>,
+
+    /// Base URL of the cellulose instance the curl command should hit.
+    #[clap(long, default_value = "http://localhost:9000")]
+    pub url: String,
+}
+
+#[derive(Debug)]
+pub struct FixtureError(String);
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for FixtureError {}
+
+fn err(msg: String) -> FixtureError {
+    FixtureError(msg)
+}
+
+/// The key id used for the fixture key.
+const KID: &str = "dev";
+
+/// Quote a string for use in a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Render the JWKS document for the public key, with the key id of
+/// [key_pair].
+pub(crate) fn jwks(key_pair: &ES256KeyPair) -> serde_json::Value {
+    serde_json::json!({ "keys": [jwk(key_pair)] })
+}
+
+/// Render the JWK of the public key, with the key id of [key_pair].
+pub(crate) fn jwk(key_pair: &ES256KeyPair) -> serde_json::Value {
+    let point = key_pair.public_key().public_key().to_bytes_uncompressed();
+    serde_json::json!({
+        "kty": "EC",
+        "crv": "P-256",
+        "use": "sig",
+        "alg": "ES256",
+        "kid": key_pair.key_id(),
+        "x": Base64UrlSafeNoPadding::encode_to_string(&point[1..33]).expect("must encode"),
+        "y": Base64UrlSafeNoPadding::encode_to_string(&point[33..]).expect("must encode"),
+    })
+}
+
+/// Render the curl command reproducing the request.
+fn curl_command(args: &FixtureArgs) -> String {
+    let mut lines = vec![
+        format!(
+            "curl -sS -o /dev/null -w {} --get",
+            shell_quote("%{http_code}\\n")
+        ),
+        "-H \"Authorization: Bearer $(cat \"$(dirname \"$0\")/token.jwt\")\"".to_string(),
+    ];
+    for header in &args.headers {
+        lines.push(format!("-H {}", shell_quote(header)));
+    }
+    if let Some(policy) = &args.policy {
+        lines.push(format!(
+            "--data-urlencode {}",
+            shell_quote(&format!("cel_str={}", policy))
+        ));
+    }
+    lines.push(shell_quote(&format!(
+        "{}/auth",
+        args.url.trim_end_matches('/')
+    )));
+
+    format!(
+        "#!/bin/sh\n\
+         # Start cellulose with the fixture keys:\n\
+         #   cellulose --key-file \"$(dirname \"$0\")/jwks.json\"\n\
+         {}\n",
+        lines.join(" \\\n  ")
+    )
+}
+
+fn write(dir: &Path, name: &str, contents: impl AsRef<[u8]>) -> Result<(), FixtureError> {
+    let path = dir.join(name);
+    fs::write(&path, contents).map_err(|e| err(format!("writing {}: {}", path.display(), e)))
+}
+
+/// Write the fixture, as described by [args].
+pub fn make_fixture(args: &FixtureArgs) -> Result<(), FixtureError> {
+    fs::create_dir_all(&args.out)
+        .map_err(|e| err(format!("creating {}: {}", args.out.display(), e)))?;
+
+    let key_pair = match &args.key_file {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| ES256KeyPair::from_pem(&pem).map_err(|e| e.to_string()))
+            .map_err(|e| err(format!("invalid ES256 key {}: {}", path.display(), e)))?,
+        None => {
+            let key_pair = ES256KeyPair::generate();
+            write(
+                &args.out,
+                "dev-key.pem",
+                key_pair
+                    .to_pem()
+                    .map_err(|e| err(format!("encoding dev key: {}", e)))?,
+            )?;
+            key_pair
+        }
+    }
+    .with_key_id(KID);
+
+    let custom_claims: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(&args.claims)
+            .map_err(|e| err(format!("--claims must be a JSON object: {}", e)))?;
+    let mut claims =
+        Claims::with_custom_claims(custom_claims, Duration::from_secs(args.valid_for_secs))
+            .with_subject(&args.subject);
+    if let Some(issuer) = &args.issuer {
+        claims = claims.with_issuer(issuer);
+    }
+    if let Some(audience) = &args.audience {
+        claims = claims.with_audience(audience);
+    }
+
+    let token = key_pair
+        .sign(claims)
+        .map_err(|e| err(format!("signing token: {}", e)))?;
+
+    write(&args.out, "token.jwt", token)?;
+    write(
+        &args.out,
+        "jwks.json",
+        serde_json::to_string_pretty(&jwks(&key_pair)).expect("JWKS must serialize"),
+    )?;
+    write(&args.out, "curl.sh", curl_command(args))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{make_fixture, shell_quote, FixtureArgs};
+    use crate::key_set::KeySet;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        args: FixtureArgs,
+    }
+
+    #[test]
+    fn quote() {
+        assert_eq!(r"'it'\''s'", shell_quote("it's"));
+    }
+
+    #[tokio::test]
+    async fn fixture() {
+        let dir = tempfile::tempdir().expect("must create dir");
+        let cli = Cli::parse_from([
+            "make-fixture",
+            "--out",
+            dir.path().to_str().unwrap(),
+            "--claims",
+            r#"{"groups": ["admin"]}"#,
+            "--header",
+            "X-Forwarded-Host: example.com",
+            "--policy",
+            "'admin' in jwt_claims.groups",
+        ]);
+        make_fixture(&cli.args).expect("must write fixture");
+
+        let jwks = std::fs::read(dir.path().join("jwks.json")).unwrap();
+        let token = std::fs::read_to_string(dir.path().join("token.jwt")).unwrap();
+        let claims = KeySet::from_jwk_json(&jwks)
+            .unwrap()
+            .verify::<serde_json::Map<String, serde_json::Value>>(&token, None)
+            .await
+            .expect("token must verify against the JWKS");
+        assert_eq!(Some("dev-user".to_string()), claims.subject);
+        assert_eq!(serde_json::json!(["admin"]), claims.custom["groups"]);
+
+        let curl = std::fs::read_to_string(dir.path().join("curl.sh")).unwrap();
+        assert!(curl.contains(r"'cel_str='\''admin'\'' in jwt_claims.groups'"));
+        assert!(dir.path().join("dev-key.pem").exists());
+    }
+}
@@ -0,0 +1,141 @@
+//! Feature flags, exposed to CEL programs as `flags`.
+//!
+//! Flags let operators change decisions without editing policies, like
+//! gating new routes, or an emergency lockdown. They're loaded from a file
+//! (TOML, YAML or JSON), mapping the flag name either to a bool, or to a
+//! table with `enabled` and an optional `until` (an RFC 3339 timestamp),
+//! after which the flag is disabled again:
+//!
+//! ```toml
+//! new_checkout = true
+//! lockdown = { enabled = true, until = "2024-09-01T12:00:00Z" }
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::Path,
+    time::SystemTime,
+};
+
+#[derive(Debug)]
+pub struct FlagsError(String);
+
+impl fmt::Display for FlagsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for FlagsError {}
+
+fn err(msg: String) -> FlagsError {
+    FlagsError(msg)
+}
+
+/// A single feature flag.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(try_from = "FlagConfig")]
+pub struct Flag {
+    pub enabled: bool,
+    /// The flag is disabled from this time on, if set.
+    pub until: Option<SystemTime>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum FlagConfig {
+    Enabled(bool),
+    Full {
+        enabled: bool,
+        until: Option<String>,
+    },
+}
+
+impl TryFrom<FlagConfig> for Flag {
+    type Error = String;
+
+    fn try_from(config: FlagConfig) -> Result<Self, Self::Error> {
+        match config {
+            FlagConfig::Enabled(enabled) => Ok(Self {
+                enabled,
+                until: None,
+            }),
+            FlagConfig::Full { enabled, until } => Ok(Self {
+                enabled,
+                until: until
+                    .map(|until| {
+                        humantime::parse_rfc3339_weak(&until)
+                            .map_err(|e| format!("invalid until {}: {}", until, e))
+                    })
+                    .transpose()?,
+            }),
+        }
+    }
+}
+
+impl Flag {
+    /// Whether the flag is enabled at the given time.
+    pub fn is_enabled(&self, now: SystemTime) -> bool {
+        self.enabled && self.until.is_none_or(|until| now < until)
+    }
+}
+
+/// All feature flags, by name.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+pub struct Flags(BTreeMap<String, Flag>);
+
+impl Flags {
+    /// Load flags from a file, in TOML, YAML or JSON format depending on its
+    /// extension.
+    pub fn load(path: &Path) -> Result<Self, FlagsError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| err(format!("unable to read {}: {}", path.display(), e)))?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| e.to_string()),
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+            Some("json") => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+            _ => Err("unknown format, expected a .toml, .yaml, .yml or .json file".to_string()),
+        }
+        .map_err(|e| err(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Whether each flag is enabled at the given time, by name.
+    pub fn values(&self, now: SystemTime) -> HashMap<String, bool> {
+        self.0
+            .iter()
+            .map(|(name, flag)| (name.clone(), flag.is_enabled(now)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::Flags;
+
+    #[test]
+    fn time_limited() {
+        let flags: Flags = toml::from_str(
+            r#"
+            new_checkout = true
+            legacy = false
+            lockdown = { enabled = true, until = "2024-09-01T12:00:00Z" }
+            "#,
+        )
+        .expect("must parse");
+
+        let until = UNIX_EPOCH + Duration::from_secs(1725192000);
+        let values = flags.values(until - Duration::from_secs(1));
+        assert!(values["new_checkout"]);
+        assert!(!values["legacy"]);
+        assert!(values["lockdown"]);
+
+        let values = flags.values(until);
+        assert!(values["new_checkout"]);
+        assert!(!values["lockdown"]);
+
+        assert!(toml::from_str::<Flags>(r#"a = { enabled = true, until = "soon" }"#).is_err());
+    }
+}
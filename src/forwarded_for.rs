@@ -0,0 +1,351 @@
+//! Validation of the X-Forwarded-For header, and of the peers sending
+//! X-Forwarded-* headers.
+//!
+//! Clients can send arbitrary X-Forwarded-For headers, which proxies append
+//! to. Overly long chains, or entries that aren't IP addresses, are either
+//! rejected, or stripped, so they can't confuse IP-based policies and logs.
+//!
+//! Clients able to reach cellulose directly, bypassing the proxy, can send
+//! arbitrary X-Forwarded-* headers altogether. If trusted proxies are
+//! configured, these headers are only accepted from them.
+use std::{fmt, net::IpAddr, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio_listener::SomeSocketAddrClonable;
+use tracing::debug;
+
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Headers set by proxies, other than the X-Forwarded-* ones.
+const PROXY_HEADERS: &[&str] = &["forwarded", "x-real-ip"];
+
+/// A CIDR range, like "10.0.0.0/8" or "2001:db8::/32". A single address is
+/// a range of just itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match cidr.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (cidr, None),
+        };
+        let network = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid address: {}", e))?;
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|l| *l <= max_len)
+                .ok_or_else(|| format!("invalid prefix length {}", prefix_len))?,
+            None => max_len,
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl Cidr {
+    /// Whether the address is in the range. IPv4-mapped IPv6 addresses are
+    /// treated as IPv4 ones, addresses of the other family never match.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // a mapped network loses the 96 bit prefix along with its family
+        let prefix_len = match (self.network, self.network.to_canonical()) {
+            (IpAddr::V6(_), IpAddr::V4(_)) => self.prefix_len.saturating_sub(96),
+            _ => self.prefix_len,
+        };
+
+        match (ip.to_canonical(), self.network.to_canonical()) {
+            (IpAddr::V4(ip), IpAddr::V4(network)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(network) & mask
+            }
+            (IpAddr::V6(ip), IpAddr::V6(network)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                u128::from(ip) & mask == u128::from(network) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What to do with requests whose X-Forwarded-For header is invalid.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum OnViolation {
+    /// Reject the request.
+    Deny,
+    /// Drop entries that aren't IP addresses, and the leftmost ones
+    /// exceeding the maximum, which were added earliest and are the least
+    /// trustworthy.
+    Strip,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ForwardedFor {
+    /// The maximum number of entries.
+    pub max_entries: usize,
+    pub on_violation: OnViolation,
+    /// The number of proxies appending to the header, the client address is
+    /// the entry this far from the right.
+    pub depth: usize,
+}
+
+impl ForwardedFor {
+    /// Validate the X-Forwarded-For header (occurring once or multiple
+    /// times). Returns an error describing the violation if the request
+    /// should be denied, otherwise the header is normalized to a single one,
+    /// with violations stripped.
+    pub fn validate(&self, headers: &mut HeaderMap) -> Result<(), String> {
+        if !headers.contains_key(X_FORWARDED_FOR) {
+            return Ok(());
+        }
+
+        let entries = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .flat_map(|v| v.as_bytes().split(|b| *b == b','))
+            .map(|entry| {
+                std::str::from_utf8(entry)
+                    .ok()
+                    .and_then(|entry| entry.trim().parse::<IpAddr>().ok())
+            })
+            .collect::<Vec<_>>();
+
+        if self.on_violation == OnViolation::Deny {
+            if entries.len() > self.max_entries {
+                return Err(format!(
+                    "{} entries, more than the maximum of {}",
+                    entries.len(),
+                    self.max_entries
+                ));
+            }
+            if entries.iter().any(Option::is_none) {
+                return Err("contains entries that aren't IP addresses".to_string());
+            }
+        }
+
+        let entries = entries.into_iter().flatten().collect::<Vec<_>>();
+        let entries = &entries[entries.len().saturating_sub(self.max_entries)..];
+
+        headers.remove(X_FORWARDED_FOR);
+        if !entries.is_empty() {
+            let value = entries
+                .iter()
+                .map(IpAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            headers.insert(
+                X_FORWARDED_FOR,
+                HeaderValue::try_from(value).expect("IP addresses must be valid header values"),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The address of the client, added by the outermost of the [depth]
+    /// proxies, which is the first one not under control of the client.
+    /// Null if the header has fewer entries, as it then didn't pass all
+    /// proxies. Expects the header to be [validate]d already.
+    pub fn client_ip(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        headers
+            .get(X_FORWARDED_FOR)?
+            .to_str()
+            .ok()?
+            .rsplit(',')
+            .nth(self.depth.checked_sub(1)?)?
+            .trim()
+            .parse()
+            .ok()
+    }
+}
+
+/// What to do with requests from peers that aren't trusted proxies.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum OnUntrusted {
+    /// Reject requests carrying X-Forwarded-* headers with a 403.
+    Deny,
+    /// Remove the X-Forwarded-* headers, so policies see the request as if
+    /// it didn't pass a proxy.
+    Strip,
+}
+
+/// The peers allowed to send X-Forwarded-* headers.
+#[derive(Clone, Debug)]
+pub struct TrustedProxies {
+    /// The trusted ranges. If empty, all peers are trusted.
+    pub proxies: Arc<[Cidr]>,
+    pub on_untrusted: OnUntrusted,
+    /// Further headers only trusted proxies may send, like the one carrying
+    /// the client certificate.
+    pub extra_headers: Arc<[HeaderName]>,
+}
+
+impl TrustedProxies {
+    /// Whether the peer is trusted. Peers without IP address (like on Unix
+    /// sockets) are local, and always trusted.
+    pub fn trusts(&self, peer: Option<IpAddr>) -> bool {
+        self.proxies.is_empty()
+            || peer.is_none_or(|peer| self.proxies.iter().any(|cidr| cidr.contains(peer)))
+    }
+
+    /// The names of the headers only trusted proxies may send.
+    fn is_proxy_header(&self, name: &HeaderName) -> bool {
+        name.as_str().starts_with("x-forwarded-")
+            || PROXY_HEADERS.contains(&name.as_str())
+            || self.extra_headers.contains(name)
+    }
+
+    /// Check the headers of a request from [peer], returning an error if it
+    /// should be denied, otherwise stripping the headers it may not send.
+    pub fn check(&self, peer: Option<IpAddr>, headers: &mut HeaderMap) -> Result<(), String> {
+        if self.trusts(peer) {
+            return Ok(());
+        }
+
+        let names = headers
+            .keys()
+            .filter(|name| self.is_proxy_header(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            return Ok(());
+        }
+        if self.on_untrusted == OnUntrusted::Deny {
+            return Err(format!("{} sent by untrusted peer", names[0]));
+        }
+        for name in names {
+            headers.remove(name);
+        }
+        Ok(())
+    }
+}
+
+/// The IP address of the peer of a connection, if any.
+pub fn peer_ip(addr: &SomeSocketAddrClonable) -> Option<IpAddr> {
+    match addr {
+        SomeSocketAddrClonable::Tcp(addr) => Some(addr.ip()),
+        _ => None,
+    }
+}
+
+/// Middleware applying [TrustedProxies::check] to all requests.
+pub async fn apply(State(trusted): State<TrustedProxies>, mut rq: Request, next: Next) -> Response {
+    let peer = rq
+        .extensions()
+        .get::<ConnectInfo<SomeSocketAddrClonable>>()
+        .and_then(|ConnectInfo(addr)| peer_ip(addr));
+
+    if let Err(e) = trusted.check(peer, rq.headers_mut()) {
+        debug!(err = %e, ?peer, "rejecting request from untrusted peer");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(rq).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{Cidr, ForwardedFor, OnUntrusted, OnViolation, TrustedProxies, X_FORWARDED_FOR};
+
+    fn headers(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for v in values {
+            headers.append(X_FORWARDED_FOR, HeaderValue::from_static(v));
+        }
+        headers
+    }
+
+    #[test]
+    fn validate() {
+        let strip = ForwardedFor {
+            max_entries: 2,
+            on_violation: OnViolation::Strip,
+            depth: 1,
+        };
+        let deny = ForwardedFor {
+            on_violation: OnViolation::Deny,
+            ..strip
+        };
+
+        let mut h = headers(&["192.0.2.1, 2001:db8::1"]);
+        deny.validate(&mut h).expect("must be valid");
+        assert_eq!("192.0.2.1, 2001:db8::1", h[X_FORWARDED_FOR]);
+
+        let mut h = headers(&["evil, 192.0.2.1", "198.51.100.1", "203.0.113.1"]);
+        assert!(deny.validate(&mut h.clone()).is_err());
+        strip.validate(&mut h).expect("must be stripped");
+        assert_eq!(1, h.get_all(X_FORWARDED_FOR).iter().count());
+        assert_eq!("198.51.100.1, 203.0.113.1", h[X_FORWARDED_FOR]);
+        assert_eq!(Some([203, 0, 113, 1].into()), strip.client_ip(&h));
+        let two_proxies = ForwardedFor { depth: 2, ..strip };
+        assert_eq!(Some([198, 51, 100, 1].into()), two_proxies.client_ip(&h));
+        let three_proxies = ForwardedFor { depth: 3, ..strip };
+        assert_eq!(None, three_proxies.client_ip(&h));
+
+        let mut h = headers(&["unknown"]);
+        strip.validate(&mut h).expect("must be stripped");
+        assert!(!h.contains_key(X_FORWARDED_FOR));
+
+        let mut h = HeaderMap::new();
+        deny.validate(&mut h).expect("absent is valid");
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let strip = TrustedProxies {
+            proxies: ["10.0.0.0/8".parse::<Cidr>().unwrap()].into(),
+            on_untrusted: OnUntrusted::Strip,
+            extra_headers: [axum::http::HeaderName::from_static("x-client-cert")].into(),
+        };
+        let deny = TrustedProxies {
+            on_untrusted: OnUntrusted::Deny,
+            ..strip.clone()
+        };
+
+        let mut h = headers(&["192.0.2.1"]);
+        h.insert("x-forwarded-uri", HeaderValue::from_static("/admin"));
+        h.insert("x-client-cert", HeaderValue::from_static("spoofed"));
+        h.insert("forwarded", HeaderValue::from_static("for=192.0.2.1"));
+        h.insert("authorization", HeaderValue::from_static("Bearer x"));
+
+        let proxy = Some([10, 1, 2, 3].into());
+        let client = Some([192, 0, 2, 1].into());
+        assert!(strip.trusts(proxy) && strip.trusts(None) && !strip.trusts(client));
+
+        let mut trusted = h.clone();
+        deny.check(proxy, &mut trusted).expect("proxy is trusted");
+        assert_eq!(h, trusted);
+
+        assert!(deny.check(client, &mut h.clone()).is_err());
+        strip.check(client, &mut h).expect("must be stripped");
+        assert_eq!(vec!["authorization"], h.keys().collect::<Vec<_>>());
+
+        // requests without proxy headers are fine from anyone
+        deny.check(client, &mut h).expect("nothing to deny");
+    }
+}
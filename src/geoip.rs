@@ -0,0 +1,471 @@
+//! GeoIP enrichment of the client address, exposed to CEL programs as `geo`.
+//!
+//! Databases are in the MaxMind DB format, like GeoLite2-Country,
+//! GeoLite2-City or GeoLite2-ASN. Multiple ones can be configured, with the
+//! first one having a field winning, so a country and an ASN database can
+//! be combined.
+//!
+//! Only the few fields needed are read, so the reader is kept minimal: it
+//! walks the search tree, and decodes the record found into JSON.
+use std::{collections::HashMap, fmt, net::IpAddr, path::Path};
+
+use cel_interpreter::Value;
+use serde_json::Value as Json;
+
+#[derive(Debug)]
+pub struct GeoIpError(String);
+
+impl fmt::Display for GeoIpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for GeoIpError {}
+
+fn err(msg: impl Into<String>) -> GeoIpError {
+    GeoIpError(msg.into())
+}
+
+/// Marks the start of the metadata, at the end of the file.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// How far from the end of the file the metadata may start.
+const METADATA_MAX_SIZE: usize = 128 * 1024;
+
+/// The size of the separator between search tree and data section.
+const DATA_SECTION_SEPARATOR: usize = 16;
+
+/// How deeply maps and arrays, and pointers, may be nested.
+const MAX_DEPTH: usize = 32;
+
+/// A single MaxMind DB file.
+pub struct Database {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// The offset of the data section.
+    data_start: usize,
+}
+
+/// The details looked up for an address. Fields not contained in any
+/// database are None.
+#[derive(Debug, Default, PartialEq)]
+pub struct Geo {
+    /// The ISO 3166-1 alpha-2 code of the country, like `DE`.
+    pub country: Option<String>,
+    /// The number of the autonomous system.
+    pub asn: Option<u64>,
+    /// The organization of the autonomous system.
+    pub as_org: Option<String>,
+}
+
+impl Geo {
+    /// The details as CEL map, with absent fields as null.
+    pub fn to_value(&self) -> Value {
+        HashMap::from([
+            ("country", Value::from(self.country.clone())),
+            (
+                "asn",
+                self.asn.map_or(Value::Null, |asn| Value::Int(asn as i64)),
+            ),
+            ("as_org", Value::from(self.as_org.clone())),
+        ])
+        .into()
+    }
+}
+
+/// Decodes values from the data section (or the metadata).
+struct Decoder<'a> {
+    buf: &'a [u8],
+    /// The offset pointers are relative to.
+    base: usize,
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], GeoIpError> {
+        self.buf
+            .get(offset..offset + len)
+            .ok_or_else(|| err("unexpected end of data"))
+    }
+
+    fn uint(&self, offset: usize, len: usize) -> Result<u64, GeoIpError> {
+        if len > 8 {
+            return Err(err("integer too large"));
+        }
+        Ok(self
+            .bytes(offset, len)?
+            .iter()
+            .fold(0, |acc, b| acc << 8 | *b as u64))
+    }
+
+    /// Decode the value at [offset], returning it along with the offset
+    /// after it.
+    fn decode(&self, offset: usize, depth: usize) -> Result<(Json, usize), GeoIpError> {
+        if depth > MAX_DEPTH {
+            return Err(err("data nested too deeply"));
+        }
+
+        let ctrl = *self.bytes(offset, 1)?.first().expect("must have a byte");
+        let mut offset = offset + 1;
+        let mut typ = ctrl >> 5;
+
+        if typ == 1 {
+            // pointers encode their size differently
+            let size = ((ctrl >> 3) & 0x3) as usize;
+            let high = (ctrl & 0x7) as u64;
+            let target = match size {
+                0 => (high << 8 | self.uint(offset, 1)?) as usize,
+                1 => (high << 16 | self.uint(offset, 2)?) as usize + 2048,
+                2 => (high << 24 | self.uint(offset, 3)?) as usize + 526336,
+                _ => self.uint(offset, 4)? as usize,
+            };
+            let (value, _) = self.decode(self.base + target, depth + 1)?;
+            return Ok((value, offset + size + 1));
+        }
+        if typ == 0 {
+            typ = 7 + self.bytes(offset, 1)?[0];
+            offset += 1;
+        }
+
+        let mut size = (ctrl & 0x1f) as usize;
+        if size >= 29 {
+            let n = size - 28;
+            let ext = self.uint(offset, n)? as usize;
+            size = match n {
+                1 => 29 + ext,
+                2 => 285 + ext,
+                _ => 65821 + ext,
+            };
+            offset += n;
+        }
+
+        Ok(match typ {
+            // UTF-8 string
+            2 => {
+                let s = std::str::from_utf8(self.bytes(offset, size)?)
+                    .map_err(|_| err("invalid UTF-8 string"))?;
+                (Json::from(s), offset + size)
+            }
+            // double
+            3 => {
+                let v = f64::from_bits(self.uint(offset, 8)?);
+                (Json::from(v), offset + 8)
+            }
+            // bytes, not needed, so they're skipped
+            4 => (Json::Null, offset + size),
+            // unsigned integers
+            5 | 6 | 9 => (Json::from(self.uint(offset, size)?), offset + size),
+            // int32
+            8 => {
+                let v = self.uint(offset, size)? as u32 as i32;
+                (Json::from(v), offset + size)
+            }
+            // uint128, only representable as string
+            10 => {
+                let bytes = self.bytes(offset, size)?;
+                let v = bytes.iter().fold(0u128, |acc, b| acc << 8 | *b as u128);
+                (Json::from(v.to_string()), offset + size)
+            }
+            // map
+            7 => {
+                let mut map = serde_json::Map::new();
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset, depth + 1)?;
+                    let Json::String(key) = key else {
+                        return Err(err("map key isn't a string"));
+                    };
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    map.insert(key, value);
+                    offset = next;
+                }
+                (Json::Object(map), offset)
+            }
+            // array
+            11 => {
+                let mut values = Vec::with_capacity(size.min(1024));
+                for _ in 0..size {
+                    let (value, next) = self.decode(offset, depth + 1)?;
+                    values.push(value);
+                    offset = next;
+                }
+                (Json::Array(values), offset)
+            }
+            // boolean
+            14 => (Json::from(size != 0), offset),
+            // float
+            15 => {
+                let v = f32::from_bits(self.uint(offset, 4)? as u32);
+                (Json::from(v), offset + 4)
+            }
+            typ => return Err(err(format!("unsupported data type {}", typ))),
+        })
+    }
+}
+
+impl Database {
+    /// Parse a database from its contents.
+    pub fn parse(buf: Vec<u8>) -> Result<Self, GeoIpError> {
+        let search_start = buf.len().saturating_sub(METADATA_MAX_SIZE);
+        let marker = buf[search_start..]
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or_else(|| err("not a MaxMind DB file, metadata not found"))?;
+        let metadata_start = search_start + marker + METADATA_MARKER.len();
+
+        let decoder = Decoder {
+            buf: &buf,
+            base: metadata_start,
+        };
+        let (metadata, _) = decoder.decode(metadata_start, 0)?;
+        let field = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(Json::as_u64)
+                .ok_or_else(|| err(format!("metadata field {} missing", name)))
+        };
+
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if !matches!(record_size, 24 | 28 | 32) {
+            return Err(err(format!("unsupported record size {}", record_size)));
+        }
+
+        let tree_size = node_count * record_size / 4;
+        let data_start = tree_size + DATA_SECTION_SEPARATOR;
+        if data_start > metadata_start {
+            return Err(err("search tree exceeds the file"));
+        }
+
+        Ok(Self {
+            buf,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+        })
+    }
+
+    /// Load a database from a file.
+    pub fn load(path: &Path) -> Result<Self, GeoIpError> {
+        let buf = std::fs::read(path)
+            .map_err(|e| err(format!("unable to read {}: {}", path.display(), e)))?;
+        Self::parse(buf).map_err(|e| err(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Read the left (0) or right (1) record of the node.
+    fn record(&self, node: usize, bit: u8) -> Result<usize, GeoIpError> {
+        let node_size = self.record_size / 4;
+        let b = self
+            .buf
+            .get(node * node_size..(node + 1) * node_size)
+            .ok_or_else(|| err("node out of bounds"))?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |acc, b| acc << 8 | *b as usize);
+        Ok(match (self.record_size, bit) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => ((b[3] as usize & 0xf0) << 20) | be(&b[0..3]),
+            (28, _) => ((b[3] as usize & 0x0f) << 24) | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        })
+    }
+
+    /// Look up the record for the address, if any.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<Json>, GeoIpError> {
+        let bits = match (ip.to_canonical(), self.ip_version) {
+            (IpAddr::V4(ip), 4) => (u32::from(ip) as u128) << 96,
+            // IPv4 addresses are in the ::/96 subtree of IPv6 databases
+            (IpAddr::V4(ip), _) => u32::from(ip) as u128,
+            (IpAddr::V6(_), 4) => return Ok(None),
+            (IpAddr::V6(ip), _) => u128::from(ip),
+        };
+        let depth = if self.ip_version == 4 { 32 } else { 128 };
+
+        let mut node = 0;
+        for i in 0..depth {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits >> (127 - i) & 1) as u8)?;
+        }
+
+        if node == self.node_count {
+            return Ok(None);
+        }
+        if node < self.node_count {
+            return Err(err("search tree deeper than the address"));
+        }
+
+        let offset = self.data_start + (node - self.node_count - DATA_SECTION_SEPARATOR);
+        let decoder = Decoder {
+            buf: &self.buf,
+            base: self.data_start,
+        };
+        Ok(Some(decoder.decode(offset, 0)?.0))
+    }
+}
+
+/// The configured GeoIP databases.
+#[derive(Default)]
+pub struct GeoIp {
+    databases: Vec<Database>,
+}
+
+impl GeoIp {
+    /// Load the databases from the given files.
+    pub fn load<P: AsRef<Path>>(paths: &[P]) -> Result<Self, GeoIpError> {
+        Ok(Self {
+            databases: paths
+                .iter()
+                .map(|p| Database::load(p.as_ref()))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Look up the address in all databases. Fields are taken from the first
+    /// database containing them; databases failing to decode the record are
+    /// skipped.
+    pub fn lookup(&self, ip: IpAddr) -> Geo {
+        let mut geo = Geo::default();
+        for database in &self.databases {
+            let record = match database.lookup(ip) {
+                Ok(Some(record)) => record,
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::debug!(err = %e, %ip, "failed to look up address");
+                    continue;
+                }
+            };
+
+            let string = |pointer: &str| {
+                record
+                    .pointer(pointer)
+                    .and_then(Json::as_str)
+                    .map(str::to_owned)
+            };
+            geo.country = geo.country.or_else(|| string("/country/iso_code"));
+            geo.asn = geo.asn.or_else(|| {
+                record
+                    .pointer("/autonomous_system_number")
+                    .and_then(Json::as_u64)
+            });
+            geo.as_org = geo
+                .as_org
+                .or_else(|| string("/autonomous_system_organization"));
+        }
+        geo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Database, Geo, GeoIp, METADATA_MARKER};
+
+    /// Encode a string (shorter than 285 bytes), map or uint32 in the MaxMind DB data format.
+    fn encode(value: &serde_json::Value, out: &mut Vec<u8>) {
+        match value {
+            serde_json::Value::String(s) => {
+                if s.len() < 29 {
+                    out.push(2 << 5 | s.len() as u8);
+                } else {
+                    out.extend([2 << 5 | 29, s.len() as u8 - 29]);
+                }
+                out.extend(s.as_bytes());
+            }
+            serde_json::Value::Number(n) => {
+                out.push(6 << 5 | 4);
+                out.extend((n.as_u64().unwrap() as u32).to_be_bytes());
+            }
+            serde_json::Value::Object(map) => {
+                out.push(7 << 5 | map.len() as u8);
+                for (k, v) in map {
+                    encode(&k.as_str().into(), out);
+                    encode(v, out);
+                }
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    /// Build an IPv4 database with 24 bit records, containing the record for
+    /// a single network.
+    fn database(network: [u8; 4], prefix_len: usize, record: serde_json::Value) -> Vec<u8> {
+        let node_count = prefix_len;
+        let bits = u32::from_be_bytes(network);
+        let data_pointer = node_count + 16;
+
+        let mut buf = Vec::new();
+        for i in 0..prefix_len {
+            let next = if i + 1 == prefix_len {
+                data_pointer
+            } else {
+                i + 1
+            };
+            let (left, right) = match bits >> (31 - i) & 1 {
+                0 => (next, node_count),
+                _ => (node_count, next),
+            };
+            buf.extend(&(left as u32).to_be_bytes()[1..]);
+            buf.extend(&(right as u32).to_be_bytes()[1..]);
+        }
+        buf.extend([0; 16]);
+        encode(&record, &mut buf);
+
+        buf.extend(METADATA_MARKER);
+        encode(
+            &serde_json::json!({
+                "node_count": node_count,
+                "record_size": 24,
+                "ip_version": 4,
+            }),
+            &mut buf,
+        );
+        buf
+    }
+
+    #[test]
+    fn lookup() {
+        let country = Database::parse(database(
+            [192, 0, 2, 0],
+            24,
+            serde_json::json!({ "country": { "iso_code": "DE" } }),
+        ))
+        .expect("must parse");
+        let asn = Database::parse(database(
+            [192, 0, 0, 0],
+            16,
+            serde_json::json!({
+                "autonomous_system_number": 64512,
+                "autonomous_system_organization": "Example",
+            }),
+        ))
+        .expect("must parse");
+        let geoip = GeoIp {
+            databases: vec![country, asn],
+        };
+
+        assert_eq!(
+            Geo {
+                country: Some("DE".to_string()),
+                asn: Some(64512),
+                as_org: Some("Example".to_string()),
+            },
+            geoip.lookup("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            Geo {
+                country: None,
+                asn: Some(64512),
+                as_org: Some("Example".to_string()),
+            },
+            geoip.lookup("::ffff:192.0.3.1".parse().unwrap())
+        );
+        assert_eq!(Geo::default(), geoip.lookup("10.0.0.1".parse().unwrap()));
+        assert_eq!(Geo::default(), geoip.lookup("2001:db8::1".parse().unwrap()));
+
+        assert!(Database::parse(b"not a database".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Recognizing health checks of proxies and load balancers sent to /auth.
+//!
+//! These are decided on like any other request, but counted separately
+//! instead of as decisions, and left out of the recent denials, so
+//! dashboards reflect traffic of actual users. The markers (user agents and
+//! paths) are sent by the client, so clients can hide from the metrics by
+//! sending them, but never gain access.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::context_request::X_FORWARDED_URI;
+
+/// Set as request extension on health checks.
+#[derive(Clone, Copy, Debug)]
+pub struct HealthCheck;
+
+#[derive(Clone, Debug, Default)]
+pub struct HealthChecks {
+    /// Substrings of the User-Agent of health checks, matched ignoring
+    /// case, like `kube-probe/` or `ELB-HealthChecker`.
+    pub user_agents: Arc<[String]>,
+    /// Paths (of the original request, without query) health checks are
+    /// sent to, like `/healthz`.
+    pub paths: Arc<[String]>,
+}
+
+impl HealthChecks {
+    /// Whether the request is a health check.
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        let get = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let user_agent = get(header::USER_AGENT.as_str()).map(str::to_ascii_lowercase);
+        if let Some(user_agent) = user_agent {
+            if self
+                .user_agents
+                .iter()
+                .any(|marker| user_agent.contains(&marker.to_ascii_lowercase()))
+            {
+                return true;
+            }
+        }
+        get(X_FORWARDED_URI)
+            .map(|uri| uri.split_once('?').map_or(uri, |(path, _)| path))
+            .is_some_and(|path| self.paths.iter().any(|p| p == path))
+    }
+}
+
+/// Middleware marking health checks with the [HealthCheck] extension.
+pub async fn apply(
+    State(health_checks): State<HealthChecks>,
+    mut rq: Request,
+    next: Next,
+) -> Response {
+    if health_checks.matches(rq.headers()) {
+        rq.extensions_mut().insert(HealthCheck);
+    }
+    next.run(rq).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::HealthChecks;
+
+    #[test]
+    fn matches() {
+        let health_checks = HealthChecks {
+            user_agents: vec!["kube-probe/".to_string()].into(),
+            paths: vec!["/healthz".to_string()].into(),
+        };
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        assert!(health_checks.matches(&headers(&[("user-agent", "Kube-Probe/1.30")])));
+        assert!(health_checks.matches(&headers(&[("x-forwarded-uri", "/healthz?full=1")])));
+        assert!(!health_checks.matches(&headers(&[("x-forwarded-uri", "/healthz/x")])));
+        assert!(!health_checks.matches(&headers(&[("user-agent", "curl/8.0")])));
+        assert!(!HealthChecks::default().matches(&headers(&[("user-agent", "kube-probe/1.30")])));
+    }
+}
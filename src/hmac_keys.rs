@@ -0,0 +1,200 @@
+//! A key source for tokens signed with a shared secret (HS256/HS384/HS512).
+use std::{fmt, path::Path, sync::Arc};
+
+use jwt_simple::{claims::JWTClaims, prelude::*};
+use tracing::warn;
+
+use crate::key_set::LoadError;
+
+/// Secrets shorter than this (in bytes) are accepted, but warned about.
+/// RFC 7518, section 3.2 requires a key at least as long as the hash output.
+const MIN_SECRET_LEN: usize = 32;
+
+/// A shared secret, usable with all HS* algorithms.
+#[derive(Clone)]
+struct Secret {
+    kid: Option<String>,
+    hs256: HS256Key,
+    hs384: HS384Key,
+    hs512: HS512Key,
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key material itself
+        f.debug_struct("Secret").field("kid", &self.kid).finish()
+    }
+}
+
+impl Secret {
+    fn new(secret: &[u8], kid: Option<String>) -> Self {
+        Self {
+            kid,
+            hs256: HS256Key::from_bytes(secret),
+            hs384: HS384Key::from_bytes(secret),
+            hs512: HS512Key::from_bytes(secret),
+        }
+    }
+
+    fn verify_token<CustomClaims>(
+        &self,
+        alg: &str,
+        token: &str,
+        options: Option<VerificationOptions>,
+    ) -> Option<Result<JWTClaims<CustomClaims>, jwt_simple::Error>>
+    where
+        CustomClaims: Serialize + serde::de::DeserializeOwned + Send,
+    {
+        Some(match alg {
+            "HS256" => self.hs256.verify_token(token, options),
+            "HS384" => self.hs384.verify_token(token, options),
+            "HS512" => self.hs512.verify_token(token, options),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HmacSource {
+    /// The issuer this source is responsible for.
+    /// If None, it is used for tokens of any issuer not explicitly routed.
+    issuer: Option<String>,
+    secrets: Arc<Vec<Secret>>,
+}
+
+/// Read a secret from a file.
+/// A single trailing newline is stripped, as most editors (and `echo`) add
+/// one.
+fn load_secret(path: &Path) -> Result<Secret, LoadError> {
+    let mut secret = std::fs::read(path)
+        .map_err(|e| LoadError(format!("unable to read {}: {}", path.display(), e)))?;
+    if secret.ends_with(b"\n") {
+        secret.pop();
+        if secret.ends_with(b"\r") {
+            secret.pop();
+        }
+    }
+
+    if secret.is_empty() {
+        return Err(LoadError(format!("{}: secret is empty", path.display())));
+    }
+    if secret.len() < MIN_SECRET_LEN {
+        warn!(
+            path = %path.display(),
+            "HMAC secret is shorter than {} bytes, consider using a longer one",
+            MIN_SECRET_LEN
+        );
+    }
+
+    // use the file name (without extension) as key id
+    let kid = path.file_stem().map(|s| s.to_string_lossy().into_owned());
+    Ok(Secret::new(&secret, kid))
+}
+
+impl HmacSource {
+    /// Load shared secrets from the given files.
+    pub fn load<P: AsRef<Path>>(issuer: Option<String>, paths: &[P]) -> Result<Self, LoadError> {
+        let secrets = paths
+            .iter()
+            .map(|p| load_secret(p.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            issuer,
+            secrets: Arc::new(secrets),
+        })
+    }
+
+    /// The issuer this source is responsible for, if any.
+    pub fn issuer(&self) -> Option<&str> {
+        self.issuer.as_deref()
+    }
+
+    /// The key ids of the secrets, in the order they were loaded.
+    pub fn key_ids(&self) -> Vec<Option<String>> {
+        self.secrets.iter().map(|s| s.kid.clone()).collect()
+    }
+
+    /// Verify the token against all secrets.
+    /// If the token carries a key id, only the secret loaded from the file
+    /// with that name is tried.
+    pub fn verify<CustomClaims>(
+        &self,
+        token: &str,
+        options: Option<VerificationOptions>,
+    ) -> Result<JWTClaims<CustomClaims>, String>
+    where
+        CustomClaims: Serialize + serde::de::DeserializeOwned + Send,
+    {
+        let metadata = Token::decode_metadata(token).map_err(|e| e.to_string())?;
+        let (alg, kid) = (metadata.algorithm(), metadata.key_id());
+
+        let mut last_err = format!("no secret found for kid {:?}, alg {}", kid, alg);
+        for secret in self
+            .secrets
+            .iter()
+            .filter(|s| kid.is_none() || s.kid.as_deref() == kid)
+        {
+            match secret.verify_token(alg, token, options.clone()) {
+                Some(Ok(claims)) => return Ok(claims),
+                Some(Err(e)) => last_err = e.to_string(),
+                // not an HMAC algorithm, no secret is going to match.
+                None => break,
+            }
+        }
+
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use jwt_simple::prelude::*;
+
+    use super::HmacSource;
+
+    #[test]
+    fn verify() {
+        let mut f = tempfile::Builder::new()
+            .suffix(".key")
+            .tempfile()
+            .expect("must create file");
+        writeln!(f, "0123456789abcdef0123456789abcdef").unwrap();
+        let kid = f.path().file_stem().unwrap().to_string_lossy().into_owned();
+
+        let source = HmacSource::load(None, &[f.path()]).expect("must load");
+
+        // the trailing newline is not part of the secret
+        let key = HS384Key::from_bytes(b"0123456789abcdef0123456789abcdef");
+        let token = key
+            .authenticate(Claims::create(Duration::from_mins(5)).with_subject("alice"))
+            .expect("must authenticate");
+        let claims = source
+            .verify::<NoCustomClaims>(&token, None)
+            .expect("must verify");
+        assert_eq!(Some("alice".to_string()), claims.subject);
+
+        // matching kid
+        let token = key
+            .clone()
+            .with_key_id(&kid)
+            .authenticate(Claims::create(Duration::from_mins(5)))
+            .expect("must authenticate");
+        assert!(source.verify::<NoCustomClaims>(&token, None).is_ok());
+
+        // another kid
+        let token = key
+            .with_key_id("other")
+            .authenticate(Claims::create(Duration::from_mins(5)))
+            .expect("must authenticate");
+        assert!(source.verify::<NoCustomClaims>(&token, None).is_err());
+
+        // another secret
+        let token = HS256Key::generate()
+            .authenticate(Claims::create(Duration::from_mins(5)))
+            .expect("must authenticate");
+        assert!(source.verify::<NoCustomClaims>(&token, None).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! Combining the identities a request presents (a token, a client
+//! certificate, an API key) into whether it may proceed to the policy.
+//!
+//! By default, only the token counts, like before other identities existed.
+//! Other combiners require all configured identities, any of them, or a
+//! minimum sum of weights, which policies for sensitive routes can raise.
+//! The result of each identity is exposed to the policy as `identities`, for
+//! finer-grained decisions.
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use axum::http::StatusCode;
+use cel_interpreter::Value;
+
+/// The kinds of identities.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Kind {
+    Jwt,
+    ClientCert,
+    ApiKey,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Jwt => "jwt",
+            Kind::ClientCert => "client_cert",
+            Kind::ApiKey => "api_key",
+        }
+    }
+}
+
+/// The verification result of an identity.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verification {
+    /// The request doesn't present the identity.
+    Absent,
+    /// The identity is valid, with its name, if it has one (like an API
+    /// key's).
+    Verified(Option<String>),
+    /// The identity is invalid, with the status code to respond with if it
+    /// was required.
+    Failed(StatusCode),
+}
+
+impl Verification {
+    fn is_verified(&self) -> bool {
+        matches!(self, Verification::Verified(_))
+    }
+
+    fn to_value(&self) -> Value {
+        let name = match self {
+            Verification::Verified(name) => name.clone(),
+            _ => None,
+        };
+        HashMap::from([
+            ("present", Value::Bool(*self != Verification::Absent)),
+            ("verified", Value::Bool(self.is_verified())),
+            ("name", Value::from(name)),
+        ])
+        .into()
+    }
+}
+
+/// The identities presented by a request. Kinds not configured are None.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identities {
+    pub jwt: Verification,
+    pub client_cert: Option<Verification>,
+    pub api_key: Option<Verification>,
+}
+
+impl Identities {
+    /// The configured identities.
+    fn iter(&self) -> impl Iterator<Item = (Kind, &Verification)> {
+        [
+            (Kind::Jwt, Some(&self.jwt)),
+            (Kind::ClientCert, self.client_cert.as_ref()),
+            (Kind::ApiKey, self.api_key.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(kind, v)| Some((kind, v?)))
+    }
+
+    /// The configured identities as CEL map, by kind, each with `present`,
+    /// `verified` and `name`.
+    pub fn to_value(&self) -> Value {
+        self.iter()
+            .map(|(kind, v)| (kind.name(), v.to_value()))
+            .collect::<HashMap<_, _>>()
+            .into()
+    }
+}
+
+/// How identities are combined.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Mode {
+    /// Only the token counts, other identities are informational.
+    Jwt,
+    /// All configured identities need to be verified.
+    All,
+    /// Any of the configured identities needs to be verified.
+    Any,
+    /// The weights of the verified identities need to add up to the minimum
+    /// weight, of the policy or the default one.
+    Weighted,
+}
+
+/// The weight of an identity kind, like `client_cert=2`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Weight {
+    pub kind: Kind,
+    pub weight: u32,
+}
+
+impl FromStr for Weight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, weight) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <kind>=<weight>, got {}", s))?;
+        Ok(Self {
+            // accept the kind as in `identities`, or as on the command line
+            kind: clap::ValueEnum::from_str(&kind.trim().replace('_', "-"), true)?,
+            weight: weight
+                .trim()
+                .parse()
+                .map_err(|e| format!("invalid weight {}: {}", weight, e))?,
+        })
+    }
+}
+
+impl fmt::Display for Weight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.kind.name(), self.weight)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Combiner {
+    pub mode: Mode,
+    /// Weights by kind, kinds not listed weigh 1.
+    pub weights: Vec<Weight>,
+    /// The minimum weight for policies not setting one.
+    pub min_weight: u32,
+}
+
+impl Default for Combiner {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Jwt,
+            weights: vec![],
+            min_weight: 1,
+        }
+    }
+}
+
+impl Combiner {
+    fn weight(&self, kind: Kind) -> u32 {
+        self.weights
+            .iter()
+            .find(|w| w.kind == kind)
+            .map_or(1, |w| w.weight)
+    }
+
+    /// Whether the identities suffice, with the policy's minimum weight, if
+    /// it sets one. Returns the status code to respond with otherwise: the
+    /// token's in the default mode, so its errors are reported like
+    /// before, otherwise 401.
+    pub fn combine(
+        &self,
+        identities: &Identities,
+        min_weight: Option<u32>,
+    ) -> Result<(), StatusCode> {
+        let sufficient = match self.mode {
+            Mode::Jwt => {
+                return match &identities.jwt {
+                    Verification::Verified(_) => Ok(()),
+                    Verification::Failed(status) => Err(*status),
+                    Verification::Absent => Err(StatusCode::UNAUTHORIZED),
+                }
+            }
+            Mode::All => identities.iter().all(|(_, v)| v.is_verified()),
+            Mode::Any => identities.iter().any(|(_, v)| v.is_verified()),
+            Mode::Weighted => {
+                let weight: u32 = identities
+                    .iter()
+                    .filter(|(_, v)| v.is_verified())
+                    .map(|(kind, _)| self.weight(kind))
+                    .sum();
+                weight >= min_weight.unwrap_or(self.min_weight)
+            }
+        };
+        if sufficient {
+            Ok(())
+        } else {
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use cel_interpreter::{Context, Program, Value};
+
+    use super::{Combiner, Identities, Mode, Verification};
+
+    #[test]
+    fn combine() {
+        let identities = Identities {
+            jwt: Verification::Failed(StatusCode::INTERNAL_SERVER_ERROR),
+            client_cert: Some(Verification::Verified(None)),
+            api_key: Some(Verification::Verified(Some("billing".into()))),
+        };
+
+        let jwt = Combiner::default();
+        assert_eq!(
+            Err(StatusCode::INTERNAL_SERVER_ERROR),
+            jwt.combine(&identities, None)
+        );
+        let all = Combiner {
+            mode: Mode::All,
+            ..Default::default()
+        };
+        assert_eq!(
+            Err(StatusCode::UNAUTHORIZED),
+            all.combine(&identities, None)
+        );
+        let any = Combiner {
+            mode: Mode::Any,
+            ..Default::default()
+        };
+        assert_eq!(Ok(()), any.combine(&identities, None));
+
+        let weighted = Combiner {
+            mode: Mode::Weighted,
+            weights: vec!["client_cert=2".parse().unwrap()],
+            min_weight: 2,
+        };
+        assert_eq!(Ok(()), weighted.combine(&identities, None));
+        assert_eq!(Ok(()), weighted.combine(&identities, Some(3)));
+        assert_eq!(
+            Err(StatusCode::UNAUTHORIZED),
+            weighted.combine(&identities, Some(4))
+        );
+
+        let mut context = Context::default();
+        context.add_variable_from_value("identities", identities.to_value());
+        for expr in [
+            "identities.jwt.present && !identities.jwt.verified",
+            "identities.client_cert.verified && identities.client_cert.name == null",
+            r#"identities.api_key.verified && identities.api_key.name == "billing""#,
+        ] {
+            let program = Program::compile(expr).expect("must compile");
+            assert_eq!(
+                Value::Bool(true),
+                program.execute(&context).expect("must execute"),
+                "{}",
+                expr
+            );
+        }
+
+        assert!("jwt".parse::<super::Weight>().is_err());
+        assert!("password=1".parse::<super::Weight>().is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//! Identity headers returned on allow, like `X-Auth-Request-User`, built
+//! from claims, so proxies can copy them to the upstream (e.g. Traefik's
+//! authResponseHeaders, Nginx's auth_request_set, Caddy's copy_headers).
+use std::{fmt, str::FromStr};
+
+use axum::http::HeaderName;
+use serde_json::Value;
+
+use crate::CustomClaims;
+
+/// A header set to the value of a claim.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdentityHeader {
+    pub name: HeaderName,
+    /// The claim, with nested ones separated by dots, like
+    /// `realm_access.roles`.
+    pub claim: String,
+}
+
+impl FromStr for IdentityHeader {
+    type Err = String;
+
+    /// Parse a mapping like `X-Auth-Request-User=sub`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, claim) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <header>=<claim>, got {}", s))?;
+        let name = HeaderName::try_from(name.trim())
+            .map_err(|e| format!("invalid header name {}: {}", name, e))?;
+        let claim = claim.trim();
+        if claim.is_empty() {
+            return Err(format!("no claim for header {}", name));
+        }
+        Ok(Self {
+            name,
+            claim: claim.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for IdentityHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.claim)
+    }
+}
+
+/// Render a scalar claim value, or None for objects and null.
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        v @ (Value::Number(_) | Value::Bool(_)) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// The identity headers for the claims. Lists (like groups) are joined with
+/// commas, headers for claims that are absent, null or objects are omitted.
+pub fn render(claims: &CustomClaims, headers: &[IdentityHeader]) -> Vec<(HeaderName, String)> {
+    headers
+        .iter()
+        .filter_map(|header| {
+            let mut path = header.claim.split('.');
+            let first = claims.get(path.next()?)?;
+            let value = path.try_fold(first, |value, key| value.get(key))?;
+
+            let rendered = match value {
+                Value::Array(values) => values
+                    .iter()
+                    .filter_map(scalar)
+                    .collect::<Vec<_>>()
+                    .join(","),
+                value => scalar(value)?,
+            };
+            Some((header.name.clone(), rendered))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, IdentityHeader};
+
+    #[test]
+    fn identity_headers() {
+        let headers = [
+            "X-Auth-Request-User=sub",
+            "X-Auth-Request-Email = email",
+            "X-Auth-Request-Groups=groups",
+            "X-Auth-Request-Roles=realm_access.roles",
+            "X-Auth-Request-Verified=email_verified",
+            "X-Auth-Request-Tenant=tenant",
+        ]
+        .map(|s| s.parse::<IdentityHeader>().expect("must parse"));
+
+        let claims = serde_json::json!({
+            "sub": "alice",
+            "email": "alice@example.com",
+            "email_verified": true,
+            "groups": ["admins", "devs"],
+            "realm_access": { "roles": ["reader"] },
+            "tenant": { "id": 1 },
+        });
+        let rendered = render(claims.as_object().unwrap(), &headers)
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("x-auth-request-user".to_string(), "alice".to_string()),
+                ("x-auth-request-email".into(), "alice@example.com".into()),
+                ("x-auth-request-groups".into(), "admins,devs".into()),
+                ("x-auth-request-roles".into(), "reader".into()),
+                ("x-auth-request-verified".into(), "true".into()),
+            ],
+            rendered
+        );
+
+        for invalid in [
+            "X-Auth-Request-User",
+            "X-Auth-Request-User=",
+            "in valid=sub",
+        ] {
+            assert!(invalid.parse::<IdentityHeader>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! OAuth 2.0 Token Introspection (RFC 7662), for IdPs issuing opaque access
+//! tokens.
+use std::fmt;
+
+use jwt_simple::token::Token;
+
+use crate::{jwe, util::HTTP_CLIENT};
+
+#[derive(Debug)]
+pub enum IntrospectionError {
+    Http(reqwest::Error),
+    /// The token is not active (expired, revoked, or never issued).
+    Inactive,
+}
+
+impl fmt::Display for IntrospectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntrospectionError::Http(e) => write!(f, "failed to introspect token: {}", e),
+            IntrospectionError::Inactive => write!(f, "token is not active"),
+        }
+    }
+}
+
+impl std::error::Error for IntrospectionError {}
+
+impl From<reqwest::Error> for IntrospectionError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Http(value)
+    }
+}
+
+/// A client for an introspection endpoint.
+#[derive(Clone)]
+pub struct Introspector {
+    endpoint: String,
+    client_id: String,
+    client_secret: String,
+}
+
+impl fmt::Debug for Introspector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Introspector")
+            .field("endpoint", &self.endpoint)
+            .field("client_id", &self.client_id)
+            .finish()
+    }
+}
+
+/// Returns whether the token is neither a JWS nor a JWE.
+pub fn is_opaque(token: &str) -> bool {
+    !jwe::is_jwe(token) && Token::decode_metadata(token).is_err()
+}
+
+impl Introspector {
+    /// Construct a client for the introspection endpoint, authenticating
+    /// with the given client credentials (client_secret_basic).
+    pub fn new(endpoint: String, client_id: String, client_secret: String) -> Self {
+        Self {
+            endpoint,
+            client_id,
+            client_secret,
+        }
+    }
+
+    /// Introspect the token, returning the introspection response if it is
+    /// active.
+    pub async fn introspect(
+        &self,
+        token: &str,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, IntrospectionError> {
+        let response: serde_json::Map<String, serde_json::Value> = HTTP_CLIENT
+            .post(&self.endpoint)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("token", token), ("token_type_hint", "access_token")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // "active" is the only required member, anything but true means the
+        // token must not be accepted.
+        match response.get("active") {
+            Some(serde_json::Value::Bool(true)) => Ok(response),
+            _ => Err(IntrospectionError::Inactive),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_opaque;
+
+    #[test]
+    fn opaque() {
+        assert!(is_opaque("2YotnFZFEjr1zCsicMWpAA"));
+        assert!(is_opaque("a.b.c"));
+        assert!(!is_opaque(
+            "eyJhbGciOiJFUzI1NiIsImtpZCI6ImZvbyJ9.e30.c2lnbmF0dXJl"
+        ));
+    }
+}
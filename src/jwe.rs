@@ -0,0 +1,357 @@
+//! Decryption of JWE-wrapped tokens (RFC 7516, compact serialization), as
+//! issued by IdPs wrapping their signed access tokens for confidentiality.
+//!
+//! Supported key management algorithms are RSA-OAEP and RSA-OAEP-256,
+//! supported content encryption algorithms A128GCM, A256GCM, A128CBC-HS256
+//! and A256CBC-HS512.
+//!
+//! The content encryption key is unwrapped with aws-lc, whose RSA
+//! decryption is constant-time, as the `rsa` crate is susceptible to timing
+//! side channels (RUSTSEC-2023-0071). As it's done before anything about
+//! the token is authenticated, at most one key is tried per token.
+use std::{fmt, path::Path, sync::Arc};
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes128Gcm, Aes256Gcm, KeyInit,
+};
+use aws_lc_rs::rsa::{
+    OaepPrivateDecryptingKey, PrivateDecryptingKey, OAEP_SHA1_MGF1SHA1, OAEP_SHA256_MGF1SHA256,
+};
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use hmac::Mac;
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder};
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey,
+    pkcs8::{DecodePrivateKey, EncodePrivateKey},
+    RsaPrivateKey,
+};
+
+use crate::key_set::LoadError;
+
+#[derive(Debug)]
+pub struct DecryptError(pub String);
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to decrypt token: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+fn err(msg: impl Into<String>) -> DecryptError {
+    DecryptError(msg.into())
+}
+
+/// A private key used to decrypt tokens.
+#[derive(Clone)]
+pub struct DecryptionKey {
+    kid: Option<String>,
+    key: Arc<OaepPrivateDecryptingKey>,
+}
+
+impl fmt::Debug for DecryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecryptionKey")
+            .field("kid", &self.kid)
+            .finish()
+    }
+}
+
+impl DecryptionKey {
+    pub fn new(kid: Option<String>, key: PrivateDecryptingKey) -> Self {
+        Self {
+            kid,
+            key: Arc::new(OaepPrivateDecryptingKey::new(key).expect("never fails")),
+        }
+    }
+
+    /// Load a PEM-encoded RSA private key (PKCS#8 or PKCS#1), using the
+    /// file name (without extension) as key id.
+    pub fn from_file(path: &Path) -> Result<Self, LoadError> {
+        let pem = std::fs::read_to_string(path)
+            .map_err(|e| LoadError(format!("unable to read {}: {}", path.display(), e)))?;
+        let key = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+            .map_err(|e| e.to_string())
+            .and_then(|key| key.to_pkcs8_der().map_err(|e| e.to_string()))
+            .and_then(|der| {
+                PrivateDecryptingKey::from_pkcs8(der.as_bytes()).map_err(|e| e.to_string())
+            })
+            .map_err(|e| {
+                LoadError(format!(
+                    "{}: invalid RSA private key: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+
+        Ok(Self::new(
+            path.file_stem().map(|s| s.to_string_lossy().into_owned()),
+            key,
+        ))
+    }
+
+    fn unwrap_cek(&self, alg: &str, encrypted_key: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        let algorithm = match alg {
+            "RSA-OAEP" => &OAEP_SHA1_MGF1SHA1,
+            "RSA-OAEP-256" => &OAEP_SHA256_MGF1SHA256,
+            alg => return Err(err(format!("unsupported key management algorithm {}", alg))),
+        };
+        let mut cek = vec![0; self.key.min_output_size()];
+        let len = self
+            .key
+            .decrypt(algorithm, encrypted_key, &mut cek, None)
+            .map_err(|_| err("unable to decrypt content encryption key"))?
+            .len();
+        cek.truncate(len);
+        Ok(cek)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Header {
+    alg: String,
+    enc: String,
+    kid: Option<String>,
+    zip: Option<String>,
+}
+
+/// Returns whether the token is a JWE in compact serialization, which has
+/// five parts, as opposed to the three of a JWS.
+pub fn is_jwe(token: &str) -> bool {
+    token.split('.').count() == 5
+}
+
+fn b64(part: &str) -> Result<Vec<u8>, DecryptError> {
+    Base64UrlSafeNoPadding::decode_to_vec(part, None).map_err(|_| err("invalid base64"))
+}
+
+/// Verify the authentication tag of AES-CBC-HMAC-SHA2 content, which is the
+/// first half of the HMAC output (RFC 7518, section 5.2.2.1).
+fn verify_cbc_tag<M: Mac + hmac::digest::KeyInit>(
+    mac_key: &[u8],
+    aad: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+    tag: &[u8],
+) -> bool {
+    if tag.len() != M::output_size() / 2 {
+        return false;
+    }
+
+    let mut mac = <M as Mac>::new_from_slice(mac_key).expect("HMAC takes keys of any size");
+    mac.update(aad);
+    mac.update(iv);
+    mac.update(ciphertext);
+    mac.update(&(aad.len() as u64 * 8).to_be_bytes());
+    mac.verify_truncated_left(tag).is_ok()
+}
+
+/// Decrypt the content with the given content encryption key.
+fn decrypt_content(
+    enc: &str,
+    cek: &[u8],
+    aad: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+    tag: &[u8],
+) -> Result<Vec<u8>, DecryptError> {
+    match enc {
+        "A128GCM" | "A256GCM" => {
+            if iv.len() != 12 {
+                return Err(err("invalid IV length"));
+            }
+            let msg = [ciphertext, tag].concat();
+            let payload = Payload { msg: &msg, aad };
+            let nonce = aes_gcm::Nonce::from_slice(iv);
+            match enc {
+                "A128GCM" => Aes128Gcm::new_from_slice(cek)
+                    .map_err(|_| err("invalid key length"))?
+                    .decrypt(nonce, payload),
+                _ => Aes256Gcm::new_from_slice(cek)
+                    .map_err(|_| err("invalid key length"))?
+                    .decrypt(nonce, payload),
+            }
+            .map_err(|_| err("authentication failed"))
+        }
+        "A128CBC-HS256" | "A256CBC-HS512" => {
+            // RFC 7518, section 5.2: the first half of the key is the MAC
+            // key, the second half the encryption key.
+            let key_len = if enc == "A128CBC-HS256" { 32 } else { 64 };
+            if cek.len() != key_len {
+                return Err(err("invalid key length"));
+            }
+            let (mac_key, enc_key) = cek.split_at(key_len / 2);
+
+            let verified = if enc == "A128CBC-HS256" {
+                verify_cbc_tag::<hmac::Hmac<sha2::Sha256>>(mac_key, aad, iv, ciphertext, tag)
+            } else {
+                verify_cbc_tag::<hmac::Hmac<sha2::Sha512>>(mac_key, aad, iv, ciphertext, tag)
+            };
+            if !verified {
+                return Err(err("authentication failed"));
+            }
+
+            if enc == "A128CBC-HS256" {
+                cbc::Decryptor::<aes::Aes128>::new_from_slices(enc_key, iv)
+                    .map_err(|_| err("invalid IV length"))?
+                    .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+            } else {
+                cbc::Decryptor::<aes::Aes256>::new_from_slices(enc_key, iv)
+                    .map_err(|_| err("invalid IV length"))?
+                    .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+            }
+            .map_err(|_| err("invalid padding"))
+        }
+        enc => Err(err(format!(
+            "unsupported content encryption algorithm {}",
+            enc
+        ))),
+    }
+}
+
+/// Decrypt a JWE in compact serialization, returning the nested token.
+/// Only the key with the key id of the JWE header is tried. JWEs without
+/// one are only accepted with a single key configured.
+pub fn decrypt(keys: &[DecryptionKey], token: &str) -> Result<String, DecryptError> {
+    let parts = token.split('.').collect::<Vec<_>>();
+    let [header_b64, encrypted_key, iv, ciphertext, tag] = parts[..] else {
+        return Err(err("not a JWE in compact serialization"));
+    };
+
+    let header: Header = serde_json::from_slice(&b64(header_b64)?)
+        .map_err(|e| err(format!("invalid header: {}", e)))?;
+    if header.zip.is_some() {
+        return Err(err("compressed payloads are not supported"));
+    }
+
+    let encrypted_key = b64(encrypted_key)?;
+    let (iv, ciphertext, tag) = (b64(iv)?, b64(ciphertext)?, b64(tag)?);
+
+    let key = match &header.kid {
+        Some(kid) => keys.iter().find(|k| k.kid.as_ref() == Some(kid)),
+        None if keys.len() == 1 => keys.first(),
+        None => return Err(err("no key id, but multiple decryption keys configured")),
+    }
+    .ok_or_else(|| err("no matching decryption key"))?;
+
+    let cek = key.unwrap_cek(&header.alg, &encrypted_key)?;
+    let plaintext = decrypt_content(
+        &header.enc,
+        &cek,
+        header_b64.as_bytes(),
+        &iv,
+        &ciphertext,
+        &tag,
+    )?;
+    String::from_utf8(plaintext).map_err(|_| err("nested token is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{
+        aead::{Aead, OsRng, Payload},
+        Aes256Gcm, KeyInit,
+    };
+    use aws_lc_rs::rsa::{
+        KeySize, OaepPublicEncryptingKey, PrivateDecryptingKey, OAEP_SHA256_MGF1SHA256,
+    };
+    use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Encoder};
+
+    use super::{decrypt, decrypt_content, is_jwe, DecryptionKey};
+
+    fn b64(data: &[u8]) -> String {
+        Base64UrlSafeNoPadding::encode_to_string(data).unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let private_key = PrivateDecryptingKey::generate(KeySize::Rsa2048).expect("must generate");
+        let public_key = OaepPublicEncryptingKey::new(private_key.public_key()).unwrap();
+        let inner = "eyJhbGciOiJub25lIn0.e30.";
+
+        let seal = |header: &[u8]| {
+            let header = b64(header);
+            let cek = Aes256Gcm::generate_key(&mut OsRng);
+            let mut encrypted_key = vec![0; public_key.ciphertext_size()];
+            public_key
+                .encrypt(&OAEP_SHA256_MGF1SHA256, &cek, &mut encrypted_key, None)
+                .expect("must encrypt");
+            let iv = [7u8; 12];
+            let mut sealed = Aes256Gcm::new(&cek)
+                .encrypt(
+                    (&iv).into(),
+                    Payload {
+                        msg: inner.as_bytes(),
+                        aad: header.as_bytes(),
+                    },
+                )
+                .expect("must encrypt");
+            let tag = sealed.split_off(sealed.len() - 16);
+
+            [
+                header,
+                b64(&encrypted_key),
+                b64(&iv),
+                b64(&sealed),
+                b64(&tag),
+            ]
+            .join(".")
+        };
+        let token = seal(br#"{"alg":"RSA-OAEP-256","enc":"A256GCM","cty":"JWT","kid":"k1"}"#);
+        assert!(is_jwe(&token));
+        assert!(!is_jwe(inner));
+
+        let k1 = DecryptionKey::new(Some("k1".to_string()), private_key.clone());
+        let k2 = DecryptionKey::new(Some("k2".to_string()), private_key);
+        assert_eq!(
+            inner,
+            decrypt(&[k2.clone(), k1.clone()], &token).expect("must decrypt")
+        );
+        // other kid
+        assert!(decrypt(std::slice::from_ref(&k2), &token).is_err());
+
+        // without kid, only with a single key
+        let token = seal(br#"{"alg":"RSA-OAEP-256","enc":"A256GCM","cty":"JWT"}"#);
+        assert_eq!(
+            inner,
+            decrypt(std::slice::from_ref(&k2), &token).expect("must decrypt")
+        );
+        assert!(decrypt(&[k1, k2], &token).is_err());
+    }
+
+    #[test]
+    fn cbc_hmac() {
+        use cbc::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+        use hmac::Mac;
+
+        let cek = [1u8; 32];
+        let (mac_key, enc_key) = cek.split_at(16);
+        let (aad, iv) = (b"header", [2u8; 16]);
+
+        let ciphertext = cbc::Encryptor::<aes::Aes128>::new_from_slices(enc_key, &iv)
+            .unwrap()
+            .encrypt_padded_vec_mut::<Pkcs7>(b"nested token");
+        let mut mac = <hmac::Hmac<sha2::Sha256> as Mac>::new_from_slice(mac_key).unwrap();
+        mac.update(aad);
+        mac.update(&iv);
+        mac.update(&ciphertext);
+        mac.update(&(aad.len() as u64 * 8).to_be_bytes());
+        let tag = mac.finalize().into_bytes();
+
+        assert_eq!(
+            b"nested token".to_vec(),
+            decrypt_content("A128CBC-HS256", &cek, aad, &iv, &ciphertext, &tag[..16])
+                .expect("must decrypt")
+        );
+
+        // the full HMAC output is not a valid tag
+        assert!(decrypt_content("A128CBC-HS256", &cek, aad, &iv, &ciphertext, &tag).is_err());
+        // neither is a tampered one
+        let mut bad_tag = tag[..16].to_vec();
+        bad_tag[0] ^= 1;
+        assert!(decrypt_content("A128CBC-HS256", &cek, aad, &iv, &ciphertext, &bad_tag).is_err());
+    }
+}
@@ -0,0 +1,511 @@
+//! Sets of public keys, loaded from JWK(S) documents or PEM files, and
+//! verification of tokens against them.
+use std::{fmt, sync::Arc};
+
+use jwt_simple::{
+    claims::JWTClaims,
+    prelude::*,
+    reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder},
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::debug;
+
+/// A public key usable to verify tokens signed with one specific algorithm.
+#[derive(Clone, Debug)]
+pub enum PublicKey {
+    RS256(RS256PublicKey),
+    RS384(RS384PublicKey),
+    RS512(RS512PublicKey),
+    PS256(PS256PublicKey),
+    PS384(PS384PublicKey),
+    PS512(PS512PublicKey),
+    ES256(ES256PublicKey),
+    ES384(ES384PublicKey),
+    ES256K(ES256kPublicKey),
+    EdDSA(Ed25519PublicKey),
+}
+
+impl PublicKey {
+    /// The JWT algorithm name this key verifies.
+    pub fn alg(&self) -> &'static str {
+        match self {
+            PublicKey::RS256(_) => "RS256",
+            PublicKey::RS384(_) => "RS384",
+            PublicKey::RS512(_) => "RS512",
+            PublicKey::PS256(_) => "PS256",
+            PublicKey::PS384(_) => "PS384",
+            PublicKey::PS512(_) => "PS512",
+            PublicKey::ES256(_) => "ES256",
+            PublicKey::ES384(_) => "ES384",
+            PublicKey::ES256K(_) => "ES256K",
+            PublicKey::EdDSA(_) => "EdDSA",
+        }
+    }
+
+    /// The raw public key material, to tell keys apart.
+    fn to_bytes(&self) -> Vec<u8> {
+        fn rsa(c: jwt_simple::algorithms::RSAPublicKeyComponents) -> Vec<u8> {
+            [c.n, c.e].concat()
+        }
+
+        match self {
+            PublicKey::RS256(k) => rsa(k.to_components()),
+            PublicKey::RS384(k) => rsa(k.to_components()),
+            PublicKey::RS512(k) => rsa(k.to_components()),
+            PublicKey::PS256(k) => rsa(k.to_components()),
+            PublicKey::PS384(k) => rsa(k.to_components()),
+            PublicKey::PS512(k) => rsa(k.to_components()),
+            PublicKey::ES256(k) => k.to_bytes(),
+            PublicKey::ES384(k) => k.to_bytes(),
+            PublicKey::ES256K(k) => k.to_bytes(),
+            PublicKey::EdDSA(k) => k.to_bytes(),
+        }
+    }
+
+    fn verify_token<CustomClaims>(
+        &self,
+        token: &str,
+        options: Option<VerificationOptions>,
+    ) -> Result<JWTClaims<CustomClaims>, jwt_simple::Error>
+    where
+        CustomClaims: Serialize + serde::de::DeserializeOwned,
+    {
+        match self {
+            PublicKey::RS256(k) => k.verify_token(token, options),
+            PublicKey::RS384(k) => k.verify_token(token, options),
+            PublicKey::RS512(k) => k.verify_token(token, options),
+            PublicKey::PS256(k) => k.verify_token(token, options),
+            PublicKey::PS384(k) => k.verify_token(token, options),
+            PublicKey::PS512(k) => k.verify_token(token, options),
+            PublicKey::ES256(k) => k.verify_token(token, options),
+            PublicKey::ES384(k) => k.verify_token(token, options),
+            PublicKey::ES256K(k) => k.verify_token(token, options),
+            PublicKey::EdDSA(k) => k.verify_token(token, options),
+        }
+    }
+}
+
+/// A public key, with an optional key id.
+#[derive(Clone, Debug)]
+pub struct Key {
+    pub kid: Option<String>,
+    pub key: PublicKey,
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.kid == other.kid
+            && self.key.alg() == other.key.alg()
+            && self.key.to_bytes() == other.key.to_bytes()
+    }
+}
+
+/// An error while loading keys.
+#[derive(Debug)]
+pub struct LoadError(pub String);
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+#[derive(Debug)]
+pub enum KeySetError {
+    /// There's no key for the key id and algorithm of the token.
+    NoMatchingKey { kid: Option<String>, alg: String },
+    /// The token failed verification.
+    Invalid(jwt_simple::Error),
+}
+
+impl fmt::Display for KeySetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySetError::NoMatchingKey { kid, alg } => {
+                write!(f, "no key found for kid {:?}, alg {}", kid, alg)
+            }
+            KeySetError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// A JSON Web Key, as described in RFC 7517, restricted to the fields needed
+/// for signature verification.
+#[derive(serde::Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    use_: Option<String>,
+    // RSA
+    n: Option<String>,
+    e: Option<String>,
+    // EC / OKP
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum JwkDocument {
+    Set { keys: Vec<serde_json::Value> },
+    Single(serde_json::Value),
+}
+
+fn b64(field: &Option<String>, name: &str) -> Result<Vec<u8>, LoadError> {
+    let v = field
+        .as_deref()
+        .ok_or_else(|| LoadError(format!("missing field {}", name)))?;
+    Base64UrlSafeNoPadding::decode_to_vec(v, None)
+        .map_err(|e| LoadError(format!("invalid base64 in field {}: {}", name, e)))
+}
+
+fn load_err(e: jwt_simple::Error) -> LoadError {
+    LoadError(e.to_string())
+}
+
+/// Construct the RSA public keys for the given algorithms from a modulus and
+/// exponent.
+fn rsa_keys(algs: &[&str], n: &[u8], e: &[u8]) -> Result<Vec<PublicKey>, LoadError> {
+    algs.iter()
+        .map(|alg| {
+            Ok(match *alg {
+                "RS256" => {
+                    PublicKey::RS256(RS256PublicKey::from_components(n, e).map_err(load_err)?)
+                }
+                "RS384" => {
+                    PublicKey::RS384(RS384PublicKey::from_components(n, e).map_err(load_err)?)
+                }
+                "RS512" => {
+                    PublicKey::RS512(RS512PublicKey::from_components(n, e).map_err(load_err)?)
+                }
+                "PS256" => {
+                    PublicKey::PS256(PS256PublicKey::from_components(n, e).map_err(load_err)?)
+                }
+                "PS384" => {
+                    PublicKey::PS384(PS384PublicKey::from_components(n, e).map_err(load_err)?)
+                }
+                "PS512" => {
+                    PublicKey::PS512(PS512PublicKey::from_components(n, e).map_err(load_err)?)
+                }
+                alg => return Err(LoadError(format!("unsupported RSA algorithm {}", alg))),
+            })
+        })
+        .collect()
+}
+
+const RSA_ALGS: &[&str] = &["RS256", "RS384", "RS512", "PS256", "PS384", "PS512"];
+
+impl Jwk {
+    fn into_keys(self) -> Result<Vec<Key>, LoadError> {
+        let keys = match self.kty.as_str() {
+            "RSA" => {
+                let (n, e) = (b64(&self.n, "n")?, b64(&self.e, "e")?);
+                match self.alg.as_deref() {
+                    Some(alg) => rsa_keys(&[alg], &n, &e)?,
+                    // no algorithm specified, the key can be used with all of them
+                    None => rsa_keys(RSA_ALGS, &n, &e)?,
+                }
+            }
+            "EC" => {
+                // uncompressed SEC1 point encoding
+                let mut point = vec![0x04];
+                point.extend(b64(&self.x, "x")?);
+                point.extend(b64(&self.y, "y")?);
+
+                vec![match self.crv.as_deref() {
+                    Some("P-256") => {
+                        PublicKey::ES256(ES256PublicKey::from_bytes(&point).map_err(load_err)?)
+                    }
+                    Some("P-384") => {
+                        PublicKey::ES384(ES384PublicKey::from_bytes(&point).map_err(load_err)?)
+                    }
+                    Some("secp256k1") => {
+                        PublicKey::ES256K(ES256kPublicKey::from_bytes(&point).map_err(load_err)?)
+                    }
+                    crv => return Err(LoadError(format!("unsupported EC curve {:?}", crv))),
+                }]
+            }
+            "OKP" => match self.crv.as_deref() {
+                Some("Ed25519") => vec![PublicKey::EdDSA(
+                    Ed25519PublicKey::from_bytes(&b64(&self.x, "x")?).map_err(load_err)?,
+                )],
+                crv => return Err(LoadError(format!("unsupported OKP curve {:?}", crv))),
+            },
+            kty => return Err(LoadError(format!("unsupported key type {}", kty))),
+        };
+
+        Ok(keys
+            .into_iter()
+            .map(|key| Key {
+                kid: self.kid.clone(),
+                key,
+            })
+            .collect())
+    }
+}
+
+/// A set of public keys.
+#[derive(Clone, Debug, Default)]
+pub struct KeySet {
+    keys: Vec<Key>,
+}
+
+impl KeySet {
+    /// Parse a JWKS document, or a single JWK.
+    /// Keys that can't be used for signature verification are skipped.
+    pub fn from_jwk_json(json: &[u8]) -> Result<Self, LoadError> {
+        let jwks = match serde_json::from_slice::<JwkDocument>(json)
+            .map_err(|e| LoadError(format!("invalid JWK document: {}", e)))?
+        {
+            JwkDocument::Set { keys } => keys,
+            JwkDocument::Single(key) => vec![key],
+        };
+
+        let mut keys = Vec::new();
+        for jwk in jwks {
+            let jwk = serde_json::from_value::<Jwk>(jwk)
+                .map_err(|e| LoadError(format!("invalid JWK: {}", e)))?;
+
+            if jwk.use_.as_deref().is_some_and(|u| u != "sig") {
+                debug!(kid = ?jwk.kid, "skipping non-signing key");
+                continue;
+            }
+
+            let kid = jwk.kid.clone();
+            match jwk.into_keys() {
+                Ok(k) => keys.extend(k),
+                Err(e) => debug!(?kid, err=%e, "skipping unsupported key"),
+            }
+        }
+
+        Ok(Self { keys })
+    }
+
+    /// Parse a PEM-encoded public key (RSA, P-256, P-384, secp256k1 or Ed25519).
+    pub fn from_pem(pem: &str, kid: Option<String>) -> Result<Self, LoadError> {
+        let keys = if let Ok(k) = RS256PublicKey::from_pem(pem) {
+            let components = k.to_components();
+            rsa_keys(RSA_ALGS, &components.n, &components.e)?
+        } else if let Ok(k) = ES256PublicKey::from_pem(pem) {
+            vec![PublicKey::ES256(k)]
+        } else if let Ok(k) = ES384PublicKey::from_pem(pem) {
+            vec![PublicKey::ES384(k)]
+        } else if let Ok(k) = ES256kPublicKey::from_pem(pem) {
+            vec![PublicKey::ES256K(k)]
+        } else if let Ok(k) = Ed25519PublicKey::from_pem(pem) {
+            vec![PublicKey::EdDSA(k)]
+        } else {
+            return Err(LoadError(
+                "unsupported or invalid PEM public key".to_string(),
+            ));
+        };
+
+        Ok(Self {
+            keys: keys
+                .into_iter()
+                .map(|key| Key {
+                    kid: kid.clone(),
+                    key,
+                })
+                .collect(),
+        })
+    }
+
+    pub fn from_keys(keys: Vec<Key>) -> Self {
+        Self { keys }
+    }
+
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn extend(&mut self, other: KeySet) {
+        self.keys.extend(other.keys)
+    }
+
+    /// Verify the token against the keys matching its algorithm and key id.
+    /// Keys without a key id match tokens with any key id.
+    /// If multiple keys match, which happens for tokens without key id, they
+    /// are tried in parallel on the blocking thread pool, see
+    /// [verify_parallel].
+    pub async fn verify<CustomClaims>(
+        &self,
+        token: &str,
+        options: Option<VerificationOptions>,
+    ) -> Result<JWTClaims<CustomClaims>, KeySetError>
+    where
+        CustomClaims: Serialize + serde::de::DeserializeOwned + Send + 'static,
+    {
+        let metadata = Token::decode_metadata(token).map_err(KeySetError::Invalid)?;
+        let (alg, kid) = (metadata.algorithm(), metadata.key_id());
+
+        let candidates = self
+            .keys
+            .iter()
+            .filter(|k| {
+                k.key.alg() == alg
+                    && match (kid, &k.kid) {
+                        (Some(kid), Some(key_kid)) => kid == key_kid,
+                        _ => true,
+                    }
+            })
+            .collect::<Vec<_>>();
+
+        let result = match candidates.len() {
+            0 => {
+                return Err(KeySetError::NoMatchingKey {
+                    kid: kid.map(str::to_owned),
+                    alg: alg.to_owned(),
+                })
+            }
+            1 => candidates[0].key.verify_token(token, options),
+            _ => verify_parallel(candidates.into_iter().cloned(), token, options).await,
+        };
+        result.map_err(KeySetError::Invalid)
+    }
+}
+
+/// The maximum number of verifications against multiple candidate keys
+/// running at once, across all requests.
+pub const MAX_PARALLEL_VERIFICATIONS: usize = 4;
+
+static PARALLEL_VERIFICATIONS: Semaphore = Semaphore::const_new(MAX_PARALLEL_VERIFICATIONS);
+
+/// Verify the token against all candidate keys, in parallel, on the blocking
+/// thread pool. Attempts wait for one of the [MAX_PARALLEL_VERIFICATIONS]
+/// permits shared by all requests, so tokens without key id can't occupy
+/// more threads than that. Returns the claims of the first successful
+/// verification, without starting further attempts, or the last error.
+async fn verify_parallel<CustomClaims>(
+    candidates: impl Iterator<Item = Key>,
+    token: &str,
+    options: Option<VerificationOptions>,
+) -> Result<JWTClaims<CustomClaims>, jwt_simple::Error>
+where
+    CustomClaims: Serialize + serde::de::DeserializeOwned + Send + 'static,
+{
+    let token: Arc<str> = token.into();
+    let mut candidates = candidates.peekable();
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+    loop {
+        tokio::select! {
+            permit = PARALLEL_VERIFICATIONS.acquire(), if candidates.peek().is_some() => {
+                let permit = permit.expect("never closed");
+                let key = candidates.next().expect("must have a candidate left");
+                let (token, options) = (token.clone(), options.clone());
+                attempts.spawn_blocking(move || {
+                    let _permit = permit;
+                    key.key.verify_token(&token, options)
+                });
+            }
+            Some(attempt) = attempts.join_next() => {
+                match attempt.map_err(|e| jwt_simple::Error::msg(e.to_string())) {
+                    Ok(Ok(claims)) => return Ok(claims),
+                    Ok(Err(e)) | Err(e) => last_err = Some(e),
+                }
+            }
+            else => break,
+        }
+    }
+
+    Err(last_err.expect("must have attempted at least one key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use jwt_simple::prelude::*;
+
+    use super::{Key, KeySet, KeySetError, PublicKey};
+
+    #[tokio::test]
+    async fn jwks_roundtrip() {
+        let key_pair = ES256KeyPair::generate().with_key_id("k1");
+        let pk = key_pair.public_key();
+        let point = pk.public_key().to_bytes_uncompressed();
+        let jwks = serde_json::json!({
+            "keys": [
+                {"kty": "EC", "crv": "P-256", "kid": "k1", "use": "sig",
+                 "x": Base64UrlSafeNoPadding::encode_to_string(&point[1..33]).unwrap(),
+                 "y": Base64UrlSafeNoPadding::encode_to_string(&point[33..]).unwrap()},
+                {"kty": "oct", "kid": "ignored", "k": "AAAA"},
+                {"kty": "RSA", "kid": "enc", "use": "enc", "n": "AQAB", "e": "AQAB"}
+            ]
+        });
+        let key_set = KeySet::from_jwk_json(jwks.to_string().as_bytes()).expect("must parse");
+        assert_eq!(1, key_set.keys().len());
+
+        let token = key_pair
+            .sign(Claims::create(Duration::from_mins(5)).with_subject("alice"))
+            .expect("must sign");
+        let claims = key_set
+            .verify::<NoCustomClaims>(&token, None)
+            .await
+            .expect("must verify");
+        assert_eq!(Some("alice".to_string()), claims.subject);
+
+        // a token with another kid doesn't match
+        let other = ES256KeyPair::generate().with_key_id("k2");
+        let token = other
+            .sign(Claims::create(Duration::from_mins(5)))
+            .expect("must sign");
+        assert!(matches!(
+            key_set.verify::<NoCustomClaims>(&token, None).await,
+            Err(KeySetError::NoMatchingKey { .. })
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn without_kid() {
+        let key_pairs = (0..6).map(|_| ES256KeyPair::generate()).collect::<Vec<_>>();
+        let key_set = KeySet::from_keys(
+            key_pairs
+                .iter()
+                .map(|k| Key {
+                    kid: None,
+                    key: PublicKey::ES256(k.public_key()),
+                })
+                .collect(),
+        );
+
+        // tried against all keys, no matter the position of the right one
+        for key_pair in [&key_pairs[0], &key_pairs[5]] {
+            let token = key_pair
+                .sign(Claims::create(Duration::from_mins(5)).with_subject("alice"))
+                .expect("must sign");
+            let claims = key_set
+                .verify::<NoCustomClaims>(&token, None)
+                .await
+                .expect("must verify");
+            assert_eq!(Some("alice".to_string()), claims.subject);
+        }
+
+        let token = ES256KeyPair::generate()
+            .sign(Claims::create(Duration::from_mins(5)))
+            .expect("must sign");
+        assert!(matches!(
+            key_set.verify::<NoCustomClaims>(&token, None).await,
+            Err(KeySetError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn pem() {
+        let key_pair = Ed25519KeyPair::generate();
+        let key_set = KeySet::from_pem(&key_pair.public_key().to_pem(), None).expect("must parse");
+
+        let token = key_pair
+            .sign(Claims::create(Duration::from_mins(5)))
+            .expect("must sign");
+        assert!(key_set.verify::<NoCustomClaims>(&token, None).await.is_ok());
+    }
+}
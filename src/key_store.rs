@@ -1,78 +1,1128 @@
-use jwt_simple::common::VerificationOptions;
-use std::{sync::Arc, time::Duration};
-use tokio::sync::RwLock;
-use tracing::warn;
+use jwt_simple::{
+    common::VerificationOptions,
+    reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder},
+};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::Mutex;
+use tokio_retry::Retry;
+use tracing::{debug, info, warn};
 
+use crate::{
+    clock,
+    hmac_keys::HmacSource,
+    jwe::{self, DecryptError, DecryptionKey},
+    key_set::{Key, KeySet, KeySetError, LoadError},
+    metrics::METRICS,
+    oidc::{self, DiscoveryError},
+    static_keys::StaticSource,
+    util::HTTP_CLIENT,
+};
+
+/// A set of key sources, tokens are routed to based on their issuer.
 #[derive(Clone)]
 pub struct KeyStore {
-    inner: Arc<RwLock<jwt_simple_jwks::KeyStore>>,
+    sources: Arc<Vec<KeySource>>,
+    /// If set, only tokens signed with one of these algorithms are accepted.
+    allowed_algs: Option<Arc<Vec<String>>>,
+    /// Keys to decrypt JWE-wrapped tokens with.
+    decryption_keys: Arc<Vec<DecryptionKey>>,
+    refresh: RefreshOptions,
+}
+
+/// How failed refreshes are retried, before giving up until the next check.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryStrategy {
+    /// How often to retry, 0 to not retry at all.
+    pub retries: u32,
+    /// The delay before the first retry.
+    pub initial_delay: Duration,
+    /// By how much the delay grows with every further retry.
+    pub factor: u32,
+}
+
+impl Default for RetryStrategy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            initial_delay: Duration::from_millis(10),
+            factor: 10,
+        }
+    }
+}
+
+impl RetryStrategy {
+    /// The delays before each retry, with jitter applied.
+    pub(crate) fn delays(self) -> impl Iterator<Item = Duration> {
+        (0..self.retries)
+            .map(move |i| self.initial_delay * self.factor.saturating_pow(i))
+            .map(tokio_retry::strategy::jitter)
+    }
+}
+
+/// When and how the JWKS sources of a [KeyStore] are refreshed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RefreshOptions {
+    interval: Duration,
+    retry: RetryStrategy,
+    max_jwks_validity: Duration,
+    /// The minimum interval between refreshes triggered by tokens with
+    /// unknown keys, if these trigger refreshes at all.
+    on_demand_interval: Option<Duration>,
+}
+
+impl Default for RefreshOptions {
+    fn default() -> Self {
+        Self {
+            interval: REFRESH_CHECK_INTERVAL,
+            retry: RetryStrategy::default(),
+            max_jwks_validity: MAX_JWKS_VALIDITY,
+            on_demand_interval: Some(ON_DEMAND_REFRESH_INTERVAL),
+        }
+    }
+}
+
+/// A source of keys.
+#[derive(Clone)]
+pub enum KeySource {
+    /// Keys fetched from a JWKS endpoint.
+    Jwks(JwksSource),
+    /// Keys loaded from local files.
+    Static(StaticSource),
+    /// Shared secrets, for HS* signed tokens.
+    Hmac(HmacSource),
+}
+
+/// A key of a source, as listed by [KeySource::describe], without the key
+/// material.
+#[derive(Debug, serde::Serialize)]
+pub struct KeyInfo {
+    pub kid: Option<String>,
+    pub alg: &'static str,
+    /// Whether the key disappeared from the source, and is only kept for the
+    /// grace period.
+    pub retired: bool,
+}
+
+/// Description of a key source and the keys it currently holds.
+#[derive(Debug, serde::Serialize)]
+pub struct SourceInfo {
+    /// jwks, static or hmac.
+    pub kind: &'static str,
+    pub issuer: Option<String>,
+    /// The JWKS endpoint or files the keys are loaded from.
+    pub locations: Vec<String>,
+    pub keys: Vec<KeyInfo>,
 }
 
-/// fallback maximum validity duration, in case there's no validity signalled in the HTTP header
+/// A single JWKS endpoint, with its own refresh schedule.
+#[derive(Clone)]
+pub struct JwksSource {
+    /// The issuer this source is responsible for.
+    /// If None, it is used for tokens of any issuer not explicitly routed.
+    issuer: Option<String>,
+    /// For sources configured via OIDC discovery, the last time the discovery
+    /// document was fetched.
+    discovered_at: Option<Arc<parking_lot::Mutex<Instant>>>,
+    state: Arc<parking_lot::RwLock<JwksState>>,
+    /// How long to keep keys that disappeared from the endpoint.
+    grace_period: Duration,
+    /// Serializes refreshes.
+    refresh_lock: Arc<Mutex<()>>,
+    /// The last time keys were refreshed because of an unknown key.
+    last_on_demand_refresh: Arc<parking_lot::Mutex<Option<Instant>>>,
+    /// The last time a refresh was attempted, successful or not.
+    last_refresh_attempt: Arc<parking_lot::Mutex<Option<Instant>>>,
+    refresh: RefreshOptions,
+}
+
+/// The keys last fetched from a JWKS endpoint.
+struct JwksState {
+    url: String,
+    keys: Arc<KeySet>,
+    /// Keys that disappeared from the endpoint, and when they were first
+    /// missing.
+    retired: Vec<(Key, SystemTime)>,
+    loaded_at: SystemTime,
+    /// How long the keys may be used for, after they were loaded, if
+    /// signalled by the endpoint.
+    max_age: Option<Duration>,
+}
+
+/// Default fallback maximum validity duration, in case there's no validity
+/// signalled in the HTTP header, see [KeyStore::with_max_jwks_validity].
 pub const MAX_JWKS_VALIDITY: std::time::Duration = std::time::Duration::from_secs(5 * 60);
 
+/// Fraction of the validity of keys after which they should be refreshed.
+const REFRESH_FRACTION: f64 = 0.5;
+
+/// How often sources are checked for whether they're due for a refresh,
+/// which is when failed refreshes are retried, by default.
+pub const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The shortest Retry-After sent while keys are expired.
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Default minimum interval between refreshes triggered by tokens with
+/// unknown keys.
+pub const ON_DEMAND_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often to re-fetch the discovery document of sources configured via
+/// OIDC discovery, in case the IdP moved its JWKS endpoint.
+pub const DISCOVERY_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Incremented on every successful refresh of any key source, so decisions
+/// can be correlated with the key rotation they happened after.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The current key set generation.
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+/// Start a new key set generation, after keys were (re)loaded.
+pub(crate) fn next_generation() -> u64 {
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    METRICS
+        .key_set_generation
+        .with_labels(&[])
+        .set(generation as f64);
+    generation
+}
+
+/// Errors that can occur while (re)loading a [KeySource].
+#[derive(Debug)]
+pub enum SourceError {
+    Discovery(DiscoveryError),
+    Http(reqwest::Error),
+    Keys(LoadError),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Discovery(e) => write!(f, "{}", e),
+            SourceError::Http(e) => write!(f, "failed to fetch JWKS: {}", e),
+            SourceError::Keys(e) => write!(f, "failed to load keys: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<DiscoveryError> for SourceError {
+    fn from(value: DiscoveryError) -> Self {
+        Self::Discovery(value)
+    }
+}
+
+impl From<LoadError> for SourceError {
+    fn from(value: LoadError) -> Self {
+        Self::Keys(value)
+    }
+}
+
+impl From<reqwest::Error> for SourceError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Http(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    /// There's no source configured for the issuer of the token.
+    UnknownIssuer(Option<String>),
+    /// The keys of the responsible source expired before they could be
+    /// refreshed, with how long until the next refresh attempt.
+    KeysExpired(Duration),
+    /// The token carries its own key material (or a reference to it) in the
+    /// JOSE header, with the name of the offending header parameter.
+    EmbeddedKey(&'static str),
+    /// The token is encrypted, and couldn't be decrypted.
+    Decryption(DecryptError),
+    /// The token is signed with an algorithm that's not allowed.
+    DisallowedAlgorithm(String),
+    /// The token failed verification.
+    Invalid(String),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::UnknownIssuer(Some(iss)) => write!(f, "no keys for issuer {}", iss),
+            VerifyError::UnknownIssuer(None) => write!(f, "no keys for tokens without issuer"),
+            VerifyError::KeysExpired(_) => write!(f, "keys expired"),
+            VerifyError::EmbeddedKey(param) => {
+                write!(f, "token header contains embedded key material ({})", param)
+            }
+            VerifyError::Decryption(e) => write!(f, "{}", e),
+            VerifyError::DisallowedAlgorithm(alg) => write!(f, "algorithm {:?} not allowed", alg),
+            VerifyError::Invalid(msg) => write!(f, "invalid token: {}", msg),
+        }
+    }
+}
+
 impl KeyStore {
-    pub async fn new_from(jwks_url: String) -> Result<Self, jwt_simple_jwks::Error> {
-        let key_store = jwt_simple_jwks::KeyStore::new_from(jwks_url).await?;
+    pub fn new(sources: Vec<KeySource>) -> Self {
+        Self {
+            sources: Arc::new(sources),
+            allowed_algs: None,
+            decryption_keys: Default::default(),
+            refresh: RefreshOptions::default(),
+        }
+    }
 
-        Ok(Self {
-            inner: Arc::new(RwLock::new(key_store)),
-        })
+    /// Apply changed refresh options to the store and its sources.
+    fn with_refresh(mut self, f: impl FnOnce(&mut RefreshOptions)) -> Self {
+        f(&mut self.refresh);
+        let refresh = self.refresh;
+        self.sources = Arc::new(
+            self.sources
+                .iter()
+                .map(|source| match source {
+                    KeySource::Jwks(s) => KeySource::Jwks(JwksSource {
+                        refresh,
+                        ..s.clone()
+                    }),
+                    source => source.clone(),
+                })
+                .collect(),
+        );
+        self
     }
 
-    /// Determine if the KeyStore should be refreshed.
-    pub async fn should_refresh(&self) -> bool {
-        let inner = self.inner.read().await;
-        let now = std::time::SystemTime::now();
-
-        if let Some(last_load_time) = inner.last_load_time() {
-            // check should_refresh(), which is deduced from the cache-control headers, if present.
-            inner.should_refresh_time(now).unwrap_or_else(|| {
-                // no header detected, refresh if too old
-                now > last_load_time
-                    + Duration::from_secs(
-                        (MAX_JWKS_VALIDITY.as_secs() as f64 * inner.refresh_interval()) as u64,
-                    )
-            })
-        } else {
-            // refresh for the first time
-            true
+    /// Check sources for whether they're due for a refresh at this interval,
+    /// instead of [REFRESH_CHECK_INTERVAL]. This is also when failed
+    /// refreshes are tried again.
+    pub fn with_refresh_interval(self, interval: Duration) -> Self {
+        self.with_refresh(|r| r.interval = interval)
+    }
+
+    /// Retry failed refreshes with this strategy, instead of three times,
+    /// after 10ms, 100ms and 1s.
+    pub fn with_refresh_retry(self, retry: RetryStrategy) -> Self {
+        self.with_refresh(|r| r.retry = retry)
+    }
+
+    /// Consider keys of JWKS endpoints not signalling how long they may be
+    /// cached for valid for this long, instead of [MAX_JWKS_VALIDITY].
+    pub fn with_max_jwks_validity(self, max_jwks_validity: Duration) -> Self {
+        self.with_refresh(|r| r.max_jwks_validity = max_jwks_validity)
+    }
+
+    /// Refresh JWKS sources when seeing tokens signed with unknown keys at
+    /// most once per the given interval, instead of
+    /// [ON_DEMAND_REFRESH_INTERVAL], or never, if None.
+    pub fn with_on_demand_refresh(self, interval: Option<Duration>) -> Self {
+        self.with_refresh(|r| r.on_demand_interval = interval)
+    }
+
+    /// How often sources should be checked with [KeyStore::refresh_due].
+    pub fn refresh_interval(&self) -> Duration {
+        self.refresh.interval
+    }
+
+    /// Refresh all sources due for it in the background, retrying failed
+    /// refreshes according to the retry strategy.
+    pub async fn refresh_due(&self) {
+        for source in self.sources.iter() {
+            if source.should_refresh().await {
+                let retry = self.refresh.retry;
+                let source = source.clone();
+                tokio::spawn(async move {
+                    let action = || source.refresh();
+                    if let Err(e) = Retry::spawn(retry.delays(), action).await {
+                        warn!(issuer = ?source.issuer(), err = %e, "failed to refresh keys");
+                    }
+                });
+            }
         }
     }
 
-    /// Refresh the KeyStore. Callers should use [should_refresh] first.
-    pub async fn refresh(&self) -> Result<(), jwt_simple_jwks::Error> {
-        let mut inner = self.inner.write().await;
-        inner.load_keys().await
+    /// Accept JWE-wrapped tokens, decrypting them with the given keys and
+    /// verifying the nested token.
+    pub fn with_decryption_keys(mut self, decryption_keys: Vec<DecryptionKey>) -> Self {
+        self.decryption_keys = Arc::new(decryption_keys);
+        self
+    }
+
+    /// Only accept tokens signed with one of the given algorithms.
+    pub fn with_allowed_algs(mut self, allowed_algs: Vec<String>) -> Self {
+        self.allowed_algs = Some(Arc::new(allowed_algs));
+        self
+    }
+
+    /// All sources of this KeyStore.
+    pub fn sources(&self) -> &[KeySource] {
+        &self.sources
     }
 
-    /// Return if keys are still considered values
+    /// Return if keys of all sources are still considered valid.
     pub async fn still_valid(&self) -> bool {
-        let inner = self.inner.read().await;
-        let now = std::time::SystemTime::now();
-
-        if let Some(last_load_time) = inner.last_load_time() {
-            warn!("last load time: {:?}", last_load_time);
-            !inner
-                .keys_expired()
-                .unwrap_or_else(|| now > last_load_time + MAX_JWKS_VALIDITY)
-        } else {
-            warn!("no last load time");
-            false // nothing loaded yet
+        for source in self.sources.iter() {
+            if !source.still_valid().await {
+                return false;
+            }
         }
+        true
+    }
+
+    /// Returns the sources responsible for the given issuer.
+    /// Sources explicitly configured for the issuer take precedence, otherwise
+    /// all issuer-less sources are returned.
+    fn sources_for(&self, iss: Option<&str>) -> Vec<&KeySource> {
+        let explicit = self
+            .sources
+            .iter()
+            .filter(|s| s.issuer().is_some() && s.issuer() == iss)
+            .collect::<Vec<_>>();
+        if !explicit.is_empty() {
+            return explicit;
+        }
+
+        self.sources
+            .iter()
+            .filter(|s| s.issuer().is_none())
+            .collect()
     }
 
     /// Verify the JWT at [token] to be valid, with optional additional [VerificationOptions].
+    /// If [allowed_algs] is set, the token needs to be signed with one of
+    /// these algorithms, in addition to the ones allowed by the KeyStore.
+    /// The token is routed to the source(s) responsible for its (unverified) issuer.
     /// If valid, return the claims, with the type parameter allowing to parse custom claims.
-    /// Ensure to run [should_refresh] and [refresh] before running this.
     pub async fn verify<CustomClaims>(
         &self,
         token: &str,
         verification_options: Option<VerificationOptions>,
-    ) -> Result<jwt_simple::claims::JWTClaims<CustomClaims>, jwt_simple_jwks::Error>
+        allowed_algs: Option<&[String]>,
+    ) -> Result<jwt_simple::claims::JWTClaims<CustomClaims>, VerifyError>
     where
-        CustomClaims: serde::Serialize + serde::de::DeserializeOwned,
+        CustomClaims: serde::Serialize + serde::de::DeserializeOwned + Send + 'static,
     {
-        self.inner.read().await.verify(token, verification_options)
+        let decrypted;
+        let token = if jwe::is_jwe(token) {
+            if self.decryption_keys.is_empty() {
+                return Err(VerifyError::Decryption(DecryptError(
+                    "encrypted token, but no decryption keys configured".to_string(),
+                )));
+            }
+            // RSA decryption takes long enough to stall other requests.
+            let (keys, jwe) = (self.decryption_keys.clone(), token.to_owned());
+            decrypted = tokio::task::spawn_blocking(move || jwe::decrypt(&keys, &jwe))
+                .await
+                .map_err(|e| VerifyError::Decryption(DecryptError(e.to_string())))?
+                .map_err(VerifyError::Decryption)?;
+            &decrypted
+        } else {
+            token
+        };
+
+        let header = unverified_header(token).unwrap_or_default();
+
+        // Never trust keys supplied by the token itself, no matter what the
+        // underlying libraries do with them.
+        if let Some(param) = embedded_key_param(&header) {
+            METRICS.embedded_key_rejections.with_labels(&[param]).inc();
+            return Err(VerifyError::EmbeddedKey(param));
+        }
+
+        let alg = header.get("alg").and_then(|v| v.as_str()).unwrap_or("");
+        for allowed in [
+            self.allowed_algs.as_deref().map(Vec::as_slice),
+            allowed_algs,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !allowed.iter().any(|a| a == alg) {
+                return Err(VerifyError::DisallowedAlgorithm(alg.to_owned()));
+            }
+        }
+
+        let iss = unverified_issuer(token);
+        let sources = self.sources_for(iss.as_deref());
+        if sources.is_empty() {
+            return Err(VerifyError::UnknownIssuer(iss));
+        }
+
+        // try all candidate sources, returning the first success, or the last error.
+        let mut last_err = VerifyError::UnknownIssuer(iss);
+        for source in sources {
+            // We already automatically refresh at regular intervals, which
+            // should happen well before expiry, so if we're in a state where
+            // all keys of a source expired, don't use them.
+            if !source.still_valid().await {
+                warn!(issuer = ?source.issuer(), "keys expired before we could refresh them");
+                last_err = VerifyError::KeysExpired(source.retry_after());
+                continue;
+            }
+
+            match source.verify(token, verification_options.clone()).await {
+                Ok(claims) => return Ok(claims),
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
+}
+
+impl KeySource {
+    /// The issuer this source is responsible for, if any.
+    pub fn issuer(&self) -> Option<&str> {
+        match self {
+            KeySource::Jwks(s) => s.issuer(),
+            KeySource::Static(s) => s.issuer(),
+            KeySource::Hmac(s) => s.issuer(),
+        }
+    }
+
+    /// Determine if the source should be refreshed.
+    /// Static sources are never refreshed periodically.
+    pub async fn should_refresh(&self) -> bool {
+        match self {
+            KeySource::Jwks(s) => s.should_refresh().await,
+            KeySource::Static(_) | KeySource::Hmac(_) => false,
+        }
+    }
+
+    /// Refresh the source. Callers should use [should_refresh] first.
+    pub async fn refresh(&self) -> Result<(), SourceError> {
+        match self {
+            KeySource::Jwks(s) => s.refresh().await,
+            KeySource::Static(s) => Ok(s.reload()?),
+            KeySource::Hmac(_) => Ok(()),
+        }
+    }
+
+    /// Describe the source and its current keys.
+    pub fn describe(&self) -> SourceInfo {
+        let key_info = |key: &Key, retired| KeyInfo {
+            kid: key.kid.clone(),
+            alg: key.key.alg(),
+            retired,
+        };
+        let (kind, locations, keys) = match self {
+            KeySource::Jwks(s) => {
+                let state = s.state.read();
+                let keys = state
+                    .keys
+                    .keys()
+                    .iter()
+                    .map(|k| key_info(k, false))
+                    .chain(
+                        state
+                            .retired_keys(s.grace_period)
+                            .keys()
+                            .iter()
+                            .map(|k| key_info(k, true)),
+                    )
+                    .collect();
+                ("jwks", vec![state.url.clone()], keys)
+            }
+            KeySource::Static(s) => (
+                "static",
+                s.paths().iter().map(|p| p.display().to_string()).collect(),
+                s.key_set()
+                    .keys()
+                    .iter()
+                    .map(|k| key_info(k, false))
+                    .collect(),
+            ),
+            KeySource::Hmac(s) => (
+                "hmac",
+                vec![],
+                s.key_ids()
+                    .into_iter()
+                    .map(|kid| KeyInfo {
+                        kid,
+                        alg: "HS256/HS384/HS512",
+                        retired: false,
+                    })
+                    .collect(),
+            ),
+        };
+
+        SourceInfo {
+            kind,
+            issuer: self.issuer().map(str::to_owned),
+            locations,
+            keys,
+        }
+    }
+
+    /// Return if keys are still considered valid.
+    pub async fn still_valid(&self) -> bool {
+        match self {
+            KeySource::Jwks(s) => s.still_valid().await,
+            KeySource::Static(_) | KeySource::Hmac(_) => true,
+        }
+    }
+
+    /// How long until keys can be expected to be refreshed, for clients to
+    /// retry after while they're expired.
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            KeySource::Jwks(s) => s.retry_after(s.refresh.interval),
+            KeySource::Static(_) | KeySource::Hmac(_) => REFRESH_CHECK_INTERVAL,
+        }
+    }
+
+    async fn verify<CustomClaims>(
+        &self,
+        token: &str,
+        verification_options: Option<VerificationOptions>,
+    ) -> Result<jwt_simple::claims::JWTClaims<CustomClaims>, VerifyError>
+    where
+        CustomClaims: serde::Serialize + serde::de::DeserializeOwned + Send + 'static,
+    {
+        match self {
+            KeySource::Jwks(s) => s
+                .verify(token, verification_options)
+                .await
+                .map_err(|e| VerifyError::Invalid(e.to_string())),
+            KeySource::Static(s) => s
+                .key_set()
+                .verify(token, verification_options)
+                .await
+                .map_err(|e| VerifyError::Invalid(e.to_string())),
+            KeySource::Hmac(s) => s
+                .verify(token, verification_options)
+                .map_err(VerifyError::Invalid),
+        }
+    }
+}
+
+/// Peek into the payload of a token to extract the (unverified!) issuer.
+fn unverified_issuer(token: &str) -> Option<String> {
+    #[derive(serde::Deserialize)]
+    struct Claims {
+        iss: Option<String>,
+    }
+
+    let payload = token.split('.').nth(1)?;
+    let payload = Base64UrlSafeNoPadding::decode_to_vec(payload, None).ok()?;
+    serde_json::from_slice::<Claims>(&payload).ok()?.iss
+}
+
+/// JOSE header parameters carrying key material, or pointing to it.
+/// None of our sources hold certificates, so an `x5c` chain can never match a
+/// key from the store, and is rejected as well.
+const EMBEDDED_KEY_PARAMS: &[&str] = &["jwk", "jku", "x5u", "x5c"];
+
+/// Decode the JOSE header of the token, without verifying it.
+fn unverified_header(token: &str) -> Option<serde_json::Map<String, serde_json::Value>> {
+    let header = token.split('.').next()?;
+    let header = Base64UrlSafeNoPadding::decode_to_vec(header, None).ok()?;
+    serde_json::from_slice(&header).ok()
+}
+
+/// Returns the first header parameter that embeds or references key
+/// material.
+/// Tokens with an undecodable header are left for the signature verification
+/// to reject.
+fn embedded_key_param(header: &serde_json::Map<String, serde_json::Value>) -> Option<&'static str> {
+    EMBEDDED_KEY_PARAMS
+        .iter()
+        .find(|param| header.contains_key(**param))
+        .copied()
+}
+
+/// Extract the max-age directive from a Cache-Control header, if any.
+fn max_age(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers
+        .get_all(reqwest::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find_map(|directive| {
+            let (k, v) = directive.trim().split_once('=')?;
+            if k.eq_ignore_ascii_case("max-age") {
+                v.trim_matches('"').parse().ok().map(Duration::from_secs)
+            } else {
+                None
+            }
+        })
+}
+
+/// Fetch and parse the JWKS document at the given URL.
+/// Returns the keys, and how long they may be cached for, if signalled.
+async fn fetch_jwks(url: &str) -> Result<(KeySet, Option<Duration>), SourceError> {
+    let response = HTTP_CLIENT.get(url).send().await?.error_for_status()?;
+    clock::observe(url, response.headers());
+    let max_age = max_age(response.headers());
+    let body = response.bytes().await?;
+
+    Ok((KeySet::from_jwk_json(&body)?, max_age))
+}
+
+/// Returns the keys that disappeared between [old] and [new], with the time
+/// they were first missing, plus the keys retired earlier that are still
+/// within the grace period.
+fn retire(
+    retired: &[(Key, SystemTime)],
+    old: &KeySet,
+    new: &KeySet,
+    now: SystemTime,
+    grace_period: Duration,
+) -> Vec<(Key, SystemTime)> {
+    retired
+        .iter()
+        .filter(|(_, since)| now <= *since + grace_period)
+        .cloned()
+        .chain(old.keys().iter().map(|k| (k.clone(), now)))
+        .filter(|(k, _)| !new.keys().contains(k))
+        .collect()
+}
+
+impl JwksState {
+    fn new(url: String, (keys, max_age): (KeySet, Option<Duration>)) -> Self {
+        Self {
+            url,
+            keys: Arc::new(keys),
+            retired: Vec::new(),
+            loaded_at: SystemTime::now(),
+            max_age,
+        }
+    }
+
+    /// Replace the keys with freshly fetched ones. Keys no longer present are
+    /// kept around for the grace period.
+    fn update(&mut self, url: String, keys: (KeySet, Option<Duration>), grace_period: Duration) {
+        let mut new = Self::new(url, keys);
+        if !grace_period.is_zero() {
+            new.retired = retire(
+                &self.retired,
+                &self.keys,
+                &new.keys,
+                new.loaded_at,
+                grace_period,
+            );
+            if new.retired.len() > self.retired.len() {
+                info!(
+                    retired = new.retired.len(),
+                    "keys disappeared from JWKS, keeping them for the grace period"
+                );
+            }
+        }
+        *self = new;
+
+        let generation = next_generation();
+        debug!(url = %self.url, generation, "loaded JWKS");
+    }
+
+    /// Returns the retired keys still within the grace period.
+    fn retired_keys(&self, grace_period: Duration) -> KeySet {
+        let now = SystemTime::now();
+        KeySet::from_keys(
+            self.retired
+                .iter()
+                .filter(|(_, since)| now <= *since + grace_period)
+                .map(|(k, _)| k.clone())
+                .collect(),
+        )
+    }
+}
+
+impl JwksSource {
+    fn from_state(issuer: Option<String>, discovered: bool, state: JwksState) -> Self {
+        Self {
+            issuer,
+            grace_period: Duration::ZERO,
+            discovered_at: discovered.then(|| Arc::new(parking_lot::Mutex::new(Instant::now()))),
+            state: Arc::new(parking_lot::RwLock::new(state)),
+            refresh_lock: Default::default(),
+            last_on_demand_refresh: Default::default(),
+            last_refresh_attempt: Default::default(),
+            refresh: RefreshOptions::default(),
+        }
+    }
+
+    /// How long keys may be used for, after they were loaded.
+    fn validity(&self, state: &JwksState) -> Duration {
+        state.max_age.unwrap_or(self.refresh.max_jwks_validity)
+    }
+
+    pub async fn new_from(issuer: Option<String>, jwks_url: String) -> Result<Self, SourceError> {
+        let keys = fetch_jwks(&jwks_url).await?;
+
+        Ok(Self::from_state(
+            issuer,
+            false,
+            JwksState::new(jwks_url, keys),
+        ))
+    }
+
+    /// Construct a source for the given issuer, looking up its JWKS endpoint
+    /// via OIDC discovery.
+    /// The discovery document is periodically re-fetched on [refresh].
+    pub async fn discover(issuer: String) -> Result<Self, SourceError> {
+        let metadata = oidc::discover(&issuer).await?;
+        let keys = fetch_jwks(&metadata.jwks_uri).await?;
+
+        Ok(Self::from_state(
+            Some(issuer),
+            true,
+            JwksState::new(metadata.jwks_uri, keys),
+        ))
+    }
+
+    /// Keep keys that disappear from the JWKS endpoint for the given
+    /// duration, so tokens signed with them shortly before a rotation at
+    /// the IdP remain valid.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Returns whether the discovery document should be re-fetched.
+    fn discovery_due(&self) -> bool {
+        self.discovered_at
+            .as_ref()
+            .is_some_and(|t| t.lock().elapsed() > DISCOVERY_REFRESH_INTERVAL)
+    }
+
+    /// The issuer this source is responsible for, if any.
+    pub fn issuer(&self) -> Option<&str> {
+        self.issuer.as_deref()
+    }
+
+    /// Determine if the source should be refreshed.
+    /// This is the case after [REFRESH_FRACTION] of the validity of the
+    /// keys elapsed, which is deduced from the cache-control headers, if
+    /// present, or [KeyStore::with_max_jwks_validity].
+    pub async fn should_refresh(&self) -> bool {
+        if self.discovery_due() {
+            return true;
+        }
+
+        let state = self.state.read();
+        SystemTime::now() > state.loaded_at + self.validity(&state).mul_f64(REFRESH_FRACTION)
+    }
+
+    /// Refresh the source. Callers should use [should_refresh] first.
+    #[tracing::instrument(name = "jwks_refresh", skip_all, fields(issuer = self.issuer.as_deref()))]
+    pub async fn refresh(&self) -> Result<(), SourceError> {
+        let _guard = self.refresh_lock.lock().await;
+        *self.last_refresh_attempt.lock() = Some(Instant::now());
+
+        let mut url = self.state.read().url.clone();
+        if let (true, Some(issuer)) = (self.discovery_due(), &self.issuer) {
+            let metadata = oidc::discover(issuer).await?;
+            if let Some(discovered_at) = &self.discovered_at {
+                *discovered_at.lock() = Instant::now();
+            }
+
+            if url != metadata.jwks_uri {
+                info!(issuer, jwks_uri = %metadata.jwks_uri, "JWKS endpoint changed");
+                url = metadata.jwks_uri;
+            }
+        }
+
+        let keys = fetch_jwks(&url).await?;
+        self.state.write().update(url, keys, self.grace_period);
+        Ok(())
+    }
+
+    /// Refresh the source because a token referenced a key we don't know,
+    /// which usually means the IdP rotated its keys.
+    /// This is rate-limited to once per [interval], so tokens with bogus key
+    /// ids can't be used to hammer the IdP, and skipped while another
+    /// refresh is in progress, so requests don't queue up behind a slow IdP.
+    async fn refresh_on_demand(&self, interval: Duration) {
+        let Ok(_guard) = self.refresh_lock.try_lock() else {
+            METRICS
+                .jwks_on_demand_refreshes
+                .with_labels(&["in_progress"])
+                .inc();
+            return;
+        };
+
+        {
+            let mut last = self.last_on_demand_refresh.lock();
+            if last.is_some_and(|t| t.elapsed() < interval) {
+                METRICS
+                    .jwks_on_demand_refreshes
+                    .with_labels(&["rate_limited"])
+                    .inc();
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+
+        let url = self.state.read().url.clone();
+        match fetch_jwks(&url).await {
+            Ok(keys) => {
+                self.state.write().update(url, keys, self.grace_period);
+                info!(
+                    issuer = ?self.issuer,
+                    generation = generation(),
+                    "refreshed JWKS after seeing an unknown key"
+                );
+                METRICS.jwks_on_demand_refreshes.with_labels(&["ok"]).inc();
+            }
+            Err(e) => {
+                warn!(issuer = ?self.issuer, err=%e, "failed to refresh JWKS");
+                METRICS
+                    .jwks_on_demand_refreshes
+                    .with_labels(&["error"])
+                    .inc();
+            }
+        }
+    }
+
+    /// How long until the next refresh attempt: right away if one is in
+    /// progress, else at the next check after the last one, which failed
+    /// if keys are expired, with checks happening every [interval].
+    fn retry_after(&self, interval: Duration) -> Duration {
+        if self.refresh_lock.try_lock().is_err() {
+            return MIN_RETRY_AFTER;
+        }
+        self.last_refresh_attempt
+            .lock()
+            .map_or(interval, |t| interval.saturating_sub(t.elapsed()))
+            .max(MIN_RETRY_AFTER)
+    }
+
+    /// Return if keys are still considered valid.
+    pub async fn still_valid(&self) -> bool {
+        let state = self.state.read();
+        SystemTime::now() <= state.loaded_at + self.validity(&state)
+    }
+
+    async fn verify<CustomClaims>(
+        &self,
+        token: &str,
+        verification_options: Option<VerificationOptions>,
+    ) -> Result<jwt_simple::claims::JWTClaims<CustomClaims>, KeySetError>
+    where
+        CustomClaims: serde::Serialize + serde::de::DeserializeOwned + Send + 'static,
+    {
+        let key_set = self.state.read().keys.clone();
+        match key_set.verify(token, verification_options.clone()).await {
+            Err(KeySetError::NoMatchingKey { .. }) => {}
+            result => return result,
+        }
+
+        // the key might have been rotated out recently
+        let retired = self.state.read().retired_keys(self.grace_period);
+        match retired.verify(token, verification_options.clone()).await {
+            Err(KeySetError::NoMatchingKey { .. }) => {}
+            result => return result,
+        }
+
+        // retry once with fresh keys
+        let Some(interval) = self.refresh.on_demand_interval else {
+            return key_set.verify(token, verification_options).await;
+        };
+        self.refresh_on_demand(interval).await;
+        let key_set = self.state.read().keys.clone();
+        key_set.verify(token, verification_options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use jwt_simple::prelude::*;
+    use reqwest::header::{HeaderMap, HeaderValue, CACHE_CONTROL};
+
+    use super::{
+        embedded_key_param, max_age, retire, unverified_header, unverified_issuer, JwksSource,
+        JwksState, KeySource, KeyStore, RetryStrategy,
+    };
+    use crate::key_set::KeySet;
+
+    #[test]
+    fn retired_keys() {
+        let jwks = |kids: &[&str]| {
+            let keys = kids
+                .iter()
+                .map(|kid| {
+                    let pk = Ed25519KeyPair::generate().public_key();
+                    let x = Base64UrlSafeNoPadding::encode_to_string(pk.to_bytes()).unwrap();
+                    serde_json::json!({"kty": "OKP", "crv": "Ed25519", "kid": kid, "x": x})
+                })
+                .collect::<Vec<_>>();
+            KeySet::from_jwk_json(serde_json::json!({ "keys": keys }).to_string().as_bytes())
+                .expect("must parse")
+        };
+        let grace = Duration::from_secs(60);
+        let t0 = std::time::SystemTime::UNIX_EPOCH;
+
+        let old = jwks(&["a", "b"]);
+        let mut new = KeySet::from_keys(old.keys()[1..].to_vec());
+        new.extend(jwks(&["c"]));
+
+        // a got removed
+        let retired = retire(&[], &old, &new, t0, grace);
+        assert_eq!(1, retired.len());
+        assert_eq!(Some("a"), retired[0].0.kid.as_deref());
+
+        // still within grace, keeps its original retirement time
+        let retired = retire(&retired, &new, &new, t0 + grace, grace);
+        assert_eq!(
+            vec![t0],
+            retired.iter().map(|(_, t)| *t).collect::<Vec<_>>()
+        );
+
+        // past the grace period
+        let retired = retire(&retired, &new, &new, t0 + grace * 2, grace);
+        assert!(retired.is_empty());
+    }
+
+    #[test]
+    fn cache_control() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, max_age(&headers));
+
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=3600, must-revalidate"),
+        );
+        assert_eq!(Some(Duration::from_secs(3600)), max_age(&headers));
+
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        assert_eq!(None, max_age(&headers));
+    }
+
+    #[test]
+    fn issuer() {
+        // {"alg":"none"}.{"iss":"https://idp.example.com","sub":"alice"}.
+        let token =
+            "eyJhbGciOiJub25lIn0.eyJpc3MiOiJodHRwczovL2lkcC5leGFtcGxlLmNvbSIsInN1YiI6ImFsaWNlIn0.";
+        assert_eq!(
+            Some("https://idp.example.com".to_string()),
+            unverified_issuer(token)
+        );
+
+        assert_eq!(None, unverified_issuer("garbage"));
+    }
+
+    #[test]
+    fn embedded_key() {
+        // {"alg":"ES256","jwk":{"kty":"EC"}}.{}.
+        let token = "eyJhbGciOiJFUzI1NiIsImp3ayI6eyJrdHkiOiJFQyJ9fQ.e30.";
+        assert_eq!(
+            Some("jwk"),
+            embedded_key_param(&unverified_header(token).unwrap())
+        );
+
+        // {"alg":"RS256","x5u":"https://evil.example.com/cert.pem"}.{}.
+        let token =
+            "eyJhbGciOiJSUzI1NiIsIng1dSI6Imh0dHBzOi8vZXZpbC5leGFtcGxlLmNvbS9jZXJ0LnBlbSJ9.e30.";
+        assert_eq!(
+            Some("x5u"),
+            embedded_key_param(&unverified_header(token).unwrap())
+        );
+
+        // {"alg":"ES256","kid":"foo"}.{}.
+        let token = "eyJhbGciOiJFUzI1NiIsImtpZCI6ImZvbyJ9.e30.";
+        assert_eq!(None, embedded_key_param(&unverified_header(token).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn allowed_algs() {
+        let mut f = tempfile::NamedTempFile::new().expect("must create file");
+        std::io::Write::write_all(&mut f, b"0123456789abcdef0123456789abcdef").unwrap();
+        let source = crate::HmacSource::load(None, &[f.path()]).expect("must load");
+
+        let token = HS256Key::from_bytes(b"0123456789abcdef0123456789abcdef")
+            .authenticate(Claims::create(jwt_simple::prelude::Duration::from_mins(5)))
+            .expect("must authenticate");
+
+        let key_store = super::KeyStore::new(vec![super::KeySource::Hmac(source)]);
+        assert!(key_store
+            .verify::<NoCustomClaims>(&token, None, None)
+            .await
+            .is_ok());
+        assert!(matches!(
+            key_store
+                .verify::<NoCustomClaims>(&token, None, Some(&["ES256".to_string()]))
+                .await,
+            Err(super::VerifyError::DisallowedAlgorithm(alg)) if alg == "HS256"
+        ));
+
+        let key_store = key_store.with_allowed_algs(vec!["RS256".to_string()]);
+        assert!(matches!(
+            key_store
+                .verify::<NoCustomClaims>(&token, None, Some(&["HS256".to_string()]))
+                .await,
+            Err(super::VerifyError::DisallowedAlgorithm(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refresh_options() {
+        let state = |max_age| JwksState {
+            url: "https://idp.example.com/jwks".to_string(),
+            keys: Default::default(),
+            retired: vec![],
+            loaded_at: std::time::SystemTime::now() - Duration::from_secs(200),
+            max_age,
+        };
+        let key_store = KeyStore::new(vec![
+            KeySource::Jwks(JwksSource::from_state(None, false, state(None))),
+            KeySource::Jwks(JwksSource::from_state(
+                None,
+                false,
+                state(Some(Duration::from_secs(3600))),
+            )),
+        ]);
+        // past half of the default validity of 5 minutes, but not expired
+        assert!(key_store.still_valid().await);
+        assert!(key_store.sources()[0].should_refresh().await);
+        assert!(!key_store.sources()[1].should_refresh().await);
+
+        let key_store = key_store
+            .with_max_jwks_validity(Duration::from_secs(60))
+            .with_refresh_interval(Duration::from_secs(30));
+        assert!(!key_store.still_valid().await);
+        assert_eq!(Duration::from_secs(30), key_store.refresh_interval());
+        assert_eq!(
+            Duration::from_secs(30),
+            key_store.sources()[0].retry_after()
+        );
+        // signalled max-age takes precedence
+        assert!(key_store.sources()[1].still_valid().await);
+
+        // on-demand refreshes don't wait for one in progress
+        let source = JwksSource::from_state(None, false, state(None));
+        let _guard = source.refresh_lock.lock().await;
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            source.refresh_on_demand(Duration::ZERO),
+        )
+        .await
+        .expect("must not wait for the refresh in progress");
+        assert!(source.last_on_demand_refresh.lock().is_none());
+
+        let retry = RetryStrategy {
+            retries: 3,
+            initial_delay: Duration::from_millis(100),
+            factor: 2,
+        };
+        let delays = retry.delays().collect::<Vec<_>>();
+        assert_eq!(3, delays.len());
+        for (delay, max) in delays.iter().zip([100, 200, 400]) {
+            assert!(*delay <= Duration::from_millis(max), "{:?}", delay);
+        }
+        assert_eq!(
+            0,
+            RetryStrategy {
+                retries: 0,
+                ..retry
+            }
+            .delays()
+            .count()
+        );
     }
 }
@@ -1,31 +1,325 @@
-use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
-};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use axum::{http::StatusCode, response::IntoResponse, routing::get, routing::Router};
+use arc_swap::ArcSwap;
+use axum::{
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::delete,
+    routing::get,
+    routing::post,
+    routing::put,
+    routing::Router,
+};
 use axum_extra::{headers::authorization::Bearer, TypedHeader};
 use cel_interpreter::Value;
-use parking_lot::RwLock;
 use tracing::{debug, warn};
 
+mod admin_auth;
+pub use admin_auth::AdminAuth;
+#[cfg(feature = "admin-ui")]
+mod admin_ui;
+pub mod api_keys;
+pub mod assertion;
+pub mod audit;
+mod baggage;
+mod batch;
+pub mod bearer_sessions;
+mod cel_functions;
+mod cel_macros;
+pub use cel_macros::{MacroError, Macros};
+pub mod chaos;
+pub mod claim_validators;
+pub mod client;
+pub mod client_cert;
+pub mod clock;
+pub mod config;
 mod context_headers;
+pub use context_headers::NonUtf8Headers;
+mod context_jwt;
+mod context_request;
+mod context_schema;
+mod decision;
+pub use decision::{DecisionMetadata, Denial};
+mod decision_cache;
+pub use decision_cache::{CachedDecision, DecisionCache};
+pub mod decision_id;
+pub mod denial_webhook;
+pub mod denylist;
+mod dpop;
+pub use dpop::DpopValidator;
+pub mod error_pages;
+mod expired_tokens;
+pub use expired_tokens::ExpiredTokens;
+pub mod fixture;
+mod flags;
+pub use flags::Flags;
+pub mod forwarded_for;
+pub mod geoip;
+pub mod health_checks;
+mod hmac_keys;
+pub mod identities;
+pub mod identity_headers;
+pub use hmac_keys::HmacSource;
+mod introspection;
+pub use introspection::{IntrospectionError, Introspector};
+mod jwe;
+pub use jwe::DecryptionKey;
+mod key_set;
 mod key_store;
-pub use key_store::KeyStore;
+pub use key_store::{
+    JwksSource, KeySource, KeyStore, RetryStrategy, SourceError, VerifyError, MAX_JWKS_VALIDITY,
+    ON_DEMAND_REFRESH_INTERVAL, REFRESH_CHECK_INTERVAL,
+};
 
+pub mod listeners;
+pub mod load_shedding;
+pub mod local_keys;
+pub mod lockout;
+pub mod log_levels;
+pub mod login;
+pub mod metrics;
+pub mod oidc;
+pub mod otlp;
+mod policy;
+pub use policy::{warn_expiring, Coercion, NumberMode, Policy};
+#[cfg(feature = "pprof")]
+mod pprof;
+mod principal;
+mod program_cache;
+pub use program_cache::ProgramCache;
+pub mod rate_limit;
+mod scopes;
+pub mod security_headers;
+pub mod sensitive_headers;
+#[cfg(all(windows, feature = "windows-service"))]
+pub mod service;
+pub mod session;
+pub mod signals;
+pub mod slo;
+pub mod spoe;
+mod static_keys;
+pub use static_keys::StaticSource;
+pub mod supervisor;
+pub mod tenant_usage;
 pub mod util;
+mod verification;
+pub use verification::VerificationConfig;
 
 #[derive(Clone)]
 pub struct AppState {
+    /// The configuration that can be reloaded at runtime.
+    /// It's swapped atomically, requests keep using the one they started
+    /// with.
+    pub reloadable: Arc<ArcSwap<Reloadable>>,
+
+    /// Compiled CEL programs, by their source.
+    pub cel_programs: Arc<ProgramCache>,
+
+    /// Used for tokens that aren't JWTs, if configured.
+    pub introspector: Option<Introspector>,
+
+    /// Validates DPoP proofs sent along with DPoP-bound tokens.
+    pub dpop: DpopValidator,
+
+    /// Claims passed on to the upstream via the baggage header on allow.
+    pub baggage_claims: Arc<[String]>,
+
+    /// Headers returned on allow, rendered from claims.
+    pub identity_headers: Arc<[identity_headers::IdentityHeader]>,
+
+    /// Maximum wall-clock time for evaluating a CEL program, if set.
+    pub cel_timeout: Option<Duration>,
+
+    /// Validation of the X-Forwarded-For header.
+    pub forwarded_for: forwarded_for::ForwardedFor,
+
+    /// Where to read client certificates forwarded by the proxy from, if
+    /// configured.
+    pub client_cert: Option<client_cert::ClientCertSource>,
+
+    /// Claims to render the principal in decision events from, in order of
+    /// preference. If empty, no principal is recorded.
+    pub principal_claims: Arc<[String]>,
+
+    /// Detection of clients repeatedly presenting the same expired token,
+    /// if enabled.
+    pub expired_tokens: Option<ExpiredTokens>,
+
+    /// Accounting of allowed requests per tenant, if enabled.
+    pub tenant_usage: Option<Arc<tenant_usage::TenantUsage>>,
+
+    /// The maximum skew of the system clock, if checked.
+    pub clock_check: Option<clock::ClockCheck>,
+
+    /// How the identities of a request are combined.
+    pub combiner: identities::Combiner,
+
+    /// Headers never passed on to CEL programs.
+    pub sensitive_headers: sensitive_headers::SensitiveHeaders,
+
+    /// What to do with header values that aren't valid UTF-8.
+    pub non_utf8_headers: NonUtf8Headers,
+
+    /// The login flow for browsers, if configured.
+    pub login: Option<Arc<login::Login>>,
+
+    /// Sessions skipping the verification of tokens seen before, if
+    /// enabled.
+    pub bearer_sessions: Option<bearer_sessions::BearerSessions>,
+
+    /// The realm of the `WWW-Authenticate` challenge sent with 401s.
+    pub realm: Arc<str>,
+
+    /// Signs the assertions returned on allow, if configured.
+    pub assertion_signer: Option<Arc<assertion::Signer>>,
+
+    /// The status code requests denied by the policy are answered with,
+    /// usually 403, while problems with the identities get a 401.
+    pub policy_denial_status: StatusCode,
+
+    /// Runs the background tasks, and reports on them.
+    pub supervisor: supervisor::Supervisor,
+
+    /// Positive decisions by token and policy, if caching them is enabled.
+    pub decision_cache: Option<Arc<DecisionCache>>,
+
+    /// Revoked tokens, rejected despite being valid otherwise, see
+    /// [claim_validators::Revocation].
+    pub denylist: Arc<denylist::Denylist>,
+
+    /// Validators of the claims of tokens, run in order after verifying
+    /// them.
+    pub claim_validators: claim_validators::ClaimValidators,
+
+    /// Limits of requests per client address and subject, if configured.
+    pub rate_limiter: Option<Arc<rate_limit::RateLimiter>>,
+
+    /// Bans of client addresses presenting invalid tokens, if enabled.
+    pub lockout: Option<Arc<lockout::Lockout>>,
+}
+
+/// The parts of the configuration that can be reloaded at runtime.
+pub struct Reloadable {
     pub key_store: KeyStore,
 
-    pub cel_programs: Arc<RwLock<HashMap<String, cel_interpreter::Program>>>,
+    /// Verification options applied to all tokens, unless overridden by the
+    /// request.
+    pub verification_defaults: VerificationConfig,
+
+    /// CEL programs by name, referenced as `?policy=<name>`.
+    pub policies: HashMap<String, Policy>,
+
+    /// Policy used for requests not specifying one, if set. Otherwise, these
+    /// are denied.
+    pub default_policy: Option<Policy>,
+
+    /// Feature flags exposed to CEL programs, if configured.
+    pub flags: Option<Flags>,
+
+    /// Constants added to the context of every CEL program.
+    pub cel_constants: HashMap<String, Value>,
+
+    /// Macros expanded into every CEL program before compiling it.
+    pub cel_macros: Macros,
+
+    /// GeoIP databases the client address is looked up in, if configured.
+    pub geoip: Option<Arc<geoip::GeoIp>>,
+
+    /// API keys accepted as identity, if configured.
+    pub api_keys: Option<api_keys::ApiKeys>,
 }
 
-pub fn gen_router() -> Router<AppState> {
+/// Routes served on the main listener.
+/// [security_headers] are added to all HTML responses, [error_pages] render
+/// the bodies of errors of /auth, [chaos] injects failures into it,
+/// decisions are recorded to the [audit_log], if any, and token-validity
+/// denials sent to the [denial_webhook], if any.
+pub fn gen_router(
+    security_headers: security_headers::SecurityHeaders,
+    trusted_proxies: forwarded_for::TrustedProxies,
+    error_pages: error_pages::ErrorPages,
+    health_checks: health_checks::HealthChecks,
+    chaos: chaos::Chaos,
+    audit_log: Option<Arc<audit::AuditLog>>,
+    denial_webhook: Option<denial_webhook::DenialWebhook>,
+) -> Router<AppState> {
     Router::new()
         .route("/", get(root))
-        .route("/auth", get(auth))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route(
+            "/auth",
+            get(auth)
+                .layer(middleware::from_fn_with_state(chaos, chaos::apply))
+                .layer(middleware::from_fn(metrics::record_decision))
+                .layer(middleware::from_fn_with_state(audit_log, audit::record))
+                .layer(middleware::from_fn_with_state(
+                    denial_webhook,
+                    denial_webhook::record,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    health_checks,
+                    health_checks::apply,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    error_pages,
+                    error_pages::apply,
+                )),
+        )
+        .route("/auth/batch", post(batch::handler))
+        .route(assertion::WELL_KNOWN_KEYS, get(assertion::keys_handler))
+        .route("/callback", get(login::callback))
+        .route("/logout", get(login::logout).post(login::logout))
+        .layer(middleware::from_fn_with_state(
+            security_headers,
+            security_headers::apply,
+        ))
+        // outermost, so no handler or middleware sees untrusted headers.
+        .layer(middleware::from_fn_with_state(
+            trusted_proxies,
+            forwarded_for::apply,
+        ))
+}
+
+/// Routes served on the admin listener, if configured.
+/// If [admin_auth] is set, all requests need to be authenticated accordingly.
+/// Metrics and the context schema are only served here, as they reveal
+/// details (like decisions per tenant) not meant for clients of /auth.
+pub fn gen_admin_router(admin_auth: Option<AdminAuth>) -> Router<AppState> {
+    let router = Router::new()
+        .route("/-/metrics", get(metrics::handler))
+        .route("/-/context-schema", get(context_schema::handler))
+        .route("/-/log-levels", get(log_levels::list))
+        .route("/-/tasks", get(supervisor::handler))
+        .route("/-/denylist", get(denylist::list).post(denylist::add))
+        .route("/-/denylist/:entry", delete(denylist::delete))
+        .route(
+            "/-/log-levels/:policy",
+            put(log_levels::put).delete(log_levels::delete),
+        );
+
+    #[cfg(feature = "pprof")]
+    let router = router
+        .route("/-/pprof/profile", get(pprof::profile))
+        .route("/-/pprof/heap", get(pprof::heap));
+
+    #[cfg(feature = "admin-ui")]
+    let router = router.route("/-/ui/status", get(admin_ui::status_handler));
+
+    let router = match admin_auth {
+        Some(admin_auth) => router.layer(middleware::from_fn_with_state(
+            admin_auth,
+            admin_auth::require,
+        )),
+        None => router,
+    };
+
+    // the page holds no data, and asks for the token to fetch the status.
+    #[cfg(feature = "admin-ui")]
+    let router = router.route("/-/ui", get(admin_ui::page));
+
+    router
 }
 
 async fn root() -> String {
@@ -36,119 +330,1057 @@ async fn root() -> String {
     )
 }
 
+/// Liveness: the process is up and serving requests.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness: keys of all sources are still valid, so tokens can be
+/// verified. While they can't be refreshed, load balancers should send
+/// traffic to other instances, rather than getting 500s from this one.
+async fn readyz(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> (StatusCode, &'static str) {
+    let key_store = state.reloadable.load_full().key_store.clone();
+    if key_store.still_valid().await {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "keys expired")
+    }
+}
+
 #[derive(serde::Deserialize)]
 struct Params {
     /// A CEL expression that returns true if access should be granted, or false
     /// if not.
     cel_str: Option<String>,
 
-    /// Allowed audiences of the JWT
-    allowed_audiences: Option<HashSet<String>>,
+    /// The name of a policy configured on the server, used instead of
+    /// cel_str.
+    policy: Option<String>,
 
-    /// Allowed issuers of the JWT
-    allowed_issuers: Option<HashSet<String>>,
+    /// Scopes relevant for this policy, separated by spaces or commas.
+    /// If set, the ones granted to the token are returned in the
+    /// X-Auth-Scopes header on allow.
+    scopes: Option<String>,
+
+    /// Signature algorithms to accept, separated by commas.
+    /// This restricts the algorithms allowed on the server even further.
+    allowed_algs: Option<String>,
 }
 
 type CustomClaims = serde_json::Map<String, serde_json::Value>;
 
 async fn auth(
-    axum::extract::State(AppState {
-        key_store,
-        cel_programs,
-    }): axum::extract::State<AppState>,
+    axum::extract::State(state): axum::extract::State<AppState>,
     maybe_auth_header: Option<TypedHeader<axum_extra::headers::Authorization<Bearer>>>,
     axum::extract::Query(params): axum::extract::Query<Params>,
+    axum::extract::Query(verification_config): axum::extract::Query<VerificationConfig>,
     rq: axum::extract::Request,
-) -> Result<impl IntoResponse, StatusCode> {
-    // Retrieve the JWT from the request
-    // FUTUREWORK: cookies?
-    let auth = maybe_auth_header.ok_or_else(|| {
+) -> Result<Response, StatusCode> {
+    // Retrieve the JWT from the request, sent either as bearer or DPoP-bound
+    // token.
+    // Browsers that logged in carry a session cookie instead, see [login].
+    // Without one, other identities may suffice, depending on the combiner.
+    let token = match &maybe_auth_header {
+        Some(auth) => Some(auth.token()),
+        None => dpop::authorization_token(rq.headers()),
+    };
+    if token.is_none() {
         debug!("no bearer auth found");
-        StatusCode::UNAUTHORIZED
-    })?;
+    }
+
+    let allowed_algs = params.allowed_algs.map(|algs| {
+        algs.split(',')
+            .map(|alg| alg.trim().to_owned())
+            .collect::<Vec<_>>()
+    });
+
+    // answered before doing any work, brute-forcing tokens should be slow.
+    let health_check = rq
+        .extensions()
+        .get::<health_checks::HealthCheck>()
+        .is_some();
+    let client_ip = state.forwarded_for.client_ip(rq.headers());
+    if let (Some(lockout), Some(ip), false) = (&state.lockout, client_ip, health_check) {
+        if let Some(retry_after) = lockout.banned(ip, std::time::Instant::now()) {
+            debug!(%ip, "client banned");
+            return Ok(Denial {
+                retry_after: Some(retry_after),
+                ..StatusCode::TOO_MANY_REQUESTS.into()
+            }
+            .into_response());
+        }
+    }
+    if let (Some(rate_limiter), false) = (&state.rate_limiter, health_check) {
+        if let Err(retry_after) = rate_limiter.check(client_ip, token) {
+            debug!(?client_ip, "rate limited");
+            return Ok(Denial {
+                retry_after: Some(retry_after),
+                ..StatusCode::TOO_MANY_REQUESTS.into()
+            }
+            .into_response());
+        }
+    }
+
+    // with a skewed clock, exp and nbf can't be validated reliably.
+    if state.clock_check.as_ref().is_some_and(|c| c.refusing()) {
+        warn!("refusing decision, the system clock is skewed");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let policy = resolve_policy(&state, params.cel_str, params.policy)?;
+    #[cfg(feature = "admin-ui")]
+    let policy_name = policy.as_ref().and_then(|p| p.name.clone());
+
+    let result = decide(
+        &state,
+        token,
+        policy,
+        &verification_config,
+        allowed_algs.as_deref(),
+        rq.headers().to_owned(),
+    )
+    .await;
+
+    #[cfg(feature = "admin-ui")]
+    if let Err(denial) = &result {
+        let health_check = rq.extensions().get::<health_checks::HealthCheck>();
+        if denial.status.is_client_error() && health_check.is_none() {
+            admin_ui::record_denial(
+                denial.status,
+                policy_name.as_deref(),
+                rq.headers(),
+                state.forwarded_for.client_ip(rq.headers()),
+            );
+        }
+    }
+
+    let mut decision = match result {
+        Ok(decision) => decision,
+        Err(denial) => {
+            if let (Some(lockout), Some(ip), true) =
+                (&state.lockout, client_ip, denial.counts_as_guess)
+            {
+                lockout.record_failure(ip, std::time::Instant::now());
+            }
+            // prompt clients stuck with an expired token to refresh it.
+            if denial.status == StatusCode::UNAUTHORIZED {
+                let expired_tokens = state.expired_tokens.as_ref();
+                if let Some(response) = expired_tokens
+                    .zip(token)
+                    .and_then(|(e, token)| e.check(token))
+                {
+                    return Ok(response);
+                }
+                // send browsers without token or session to log in.
+                if let (Some(login), None) = (&state.login, token) {
+                    if login.session(rq.headers()).await.is_none() {
+                        if let Some(response) = login.redirect(rq.headers()).await {
+                            return Ok(response);
+                        }
+                    }
+                }
+            }
+            let challenge = denial.challenge(&state.realm);
+            let mut response = denial.into_response();
+            if let Some(challenge) = challenge {
+                response
+                    .headers_mut()
+                    .insert(axum::http::header::WWW_AUTHENTICATE, challenge);
+            }
+            return Ok(response);
+        }
+    };
+
+    let relevant_scopes = params.scopes.as_deref().map(scopes::parse_list);
+    let session_cookie = decision.session_cookie.take();
+    let mut headers = decision.metadata(relevant_scopes.as_deref()).to_headers();
+    if let Some(session_cookie) = session_cookie {
+        headers.append(axum::http::header::SET_COOKIE, session_cookie);
+    }
+
+    Ok((headers, "Access granted").into_response())
+}
+
+/// Returns the policy to evaluate, either a CEL program sent directly or
+/// referenced by the name of a configured policy. Named policies outside of
+/// their validity window are replaced by their fallback, see [Policy::effective].
+fn resolve_policy(
+    state: &AppState,
+    cel_str: Option<String>,
+    policy: Option<String>,
+) -> Result<Option<Policy>, StatusCode> {
+    match (cel_str, policy) {
+        (Some(_), Some(_)) => {
+            debug!("both cel_str and policy set");
+            Err(StatusCode::BAD_REQUEST)
+        }
+        (None, Some(name)) => {
+            let policies = &state.reloadable.load().policies;
+            match policies.get(&name) {
+                Some(policy) => Ok(Some(
+                    policy.effective(policies, std::time::SystemTime::now().into()),
+                )),
+                None => {
+                    warn!(policy = %name, "unknown policy");
+                    Err(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            }
+        }
+        (Some(cel_str), None) => Ok(Some(cel_str.into())),
+        (None, None) => Ok(state.reloadable.load().default_policy.clone()),
+    }
+}
+
+/// Execute the CEL program, and the backend expression of the policy, if
+/// any, for requests the program allows.
+/// If a timeout is set, it's executed on a blocking thread, so a
+/// pathological program can't stall the handler. The interpreter can't be
+/// interrupted, so on timeout, the thread keeps running until the program
+/// finishes, but the request doesn't wait for it.
+#[tracing::instrument(name = "cel", skip_all)]
+async fn execute(
+    program: Arc<cel_interpreter::Program>,
+    backend: Option<Arc<cel_interpreter::Program>>,
+    context: cel_interpreter::Context<'static>,
+    timeout: Option<Duration>,
+) -> Result<(Value, Option<Value>), StatusCode> {
+    let run = move || {
+        let result = program.execute(&context)?;
+        let backend = match (&result, backend) {
+            (Value::Bool(false) | Value::String(_), _) | (_, None) => None,
+            (_, Some(backend)) => Some(backend.execute(&context)?),
+        };
+        Ok((result, backend))
+    };
+    let result = match timeout {
+        None => run(),
+        Some(timeout) => {
+            let task = tokio::task::spawn_blocking(run);
+            match tokio::time::timeout(timeout, task).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(e)) => {
+                    warn!(err=%e, "CEL program panicked");
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+                Err(_) => {
+                    warn!(?timeout, "CEL program exceeded timeout");
+                    metrics::METRICS
+                        .cel_evaluation_timeouts
+                        .with_labels(&[])
+                        .inc();
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+        }
+    };
 
-    // We already automatically refresh at regular intervals, which should
-    // happen well before expiry, so if we're in a state where all our keys
-    // expired, disallow access.
-    if !key_store.still_valid().await {
-        warn!("keys expired before we could refresh them");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    result.map_err(|e: cel_interpreter::ExecutionError| {
+        warn!(err=%e, "failed to execute CEL program");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Details about a positive decision.
+struct Decision {
+    /// The scopes granted to the token.
+    token_scopes: Vec<String>,
+    /// The baggage header to send upstream, if any claims are configured.
+    baggage: Option<String>,
+    /// Further headers to send upstream, from claims and the policy.
+    headers: Vec<(HeaderName, String)>,
+    /// The Set-Cookie header of a session issued for the token, if any.
+    session_cookie: Option<HeaderValue>,
+}
+
+impl Decision {
+    /// The metadata to pass on to the upstream, with the token scopes
+    /// restricted to the relevant ones, if requested.
+    fn metadata(self, relevant_scopes: Option<&[String]>) -> DecisionMetadata {
+        DecisionMetadata {
+            scopes: relevant_scopes.map(|relevant| scopes::intersect(&self.token_scopes, relevant)),
+            baggage: self.baggage,
+            headers: self.headers,
+        }
     }
+}
+
+/// Verify the token, via introspection for opaque tokens if configured,
+/// returning its claims, or the denial to respond with.
+/// JWTs are skipped if the request carries a session for them, else one is
+/// issued along with the claims, if enabled, see [bearer_sessions].
+async fn verify_token(
+    AppState {
+        reloadable,
+        introspector,
+        dpop,
+        bearer_sessions,
+        claim_validators,
+        ..
+    }: &AppState,
+    token: &str,
+    verification_config: &VerificationConfig,
+    allowed_algs: Option<&[String]>,
+    headers: &axum::http::HeaderMap,
+) -> Result<(CustomClaims, Option<HeaderValue>), Denial> {
+    let mut session_cookie = None;
+    let jwt_claims: CustomClaims = match introspector {
+        // Opaque tokens are sent to the introspection endpoint, if configured.
+        Some(introspector) if introspection::is_opaque(token) => {
+            introspector.introspect(token).await.map_err(|e| match e {
+                IntrospectionError::Inactive => {
+                    debug!("inactive token");
+                    Denial::invalid_token("The access token is inactive")
+                }
+                e => {
+                    warn!(err=%e, "failed to introspect token");
+                    StatusCode::INTERNAL_SERVER_ERROR.into()
+                }
+            })?
+        }
+        // Verify the JWT
+        _ => {
+            let reloadable = reloadable.load_full();
+            let Reloadable {
+                key_store,
+                verification_defaults,
+                ..
+            } = &*reloadable;
+            let config = verification_config.or(verification_defaults);
+            let session = bearer_sessions
+                .as_ref()
+                .and_then(|s| s.claims(headers, token, &config, allowed_algs));
+            if let Some(claims) = session {
+                claims
+            } else {
+                let jwt_claims = verify_jwt(key_store, token, &config, allowed_algs).await?;
+                session_cookie = bearer_sessions
+                    .as_ref()
+                    .and_then(|s| s.issue(headers, token, &config, allowed_algs, &jwt_claims));
+                jwt_claims
+            }
+        }
+    };
+
+    claim_validators.validate(token, &jwt_claims).await?;
 
-    // Verify the JWT
+    // Tokens bound to a key need to come with a proof of possession.
+    dpop.check(headers, token, &jwt_claims).map_err(|e| {
+        debug!(err=%e, "rejecting token");
+        Denial::invalid_token("The DPoP proof is invalid")
+    })?;
+
+    Ok((jwt_claims, session_cookie))
+}
+
+/// The description of tokens failing verification for reasons other than
+/// expiry, like an invalid signature.
+const INVALID_TOKEN: &str = "The access token is invalid";
+
+/// Verify the JWT against the key store.
+#[tracing::instrument(name = "verify_jwt", skip_all)]
+async fn verify_jwt(
+    key_store: &KeyStore,
+    token: &str,
+    config: &VerificationConfig,
+    allowed_algs: Option<&[String]>,
+) -> Result<CustomClaims, Denial> {
     let jwt_claims = key_store
-        .verify::<CustomClaims>(
-            auth.token(),
-            Some(jwt_simple::prelude::VerificationOptions {
-                allowed_issuers: params.allowed_issuers,
-                allowed_audiences: params.allowed_audiences,
-                ..Default::default()
-            }),
-        )
+        .verify::<CustomClaims>(token, Some(config.to_options()), allowed_algs)
         .await
-        .map_err(|jwt_simple_jwks::Error { msg, .. }| {
-            debug!(%msg, "invalid token");
+        .map_err(|e| match e {
+            // the keys responsible for this token expired, disallow access
+            // until they're refreshed.
+            VerifyError::KeysExpired(retry_after) => Denial {
+                retry_after: Some(retry_after),
+                ..StatusCode::INTERNAL_SERVER_ERROR.into()
+            },
+            e @ VerifyError::EmbeddedKey(_) => {
+                warn!(err=%e, "rejecting token with embedded key material");
+                Denial {
+                    counts_as_guess: true,
+                    ..Denial::invalid_token(INVALID_TOKEN)
+                }
+            }
+            e => {
+                debug!(err=%e, "invalid token");
+                if expired_tokens::is_expired(token, expired_tokens::now()) {
+                    Denial::invalid_token("The access token expired")
+                } else {
+                    Denial {
+                        counts_as_guess: true,
+                        ..Denial::invalid_token(INVALID_TOKEN)
+                    }
+                }
+            }
+        })?;
+
+    match serde_json::to_value(jwt_claims) {
+        Ok(serde_json::Value::Object(claims)) => Ok(claims),
+        _ => unreachable!("claims always serialize to an object"),
+    }
+}
+
+/// Verify the identities of the request, and evaluate the CEL program against
+/// them and the request headers. Returns the [Decision] if access should be
+/// granted, or the status code to respond with otherwise.
+/// Events are tagged with the key set generation, to correlate them with key
+/// rotations, the name of the policy, if configured on the server, and the
+/// principal, once the token is verified and if display
+/// claims are configured, and whether the policy allowed access, once
+/// executed.
+#[tracing::instrument(
+    name = "decision",
+    skip_all,
+    fields(
+        key_set_generation = key_store::generation(),
+        policy = policy.as_ref().and_then(|p| p.name.as_deref()),
+        principal = tracing::field::Empty,
+        allowed = tracing::field::Empty
+    )
+)]
+async fn decide(
+    state: &AppState,
+    token: Option<&str>,
+    policy: Option<Policy>,
+    verification_config: &VerificationConfig,
+    allowed_algs: Option<&[String]>,
+    mut headers: axum::http::HeaderMap,
+) -> Result<Decision, Denial> {
+    let AppState {
+        reloadable,
+        cel_programs,
+        baggage_claims,
+        identity_headers,
+        cel_timeout,
+        forwarded_for,
+        client_cert,
+        principal_claims,
+        tenant_usage,
+        combiner,
+        policy_denial_status,
+        assertion_signer,
+        sensitive_headers,
+        non_utf8_headers,
+        login,
+        decision_cache,
+        ..
+    } = state;
+
+    // continue the trace of the proxy, before spans are started within.
+    otlp::continue_trace(&headers);
+    // referenced by exemplars of the decision metrics.
+    metrics::record_trace();
+
+    // options of the request take precedence over those of the policy.
+    let verification_config = &match &policy {
+        Some(policy) => verification_config.or(&policy.verification),
+        None => verification_config.clone(),
+    };
+
+    forwarded_for.validate(&mut headers).map_err(|e| {
+        debug!(err = %e, "invalid X-Forwarded-For header");
+        StatusCode::BAD_REQUEST
+    })?;
+    non_utf8_headers.apply(&mut headers).map_err(|e| {
+        debug!(err = %e, "rejecting request with non-UTF-8 header");
+        StatusCode::BAD_REQUEST
+    })?;
+    audit::note(|details| {
+        details.client_ip = forwarded_for.client_ip(&headers);
+        if let Some(policy) = &policy {
+            details.set_policy(policy);
+        }
+    });
 
-            StatusCode::UNAUTHORIZED
+    // skip verifying the token and executing the policy if decided on
+    // before, and the decision only depends on them, see [decision_cache].
+    // Opaque tokens are introspected every time, as they may have become
+    // inactive since.
+    let cache_key = match (decision_cache, token, &policy) {
+        (Some(_), Some(token), Some(policy))
+            if client_cert.is_none()
+                && reloadable.load().api_keys.is_none()
+                && baggage_claims.is_empty()
+                && assertion_signer.is_none()
+                && !(state.introspector.is_some() && introspection::is_opaque(token)) =>
+        {
+            Some(DecisionCache::key(
+                token,
+                policy,
+                verification_config,
+                allowed_algs,
+            ))
+        }
+        _ => None,
+    };
+    if let (Some(cache), Some(key), Some(token)) = (decision_cache, &cache_key, token) {
+        if let Some(cached) = cache.get(key) {
+            // the claims may have been revoked since, or fail custom checks.
+            state
+                .claim_validators
+                .validate(token, &cached.claims)
+                .await?;
+            audit::note(|details| details.identity = cached.identity.clone());
+            if let Some(principal) = &cached.principal {
+                tracing::Span::current().record("principal", principal);
+            }
+            if let (Some(tenant_usage), Some(tenant)) = (tenant_usage, &cached.tenant) {
+                tenant_usage.record(tenant.clone());
+            }
+            return Ok(Decision {
+                token_scopes: cached.token_scopes.clone(),
+                baggage: None,
+                headers: cached.headers.clone(),
+                session_cookie: None,
+            });
+        }
+    }
+
+    let (jwt_claims, session_cookie) = match token {
+        Some(token) => {
+            match verify_token(state, token, verification_config, allowed_algs, &headers).await {
+                Ok((claims, session_cookie)) => (Some(Ok(claims)), session_cookie),
+                Err(denial) => (Some(Err(denial)), None),
+            }
+        }
+        // browsers that logged in carry a session instead.
+        None => match login {
+            Some(login) => (login.session(&headers).await.map(Ok), None),
+            None => (None, None),
+        },
+    };
+    // the proxy verified the certificate already.
+    let cert = client_cert.as_ref().map(|source| source.extract(&headers));
+    let api_key = reloadable.load().api_keys.as_ref().map(|keys| {
+        match headers.get(api_keys::X_API_KEY).map(|v| v.to_str()) {
+            None => identities::Verification::Absent,
+            Some(key) => match key.ok().and_then(|key| keys.verify(key)) {
+                Some(name) => identities::Verification::Verified(Some(name.to_owned())),
+                None => identities::Verification::Failed(StatusCode::UNAUTHORIZED),
+            },
+        }
+    });
+
+    let identities = identities::Identities {
+        jwt: match &jwt_claims {
+            None => identities::Verification::Absent,
+            Some(Ok(_)) => identities::Verification::Verified(None),
+            Some(Err(denial)) => identities::Verification::Failed(denial.status),
+        },
+        client_cert: cert.as_ref().map(|cert| match cert {
+            Some(_) => identities::Verification::Verified(None),
+            None => identities::Verification::Absent,
+        }),
+        api_key,
+    };
+    combiner
+        .combine(
+            &identities,
+            policy.as_ref().and_then(|p| p.min_identity_weight),
+        )
+        // report the token's problem, if it's what's missing.
+        .map_err(|status| match &jwt_claims {
+            Some(Err(denial)) if denial.status == status => denial.clone(),
+            _ => status.into(),
         })?;
+    let mut jwt_claims = jwt_claims.and_then(Result::ok).unwrap_or_default();
+    let audit_identity = audit::Identity::from_claims(&jwt_claims);
+    audit::note(|details| details.identity = audit_identity.clone());
+
+    let principal = (!principal_claims.is_empty())
+        .then(|| principal::render(&jwt_claims, principal_claims))
+        .flatten();
+    if let Some(principal) = &principal {
+        tracing::Span::current().record("principal", principal);
+    }
+    // tokens bound to a key need to be checked against a proof every time.
+    let cache_key = cache_key.filter(|_| !jwt_claims.contains_key("cnf"));
+    let cached_claims = cache_key.is_some().then(|| jwt_claims.clone());
+    let token_exp = jwt_claims.get("exp").and_then(|exp| exp.as_f64());
 
-    let cel_str = params.cel_str.ok_or_else(|| {
-        warn!("no CEL program specified, rejecting request");
+    let policy = policy.ok_or_else(|| {
+        warn!("no CEL program specified and no default set, rejecting request");
         StatusCode::UNAUTHORIZED
     })?;
 
+    let tenant = tenant_usage.as_ref().map(|u| u.tenant(&jwt_claims));
+    let token_scopes = scopes::token_scopes(&jwt_claims);
+    let baggage = (!baggage_claims.is_empty())
+        .then(|| {
+            let incoming = headers.get(baggage::BAGGAGE).and_then(|v| v.to_str().ok());
+            baggage::render(&jwt_claims, baggage_claims, incoming)
+        })
+        .flatten();
+    // identity headers override those of the policy.
+    let mut response_headers = policy.headers.clone();
+    let identity = identity_headers::render(&jwt_claims, identity_headers);
+    response_headers.retain(|(name, _)| identity.iter().all(|(n, _)| n != name));
+    response_headers.extend(identity);
+    // the subject and original host, to sign the assertion with.
+    let subject = jwt_claims
+        .get("sub")
+        .and_then(|sub| sub.as_str())
+        .map(str::to_owned);
+    let host = headers
+        .get(context_request::X_FORWARDED_HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+
+    // the identities are verified, keep credentials away from the program.
+    sensitive_headers.strip(&mut headers);
+
     // populate the context
     let context = {
         let mut context = cel_interpreter::Context::default();
+        cel_functions::register(&mut context);
+
+        // add the time of the decision
+        context.add_variable_from_value(context_schema::NOW.name, cel_functions::now());
+
+        let client_ip = forwarded_for.client_ip(&headers);
+
+        // add details about the original request
+        context.add_variable_from_value(
+            context_schema::REQUEST.name,
+            context_request::parse_request(&headers, client_ip),
+        );
+
+        // add the original URL, if known
+        context.add_variable_from_value(
+            context_schema::URL.name,
+            context_request::parse_url(&headers),
+        );
+
+        // add the client address, if known
+        context.add_variable_from_value(
+            context_schema::CLIENT_IP.name,
+            client_ip.map(|ip| ip.to_string()),
+        );
+
+        // add the location of the client, if configured
+        if let Some(geoip) = &reloadable.load().geoip {
+            context.add_variable_from_value(
+                context_schema::GEO.name,
+                client_ip.map_or(Value::Null, |ip| geoip.lookup(ip).to_value()),
+            );
+        }
+
+        // add the client certificate, if configured
+        if let Some(cert) = cert {
+            context.add_variable_from_value(
+                context_schema::CLIENT_CERT.name,
+                cert.map_or(Value::Null, |cert| cert.to_value()),
+            );
+        }
+
+        // add the verification result of each identity
+        context.add_variable_from_value(context_schema::IDENTITIES.name, identities.to_value());
 
         // add request headers
         context
             .add_variable(
-                "request_headers",
-                context_headers::parse_headers(rq.headers().to_owned()),
+                context_schema::REQUEST_HEADERS.name,
+                context_headers::parse_headers(headers, *non_utf8_headers),
             )
             .expect("add request_headers must not fail");
 
+        // add the configured constants
+        for (name, value) in &reloadable.load().cel_constants {
+            context.add_variable_from_value(name.clone(), value.clone());
+        }
+
+        // add feature flags, if configured
+        if let Some(flags) = &reloadable.load().flags {
+            context.add_variable_from_value(
+                context_schema::FLAGS.name,
+                flags.values(std::time::SystemTime::now()),
+            );
+        }
+
         // add JWT-related fields
-        context
-            .add_variable("jwt_claims", jwt_claims)
-            .expect("add jwt_claims must not fail");
+        context.add_variable_from_value(
+            context_schema::JWT.name,
+            context_jwt::standard_claims(&jwt_claims),
+        );
+        context.add_variable_from_value(
+            context_schema::ACTORS.name,
+            context_jwt::actors(&jwt_claims, policy.numbers),
+        );
+        policy.filter_claims(&mut jwt_claims);
+        policy.coerce_claims(&mut jwt_claims);
+        context.add_variable_from_value(
+            context_schema::JWT_CLAIMS.name,
+            context_jwt::to_value(jwt_claims.into(), policy.numbers),
+        );
 
         context
     };
 
-    // lookup the CEL program from the state, compile for the first time and
-    // insert if not seen yet.
-    let mut programs = cel_programs.upgradable_read();
-    let cel_result = match programs.get(&cel_str) {
-        Some(program) => program.execute(&context),
-        None => {
-            // compile the program.
-            let program = cel_interpreter::Program::compile(&cel_str).map_err(|e| {
-                warn!(err=%e, "failed to compile CEL program");
+    // lookup the CEL program from the cache, compiling it if not seen yet.
+    let cel_str = policy
+        .expand(&policy.cel, &reloadable.load().cel_macros)
+        .map_err(|e| {
+            warn!(err=%e, "failed to expand macros in CEL program");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let program = cel_programs.get_or_compile(&cel_str).map_err(|e| {
+        warn!(err=%e, "failed to compile CEL program");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let backend = match &policy.backend {
+        Some(backend) => {
+            let cel_str = policy
+                .expand(backend, &reloadable.load().cel_macros)
+                .map_err(|e| {
+                    warn!(err=%e, "failed to expand macros in backend expression");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            Some(cel_programs.get_or_compile(&cel_str).map_err(|e| {
+                warn!(err=%e, "failed to compile backend expression");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?)
+        }
+        None => None,
+    };
+    let cache_key = cache_key.filter(|_| {
+        let constants = &reloadable.load().cel_constants;
+        std::iter::once(&program)
+            .chain(&backend)
+            .all(|program| decision_cache::cacheable(program, constants))
+    });
+    let (cel_result, backend) = execute(program, backend, context, *cel_timeout).await?;
+    tracing::Span::current().record(
+        "allowed",
+        matches!(cel_result, Value::Bool(true) | Value::Map(_)),
+    );
+
+    match cel_result {
+        Value::Bool(false) => return Err((*policy_denial_status).into()),
+        Value::Bool(true) => {}
+        // allow, and set the headers, overriding identity headers.
+        Value::Map(map) => {
+            let headers = decision::policy_headers(&map).map_err(|e| {
+                warn!(err = %e, "CEL program returned invalid headers");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            response_headers.retain(|(name, _)| headers.iter().all(|(n, _)| n != name));
+            response_headers.extend(headers);
+        }
+        // deny, with the reason.
+        Value::String(reason) => {
+            debug!(%reason, "denied by policy");
+            return Err(Denial {
+                reason: Some(reason.to_string()),
+                ..(*policy_denial_status).into()
+            });
+        }
+        _ => {
+            warn!("CEL program didn't return a boolean, map or string, bailing out");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
+    }
+
+    // hint at the upstream to route to, overriding a header of that name.
+    if let Some(backend) = backend {
+        let backend = decision::backend(&backend).map_err(|e| {
+            warn!(err = %e, "backend expression returned an invalid backend");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        response_headers.retain(|(name, _)| name != decision::X_AUTH_BACKEND);
+        if let Some(backend) = backend {
+            response_headers.push((HeaderName::from_static(decision::X_AUTH_BACKEND), backend));
+        }
+    }
+
+    // sign the headers returned, for the host of the original request.
+    if let Some(signer) = assertion_signer {
+        let assertion = signer
+            .sign(subject.as_deref(), host.as_deref(), &response_headers)
+            .map_err(|e| {
+                warn!(err = %e, "failed to sign assertion");
                 StatusCode::INTERNAL_SERVER_ERROR
             })?;
+        response_headers.push((
+            HeaderName::from_static(assertion::X_AUTH_ASSERTION),
+            assertion,
+        ));
+    }
 
-            // execute with the context
-            let result = program.execute(&context);
+    if let (Some(cache), Some(key), Some(claims)) = (decision_cache, cache_key, cached_claims) {
+        let decision = decision_cache::CachedDecision {
+            claims,
+            token_scopes: token_scopes.clone(),
+            headers: response_headers.clone(),
+            tenant: tenant.clone(),
+            principal,
+            identity: audit_identity,
+        };
+        cache.insert(key, token_exp, decision);
+    }
 
-            // insert the compiled program, briefly upgrading the lock to writeable
-            programs.with_upgraded(|programs| programs.insert(cel_str, program));
+    if let (Some(tenant_usage), Some(tenant)) = (tenant_usage, tenant) {
+        tenant_usage.record(tenant);
+    }
+    Ok(Decision {
+        token_scopes,
+        baggage,
+        headers: response_headers,
+        session_cookie,
+    })
+}
 
-            result
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{io::Write, num::NonZeroUsize, sync::Arc, time::Duration};
+
+    use arc_swap::ArcSwap;
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+        response::Response,
+        Router,
+    };
+    use jwt_simple::prelude::{Claims, HS256Key, MACLike};
+    use tower::ServiceExt;
+
+    use crate::{
+        claim_validators::{ClaimValidators, Revocation},
+        forwarded_for, gen_router, identities, lockout, rate_limit, supervisor, AppState,
+        DecisionCache, HmacSource, KeySource, KeyStore, Policy, ProgramCache, Reloadable,
+        VerificationConfig,
+    };
+
+    /// The secret the tokens accepted by [state] are signed with.
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    /// A state accepting tokens signed with [SECRET], see [token], and
+    /// evaluating [default_cel] for requests without policy. The named
+    /// policy `admins` allows admins only.
+    pub(crate) fn state(default_cel: &str) -> AppState {
+        let mut secret = tempfile::NamedTempFile::new().expect("must create file");
+        secret.write_all(SECRET).unwrap();
+        let key_store = KeyStore::new(vec![KeySource::Hmac(
+            HmacSource::load(None, &[secret.path()]).expect("must load"),
+        )]);
+        let denylist = Arc::new(crate::denylist::Denylist::default());
+
+        AppState {
+            reloadable: Arc::new(ArcSwap::from_pointee(Reloadable {
+                key_store,
+                verification_defaults: VerificationConfig::default(),
+                policies: [(
+                    "admins".to_string(),
+                    Policy {
+                        name: Some("admins".into()),
+                        ..Policy::from(r#"jwt_claims.sub == "admin""#.to_string())
+                    },
+                )]
+                .into(),
+                default_policy: Some(Policy::from(default_cel.to_string())),
+                flags: None,
+                cel_constants: Default::default(),
+                cel_macros: Default::default(),
+                geoip: None,
+                api_keys: None,
+            })),
+            cel_programs: Arc::new(ProgramCache::new(NonZeroUsize::new(16).unwrap())),
+            introspector: None,
+            dpop: Default::default(),
+            baggage_claims: [].into(),
+            identity_headers: [].into(),
+            cel_timeout: None,
+            forwarded_for: forwarded_for::ForwardedFor {
+                max_entries: 10,
+                on_violation: forwarded_for::OnViolation::Deny,
+                depth: 1,
+            },
+            client_cert: None,
+            principal_claims: [].into(),
+            expired_tokens: None,
+            tenant_usage: None,
+            clock_check: None,
+            combiner: identities::Combiner::default(),
+            sensitive_headers: Default::default(),
+            non_utf8_headers: Default::default(),
+            login: None,
+            bearer_sessions: None,
+            realm: "cellulose".into(),
+            assertion_signer: None,
+            policy_denial_status: StatusCode::FORBIDDEN,
+            supervisor: supervisor::Supervisor::default(),
+            decision_cache: None,
+            denylist: denylist.clone(),
+            claim_validators: ClaimValidators::default().with(Revocation(denylist)),
+            rate_limiter: None,
+            lockout: None,
         }
     }
-    .map_err(|e| {
-        warn!(err=%e, "failed to execute CEL program");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
 
-    match cel_result {
-        Value::Bool(true) => Ok("Access granted"),
-        Value::Bool(false) => Err(StatusCode::UNAUTHORIZED),
-        _ => {
-            warn!("CEL program didn't return boolean, bailing out");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+    /// A token for [sub] accepted by [state], with [sub] as `jti` too.
+    pub(crate) fn token(sub: &str) -> String {
+        HS256Key::from_bytes(SECRET)
+            .authenticate(
+                Claims::create(jwt_simple::prelude::Duration::from_mins(5))
+                    .with_subject(sub)
+                    .with_jwt_id(sub),
+            )
+            .expect("must authenticate")
+    }
+
+    pub(crate) fn router(state: AppState) -> Router {
+        gen_router(
+            Default::default(),
+            forwarded_for::TrustedProxies {
+                proxies: [].into(),
+                on_untrusted: forwarded_for::OnUntrusted::Deny,
+                extra_headers: [].into(),
+            },
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            None,
+            None,
+        )
+        .with_state(state)
+    }
+
+    /// Ask [router] for a decision on a request from [ip] with [token].
+    async fn auth(router: &Router, uri: &str, token: Option<&str>, ip: &str) -> Response {
+        let mut rq = Request::get(uri).header("x-forwarded-for", ip);
+        if let Some(token) = token {
+            rq = rq.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        router
+            .clone()
+            .oneshot(rq.body(Body::empty()).unwrap())
+            .await
+            .expect("infallible")
+    }
+
+    #[tokio::test]
+    async fn decisions() {
+        let router = router(state(r#"jwt_claims.sub == "alice""#));
+        let ip = "192.0.2.1";
+
+        let response = auth(&router, "/auth", Some(&token("alice")), ip).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let response = auth(&router, "/auth", Some(&token("bob")), ip).await;
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+        // without or with an invalid token
+        let response = auth(&router, "/auth", None, ip).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let forged = HS256Key::generate()
+            .authenticate(Claims::create(jwt_simple::prelude::Duration::from_mins(5)))
+            .unwrap();
+        let response = auth(&router, "/auth", Some(&forged), ip).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert!(response.headers()[header::WWW_AUTHENTICATE]
+            .to_str()
+            .unwrap()
+            .contains(r#"error="invalid_token""#));
+
+        // named policies, and programs sent along
+        let response = auth(&router, "/auth?policy=admins", Some(&token("admin")), ip).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let response = auth(&router, "/auth?policy=admins", Some(&token("alice")), ip).await;
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        let response = auth(&router, "/auth?policy=unknown", Some(&token("alice")), ip).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        let uri = "/auth?cel_str=jwt_claims.sub%20%3D%3D%20%22bob%22";
+        let response = auth(&router, uri, Some(&token("bob")), ip).await;
+        assert_eq!(StatusCode::OK, response.status());
+
+        // admin endpoints are only served on the admin listener
+        for uri in ["/-/metrics", "/-/context-schema"] {
+            let response = auth(&router, uri, None, ip).await;
+            assert_eq!(StatusCode::NOT_FOUND, response.status());
         }
     }
+
+    #[tokio::test]
+    async fn lockout() {
+        let state = AppState {
+            lockout: Some(Arc::new(lockout::Lockout::new(
+                2,
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+            ))),
+            ..state(r#"jwt_claims.sub == "alice""#)
+        };
+        let router = router(state);
+        let forged = HS256Key::generate()
+            .authenticate(Claims::create(jwt_simple::prelude::Duration::from_mins(5)))
+            .unwrap();
+
+        // denials by the policy don't count, invalid signatures do
+        for _ in 0..3 {
+            let response = auth(&router, "/auth", Some(&token("bob")), "192.0.2.1").await;
+            assert_eq!(StatusCode::FORBIDDEN, response.status());
+        }
+        for _ in 0..2 {
+            let response = auth(&router, "/auth", Some(&forged), "192.0.2.1").await;
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        }
+        let response = auth(&router, "/auth", Some(&token("alice")), "192.0.2.1").await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+
+        // other clients aren't affected
+        let response = auth(&router, "/auth", Some(&token("alice")), "192.0.2.2").await;
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let limit = rate_limit::Limit {
+            per_second: 0.001,
+            burst: 2,
+        };
+        let state = AppState {
+            rate_limiter: Some(Arc::new(rate_limit::RateLimiter::new(Some(limit), None))),
+            ..state("true")
+        };
+        let router = router(state);
+
+        for _ in 0..2 {
+            let response = auth(&router, "/auth", Some(&token("alice")), "192.0.2.1").await;
+            assert_eq!(StatusCode::OK, response.status());
+        }
+        let response = auth(&router, "/auth", Some(&token("alice")), "192.0.2.1").await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        let response = auth(&router, "/auth", Some(&token("alice")), "192.0.2.2").await;
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[tokio::test]
+    async fn decision_cache() {
+        let cache = Arc::new(DecisionCache::new(Duration::from_secs(60)));
+        let state = AppState {
+            decision_cache: Some(cache.clone()),
+            ..state(r#"jwt_claims.sub == "alice""#)
+        };
+        let denylist = state.denylist.clone();
+        let router = router(state);
+        let (alice, bob) = (token("alice"), token("bob"));
+
+        // only allows are cached
+        let response = auth(&router, "/auth", Some(&alice), "192.0.2.1").await;
+        assert_eq!(StatusCode::OK, response.status());
+        let response = auth(&router, "/auth", Some(&bob), "192.0.2.1").await;
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        assert_eq!(1, cache.len());
+
+        // hits are answered the same
+        let response = auth(&router, "/auth", Some(&alice), "192.0.2.1").await;
+        assert_eq!(StatusCode::OK, response.status());
+
+        // but still denied once revoked
+        denylist.add("jti:alice".to_string(), None).unwrap();
+        let response = auth(&router, "/auth", Some(&alice), "192.0.2.1").await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
 }
@@ -0,0 +1,123 @@
+//! Validation of the addresses of the listeners (main, admin, SPOE) before
+//! binding any of them, so conflicts fail startup with an error naming both
+//! listeners, rather than the OS error of whichever happens to bind second,
+//! or, worse, admin endpoints ending up reachable on the address proxies
+//! send /auth requests to.
+use std::{fmt, net::SocketAddr};
+
+use tokio_listener::ListenerAddress;
+
+#[derive(Debug)]
+pub struct ListenerError(String);
+
+impl fmt::Display for ListenerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ListenerError {}
+
+fn err(msg: String) -> ListenerError {
+    ListenerError(msg)
+}
+
+/// Whether both sockets can't be bound at the same time. Unspecified
+/// addresses overlap with all of their family, `[::]` also with IPv4 ones,
+/// as it's dual-stack by default. Port 0 picks a free one.
+fn tcp_conflict(a: &SocketAddr, b: &SocketAddr) -> bool {
+    if a.port() != b.port() || a.port() == 0 {
+        return false;
+    }
+    let covers = |a: &SocketAddr, b: &SocketAddr| {
+        a.ip().is_unspecified() && (a.is_ipv6() || a.is_ipv4() == b.is_ipv4())
+    };
+    a.ip() == b.ip() || covers(a, b) || covers(b, a)
+}
+
+/// Whether both addresses can't be used by different listeners.
+fn conflict(a: &ListenerAddress, b: &ListenerAddress) -> bool {
+    use ListenerAddress::*;
+    match (a, b) {
+        (Tcp(a), Tcp(b)) => tcp_conflict(a, b),
+        (Path(a), Path(b)) => {
+            let absolute = |p| std::path::absolute(p).ok();
+            a == b || absolute(a).is_some_and(|a| Some(a) == absolute(b))
+        }
+        (Abstract(a), Abstract(b)) => a == b,
+        // there's only one stdin.
+        (Inetd, Inetd) => true,
+        (FromFd(a), FromFd(b)) => a == b,
+        (FromFdNamed(a), FromFdNamed(b)) => a == b || a == "*" || b == "*",
+        // all passed sockets are taken.
+        (FromFdNamed(a), FromFd(_)) | (FromFd(_), FromFdNamed(a)) => a == "*",
+        _ => false,
+    }
+}
+
+/// Check that none of the listeners, by name and address, conflict.
+pub fn check(listeners: &[(&str, &ListenerAddress)]) -> Result<(), ListenerError> {
+    for (i, (name, address)) in listeners.iter().enumerate() {
+        for (other_name, other_address) in &listeners[..i] {
+            if !conflict(address, other_address) {
+                continue;
+            }
+            let mut msg = format!(
+                "{} listener address {} conflicts with {} listener address {}",
+                name, address, other_name, other_address
+            );
+            if [*name, *other_name].contains(&"admin") {
+                msg.push_str(", admin endpoints need a listener of their own");
+            }
+            return Err(err(msg));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_listener::ListenerAddress;
+
+    use super::{check, conflict};
+
+    #[test]
+    fn conflicts() {
+        let addr = |s: &str| s.parse::<ListenerAddress>().unwrap();
+        for (a, b) in [
+            ("127.0.0.1:9000", "127.0.0.1:9000"),
+            ("[::]:9000", "127.0.0.1:9000"),
+            ("0.0.0.0:9000", "10.0.0.1:9000"),
+            ("[::]:9000", "[::1]:9000"),
+            ("/run/cellulose.sock", "/run/cellulose.sock"),
+            ("@cellulose", "@cellulose"),
+            ("sd-listen", "sd-listen"),
+            ("sd-listen:*", "sd-listen:admin"),
+        ] {
+            assert!(conflict(&addr(a), &addr(b)), "{} {}", a, b);
+            assert!(conflict(&addr(b), &addr(a)), "{} {}", b, a);
+        }
+        for (a, b) in [
+            ("127.0.0.1:9000", "127.0.0.1:9001"),
+            ("127.0.0.1:9000", "127.0.0.2:9000"),
+            ("0.0.0.0:9000", "[::1]:9000"),
+            ("127.0.0.1:0", "127.0.0.1:0"),
+            ("/run/cellulose.sock", "/run/admin.sock"),
+            ("sd-listen:main", "sd-listen:admin"),
+            ("127.0.0.1:9000", "/run/cellulose.sock"),
+        ] {
+            assert!(!conflict(&addr(a), &addr(b)), "{} {}", a, b);
+        }
+
+        let main = addr("[::]:9000");
+        let admin = addr("127.0.0.1:9000");
+        let spoe = addr("127.0.0.1:9001");
+        assert!(check(&[("main", &main), ("SPOE", &spoe)]).is_ok());
+        let e = check(&[("main", &main), ("SPOE", &spoe), ("admin", &admin)])
+            .expect_err("must conflict");
+        assert_eq!(
+            "admin listener address 127.0.0.1:9000 conflicts with main listener address [::]:9000, admin endpoints need a listener of their own",
+            e.to_string()
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Load shedding on the main listener: a limit of requests in flight, and a
+//! timeout for each of them.
+//!
+//! Requests exceeding the limit are answered with a 503 right away, rather
+//! than queueing up without bounds, and so are requests taking longer than
+//! the timeout, rather than leaving the proxy to time out on its own. Either
+//! way, the proxy gets an answer it can be configured to fail open or closed
+//! on, and clients a Retry-After.
+//!
+//! /healthz is exempt, so liveness probes don't restart instances that are
+//! merely overloaded, while /readyz is shed like everything else, taking
+//! them out of rotation.
+use std::{future::Future, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::{sync::Semaphore, time};
+use tracing::debug;
+
+use crate::{metrics::METRICS, Denial};
+
+/// How long clients are asked to wait before retrying shed requests.
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Default)]
+pub struct LoadShedding {
+    /// Permits for requests in flight, if limited.
+    in_flight: Option<Arc<Semaphore>>,
+    /// How long requests may take, if limited.
+    timeout: Option<Duration>,
+}
+
+impl LoadShedding {
+    /// Allow at most [max_in_flight] requests at once, each taking at most
+    /// [timeout], if set.
+    pub fn new(max_in_flight: Option<usize>, timeout: Option<Duration>) -> Self {
+        Self {
+            in_flight: max_in_flight.map(|n| Arc::new(Semaphore::new(n.max(1)))),
+            timeout,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.in_flight.is_some() || self.timeout.is_some()
+    }
+
+    /// Run [handle] to answer a request, unless too many are in flight, or
+    /// it takes too long.
+    pub async fn run(&self, handle: impl Future<Output = Response>) -> Response {
+        let _permit = match &self.in_flight {
+            Some(in_flight) => match in_flight.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    debug!("too many requests in flight, shedding");
+                    return shed("concurrency");
+                }
+            },
+            None => None,
+        };
+        match self.timeout {
+            Some(timeout) => time::timeout(timeout, handle).await.unwrap_or_else(|_| {
+                debug!(?timeout, "request timed out, shedding");
+                shed("timeout")
+            }),
+            None => handle.await,
+        }
+    }
+}
+
+fn shed(reason: &str) -> Response {
+    METRICS.shed_requests.with_labels(&[reason]).inc();
+    Denial {
+        retry_after: Some(RETRY_AFTER),
+        ..StatusCode::SERVICE_UNAVAILABLE.into()
+    }
+    .into_response()
+}
+
+/// Middleware shedding requests exceeding the limits, see the module docs.
+pub async fn apply(State(shedding): State<LoadShedding>, rq: Request, next: Next) -> Response {
+    if rq.uri().path() == "/healthz" {
+        return next.run(rq).await;
+    }
+    shedding.run(next.run(rq)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        http::{header, StatusCode},
+        response::IntoResponse,
+    };
+    use tokio::{sync::oneshot, time};
+
+    use super::LoadShedding;
+
+    #[tokio::test(start_paused = true)]
+    async fn shed() {
+        let shedding = LoadShedding::new(Some(1), Some(Duration::from_secs(10)));
+        let slow = |secs| async move {
+            time::sleep(Duration::from_secs(secs)).await;
+            ().into_response()
+        };
+
+        // the first request holds the only permit
+        let (started_tx, started_rx) = oneshot::channel();
+        let first = tokio::spawn({
+            let shedding = shedding.clone();
+            async move {
+                shedding
+                    .run(async move {
+                        started_tx.send(()).unwrap();
+                        slow(5).await
+                    })
+                    .await
+            }
+        });
+        started_rx.await.unwrap();
+        let response = shedding.run(slow(0)).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!("1", response.headers()[header::RETRY_AFTER]);
+        assert_eq!(StatusCode::OK, first.await.unwrap().status());
+
+        // and releases it once done
+        assert_eq!(StatusCode::OK, shedding.run(slow(9)).await.status());
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            shedding.run(slow(11)).await.status()
+        );
+        assert!(!LoadShedding::default().enabled());
+    }
+}
@@ -0,0 +1,320 @@
+//! Keys cellulose generates itself, to sign assertions and encrypt bearer
+//! session cookies with, rotated periodically and persisted in the state
+//! directory, so they survive restarts and nobody needs to rotate them by
+//! hand.
+//!
+//! A new key is generated once the newest one is older than the rotation
+//! period. It's published right away (in the JWKS of assertion keys), but
+//! only used after a quarter of the period, so verifiers caching the keys
+//! pick it up before. Keys are accepted (for decrypting sessions) and
+//! published until twice the period after they were generated, well after
+//! the next one took over. Artifacts carry the id of the key they were made
+//! with.
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use jwt_simple::{
+    prelude::ES256KeyPair,
+    reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder},
+};
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::{assertion, bearer_sessions::BearerSessions};
+
+/// How often keys are checked for being due for rotation.
+const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+pub struct LocalKeyError(String);
+
+impl fmt::Display for LocalKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LocalKeyError {}
+
+fn err(msg: String) -> LocalKeyError {
+    LocalKeyError(msg)
+}
+
+/// A key, as persisted.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct LocalKey {
+    kid: String,
+    /// When the key was generated, in seconds since the epoch.
+    created: u64,
+    /// The key material, base64url-encoded.
+    secret: String,
+}
+
+impl LocalKey {
+    fn generate(created: u64, secret: &[u8]) -> Self {
+        let mut kid = [0; 8];
+        OsRng.fill_bytes(&mut kid);
+        Self {
+            kid: kid.iter().map(|b| format!("{:02x}", b)).collect(),
+            created,
+            secret: Base64UrlSafeNoPadding::encode_to_string(secret).expect("must encode"),
+        }
+    }
+
+    fn secret(&self) -> Result<Vec<u8>, LocalKeyError> {
+        Base64UrlSafeNoPadding::decode_to_vec(&self.secret, None)
+            .map_err(|e| err(format!("invalid secret of key {}: {}", self.kid, e)))
+    }
+}
+
+/// Generate a key if the newest of [keys] is older than [period] at [now],
+/// and drop the ones older than twice that. Returns whether keys changed.
+fn rotate(
+    keys: &mut Vec<LocalKey>,
+    now: u64,
+    period: Duration,
+    generate: impl Fn() -> Vec<u8>,
+) -> bool {
+    let period = period.as_secs();
+    let before = keys.len();
+    keys.retain(|key| now.saturating_sub(key.created) < 2 * period);
+    let due = keys
+        .iter()
+        .map(|key| key.created)
+        .max()
+        .is_none_or(|newest| now.saturating_sub(newest) >= period);
+    if due {
+        keys.push(LocalKey::generate(now, &generate()));
+    }
+    due || keys.len() != before
+}
+
+/// The index of the key to use at [now]: the newest one that's been
+/// published for a quarter of [period], or the newest one if none has been
+/// yet.
+fn active(keys: &[LocalKey], now: u64, period: Duration) -> Option<usize> {
+    let lead = period.as_secs() / 4;
+    let newest = |published_for: u64| {
+        (keys.iter().enumerate())
+            .filter(|(_, key)| now.saturating_sub(key.created) >= published_for)
+            .max_by_key(|(_, key)| key.created)
+            .map(|(i, _)| i)
+    };
+    newest(lead).or_else(|| newest(0))
+}
+
+fn load(path: &Path) -> Result<Vec<LocalKey>, LocalKeyError> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map_err(|e| err(format!("failed to parse {}: {}", path.display(), e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(err(format!("failed to read {}: {}", path.display(), e))),
+    }
+}
+
+/// Write the keys via a temporary file, readable by the owner only, so a
+/// crash doesn't leave a truncated file behind.
+fn save(path: &Path, keys: &[LocalKey]) -> Result<(), LocalKeyError> {
+    let write = || {
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(keys)?)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        std::fs::rename(tmp_path, path)
+    };
+    write().map_err(|e| err(format!("failed to write {}: {}", path.display(), e)))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Rotates the local keys of the features using them, see the module docs.
+#[derive(Clone)]
+pub struct LocalKeys {
+    state_dir: PathBuf,
+    period: Duration,
+    assertion_signer: Option<Arc<assertion::Signer>>,
+    bearer_sessions: Option<BearerSessions>,
+}
+
+impl LocalKeys {
+    /// Rotate keys every [period], keeping them in [state_dir].
+    pub fn new(state_dir: PathBuf, period: Duration) -> Self {
+        Self {
+            state_dir,
+            period,
+            assertion_signer: None,
+            bearer_sessions: None,
+        }
+    }
+
+    /// Sign assertions with local keys.
+    pub fn with_assertion_signer(mut self, signer: Arc<assertion::Signer>) -> Self {
+        self.assertion_signer = Some(signer);
+        self
+    }
+
+    /// Encrypt bearer sessions with local keys.
+    pub fn with_bearer_sessions(mut self, bearer_sessions: BearerSessions) -> Self {
+        self.bearer_sessions = Some(bearer_sessions);
+        self
+    }
+
+    /// Rotate the keys in the file [name] in the state dir, if due, and
+    /// return them, along with the index of the active one.
+    fn rotate_file(
+        &self,
+        name: &str,
+        generate: impl Fn() -> Vec<u8>,
+    ) -> Result<(Vec<LocalKey>, usize), LocalKeyError> {
+        std::fs::create_dir_all(&self.state_dir).map_err(|e| {
+            err(format!(
+                "failed to create {}: {}",
+                self.state_dir.display(),
+                e
+            ))
+        })?;
+        let path = self.state_dir.join(name);
+        let mut keys = load(&path)?;
+        let now = now();
+        if rotate(&mut keys, now, self.period, generate) {
+            save(&path, &keys)?;
+            info!(path = %path.display(), "rotated local keys");
+        }
+        let active = active(&keys, now, self.period).expect("rotation leaves a key");
+        Ok((keys, active))
+    }
+
+    /// Rotate the keys, if due, and hand them to the features using them.
+    pub fn rotate(&self) -> Result<(), LocalKeyError> {
+        if let Some(signer) = &self.assertion_signer {
+            let (keys, active) = self.rotate_file("assertion-keys.json", || {
+                ES256KeyPair::generate().to_bytes()
+            })?;
+            let published = (keys.iter())
+                .map(|key| {
+                    ES256KeyPair::from_bytes(&key.secret()?)
+                        .map(|key_pair| key_pair.with_key_id(&key.kid))
+                        .map_err(|e| err(format!("invalid key {}: {}", key.kid, e)))
+                })
+                .collect::<Result<_, _>>()?;
+            signer.set_keys(published, active);
+        }
+        if let Some(bearer_sessions) = &self.bearer_sessions {
+            let (keys, active) = self.rotate_file("bearer-session-keys.json", || {
+                let mut key = vec![0; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            })?;
+            let keys = (keys.iter())
+                .map(|key| Ok((key.kid.clone(), key.secret()?)))
+                .collect::<Result<Vec<_>, LocalKeyError>>()?;
+            bearer_sessions.set_keys(&keys, &keys[active].0);
+        }
+        Ok(())
+    }
+
+    /// Rotate keys periodically.
+    pub async fn run(self) {
+        let mut interval = time::interval(ROTATION_CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.rotate() {
+                warn!(err = %e, "failed to rotate local keys");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::http::{header, HeaderMap, HeaderValue};
+
+    use super::{active, rotate, LocalKeys};
+    use crate::{assertion::Signer, bearer_sessions::BearerSessions, VerificationConfig};
+
+    #[test]
+    fn rotation() {
+        let period = Duration::from_secs(400);
+        let mut keys = vec![];
+        assert!(rotate(&mut keys, 1000, period, || vec![1]));
+        assert_eq!(1, keys.len());
+        // used right away, as there's no other one
+        assert_eq!(Some(0), active(&keys, 1000, period));
+        assert!(!rotate(&mut keys, 1399, period, || vec![2]));
+
+        // the next one is published, but used only after 100s
+        assert!(rotate(&mut keys, 1400, period, || vec![2]));
+        assert_eq!(2, keys.len());
+        assert_eq!(Some(0), active(&keys, 1499, period));
+        assert_eq!(Some(1), active(&keys, 1500, period));
+
+        // the first one is dropped after twice the period
+        assert!(!rotate(&mut keys, 1799, period, || vec![3]));
+        assert!(rotate(&mut keys, 1800, period, || vec![3]));
+        assert_eq!(
+            vec![1400, 1800],
+            keys.iter().map(|k| k.created).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn features() {
+        let dir = tempfile::tempdir().unwrap();
+        let signer = Arc::new(Signer::generate(Duration::from_secs(60)));
+        let bearer_sessions = BearerSessions::new(Duration::from_secs(60), None);
+        let local_keys = LocalKeys::new(dir.path().to_owned(), Duration::from_secs(3600))
+            .with_assertion_signer(signer.clone())
+            .with_bearer_sessions(bearer_sessions.clone());
+        local_keys.rotate().expect("must rotate");
+
+        let kid = signer.jwks()["keys"][0]["kid"].clone();
+        let claims = serde_json::json!({ "sub": "alice" });
+        let claims = claims.as_object().unwrap();
+        let options = VerificationConfig::default();
+        let cookie = bearer_sessions
+            .issue(&HeaderMap::new(), "a.b.c", &options, None, claims)
+            .expect("must issue");
+        let value = cookie
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+        assert!(value.contains('.'), "{}", value);
+
+        // the same keys after a restart
+        let signer = Arc::new(Signer::generate(Duration::from_secs(60)));
+        let bearer_sessions = BearerSessions::new(Duration::from_secs(60), None);
+        LocalKeys::new(dir.path().to_owned(), Duration::from_secs(3600))
+            .with_assertion_signer(signer.clone())
+            .with_bearer_sessions(bearer_sessions.clone())
+            .rotate()
+            .expect("must rotate");
+        assert_eq!(kid, signer.jwks()["keys"][0]["kid"]);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_str(&value).unwrap());
+        assert_eq!(
+            Some(claims),
+            bearer_sessions
+                .claims(&headers, "a.b.c", &options, None)
+                .as_ref()
+        );
+    }
+}
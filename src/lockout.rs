@@ -0,0 +1,136 @@
+//! Temporary bans of client addresses presenting invalid tokens repeatedly,
+//! like when guessing signatures, on top of rate limiting all requests.
+//!
+//! Failures are counted per client address (as determined from
+//! X-Forwarded-For) over a window, starting with the first one. Once they
+//! reach the threshold, the address is banned: its requests are answered
+//! with a 429 right away, without verifying tokens, until the ban ends.
+//! Expired, revoked or otherwise rejected, but properly signed tokens don't
+//! count.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+
+use crate::metrics::METRICS;
+
+/// The maximum number of addresses tracked. Once reached, the ones not
+/// banned and past their window are forgotten, and if that's not enough,
+/// failures of further addresses aren't counted.
+const MAX_TRACKED: usize = 100_000;
+
+struct Entry {
+    failures: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Bans addresses with too many invalid tokens, see the module docs.
+pub struct Lockout {
+    threshold: u32,
+    window: Duration,
+    ban: Duration,
+    entries: Mutex<HashMap<IpAddr, Entry>>,
+}
+
+impl Lockout {
+    /// Ban addresses for [ban] once they presented [threshold] invalid
+    /// tokens within [window].
+    pub fn new(threshold: u32, window: Duration, ban: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            window,
+            ban,
+            entries: Default::default(),
+        }
+    }
+
+    /// How long [ip] remains banned at [now], if it is.
+    pub fn banned(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let entries = self.entries.lock();
+        let until = entries.get(&ip)?.banned_until?;
+        Some(until.saturating_duration_since(now)).filter(|d| !d.is_zero())
+    }
+
+    /// Count an invalid token presented by [ip] at [now], banning it once
+    /// the threshold is reached.
+    pub fn record_failure(&self, ip: IpAddr, now: Instant) {
+        let mut entries = self.entries.lock();
+        let expired = |entry: &Entry| {
+            now.saturating_duration_since(entry.window_start) >= self.window
+                && entry.banned_until.is_none_or(|until| until <= now)
+        };
+        if entries.len() >= MAX_TRACKED && !entries.contains_key(&ip) {
+            entries.retain(|_, entry| !expired(entry));
+            if entries.len() >= MAX_TRACKED {
+                debug!("too many addresses with invalid tokens, not counting");
+                return;
+            }
+        }
+
+        let entry = entries.entry(ip).or_insert(Entry {
+            failures: 0,
+            window_start: now,
+            banned_until: None,
+        });
+        if expired(entry) {
+            *entry = Entry {
+                failures: 0,
+                window_start: now,
+                banned_until: None,
+            };
+        }
+        entry.failures += 1;
+        if entry.failures >= self.threshold && entry.banned_until.is_none() {
+            entry.banned_until = Some(now + self.ban);
+            warn!(%ip, failures = entry.failures, ban = ?self.ban, "banning client presenting invalid tokens");
+            METRICS.lockouts.with_labels(&[]).inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    use super::Lockout;
+
+    #[test]
+    fn ban() {
+        let lockout = Lockout::new(3, Duration::from_secs(60), Duration::from_secs(600));
+        let ip: IpAddr = [192, 0, 2, 1].into();
+        let t0 = Instant::now();
+
+        // failures spread over more than the window don't add up
+        lockout.record_failure(ip, t0);
+        lockout.record_failure(ip, t0 + Duration::from_secs(30));
+        lockout.record_failure(ip, t0 + Duration::from_secs(61));
+        assert_eq!(None, lockout.banned(ip, t0 + Duration::from_secs(61)));
+
+        let t1 = t0 + Duration::from_secs(62);
+        lockout.record_failure(ip, t1);
+        lockout.record_failure(ip, t1);
+        assert_eq!(Some(Duration::from_secs(600)), lockout.banned(ip, t1));
+        assert_eq!(None, lockout.banned([192, 0, 2, 2].into(), t1));
+
+        // failures while banned don't extend the ban
+        lockout.record_failure(ip, t1 + Duration::from_secs(100));
+        assert_eq!(
+            Some(Duration::from_secs(500)),
+            lockout.banned(ip, t1 + Duration::from_secs(100))
+        );
+        assert_eq!(None, lockout.banned(ip, t1 + Duration::from_secs(600)));
+
+        // and start over afterwards
+        let t2 = t1 + Duration::from_secs(601);
+        lockout.record_failure(ip, t2);
+        assert_eq!(None, lockout.banned(ip, t2));
+    }
+}
@@ -0,0 +1,147 @@
+//! Per-policy log levels, changeable at runtime via the admin API.
+//!
+//! Overrides are applied as directives on the decision span, like
+//! `cellulose[decision{policy=checkout}]=debug`, on top of the log filter
+//! configured on startup. Events of decisions for other policies are
+//! filtered as before, so a single policy can be debugged without drowning
+//! the logs.
+use std::{collections::BTreeMap, sync::OnceLock};
+
+use axum::{extract::Path, http::StatusCode, Json};
+use parking_lot::Mutex;
+use tracing::{info, level_filters::LevelFilter};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+static LOG_LEVELS: OnceLock<LogLevels> = OnceLock::new();
+
+pub struct LogLevels {
+    /// The filter configured on startup, in RUST_LOG syntax.
+    base: String,
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// The levels overridden per policy name.
+    policies: Mutex<BTreeMap<String, LevelFilter>>,
+}
+
+/// Build a filter from the directives, defaulting to INFO.
+pub(crate) fn build_filter(directives: &str) -> Result<EnvFilter, String> {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse(directives)
+        .map_err(|e| e.to_string())
+}
+
+/// The directives for the base filter with the policy overrides.
+fn directives(base: &str, policies: &BTreeMap<String, LevelFilter>) -> String {
+    std::iter::once(base.to_owned())
+        .chain(policies.iter().map(|(name, level)| {
+            format!(
+                "{}[decision{{policy={}}}]={}",
+                env!("CARGO_CRATE_NAME"),
+                regex::escape(name),
+                level
+            )
+        }))
+        .filter(|d| !d.is_empty())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl LogLevels {
+    /// Register the handle to the reloadable filter, built from [base].
+    pub(crate) fn init(base: String, handle: reload::Handle<EnvFilter, Registry>) {
+        let _ = LOG_LEVELS.set(Self {
+            base,
+            handle,
+            policies: Default::default(),
+        });
+    }
+
+    /// Set (or with None, reset) the level for the policy.
+    pub fn set(&self, policy: &str, level: Option<LevelFilter>) -> Result<(), String> {
+        let mut policies = self.policies.lock();
+        let mut updated = policies.clone();
+        match level {
+            Some(level) => updated.insert(policy.to_owned(), level),
+            None => updated.remove(policy),
+        };
+
+        let filter = build_filter(&directives(&self.base, &updated))?;
+        self.handle.reload(filter).map_err(|e| e.to_string())?;
+        *policies = updated;
+        Ok(())
+    }
+
+    /// The levels overridden per policy.
+    pub fn policies(&self) -> BTreeMap<String, String> {
+        self.policies
+            .lock()
+            .iter()
+            .map(|(name, level)| (name.clone(), level.to_string()))
+            .collect()
+    }
+}
+
+/// Returns the log levels, unless tracing wasn't set up via
+/// [crate::util::setup_tracing].
+fn log_levels() -> Result<&'static LogLevels, (StatusCode, String)> {
+    LOG_LEVELS.get().ok_or_else(|| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "log levels can't be changed".to_string(),
+        )
+    })
+}
+
+/// List the levels overridden per policy.
+pub async fn list() -> Result<Json<BTreeMap<String, String>>, (StatusCode, String)> {
+    Ok(Json(log_levels()?.policies()))
+}
+
+/// Override the level of a policy, passed as body (like `debug`).
+pub async fn put(
+    Path(policy): Path<String>,
+    level: String,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let level = level
+        .trim()
+        .parse::<LevelFilter>()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    log_levels()?
+        .set(&policy, Some(level))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    info!(policy, %level, "overriding log level");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reset the level of a policy to the configured filter.
+pub async fn delete(Path(policy): Path<String>) -> Result<StatusCode, (StatusCode, String)> {
+    log_levels()?
+        .set(&policy, None)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    info!(policy, "resetting log level");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tracing::level_filters::LevelFilter;
+
+    use super::{build_filter, directives};
+
+    #[test]
+    fn policy_directives() {
+        let policies = BTreeMap::from([
+            ("checkout".to_string(), LevelFilter::DEBUG),
+            ("a.b".to_string(), LevelFilter::TRACE),
+        ]);
+        let d = directives("warn", &policies);
+        assert_eq!(
+            "warn,cellulose[decision{policy=a\\.b}]=trace,cellulose[decision{policy=checkout}]=debug",
+            d
+        );
+        build_filter(&d).expect("must parse");
+        assert_eq!("", directives("", &BTreeMap::new()));
+    }
+}
@@ -0,0 +1,680 @@
+//! Logging in browsers via OpenID Connect (authorization code flow), so
+//! cellulose can guard web applications, not only APIs.
+//!
+//! Browser navigations without token are redirected to the authorization
+//! endpoint of the provider. It sends the browser back to the callback
+//! route, where the code is exchanged for an ID token. Once verified, a
+//! session is established, and its id set as cookie. Requests carrying it
+//! are decided on with the claims of the ID token, as if it was sent as
+//! bearer token.
+//!
+//! Browsers are logged out at the logout route, which ends the session, and
+//! optionally the one at the provider, too.
+//!
+//! Logins in progress and sessions are kept in a [SessionStore]. Each login
+//! is identified by a random, single-use `state`, and carries a PKCE code
+//! verifier (S256) and a `nonce`, kept along with it: the code can only be
+//! exchanged with the verifier, and the ID token needs to contain the nonce,
+//! so codes and tokens intercepted or issued for other logins are useless.
+//! The state is also bound to the browser starting the login, by a
+//! short-lived cookie with its hash, which the callback requires: otherwise,
+//! an attacker could send victims to the callback with the code of their own
+//! login, logging them in as the attacker (login CSRF).
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{AppendHeaders, IntoResponse, Response},
+};
+use jwt_simple::{
+    prelude::{HashSet, VerificationOptions},
+    reexports::ct_codecs::{Base64UrlSafeNoPadding, Encoder},
+};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::{
+    context_request::{X_FORWARDED_HOST, X_FORWARDED_METHOD, X_FORWARDED_PROTO, X_FORWARDED_URI},
+    oidc,
+    session::SessionStore,
+    util::{random_id, HTTP_CLIENT},
+    AppState, CustomClaims,
+};
+
+/// The name of the session cookie.
+pub const SESSION_COOKIE: &str = "cellulose_session";
+
+/// The name of the cookie binding a login in progress to the browser.
+pub const LOGIN_COOKIE: &str = "cellulose_login";
+
+/// How long browsers have to complete a login.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+pub struct LoginError(String);
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+fn err(msg: String) -> LoginError {
+    LoginError(msg)
+}
+
+#[derive(Clone, Debug)]
+pub struct LoginConfig {
+    /// The issuer, its endpoints are looked up via OIDC discovery.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// The URL of the callback route, as reachable by browsers.
+    pub redirect_uri: String,
+    /// The scopes to request, separated by spaces.
+    pub scopes: String,
+    /// The domain to set the session cookie for, if not only the host of
+    /// the callback route, like `example.com` to cover its subdomains.
+    /// Also used for the cookie set when starting logins, so it needs to
+    /// cover the callback route if that's on another host than the
+    /// applications.
+    pub cookie_domain: Option<String>,
+    /// Whether to also log out at the provider, if it supports it.
+    pub end_provider_session: bool,
+    /// Where to send browsers after logging out, if anywhere. When logging
+    /// out at the provider, it needs to be registered there.
+    pub post_logout_redirect_uri: Option<String>,
+}
+
+/// A login in progress, stored by state.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Pending {
+    /// The URL to send the browser back to.
+    return_to: String,
+    /// The PKCE code verifier, its challenge is sent along with the login.
+    code_verifier: String,
+    /// The nonce the ID token needs to contain.
+    nonce: String,
+}
+
+/// An established session, stored by id.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Session {
+    claims: CustomClaims,
+    /// The ID token, as hint for logging out at the provider.
+    #[serde(default)]
+    id_token: Option<String>,
+}
+
+pub struct Login {
+    config: LoginConfig,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    end_session_endpoint: Option<String>,
+    store: Arc<dyn SessionStore>,
+}
+
+/// The key of a login in progress in the store.
+fn pending_key(state: &str) -> String {
+    format!("login:{}", state)
+}
+
+/// The key of a session in the store.
+fn session_key(id: &str) -> String {
+    format!("session:{}", id)
+}
+
+/// The PKCE code challenge of the verifier, with the S256 method.
+fn code_challenge(code_verifier: &str) -> String {
+    Base64UrlSafeNoPadding::encode_to_string(Sha256::digest(code_verifier)).expect("must encode")
+}
+
+/// The value of the login cookie for the state.
+fn state_hash(state: &str) -> String {
+    Base64UrlSafeNoPadding::encode_to_string(Sha256::digest(state)).expect("must encode")
+}
+
+/// The response of the token endpoint.
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The URL of the original request, from the X-Forwarded-* headers.
+fn original_url(headers: &HeaderMap) -> Option<String> {
+    let get = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    Some(format!(
+        "{}://{}{}",
+        get(X_FORWARDED_PROTO).unwrap_or("https"),
+        get(X_FORWARDED_HOST)?,
+        get(X_FORWARDED_URI).unwrap_or("/")
+    ))
+}
+
+/// Whether the request is a browser navigating to a page, which can be
+/// sent to log in, unlike API calls or subresources.
+fn is_navigation(headers: &HeaderMap) -> bool {
+    let get = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    if get(X_FORWARDED_METHOD).is_some_and(|m| !m.eq_ignore_ascii_case("GET")) {
+        return false;
+    }
+    match get("sec-fetch-mode") {
+        Some(mode) => mode == "navigate",
+        None => get(header::ACCEPT.as_str()).is_some_and(|a| a.contains("text/html")),
+    }
+}
+
+/// The value of the cookie, if sent.
+pub(crate) fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let (k, v) = pair.trim().split_once('=')?;
+            (k == name).then_some(v)
+        })
+}
+
+impl Login {
+    /// Look up the endpoints of the issuer.
+    pub async fn discover(
+        config: LoginConfig,
+        store: Arc<dyn SessionStore>,
+    ) -> Result<Self, LoginError> {
+        let metadata = oidc::discover(&config.issuer)
+            .await
+            .map_err(|e| err(e.to_string()))?;
+        let (Some(authorization_endpoint), Some(token_endpoint)) =
+            (metadata.authorization_endpoint, metadata.token_endpoint)
+        else {
+            return Err(err(format!(
+                "{} doesn't advertise an authorization and token endpoint",
+                config.issuer
+            )));
+        };
+        if let Some(methods) = &metadata.code_challenge_methods_supported {
+            if !methods.iter().any(|m| m == "S256") {
+                return Err(err(format!(
+                    "{} doesn't support PKCE with S256",
+                    config.issuer
+                )));
+            }
+        }
+        let login = Self::new(config, authorization_endpoint, token_endpoint, store);
+        Ok(match metadata.end_session_endpoint {
+            Some(endpoint) => login.with_end_session_endpoint(endpoint),
+            None => login,
+        })
+    }
+
+    pub fn new(
+        config: LoginConfig,
+        authorization_endpoint: String,
+        token_endpoint: String,
+        store: Arc<dyn SessionStore>,
+    ) -> Self {
+        Self {
+            config,
+            authorization_endpoint,
+            token_endpoint,
+            end_session_endpoint: None,
+            store,
+        }
+    }
+
+    /// Log out at the provider's end session endpoint too, if configured.
+    pub fn with_end_session_endpoint(mut self, end_session_endpoint: String) -> Self {
+        self.end_session_endpoint = Some(end_session_endpoint);
+        self
+    }
+
+    /// Start a login, returning the URL of the authorization endpoint to
+    /// send the browser to, and its state, or None if it can't be stored.
+    async fn start(&self, return_to: String) -> Option<(String, String)> {
+        let state = random_id();
+        let pending = Pending {
+            return_to,
+            code_verifier: random_id(),
+            nonce: random_id(),
+        };
+        let code_challenge = code_challenge(&pending.code_verifier);
+        let nonce = pending.nonce.clone();
+        let pending = serde_json::to_string(&pending).ok()?;
+        if let Err(e) = self
+            .store
+            .put(&pending_key(&state), pending, LOGIN_TIMEOUT)
+            .await
+        {
+            warn!(err = %e, "failed to start login");
+            return None;
+        }
+
+        let url = reqwest::Url::parse_with_params(
+            &self.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("scope", &self.config.scopes),
+                ("state", &state),
+                ("nonce", &nonce),
+                ("code_challenge", &code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .ok()?;
+        Some((url.into(), state))
+    }
+
+    /// The redirect to log in, for browser navigations, binding the login
+    /// to the browser by the login cookie.
+    pub(crate) async fn redirect(&self, headers: &HeaderMap) -> Option<Response> {
+        if !is_navigation(headers) {
+            return None;
+        }
+        let (url, state) = self.start(original_url(headers)?).await?;
+        let cookie = self.cookie(LOGIN_COOKIE, &state_hash(&state), LOGIN_TIMEOUT)?;
+        Some(
+            (
+                StatusCode::FOUND,
+                [(header::SET_COOKIE, cookie)],
+                [(header::LOCATION, url)],
+            )
+                .into_response(),
+        )
+    }
+
+    /// The claims of the session the request carries, if it's valid.
+    pub(crate) async fn session(&self, headers: &HeaderMap) -> Option<CustomClaims> {
+        let id = cookie(headers, SESSION_COOKIE)?;
+        let session = match self.store.get(&session_key(id)).await {
+            Ok(session) => session?,
+            Err(e) => {
+                warn!(err = %e, "failed to look up session");
+                return None;
+            }
+        };
+        serde_json::from_str::<Session>(&session)
+            .ok()
+            .map(|s| s.claims)
+    }
+
+    /// Complete a login started by the browser sending the [login_cookie],
+    /// returning the id of the new session, how long it's valid for, and
+    /// where to send the browser back to.
+    async fn finish(
+        &self,
+        state: &AppState,
+        code: &str,
+        login_state: &str,
+        login_cookie: Option<&str>,
+    ) -> Result<(String, Duration, String), LoginError> {
+        if login_cookie != Some(state_hash(login_state).as_str()) {
+            return Err(err("login state not started by this browser".to_string()));
+        }
+        let pending = self
+            .store
+            .take(&pending_key(login_state))
+            .await
+            .map_err(|e| err(e.to_string()))?
+            .and_then(|p| serde_json::from_str::<Pending>(&p).ok())
+            .ok_or_else(|| err("unknown or expired login state".to_string()))?;
+
+        let response: TokenResponse = HTTP_CLIENT
+            .post(&self.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("client_id", &self.config.client_id),
+                ("client_secret", &self.config.client_secret),
+                ("code_verifier", &pending.code_verifier),
+            ])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| err(format!("exchanging code: {}", e)))?
+            .json()
+            .await
+            .map_err(|e| err(format!("exchanging code: {}", e)))?;
+
+        let options = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_iter([self.config.issuer.clone()])),
+            allowed_audiences: Some(HashSet::from_iter([self.config.client_id.clone()])),
+            required_nonce: Some(pending.nonce),
+            ..Default::default()
+        };
+        let reloadable = state.reloadable.load_full();
+        let claims = reloadable
+            .key_store
+            .verify::<CustomClaims>(&response.id_token, Some(options), None)
+            .await
+            .map_err(|e| err(format!("invalid ID token: {}", e)))?;
+
+        let expires = claims
+            .expires_at
+            .map(|exp| UNIX_EPOCH + Duration::from_secs(exp.as_secs()))
+            .ok_or_else(|| err("ID token without expiry".to_string()))?;
+        let claims = match serde_json::to_value(claims) {
+            Ok(serde_json::Value::Object(claims)) => claims,
+            _ => unreachable!("claims always serialize to an object"),
+        };
+
+        let max_age = expires
+            .duration_since(SystemTime::now())
+            .map_err(|_| err("ID token expired".to_string()))?;
+        let session = serde_json::to_string(&Session {
+            claims,
+            id_token: Some(response.id_token),
+        })
+        .map_err(|e| err(format!("serializing session: {}", e)))?;
+        let id = random_id();
+        self.store
+            .put(&session_key(&id), session, max_age)
+            .await
+            .map_err(|e| err(e.to_string()))?;
+
+        Ok((id, max_age, pending.return_to))
+    }
+
+    /// End the session the request carries, if any, returning its ID token.
+    async fn end_session(&self, headers: &HeaderMap) -> Option<String> {
+        let id = cookie(headers, SESSION_COOKIE)?;
+        match self.store.take(&session_key(id)).await {
+            Ok(session) => serde_json::from_str::<Session>(&session?).ok()?.id_token,
+            Err(e) => {
+                warn!(err = %e, "failed to end session");
+                None
+            }
+        }
+    }
+
+    /// Where to send the browser after ending its session: to the provider,
+    /// if configured to log out there too, else the configured URL, if any.
+    fn logout_location(&self, id_token: Option<&str>) -> Option<String> {
+        let post_logout = self.config.post_logout_redirect_uri.as_deref();
+        let endpoint = match &self.end_session_endpoint {
+            Some(endpoint) if self.config.end_provider_session => endpoint,
+            _ => return post_logout.map(str::to_owned),
+        };
+        let params = [
+            ("client_id", Some(self.config.client_id.as_str())),
+            ("id_token_hint", id_token),
+            ("post_logout_redirect_uri", post_logout),
+        ];
+        let url = reqwest::Url::parse_with_params(
+            endpoint,
+            params
+                .into_iter()
+                .filter_map(|(name, value)| Some((name, value?))),
+        )
+        .ok()?;
+        Some(url.into())
+    }
+
+    /// The Set-Cookie header establishing the session.
+    fn session_cookie(&self, id: &str, max_age: Duration) -> Option<HeaderValue> {
+        self.cookie(SESSION_COOKIE, id, max_age)
+    }
+
+    /// The Set-Cookie header for a cookie only sent to cellulose.
+    fn cookie(&self, name: &str, value: &str, max_age: Duration) -> Option<HeaderValue> {
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            name,
+            value,
+            max_age.as_secs()
+        );
+        if self.config.redirect_uri.starts_with("https://") {
+            cookie.push_str("; Secure");
+        }
+        if let Some(domain) = &self.config.cookie_domain {
+            cookie.push_str(&format!("; Domain={}", domain));
+        }
+        HeaderValue::try_from(cookie).ok()
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// The route browsers are sent back to by the provider.
+pub async fn callback(
+    State(state): State<AppState>,
+    Query(params): Query<CallbackParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(login) = state.login.clone() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let (Some(code), Some(login_state)) = (params.code, params.state) else {
+        debug!(error = params.error, "login failed at the provider");
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let login_cookie = cookie(&headers, LOGIN_COOKIE);
+    match login
+        .finish(&state, &code, &login_state, login_cookie)
+        .await
+    {
+        Ok((id, max_age, return_to)) => {
+            let (Some(cookie), Some(clear_login)) = (
+                login.session_cookie(&id, max_age),
+                login.cookie(LOGIN_COOKIE, "", Duration::ZERO),
+            ) else {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            };
+            (
+                StatusCode::FOUND,
+                AppendHeaders([
+                    (header::SET_COOKIE, cookie),
+                    (header::SET_COOKIE, clear_login),
+                ]),
+                [(header::LOCATION, return_to)],
+            )
+                .into_response()
+        }
+        Err(e) => {
+            warn!(err = %e, "failed to complete login");
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+}
+
+/// The route logging browsers out: the session is ended and its cookie
+/// cleared, before they're sent on, see [Login::logout_location].
+pub async fn logout(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let Some(login) = state.login.clone() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let id_token = login.end_session(&headers).await;
+    let Some(cookie) = login.session_cookie("", Duration::ZERO) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    match login.logout_location(id_token.as_deref()) {
+        Some(location) => (
+            StatusCode::FOUND,
+            [(header::SET_COOKIE, cookie)],
+            [(header::LOCATION, location)],
+        )
+            .into_response(),
+        None => ([(header::SET_COOKIE, cookie)], "Logged out").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+
+    use super::{
+        code_challenge, cookie, pending_key, session_key, state_hash, Login, LoginConfig, Pending,
+    };
+    use crate::session::{MemoryStore, SessionStore};
+
+    fn login(store: Arc<MemoryStore>) -> Login {
+        Login::new(
+            LoginConfig {
+                issuer: "https://idp.example.com".to_string(),
+                client_id: "app".to_string(),
+                client_secret: "secret".to_string(),
+                redirect_uri: "https://auth.example.com/callback".to_string(),
+                scopes: "openid email".to_string(),
+                cookie_domain: Some("example.com".to_string()),
+                end_provider_session: true,
+                post_logout_redirect_uri: Some("https://app.example.com/".to_string()),
+            },
+            "https://idp.example.com/authorize".to_string(),
+            "https://idp.example.com/token".to_string(),
+            store,
+        )
+    }
+
+    #[tokio::test]
+    async fn redirect() {
+        let store = Arc::new(MemoryStore::default());
+        let login = login(store.clone());
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("x-forwarded-method", "GET"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "app.example.com"),
+            ("x-forwarded-uri", "/dashboard?tab=1"),
+            ("accept", "text/html,*/*;q=0.8"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        let response = login.redirect(&headers).await.expect("must redirect");
+        assert_eq!(StatusCode::FOUND, response.status());
+        let location = response.headers()[header::LOCATION].to_str().unwrap();
+        assert!(location.starts_with(
+            "https://idp.example.com/authorize?response_type=code&client_id=app&\
+             redirect_uri=https%3A%2F%2Fauth.example.com%2Fcallback&scope=openid+email&state="
+        ));
+        let params = reqwest::Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect::<std::collections::HashMap<_, _>>();
+        let pending = store
+            .get(&pending_key(&params["state"]))
+            .await
+            .unwrap()
+            .unwrap();
+        let pending: Pending = serde_json::from_str(&pending).unwrap();
+        assert_eq!("https://app.example.com/dashboard?tab=1", pending.return_to);
+        assert_eq!(params["nonce"], pending.nonce);
+        // bound to the browser
+        assert_eq!(
+            format!(
+                "cellulose_login={}; Path=/; Max-Age=600; HttpOnly; SameSite=Lax; Secure; Domain=example.com",
+                state_hash(&params["state"])
+            ),
+            response.headers()[header::SET_COOKIE]
+        );
+        assert_eq!("S256", params["code_challenge_method"]);
+        assert_eq!(
+            params["code_challenge"],
+            code_challenge(&pending.code_verifier)
+        );
+        // the example of RFC 7636
+        assert_eq!(
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM",
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk")
+        );
+        // unique per login
+        let (other, other_state) = login
+            .start("https://app.example.com/".to_string())
+            .await
+            .unwrap();
+        assert!(!other.contains(&params["nonce"]));
+        assert_ne!(state_hash(&params["state"]), state_hash(&other_state));
+
+        // API calls get a 401 instead.
+        headers.insert("accept", HeaderValue::from_static("application/json"));
+        assert!(login.redirect(&headers).await.is_none());
+        headers.insert("sec-fetch-mode", HeaderValue::from_static("navigate"));
+        assert!(login.redirect(&headers).await.is_some());
+        headers.insert("x-forwarded-method", HeaderValue::from_static("POST"));
+        assert!(login.redirect(&headers).await.is_none());
+
+        let cookie_header = login
+            .session_cookie("abc", std::time::Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(
+            "cellulose_session=abc; Path=/; Max-Age=60; HttpOnly; SameSite=Lax; Secure; Domain=example.com",
+            cookie_header
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("a=1; cellulose_session=abc"),
+        );
+        assert_eq!(Some("abc"), cookie(&headers, "cellulose_session"));
+        assert_eq!(None, login.session(&headers).await);
+    }
+
+    #[tokio::test]
+    async fn logout() {
+        let store = Arc::new(MemoryStore::default());
+        let login = login(store.clone());
+        store
+            .put(
+                &session_key("abc"),
+                r#"{"claims":{"sub":"alice"},"id_token":"a.b.c"}"#.to_string(),
+                std::time::Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("cellulose_session=abc"),
+        );
+        let claims = login.session(&headers).await.expect("must have session");
+        assert_eq!("alice", claims["sub"]);
+
+        let id_token = login.end_session(&headers).await;
+        assert_eq!(Some("a.b.c"), id_token.as_deref());
+        assert_eq!(None, login.session(&headers).await);
+        assert_eq!(None, login.end_session(&headers).await);
+
+        // the provider doesn't support logging out
+        assert_eq!(
+            Some("https://app.example.com/"),
+            login.logout_location(id_token.as_deref()).as_deref()
+        );
+
+        let login = login.with_end_session_endpoint("https://idp.example.com/logout".to_string());
+        assert_eq!(
+            Some(
+                "https://idp.example.com/logout?client_id=app&id_token_hint=a.b.c&\
+                 post_logout_redirect_uri=https%3A%2F%2Fapp.example.com%2F"
+            ),
+            login.logout_location(id_token.as_deref()).as_deref()
+        );
+        assert_eq!(
+            "cellulose_session=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax; Secure; Domain=example.com",
+            login
+                .session_cookie("", std::time::Duration::ZERO)
+                .unwrap()
+        );
+    }
+}
@@ -1,11 +1,53 @@
-use cellulose::{gen_router, AppState, KeyStore};
-use clap::Parser;
-use parking_lot::RwLock;
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use arc_swap::ArcSwap;
+use axum::{
+    http::{HeaderName, StatusCode},
+    middleware,
+};
+use cellulose::{
+    api_keys::ApiKeys,
+    assertion,
+    audit::{self, AuditLog},
+    bearer_sessions::BearerSessions,
+    chaos::Chaos,
+    claim_validators::{Acr, Audience, ClaimValidators, Issuer, Revocation},
+    client_cert, clock, config,
+    decision_id::{self, DecisionIds},
+    denial_webhook::DenialWebhook,
+    denylist::{self, Denylist},
+    error_pages::{self, ErrorPages},
+    fixture::{self, FixtureArgs},
+    forwarded_for, gen_admin_router, gen_router,
+    geoip::GeoIp,
+    health_checks::HealthChecks,
+    identities, identity_headers, listeners,
+    load_shedding::{self, LoadShedding},
+    local_keys::LocalKeys,
+    lockout,
+    login::{Login, LoginConfig},
+    metrics::METRICS,
+    rate_limit,
+    security_headers::{self, SecurityHeaders},
+    sensitive_headers,
+    session::{self, SessionStore},
+    signals,
+    slo::{self, SloConfig},
+    spoe,
+    supervisor::{Supervisor, SHUTDOWN_TIMEOUT},
+    tenant_usage, warn_expiring, AdminAuth, AppState, DecisionCache, DecryptionKey, DpopValidator,
+    ExpiredTokens, Flags, HmacSource, Introspector, JwksSource, KeySource, KeyStore,
+    NonUtf8Headers, Policy, ProgramCache, Reloadable, StaticSource,
+};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::{
+    collections::{HashMap, HashSet},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::time;
-use tokio_retry::{strategy::ExponentialBackoff, Retry};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{info, warn};
 
 /// JWT-validating HTTP server, for forward_auth usecases.
 ///
@@ -17,83 +59,1617 @@ use tracing::info;
 ///  - The original URL is expected in the X-Forwarded-Uri header.
 ///  - The original Host is expected in the X-Forwarded-Host header.
 ///  - The original Source IP is expected in the X-Forwarded-For header.
+///    Overly long chains and entries that aren't IP addresses are stripped or
+///    denied, see --xff-on-violation.
+///
+/// If --trusted-proxies is set, X-Forwarded-* headers are only accepted from
+/// these, see --on-untrusted-proxy.
 ///
 /// The URL used in the validating request can be used to configure validating
 /// behaviour, mostly by encoding a small CEL program returning a boolean value
-/// on whether access should be granted.
+/// on whether access should be granted. Programs can also return a map of
+/// response headers to grant access with, or a string to deny access with, as
+/// reason in the response body.
+///
+/// Instead of sending the program, a policy configured on the server (in the
+/// config file or the policy directory) can be referenced by name, as
+/// `policy=<name>`.
 ///
-/// In case no program is sent, access is always denied.
+/// In case no program is sent, the default program configured on the server is
+/// evaluated, if any. Otherwise, access is denied.
+///
+/// Requests without a valid token are answered with a 401 Unauthorized, with
+/// a `WWW-Authenticate` challenge (see --realm), requests denied by the
+/// program with a 403 Forbidden (see --unauthorized-on-policy-denial).
 ///
 /// Said CEL program has access to the following variables:
 ///
 ///  - `request_headers`
 ///    A map from header name (string) to value (String/Bytes or list of these),
-///    as headers exist multiple times.
+///    as headers exist multiple times. Credentials are omitted, see
+///    --sensitive-header.
+///  - `jwt_claims`
+///    All claims of the token, as contained in it.
+///  - `jwt`
+///    The registered claims, typed: `jwt.sub`, `jwt.iss` and `jwt.jti` as
+///    strings, `jwt.exp`, `jwt.nbf` and `jwt.iat` as timestamps, and `jwt.aud`
+///    as a list, each null if absent.
+///  - `actors`
+///    The delegation chain from the `act` claim (token exchange), the
+///    current actor first, each with its claims. `impersonatedBy("gateway-svc")`
+///    checks whether a service is part of it.
+///  - `request`
+///    The original request from the X-Forwarded-* headers, as
+///    `request.method`, `request.host`, `request.scheme` and
+///    `request.source_ip`, each null if unknown.
+///  - `url`
+///    The X-Forwarded-Uri header, parsed into `url.path`, `url.query` (a map
+///    of decoded query parameters) and `url.raw`, or null.
+///  - `client_ip`
+///    The X-Forwarded-For address added by the outermost proxy (see
+///    --xff-depth), or null, to be checked with
+///    `ip_in_cidr(client_ip, "10.0.0.0/8")`.
+///  - `geo`
+///    The client address looked up in the GeoIP databases, if configured, as
+///    `geo.country` (ISO code), `geo.asn` and `geo.as_org`, or null.
+///  - `client_cert`
+///    The client certificate forwarded by the proxy, if a format is
+///    configured, with `hash`, `subject`, `issuer`, `serial`, `uris`, `dns`,
+///    `spiffe_id` and (for Envoy) `by`.
+///  - `identities`
+///    Whether each identity (`jwt`, and `client_cert` and `api_key` if
+///    configured) is `present` and `verified`, see --identity-combiner.
+///  - `now`
+///    The time of the decision, as timestamp. Accessors like
+///    `now.getHours("+01:00")` or `now.getDayOfWeek()` (UTC, 0 is Sunday)
+///    allow time-of-day or day-of-week access windows.
+///  - `flags`
+///    A map from feature flag name to whether it's enabled, if a flags file is
+///    configured.
 ///
 /// Independent of the program return value, all JWTs need to have a valid
 /// (not-expired) signature, and said key needs to be present in the JWKS.
 ///
 /// Additionally, it is STRONGLY recommended to set allowed_audiences /
 /// allowed_issuers in the URL parameters too.
-//
-// FUTUREWORK: In case of a successful authentication, allow adding additional
-// headers in the response.
-// TODO: think about whether we can/should allow some user flows here too.
-// It'd be very nice if we could redirect a user to a login page.
+/// Further verification knobs (required_subject, required_nonce,
+/// required_key_id, reject_before, accept_future, time_tolerance,
+/// max_validity) can be set the same way.
+///
+/// On allow, the identity of the token can be returned in headers built from
+/// its claims (see --identity-header), for the proxy to pass on upstream.
+/// These can be signed, see --assertion-key.
+///
+/// Browsers can also be sent to log in at an OpenID Connect provider, see
+/// --login-issuer, and are then let through with a session cookie, until
+/// they log out at /logout.
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// Location of the JWKS endpoint
-    jwks_uri: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Config file (.toml, .yaml or .yml).
+    /// Top-level keys are the names of command line flags, which override
+    /// them. Additionally, a `verification` table can set default
+    /// verification options (with the names of the URL parameters), and a
+    /// `policies` table named CEL programs, referenced as `?policy=<name>`.
+    /// Policies can also be tables, with the program as `cel`, and a
+    /// `coerce` table with the types to coerce claims to (int, float, bool,
+    /// string or list) before evaluating it. `numbers = "int"` converts
+    /// integral numbers in claims to CEL ints instead of uints (or doubles),
+    /// and ones not fitting to strings, so large IDs keep their precision.
+    /// `context_claims`, a list of claim names, limits `jwt_claims` to
+    /// those, saving the conversion of the others for tokens with many.
+    /// `valid_from` and `valid_until`
+    /// (RFC 3339 timestamps) limit when a policy is in effect, outside of
+    /// that, the policy named as `fallback` applies, or access is denied.
+    /// `backend`, a CEL expression returning a name or null, hints at the
+    /// upstream to route allowed requests to, returned as X-Auth-Backend,
+    /// like `jwt_claims.tier == "premium" ? "premium" : null`.
+    /// `verification` sets verification options (overridden by the request),
+    /// `headers` a table of headers added to allowed responses, and `macros`
+    /// snippets expanded into the policy only. `extends` names another
+    /// policy of the config file to inherit all settings unset from, with
+    /// tables merged, so routes can share them.
+    /// A `cel.constants` table defines variables available to all programs,
+    /// and `cel.macros` snippets expanded into them, like
+    /// `"is_internal(ip)" = 'ip_in_cidr(ip, "10.0.0.0/8")'`.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Directory with named policies, one CEL program per `<name>.cel` file,
+    /// referenced as `?policy=<name>`. Names must not clash with policies
+    /// from the config file.
+    #[clap(long)]
+    policy_dir: Option<PathBuf>,
+
+    /// Warn about policies expiring (by `valid_until`) within that many
+    /// seconds, checked hourly. 0 disables the warnings.
+    #[clap(long, default_value_t = 7 * 24 * 3600)]
+    policy_expiry_warning_secs: u64,
+
+    /// CEL program evaluated for requests specifying neither `cel_str` nor
+    /// `policy`. Without it, these are denied.
+    #[clap(long)]
+    default_cel: Option<String>,
+
+    /// Maximum number of compiled CEL programs to keep. The least recently
+    /// used ones are evicted first, and compiled again when used next.
+    #[clap(long, default_value = "1000")]
+    cel_cache_size: NonZeroUsize,
+
+    /// Maximum time for evaluating a CEL program, in milliseconds. Requests
+    /// exceeding it are answered with a 500. 0 disables the timeout, and
+    /// evaluates programs inline.
+    #[clap(long, default_value_t = 100)]
+    cel_timeout_ms: u64,
+
+    /// Answer requests denied by the policy with a 401 Unauthorized, like
+    /// requests without a valid token, instead of a 403 Forbidden.
+    #[clap(long)]
+    unauthorized_on_policy_denial: bool,
+
+    /// Maximum number of entries in the X-Forwarded-For header.
+    #[clap(long, default_value_t = 20)]
+    xff_max_entries: usize,
+
+    /// What to do with requests whose X-Forwarded-For header has too many
+    /// entries, or entries that aren't IP addresses: deny them with a 400,
+    /// or strip the offending entries (keeping the rightmost ones).
+    #[clap(long, value_enum, default_value = "strip")]
+    xff_on_violation: forwarded_for::OnViolation,
+
+    /// Number of proxies in front of cellulose appending to the
+    /// X-Forwarded-For header. The client address is the entry this far from
+    /// the right, entries left of it were sent by the client.
+    #[clap(long, default_value = "1")]
+    xff_depth: NonZeroUsize,
+
+    /// Addresses or CIDR ranges of the proxies allowed to send X-Forwarded-*
+    /// headers (and the client certificate header), comma-separated.
+    /// Connections over Unix sockets are always trusted. If unset, all peers
+    /// are trusted, so clients able to reach cellulose directly can spoof
+    /// these headers.
+    #[clap(long, value_delimiter = ',')]
+    trusted_proxies: Vec<forwarded_for::Cidr>,
+
+    /// Substring of the User-Agent of health checks of proxies and load
+    /// balancers sent to /auth, like `kube-probe/`, matched ignoring case.
+    /// Health checks are decided on as usual, but counted separately
+    /// instead of as decisions. Can be passed multiple times.
+    #[clap(long = "health-check-user-agent")]
+    health_check_user_agents: Vec<String>,
+
+    /// Path of the original request (X-Forwarded-Uri, without query) of
+    /// health checks, like `/healthz`, see --health-check-user-agent.
+    /// Can be passed multiple times.
+    #[clap(long = "health-check-path")]
+    health_check_paths: Vec<String>,
+
+    /// Delay every /auth response by that many milliseconds, to test how
+    /// proxies deal with a slow instance. Not for production use.
+    #[clap(long, default_value_t = 0, hide = true)]
+    chaos_delay_ms: u64,
+
+    /// Answer that percentage of /auth requests with 500, to test whether
+    /// proxies fail open or closed. Not for production use.
+    #[clap(long, default_value_t = 0.0, hide = true)]
+    chaos_error_percent: f64,
+
+    /// Answer all /auth requests as if the keys expired, with 500 and
+    /// Retry-After. Not for production use.
+    #[clap(long, hide = true)]
+    chaos_expired_keys: bool,
+
+    /// What to do with requests from other peers carrying these headers:
+    /// deny them with a 403, or strip the headers. SPOE connections from
+    /// other peers are closed.
+    #[clap(long, value_enum, default_value = "strip")]
+    on_untrusted_proxy: forwarded_for::OnUntrusted,
+
+    /// The proxy forwarding client certificates, which determines the
+    /// header format: envoy (X-Forwarded-Client-Cert), traefik
+    /// (X-Forwarded-Tls-Client-Cert), nginx (the escaped PEM in
+    /// X-SSL-Client-Cert) or caddy (the base64 DER in X-Client-Cert).
+    /// Certificates are exposed to CEL programs as `client_cert`. Only set
+    /// this if the proxy always overwrites the header.
+    #[clap(long, value_enum)]
+    client_cert_format: Option<client_cert::Format>,
+
+    /// Header to read client certificates from, instead of the default of
+    /// the client certificate format.
+    #[clap(long, requires = "client_cert_format")]
+    client_cert_header: Option<HeaderName>,
+
+    /// File with feature flags (.toml, .yaml, .yml or .json), exposed to CEL
+    /// programs as `flags.<name>`. Flags are either a bool, or a table with
+    /// `enabled` and an optional `until` timestamp (RFC 3339), after which
+    /// they're disabled. Reloaded along with the config file.
+    #[clap(long)]
+    flags_file: Option<PathBuf>,
+
+    /// MaxMind DB file (like GeoLite2-Country or GeoLite2-ASN) the client
+    /// address is looked up in, exposed to CEL programs as `geo`. Can be
+    /// passed multiple times, to combine a country and an ASN database.
+    /// Reloaded along with the config file.
+    #[clap(long = "geoip-db")]
+    geoip_dbs: Vec<PathBuf>,
+
+    /// Run as a Windows service, to be passed when registering the service.
+    #[cfg(all(windows, feature = "windows-service"))]
+    #[clap(long)]
+    windows_service: bool,
+
+    /// Log filter, like "info" or "info,cellulose=debug".
+    #[clap(long, env = "RUST_LOG")]
+    log_filter: Option<String>,
+
+    /// Format of log lines: compact, for humans, or json, one object per
+    /// line, for log pipelines.
+    #[clap(long, value_enum, default_value = "compact")]
+    log_format: cellulose::util::LogFormat,
+
+    /// OpenTelemetry collector to export spans of decisions (including
+    /// token verification and policy evaluation) and key refreshes to, via
+    /// OTLP/HTTP, like "http://localhost:4318". Spans follow --log-filter.
+    /// Decision metrics then reference traces as exemplars, served to
+    /// scrapers accepting OpenMetrics.
+    #[clap(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Write an audit record of every /auth request (outcome, subject,
+    /// issuer and audience, policy, client address, forwarded request and
+    /// latency) to this file, as JSON lines, or to stdout with "-". Records
+    /// are written regardless of --log-filter, and chained by hashes, which
+    /// the verify-audit-log command checks.
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Rotate the audit log file once it reaches that size.
+    #[clap(long, default_value_t = 100 * 1024 * 1024)]
+    audit_log_max_bytes: u64,
+
+    /// How many rotated audit log files to keep, as `<file>.1` (the most
+    /// recent) and so on.
+    #[clap(long, default_value_t = 10)]
+    audit_log_keep: usize,
+
+    /// Hand users denied access an id of the decision, for support staff to
+    /// ask for and look up in the audit log (as `decision_id`), as
+    /// `cellulose_decision_id` cookie or `X-Decision-Id` header. Values
+    /// are signed, see the verify-decision-id command.
+    #[clap(long, value_enum, requires = "audit_log")]
+    decision_id: Option<decision_id::Delivery>,
+
+    /// File with the key (at least 32 bytes) decision ids are signed with.
+    /// If unset, a random one is used, and values can't be verified after
+    /// a restart.
+    #[clap(long)]
+    decision_id_key_file: Option<PathBuf>,
+
+    /// How long decision id cookies are kept, in seconds.
+    #[clap(long, default_value_t = 3600)]
+    decision_id_ttl_secs: u64,
+
+    /// POST requests denied for token-validity reasons (invalid signature,
+    /// expired, revoked or inactive token, invalid DPoP proof) to this URL,
+    /// in batches, as JSON object with a list of `denials`, each with the
+    /// time, status, error, client address and forwarded request.
+    #[clap(long)]
+    denial_webhook_url: Option<String>,
+
+    /// The maximum number of denials sent to the webhook at once.
+    #[clap(long, default_value_t = 100)]
+    denial_webhook_batch_size: usize,
+
+    /// How often to send denials to the webhook, if the batch didn't fill up
+    /// before.
+    #[clap(long, default_value_t = 10)]
+    denial_webhook_interval_secs: u64,
+
+    /// Location of the JWKS endpoint(s).
+    /// Keys from these are used for tokens of any issuer not routed via
+    /// --issuer-jwks-uri.
+    jwks_uri: Vec<String>,
+
+    /// Issuer URL to look up the JWKS endpoint for via OIDC discovery.
+    /// Keys are only used for tokens of that issuer.
+    /// Can be passed multiple times.
+    #[clap(long = "issuer")]
+    issuers: Vec<String>,
+
+    /// JWKS endpoint for tokens of a specific issuer, as ISSUER=URL.
+    /// Can be passed multiple times, each endpoint is refreshed independently.
+    #[clap(long = "issuer-jwks-uri", value_parser = parse_issuer_jwks_uri)]
+    issuer_jwks_uris: Vec<(String, String)>,
+
+    /// Keep keys that disappear from a JWKS endpoint for this many seconds,
+    /// so tokens signed shortly before an abrupt key rotation stay valid.
+    #[clap(long, default_value_t = 0)]
+    jwks_grace_period_secs: u64,
+
+    /// Signature algorithms to accept, like RS256,ES256. Tokens signed with
+    /// any other algorithm are rejected. Defaults to all supported ones.
+    #[clap(long, value_delimiter = ',')]
+    allowed_algs: Option<Vec<String>>,
+
+    /// PEM file containing an RSA private key (2048 to 8192 bits) to decrypt
+    /// JWE-wrapped tokens with (RSA-OAEP / RSA-OAEP-256). The nested token
+    /// is verified as usual. Encrypted tokens are only decrypted with the key
+    /// from the file named like their key id (without extension), ones
+    /// without key id only if there's a single key.
+    /// Can be passed multiple times.
+    #[clap(long = "jwe-key-file")]
+    jwe_key_files: Vec<PathBuf>,
+
+    /// OAuth 2.0 introspection endpoint (RFC 7662) to validate tokens that
+    /// aren't JWTs with. The introspection response is exposed as jwt_claims.
+    /// Verification knobs passed as URL parameters don't apply to these.
+    #[clap(long, requires_all = ["introspection_client_id", "introspection_client_secret_file"])]
+    introspection_endpoint: Option<String>,
+
+    /// Client ID to authenticate at the introspection endpoint with.
+    #[clap(long)]
+    introspection_client_id: Option<String>,
+
+    /// File containing the client secret to authenticate at the
+    /// introspection endpoint with.
+    #[clap(long)]
+    introspection_client_secret_file: Option<PathBuf>,
+
+    /// Issuer to log in browsers with, via the OpenID Connect authorization
+    /// code flow. Browser navigations without token are redirected to its
+    /// authorization endpoint, which sends them back to /callback (see
+    /// --login-redirect-uri), establishing a session. Its keys are used
+    /// like with --issuer.
+    #[clap(long, requires_all = ["login_client_id", "login_client_secret_file", "login_redirect_uri"])]
+    login_issuer: Option<String>,
+
+    /// Client ID to log in with.
+    #[clap(long)]
+    login_client_id: Option<String>,
+
+    /// File containing the client secret to log in with.
+    #[clap(long)]
+    login_client_secret_file: Option<PathBuf>,
+
+    /// URL of the /callback route, as reachable by browsers, like
+    /// `https://auth.example.com/callback`. It needs to be registered at the
+    /// provider.
+    #[clap(long)]
+    login_redirect_uri: Option<String>,
+
+    /// Scopes to request when logging in, separated by spaces.
+    #[clap(long, default_value = "openid profile email")]
+    login_scopes: String,
+
+    /// Also log browsers out at the provider when they visit /logout, if it
+    /// supports RP-Initiated Logout (advertises an end_session_endpoint).
+    #[clap(long, requires = "login_issuer")]
+    logout_at_provider: bool,
+
+    /// Where to send browsers after logging out. With --logout-at-provider,
+    /// it needs to be registered at the provider.
+    #[clap(long, requires = "login_issuer")]
+    post_logout_redirect_uri: Option<String>,
+
+    /// Domain to set session cookies for, like `example.com` to cover all
+    /// its subdomains. Without it, login sessions are only sent to the host
+    /// of --login-redirect-uri, bearer sessions to the host of the original
+    /// request. It's also used for the cookie binding logins in progress to
+    /// browsers, which is set on the original request, and needs to reach
+    /// --login-redirect-uri.
+    #[clap(long)]
+    session_cookie_domain: Option<String>,
+
+    /// Redis server to keep logins in progress and sessions in, like
+    /// `redis://redis:6379`, so instances behind a load balancer share them.
+    /// Without it, they're kept in memory. Needs Redis 6.2 or later.
+    #[cfg(feature = "redis")]
+    #[clap(long, requires = "login_issuer")]
+    session_redis_url: Option<String>,
+
+    /// Set a session cookie after verifying a bearer token, valid for at
+    /// most this many seconds, and never beyond the expiry of the token.
+    /// Later checks sending it along with the same token skip verifying the
+    /// token, which helps with clients sending the same token at a high
+    /// rate, like for static assets. The proxy needs to return the
+    /// Set-Cookie header to the client. 0 disables it.
+    #[clap(long, default_value_t = 0)]
+    bearer_session_ttl_secs: u64,
+
+    /// Cache positive decisions by token and policy for at most this many
+    /// seconds, never beyond the expiry of the token, so repeated requests
+    /// with the same token skip verifying it and executing the policy.
+    /// Only done for policies referencing nothing but claims and constants,
+    /// and tokens not bound to a key. 0 disables it.
+    #[clap(long, default_value_t = 0)]
+    decision_cache_ttl_secs: u64,
+
+    /// File to persist the denylist of revoked tokens in, so it survives
+    /// restarts. Without it, the list is only kept in memory.
+    #[clap(long)]
+    denylist_file: Option<PathBuf>,
+
+    /// Require the `aud` claim of all tokens, including introspected ones,
+    /// to contain one of these audiences, comma-separated. Checked in
+    /// addition to allowed_audiences of the verification options, which
+    /// only apply to JWTs.
+    #[clap(long, value_delimiter = ',')]
+    require_audience: Vec<String>,
+
+    /// Require the `iss` claim of all tokens, including introspected ones,
+    /// to be one of these issuers, comma-separated.
+    #[clap(long, value_delimiter = ',')]
+    require_issuer: Vec<String>,
+
+    /// Require the `acr` claim of all tokens (the authentication context
+    /// class, like whether a second factor was used) to be one of these
+    /// values, comma-separated.
+    #[clap(long, value_delimiter = ',')]
+    require_acr: Vec<String>,
+
+    /// URL to poll revoked tokens from, as JSON document with lists of
+    /// revoked `jti` and `sub` claims, like `{"jti": ["..."], "sub": []}`,
+    /// rejected in addition to the denylist.
+    #[clap(long)]
+    revocation_url: Option<String>,
+
+    /// How often to poll --revocation-url, in seconds. Unchanged lists are
+    /// skipped via ETag.
+    #[clap(long, default_value_t = 60)]
+    revocation_refresh_secs: u64,
+
+    /// File containing a secret (at least 32 bytes) to encrypt bearer
+    /// session cookies with, to share them between instances. Without it,
+    /// a random one is used, and sessions are only valid for this instance.
+    #[clap(long)]
+    bearer_session_secret_file: Option<PathBuf>,
+
+    /// Maximum age of DPoP proofs, in seconds.
+    /// Tokens bound to a key (via cnf.jkt) are only accepted along with a
+    /// fresh proof of possession in the DPoP header, signed with that key.
+    #[clap(long, default_value_t = 60)]
+    dpop_proof_max_age_secs: u64,
+
+    /// Claims to pass on to the upstream in the W3C baggage header on allow,
+    /// comma-separated. Only meant for low-sensitivity claims like tenant or
+    /// plan tier, as baggage is propagated further downstream.
+    /// Members of the incoming baggage header with the same keys are
+    /// replaced.
+    #[clap(long, value_delimiter = ',')]
+    baggage_claims: Vec<String>,
+
+    /// Header to return on allow, set to the value of a claim, like
+    /// `X-Auth-Request-User=sub`, for the proxy to copy to the upstream.
+    /// Nested claims are separated by dots (`realm_access.roles`), lists are
+    /// joined with commas. Omitted if the claim is absent. Can be passed
+    /// multiple times. The proxy needs to be configured to overwrite these
+    /// headers, so clients can't set them.
+    #[clap(long = "identity-header")]
+    identity_headers: Vec<identity_headers::IdentityHeader>,
+
+    /// The realm of the `WWW-Authenticate` challenge 401s are answered with
+    /// (RFC 6750).
+    #[clap(long, default_value = "cellulose")]
+    realm: String,
+
+    /// Header never passed on to CEL programs, or rendered in logs, in
+    /// addition to Authorization, Cookie, Proxy-Authorization and X-Api-Key.
+    /// Can be passed multiple times.
+    #[clap(long = "sensitive-header")]
+    sensitive_headers: Vec<HeaderName>,
+
+    /// What to do with request header values that aren't valid UTF-8: expose
+    /// them to CEL programs as bytes (like all values with characters other
+    /// than visible ASCII), which string functions don't work on, reject
+    /// requests carrying them with a 400, or strip them. With reject and
+    /// strip, all remaining values are exposed as strings. Occurrences are
+    /// counted as `cellulose_non_utf8_headers_total`.
+    #[clap(long, value_enum, default_value = "bytes")]
+    non_utf8_headers: NonUtf8Headers,
+
+    /// ES256 private key (PEM) to sign the `X-Auth-Assertion` returned on
+    /// allow with, covering the subject and the headers returned along with
+    /// it, so upstreams can verify them (see the `client` module). The
+    /// public key is served at /.well-known/cellulose-keys.
+    #[clap(long)]
+    assertion_key: Option<PathBuf>,
+
+    /// Sign assertions with keys cellulose generates and rotates itself,
+    /// see --local-key-rotation-days, instead of --assertion-key.
+    #[clap(long, conflicts_with = "assertion_key")]
+    assertion_local_keys: bool,
+
+    /// How long assertions are valid for, in seconds.
+    #[clap(long, default_value_t = 60)]
+    assertion_ttl_secs: u64,
+
+    /// File with API keys (.toml, .yaml, .yml or .json), mapping a name to
+    /// the hex-encoded SHA-256 hash of the key. Keys are sent in the
+    /// X-API-Key header, and are an identity next to the token, see
+    /// --identity-combiner. Reloaded along with the config file.
+    #[clap(long)]
+    api_keys_file: Option<PathBuf>,
+
+    /// How the identities of a request (token, client certificate, API key)
+    /// are combined before evaluating the policy: only the token counts
+    /// (jwt), all configured ones need to be verified (all), any of them
+    /// (any), or the weights of the verified ones need to add up to the
+    /// policy's min_identity_weight, or --min-identity-weight (weighted).
+    /// Policies can check each as `identities.<kind>.verified`.
+    #[clap(long, value_enum, default_value = "jwt")]
+    identity_combiner: identities::Mode,
+
+    /// Weight of an identity kind, like `client_cert=2`, for the weighted
+    /// combiner. Kinds not listed weigh 1. Can be passed multiple times.
+    #[clap(long = "identity-weight")]
+    identity_weights: Vec<identities::Weight>,
+
+    /// Minimum weight of the verified identities, for policies not setting
+    /// min_identity_weight.
+    #[clap(long, default_value_t = 1)]
+    min_identity_weight: u32,
+
+    /// Claims to render a human-friendly principal from in decision log
+    /// events, comma-separated, in order of preference, like
+    /// `preferred_username,email`. The first one set is logged along with a
+    /// prefix of the subject, e.g. `alice (8c0a1f3e)`.
+    #[clap(long, value_delimiter = ',')]
+    principal_claims: Vec<String>,
+
+    /// Number of times the same expired token may be presented within
+    /// --expired-token-loop-window-secs, before the client is considered
+    /// stuck in a loop (like a single-page app failing to refresh tokens).
+    /// It's then answered with a 401 carrying a `WWW-Authenticate` challenge
+    /// to refresh the token, or redirected to --expired-token-loop-redirect.
+    #[clap(long)]
+    expired_token_loop_threshold: Option<NonZeroU32>,
+
+    /// Limit /auth requests per client address (as determined from
+    /// X-Forwarded-For, see --xff-depth) to this many per second, answering
+    /// the ones exceeding it with a 429. Requests without client address
+    /// aren't limited.
+    #[clap(long, value_parser = positive_rate)]
+    rate_limit_ip_rps: Option<f64>,
+
+    /// Number of requests per client address allowed at once, on top of the
+    /// rate.
+    #[clap(long, default_value = "20")]
+    rate_limit_ip_burst: NonZeroU32,
+
+    /// Limit /auth requests per subject, read from the token before
+    /// verifying it, to this many per second, answering the ones exceeding
+    /// it with a 429.
+    #[clap(long, value_parser = positive_rate)]
+    rate_limit_subject_rps: Option<f64>,
+
+    /// Number of requests per subject allowed at once, on top of the rate.
+    #[clap(long, default_value = "20")]
+    rate_limit_subject_burst: NonZeroU32,
+
+    /// Answer requests to the main listener with a 503 while this many are
+    /// in flight already, rather than queueing them up. /healthz is exempt.
+    #[clap(long)]
+    max_in_flight_requests: Option<NonZeroUsize>,
+
+    /// Answer requests to the main listener with a 503 once they take longer
+    /// than this, in milliseconds, rather than leaving the proxy to time out.
+    /// /healthz is exempt.
+    #[clap(long)]
+    request_timeout_ms: Option<NonZeroU64>,
+
+    /// Ban client addresses (as determined from X-Forwarded-For) once they
+    /// presented this many tokens failing verification, like with an invalid
+    /// signature, within --lockout-window-secs. Requests of banned addresses
+    /// are answered with a 429 without verifying their tokens.
+    #[clap(long)]
+    lockout_threshold: Option<NonZeroU32>,
+
+    /// How long invalid tokens are counted, from the first one, in seconds.
+    #[clap(long, default_value_t = 60, requires = "lockout_threshold")]
+    lockout_window_secs: u64,
+
+    /// How long addresses are banned for, in seconds.
+    #[clap(long, default_value_t = 900, requires = "lockout_threshold")]
+    lockout_duration_secs: u64,
+
+    /// How long occurrences of an expired token are counted, from the first
+    /// one, in seconds.
+    #[clap(long, default_value_t = 60, requires = "expired_token_loop_threshold")]
+    expired_token_loop_window_secs: u64,
+
+    /// URL to redirect clients stuck with an expired token to, like a login
+    /// page, instead of answering with a challenge.
+    #[clap(long, requires = "expired_token_loop_threshold")]
+    expired_token_loop_redirect: Option<String>,
+
+    /// Claim holding the tenant, to count allowed requests per tenant by.
+    /// Counts are exposed as `cellulose_tenant_allowed_requests_total`, and
+    /// exported per window to --tenant-usage-dir, if set.
+    #[clap(long)]
+    tenant_claim: Option<String>,
+
+    /// Length of the windows requests are counted over, in seconds.
+    #[clap(long, default_value_t = 3600, requires = "tenant_claim")]
+    tenant_usage_window_secs: u64,
+
+    /// Directory to write the counts of each window to, once it ends, as
+    /// `tenant-usage-<window start>.<json|csv>`.
+    #[clap(long, requires = "tenant_claim")]
+    tenant_usage_dir: Option<PathBuf>,
+
+    /// Format of the files written to --tenant-usage-dir.
+    #[clap(long, value_enum, default_value = "json")]
+    tenant_usage_format: tenant_usage::ExportFormat,
+
+    /// Number of files to keep in --tenant-usage-dir, older ones are
+    /// removed.
+    #[clap(long, default_value_t = 168)]
+    tenant_usage_keep: usize,
+
+    /// Maximum skew of the system clock against the Date header of JWKS
+    /// responses, in seconds, before warning about it. A skewed clock makes
+    /// tokens be rejected as expired or not yet valid. 0 disables the check.
+    #[clap(long, default_value_t = 60)]
+    max_clock_skew_secs: u64,
+
+    /// Refuse to start, and answer /auth with 503, while the clock skew
+    /// exceeds --max-clock-skew-secs, instead of only warning.
+    #[clap(long)]
+    refuse_on_clock_skew: bool,
+
+    /// Local file containing public keys, either PEM or JWK(S) JSON.
+    /// Keys from these are used for tokens of any issuer not explicitly
+    /// routed. Can be passed multiple times.
+    #[clap(long = "key-file")]
+    key_files: Vec<PathBuf>,
+
+    /// Watch the key files for changes, and reload them.
+    #[clap(long)]
+    watch_key_files: bool,
+
+    /// File containing a shared secret, to verify HS256/HS384/HS512 signed
+    /// tokens. Tokens with a key id are only checked against the secret from
+    /// the file with that name (without extension).
+    /// Can be passed multiple times.
+    #[clap(long = "hmac-secret-file")]
+    hmac_secret_files: Vec<PathBuf>,
 
     /// The address to listen on.
+    /// Besides /auth, `/healthz` (liveness) and `/readyz` (503 while keys
+    /// are expired) are served there, for probes of orchestrators and load
+    /// balancers.
     #[clap(flatten)]
     listen_args: tokio_listener::ListenerAddressLFlag,
+
+    /// The address to serve admin endpoints (metrics, profiling) on.
+    /// Metrics are served at `/-/metrics`, and the variables and functions
+    /// available to CEL programs described at `/-/context-schema`, only
+    /// there.
+    /// The state of background tasks is reported at `/-/tasks`.
+    /// Log levels can be overridden per policy there, with
+    /// `PUT /-/log-levels/<policy>` and the level (like `debug`) as body,
+    /// and reset with `DELETE`.
+    /// Tokens are revoked with `POST /-/denylist` and a JSON body with
+    /// either the `jti` or the whole `token` (then expiring along with it),
+    /// and optionally `expires_at`, in seconds since the epoch. Entries are
+    /// listed with `GET`, and removed with `DELETE /-/denylist/<entry>`.
+    /// With the `admin-ui` feature, a status page is served at `/-/ui`.
+    /// Startup fails if it overlaps with the main or SPOE listener.
+    #[clap(long)]
+    admin_listen_address: Option<tokio_listener::ListenerAddress>,
+
+    /// The address to serve HAProxy SPOE (Stream Processing Offload Engine)
+    /// connections on, as an alternative to forward_auth subrequests.
+    /// Decisions are returned as variables, see the `spoe` module for the
+    /// expected messages.
+    #[clap(long)]
+    spoe_listen_address: Option<tokio_listener::ListenerAddress>,
+
+    /// File containing a static bearer token required for all requests to
+    /// the admin listener.
+    /// There's no TLS support on the admin listener, so for anything but
+    /// loopback or a unix socket, put it behind a TLS terminator.
+    #[clap(long, conflicts_with = "admin_policy")]
+    admin_token_file: Option<PathBuf>,
+
+    /// CEL policy required for all requests to the admin listener.
+    /// Requests need to carry a bearer JWT verifiable with the configured
+    /// keys, the policy has access to `jwt_claims` and `request_headers`.
+    #[clap(long)]
+    admin_policy: Option<String>,
+
+    /// Body of error responses: the reason returned by the policy as plain
+    /// text (if any), or negotiated with the Accept header of the original
+    /// request, as JSON document (`{"error": ..., "reason": ...}`) or HTML
+    /// page.
+    #[clap(long, value_enum, default_value = "plain")]
+    error_body: error_pages::Format,
+
+    /// HTML template for error pages, instead of the built-in one. `{{status}}`,
+    /// `{{error}}` and `{{reason}}` are replaced with the status code, its
+    /// description and the reason returned by the policy.
+    #[clap(long)]
+    error_page_template: Option<PathBuf>,
+
+    /// Translated HTML template for error pages, as `<language>=<path>`, like
+    /// `de=/etc/cellulose/error.de.html`. Picked by the Accept-Language
+    /// header, by exact match or by primary language (`de` for `de-AT`).
+    /// Can be passed multiple times.
+    #[clap(long = "error-page-translation")]
+    error_page_translations: Vec<String>,
+
+    /// Content-Security-Policy sent along with HTML pages (error and login
+    /// pages). Set to an empty string to not send one.
+    #[clap(long, default_value = security_headers::DEFAULT_CONTENT_SECURITY_POLICY)]
+    content_security_policy: String,
+
+    /// Referrer-Policy sent along with HTML pages. Set to an empty string to
+    /// not send one.
+    #[clap(long, default_value = security_headers::DEFAULT_REFERRER_POLICY)]
+    referrer_policy: String,
+
+    /// max-age of the Strict-Transport-Security header sent along with HTML
+    /// pages, if the request was received via TLS (per X-Forwarded-Proto).
+    /// Set to 0 to not send one.
+    #[clap(long, default_value_t = 365 * 24 * 3600)]
+    hsts_max_age_secs: u64,
+
+    /// Directory to keep state across restarts in.
+    /// If set, counters are persisted there on shutdown and restored on
+    /// startup, so long-window dashboards don't reset on every deploy.
+    #[clap(long, env = "STATE_DIRECTORY")]
+    state_dir: Option<PathBuf>,
+
+    /// Rotate keys cellulose generates itself every that many days,
+    /// keeping them in --state-dir: those assertions are signed with, with
+    /// --assertion-local-keys, and bearer sessions are encrypted with, if
+    /// there's no --bearer-session-secret-file. New keys are published a
+    /// quarter of the period before they're used, and old ones kept for
+    /// another period. 0 disables it.
+    #[clap(long, default_value_t = 0, value_parser = rotation_days)]
+    local_key_rotation_days: u64,
+
+    /// Latency SLO threshold, in milliseconds.
+    /// Enables evaluating the latency SLO, exposed at /-/metrics.
+    #[clap(long)]
+    slo_latency_ms: Option<u64>,
+
+    /// Fraction of decisions that need to be faster than the latency SLO
+    /// threshold, between 0 and 1 (exclusive).
+    #[clap(long, default_value_t = 0.99, value_parser = objective)]
+    slo_latency_objective: f64,
+
+    /// Fraction of decisions that must not fail with a server error, between
+    /// 0 and 1 (exclusive). Enables evaluating the availability SLO, exposed
+    /// at /-/metrics.
+    #[clap(long, value_parser = objective)]
+    slo_availability_objective: Option<f64>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write a self-contained fixture to reproduce a decision: a signed test
+    /// token, the JWKS to verify it with, and a curl command hitting /auth.
+    MakeFixture(FixtureArgs),
+    /// Check the hash chain of audit log files, passed oldest first, like
+    /// `audit.log.2 audit.log.1 audit.log`.
+    VerifyAuditLog { files: Vec<PathBuf> },
+    /// Check that a decision id handed to a user was signed with the key
+    /// (see --decision-id-key-file), and print the id to look up in the
+    /// audit log.
+    VerifyDecisionId {
+        #[clap(long)]
+        key_file: PathBuf,
+        value: String,
+    },
+}
+
+fn parse_issuer_jwks_uri(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(issuer, uri)| (issuer.to_owned(), uri.to_owned()))
+        .ok_or_else(|| "expected ISSUER=URL".to_owned())
+}
+
+/// SLO objectives need an error budget (1 - objective) to burn.
+fn objective(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(objective) if objective > 0.0 && objective < 1.0 => Ok(objective),
+        _ => Err("expected a number between 0 and 1 (exclusive)".to_owned()),
+    }
+}
+
+/// Rotation periods are handled in seconds, keys are kept for two of them.
+fn rotation_days(s: &str) -> Result<u64, String> {
+    let days = s.parse::<u64>().map_err(|e| e.to_string())?;
+    match days.checked_mul(2 * 24 * 3600) {
+        Some(_) => Ok(days),
+        None => Err("rotation period too long".to_owned()),
+    }
+}
+
+fn positive_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err("expected a positive number".to_owned()),
+    }
+}
+
+// Use jemalloc with heap profiling enabled, so /-/pprof/heap can dump profiles.
+#[cfg(feature = "pprof")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "pprof")]
+#[allow(non_upper_case_globals)]
+#[export_name = "_rjem_malloc_conf"]
+pub static malloc_conf: &[u8] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
+
+/// Build the parts of the configuration that can be reloaded at runtime,
+/// along with the compiled named policies.
+async fn load_reloadable(
+    cli: &Cli,
+    config: config::ConfigFile,
+) -> eyre::Result<(Reloadable, HashMap<String, cel_interpreter::Program>)> {
+    let grace_period = Duration::from_secs(cli.jwks_grace_period_secs);
+    let mut sources = Vec::new();
+    for jwks_uri in &cli.jwks_uri {
+        let source = JwksSource::new_from(None, jwks_uri.clone()).await?;
+        sources.push(KeySource::Jwks(source.with_grace_period(grace_period)));
+    }
+    for (issuer, jwks_uri) in &cli.issuer_jwks_uris {
+        let source = JwksSource::new_from(Some(issuer.clone()), jwks_uri.clone()).await?;
+        sources.push(KeySource::Jwks(source.with_grace_period(grace_period)));
+    }
+    let login_issuer = cli
+        .login_issuer
+        .iter()
+        .filter(|issuer| !cli.issuers.contains(issuer));
+    for issuer in cli.issuers.iter().chain(login_issuer) {
+        let source = JwksSource::discover(issuer.clone()).await?;
+        sources.push(KeySource::Jwks(source.with_grace_period(grace_period)));
+    }
+    if !cli.key_files.is_empty() {
+        let source = StaticSource::load(None, cli.key_files.clone())?;
+        if cli.watch_key_files {
+            source.watch()?;
+        }
+        sources.push(KeySource::Static(source));
+    }
+    if !cli.hmac_secret_files.is_empty() {
+        sources.push(KeySource::Hmac(HmacSource::load(
+            None,
+            &cli.hmac_secret_files,
+        )?));
+    }
+
+    let mut key_store = KeyStore::new(sources);
+    if let Some(allowed_algs) = &cli.allowed_algs {
+        key_store = key_store.with_allowed_algs(allowed_algs.clone());
+    }
+    if !cli.jwe_key_files.is_empty() {
+        let keys = cli
+            .jwe_key_files
+            .iter()
+            .map(|path| DecryptionKey::from_file(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        key_store = key_store.with_decryption_keys(keys);
+    }
+
+    let mut policies = config.policies;
+    if let Some(path) = &cli.config {
+        let source: Arc<str> = path.display().to_string().into();
+        for policy in policies.values_mut() {
+            policy.source = Some(source.clone());
+        }
+    }
+    if let Some(policy_dir) = &cli.policy_dir {
+        for (name, policy) in config::load_policy_dir(policy_dir)? {
+            if policies.contains_key(&name) {
+                eyre::bail!("policy {} defined both in config and policy dir", name);
+            }
+            policies.insert(name, policy);
+        }
+    }
+
+    for (name, policy) in policies.iter_mut() {
+        policy.name = Some(name.as_str().into());
+    }
+    for (name, policy) in &policies {
+        if let Some(fallback) = &policy.fallback {
+            if !policies.contains_key(fallback) {
+                eyre::bail!("fallback {} of policy {} doesn't exist", fallback, name);
+            }
+        }
+    }
+    let default_policy = cli.default_cel.clone().map(|cel| Policy {
+        name: Some("default".into()),
+        source: Some("--default-cel".into()),
+        ..Policy::from(cel)
+    });
+
+    // compile named policies upfront, so errors surface on startup.
+    let mut programs = HashMap::new();
+    let named = policies
+        .iter()
+        .map(|(name, policy)| (name.as_str(), policy));
+    for (name, policy) in named.chain(default_policy.iter().map(|p| ("default", p))) {
+        for cel in std::iter::once(&policy.cel).chain(&policy.backend) {
+            let cel_str = policy
+                .expand(cel, &config.cel.macros)
+                .map_err(|e| eyre::eyre!("failed to expand policy {}: {}", name, e))?;
+            let program = cel_interpreter::Program::compile(&cel_str)
+                .map_err(|e| eyre::eyre!("failed to compile policy {}: {}", name, e))?;
+            programs.insert(cel_str, program);
+        }
+    }
+
+    Ok((
+        Reloadable {
+            key_store,
+            verification_defaults: config.verification,
+            policies,
+            default_policy,
+            flags: cli.flags_file.as_deref().map(Flags::load).transpose()?,
+            cel_constants: config.cel.constants()?,
+            cel_macros: config.cel.macros,
+            api_keys: cli
+                .api_keys_file
+                .as_deref()
+                .map(ApiKeys::load)
+                .transpose()?,
+            geoip: if cli.geoip_dbs.is_empty() {
+                None
+            } else {
+                Some(Arc::new(GeoIp::load(&cli.geoip_dbs)?))
+            },
+        },
+        programs,
+    ))
+}
+
+/// Insert the programs of the named policies into the cache.
+fn insert_programs(cache: &ProgramCache, programs: HashMap<String, cel_interpreter::Program>) {
+    if programs.len() > cache.capacity() {
+        warn!(
+            policies = programs.len(),
+            capacity = cache.capacity(),
+            "more policies than fit into the CEL program cache, these will be recompiled"
+        );
+    }
+    for (cel_str, program) in programs {
+        cache.insert(cel_str, Arc::new(program));
+    }
+}
+
+/// Reload the configuration from the config file and command line, and swap
+/// it in. Settings that aren't part of [Reloadable] (like listeners) need a
+/// restart to change.
+async fn reload_config(state: &AppState) -> eyre::Result<()> {
+    // the command line is the same as on startup, so only the config file
+    // can fail to parse.
+    let (matches, config) = config::parse_args(Cli::command(), "config")?;
+    let cli = Cli::from_arg_matches(&matches)?;
+
+    let (reloadable, programs) = load_reloadable(&cli, config).await?;
+    insert_programs(&state.cel_programs, programs);
+    state.reloadable.store(Arc::new(reloadable));
+    if let Some(decision_cache) = &state.decision_cache {
+        decision_cache.clear();
+    }
+
+    Ok(())
+}
+
+fn main() -> eyre::Result<()> {
+    #[cfg(all(windows, feature = "windows-service"))]
+    if std::env::args_os().any(|arg| arg == "--windows-service") {
+        // there's no stderr for services, so errors can only be logged.
+        return Ok(cellulose::service::run(|| {
+            run_blocking()
+                .inspect_err(|e| tracing::error!(err = %e, "failed to run"))
+                .is_ok()
+        })?);
+    }
+
+    run_blocking()
+}
+
+fn run_blocking() -> eyre::Result<()> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run())
 }
 
-#[tokio::main]
-async fn main() -> eyre::Result<()> {
-    cellulose::util::setup_tracing();
+async fn run() -> eyre::Result<()> {
+    let (matches, config) = config::parse_args(Cli::command(), "config")?;
+    let cli = Cli::from_arg_matches(&matches)?;
+
+    let otlp_exporter = cellulose::util::setup_tracing(
+        cli.log_format,
+        cli.log_filter.as_deref(),
+        cli.otlp_endpoint.as_deref(),
+    );
+
+    match &cli.command {
+        Some(Command::MakeFixture(args)) => {
+            fixture::make_fixture(args)?;
+            info!(out = %args.out.display(), "wrote fixture");
+            return Ok(());
+        }
+        Some(Command::VerifyAuditLog { files }) => {
+            let records = audit::verify(files)?;
+            info!(records, "audit log chain intact");
+            return Ok(());
+        }
+        Some(Command::VerifyDecisionId { key_file, value }) => {
+            let decision_ids = DecisionIds::new(
+                DecisionIds::load_key(key_file)?,
+                decision_id::Delivery::Header,
+                Duration::ZERO,
+            );
+            let Some(id) = decision_ids.verify(value) else {
+                eyre::bail!("invalid decision id");
+            };
+            println!("{}", id);
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if cli.jwks_uri.is_empty()
+        && cli.issuer_jwks_uris.is_empty()
+        && cli.issuers.is_empty()
+        && cli.login_issuer.is_none()
+        && cli.key_files.is_empty()
+        && cli.hmac_secret_files.is_empty()
+        && cli.introspection_endpoint.is_none()
+    {
+        eyre::bail!(
+            "at least one JWKS endpoint, key file, HMAC secret or introspection endpoint needs to be configured"
+        );
+    }
+
+    let (reloadable, programs) = load_reloadable(&cli, config).await?;
+
+    // the JWKS were fetched by now, check the clock against their Date.
+    let clock_check = (cli.max_clock_skew_secs > 0).then(|| clock::ClockCheck {
+        max_skew: Duration::from_secs(cli.max_clock_skew_secs),
+        refuse: cli.refuse_on_clock_skew,
+    });
+    if let Some(o) = clock_check.as_ref().and_then(|c| c.exceeded()) {
+        if cli.refuse_on_clock_skew {
+            eyre::bail!(
+                "system clock is {}s off the time of {}, refusing to start",
+                o.skew,
+                o.source
+            );
+        }
+        warn!(skew = o.skew, source = o.source, "system clock is skewed");
+    }
+
+    let introspector = match (
+        cli.introspection_endpoint,
+        cli.introspection_client_id,
+        cli.introspection_client_secret_file,
+    ) {
+        (Some(endpoint), Some(client_id), Some(secret_file)) => {
+            let client_secret = std::fs::read_to_string(&secret_file)?;
+            Some(Introspector::new(
+                endpoint,
+                client_id,
+                client_secret.trim().to_owned(),
+            ))
+        }
+        _ => None,
+    };
+
+    let login = match (
+        &cli.login_issuer,
+        &cli.login_client_id,
+        &cli.login_client_secret_file,
+        &cli.login_redirect_uri,
+    ) {
+        (Some(issuer), Some(client_id), Some(secret_file), Some(redirect_uri)) => {
+            let client_secret = std::fs::read_to_string(secret_file)?;
+            #[cfg(feature = "redis")]
+            let store: Arc<dyn SessionStore> = match &cli.session_redis_url {
+                Some(url) => Arc::new(session::RedisStore::connect(url).await?),
+                None => Arc::new(session::MemoryStore::default()),
+            };
+            #[cfg(not(feature = "redis"))]
+            let store: Arc<dyn SessionStore> = Arc::new(session::MemoryStore::default());
+            let login = Login::discover(
+                LoginConfig {
+                    issuer: issuer.clone(),
+                    client_id: client_id.clone(),
+                    client_secret: client_secret.trim().to_owned(),
+                    redirect_uri: redirect_uri.clone(),
+                    scopes: cli.login_scopes.clone(),
+                    cookie_domain: cli.session_cookie_domain.clone(),
+                    end_provider_session: cli.logout_at_provider,
+                    post_logout_redirect_uri: cli.post_logout_redirect_uri.clone(),
+                },
+                store,
+            )
+            .await?;
+            Some(Arc::new(login))
+        }
+        _ => None,
+    };
 
-    let cli = Cli::parse();
+    if let Some(state_dir) = &cli.state_dir {
+        match METRICS.load(state_dir) {
+            Ok(true) => info!(state_dir = %state_dir.display(), "restored metrics snapshot"),
+            Ok(false) => {}
+            Err(e) => warn!(err = %e, "failed to restore metrics snapshot"),
+        }
+    }
+
+    let cel_programs = ProgramCache::new(cli.cel_cache_size);
+    insert_programs(&cel_programs, programs);
+
+    let denylist = Arc::new(match &cli.denylist_file {
+        Some(path) => Denylist::load(path.clone())?,
+        None => Denylist::default(),
+    });
+    // revoked tokens are rejected first, sparing the others.
+    let mut claim_validators = ClaimValidators::default().with(Revocation(denylist.clone()));
+    let set = |values: &[String]| values.iter().cloned().collect::<HashSet<_>>();
+    if !cli.require_issuer.is_empty() {
+        claim_validators = claim_validators.with(Issuer(set(&cli.require_issuer)));
+    }
+    if !cli.require_audience.is_empty() {
+        claim_validators = claim_validators.with(Audience(set(&cli.require_audience)));
+    }
+    if !cli.require_acr.is_empty() {
+        claim_validators = claim_validators.with(Acr(set(&cli.require_acr)));
+    }
 
     let state = AppState {
-        key_store: KeyStore::new_from(cli.jwks_uri).await?,
-        cel_programs: Arc::new(RwLock::new(HashMap::new())),
+        reloadable: Arc::new(ArcSwap::from_pointee(reloadable)),
+        cel_programs: Arc::new(cel_programs),
+        introspector,
+        dpop: DpopValidator::new(Duration::from_secs(cli.dpop_proof_max_age_secs)),
+        baggage_claims: cli.baggage_claims.clone().into(),
+        identity_headers: cli.identity_headers.clone().into(),
+        cel_timeout: (cli.cel_timeout_ms > 0).then(|| Duration::from_millis(cli.cel_timeout_ms)),
+        forwarded_for: forwarded_for::ForwardedFor {
+            max_entries: cli.xff_max_entries,
+            on_violation: cli.xff_on_violation,
+            depth: cli.xff_depth.get(),
+        },
+        client_cert: cli
+            .client_cert_format
+            .map(|format| client_cert::ClientCertSource {
+                format,
+                header: cli
+                    .client_cert_header
+                    .clone()
+                    .unwrap_or_else(|| format.default_header()),
+            }),
+        principal_claims: cli.principal_claims.clone().into(),
+        expired_tokens: cli.expired_token_loop_threshold.map(|threshold| {
+            ExpiredTokens::new(
+                threshold.get(),
+                Duration::from_secs(cli.expired_token_loop_window_secs),
+                cli.expired_token_loop_redirect.clone(),
+            )
+        }),
+        tenant_usage: cli.tenant_claim.clone().map(|claim| {
+            Arc::new(tenant_usage::TenantUsage::new(
+                claim,
+                Duration::from_secs(cli.tenant_usage_window_secs),
+                cli.tenant_usage_dir
+                    .clone()
+                    .map(|dir| tenant_usage::Export {
+                        dir,
+                        format: cli.tenant_usage_format,
+                        keep: cli.tenant_usage_keep,
+                    }),
+            ))
+        }),
+        clock_check,
+        combiner: identities::Combiner {
+            mode: cli.identity_combiner,
+            weights: cli.identity_weights.clone(),
+            min_weight: cli.min_identity_weight,
+        },
+        login,
+        bearer_sessions: (cli.bearer_session_ttl_secs > 0)
+            .then(|| {
+                let ttl = Duration::from_secs(cli.bearer_session_ttl_secs);
+                let cookie_domain = cli.session_cookie_domain.clone();
+                match &cli.bearer_session_secret_file {
+                    Some(path) => BearerSessions::load(path, ttl, cookie_domain),
+                    None => Ok(BearerSessions::new(ttl, cookie_domain)),
+                }
+            })
+            .transpose()?,
+        realm: cli.realm.as_str().into(),
+        sensitive_headers: sensitive_headers::SensitiveHeaders::new(cli.sensitive_headers.clone()),
+        non_utf8_headers: cli.non_utf8_headers,
+        assertion_signer: match &cli.assertion_key {
+            Some(path) => Some(assertion::Signer::load(
+                path,
+                Duration::from_secs(cli.assertion_ttl_secs),
+            )?),
+            // the keys are replaced by local ones right away.
+            None if cli.assertion_local_keys => Some(assertion::Signer::generate(
+                Duration::from_secs(cli.assertion_ttl_secs),
+            )),
+            None => None,
+        }
+        .map(Arc::new),
+        policy_denial_status: if cli.unauthorized_on_policy_denial {
+            StatusCode::UNAUTHORIZED
+        } else {
+            StatusCode::FORBIDDEN
+        },
+        supervisor: Supervisor::default(),
+        decision_cache: (cli.decision_cache_ttl_secs > 0).then(|| {
+            Arc::new(DecisionCache::new(Duration::from_secs(
+                cli.decision_cache_ttl_secs,
+            )))
+        }),
+        denylist,
+        claim_validators,
+        rate_limiter: (cli.rate_limit_ip_rps.is_some() || cli.rate_limit_subject_rps.is_some())
+            .then(|| {
+                let limit = |per_second: Option<f64>, burst: NonZeroU32| {
+                    per_second.map(|per_second| rate_limit::Limit {
+                        per_second,
+                        burst: burst.get(),
+                    })
+                };
+                Arc::new(rate_limit::RateLimiter::new(
+                    limit(cli.rate_limit_ip_rps, cli.rate_limit_ip_burst),
+                    limit(cli.rate_limit_subject_rps, cli.rate_limit_subject_burst),
+                ))
+            }),
+        lockout: cli.lockout_threshold.map(|threshold| {
+            Arc::new(lockout::Lockout::new(
+                threshold.get(),
+                Duration::from_secs(cli.lockout_window_secs),
+                Duration::from_secs(cli.lockout_duration_secs),
+            ))
+        }),
     };
 
-    // setup automatic refresh attempts
-    tokio::spawn({
-        let key_store = state.key_store.clone();
+    let supervisor = state.supervisor.clone();
+
+    // rotate locally held keys, if enabled.
+    if cli.local_key_rotation_days > 0 {
+        let Some(state_dir) = &cli.state_dir else {
+            eyre::bail!("--local-key-rotation-days requires --state-dir");
+        };
+        let mut local_keys = LocalKeys::new(
+            state_dir.clone(),
+            Duration::from_secs(cli.local_key_rotation_days * 24 * 3600),
+        );
+        if cli.assertion_local_keys {
+            if let Some(signer) = &state.assertion_signer {
+                local_keys = local_keys.with_assertion_signer(signer.clone());
+            }
+        }
+        if cli.bearer_session_secret_file.is_none() {
+            if let Some(bearer_sessions) = &state.bearer_sessions {
+                local_keys = local_keys.with_bearer_sessions(bearer_sessions.clone());
+            }
+        }
+        local_keys.rotate()?;
+        supervisor.spawn("local-key-rotation", move || local_keys.clone().run());
+    } else if cli.assertion_local_keys {
+        eyre::bail!("--assertion-local-keys requires --local-key-rotation-days");
+    }
 
-        async move {
-            let mut interval = time::interval(Duration::from_secs(1 * 60));
-            interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+    if let Some(exporter) = otlp_exporter {
+        supervisor.spawn_once("otlp-export", exporter.run());
+    }
 
-            loop {
-                interval.tick().await;
-                if key_store.should_refresh().await {
-                    let retry_strategy = ExponentialBackoff::from_millis(10)
-                        .map(tokio_retry::strategy::jitter)
-                        .take(3);
+    // reload the configuration on SIGHUP (Ctrl+Break on Windows)
+    supervisor.spawn("config-reload", {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                let mut reload = match signals::Reload::new() {
+                    Ok(reload) => reload,
+                    Err(e) => {
+                        warn!(err = %e, "unable to listen for reload signals");
+                        return;
+                    }
+                };
+                loop {
+                    reload.recv().await;
+                    match reload_config(&state).await {
+                        Ok(()) => info!("reloaded configuration"),
+                        Err(e) => {
+                            warn!(err = %e, "failed to reload configuration, keeping the previous one")
+                        }
+                    }
+                }
+            }
+        }
+    });
 
-                    let key_store = key_store.clone();
-                    let action = || key_store.refresh();
+    // setup automatic refresh attempts
+    supervisor.spawn("key-refresh", {
+        let reloadable = state.reloadable.clone();
+        move || {
+            let reloadable = reloadable.clone();
+            async move {
+                let refresh_interval = reloadable.load().key_store.refresh_interval();
+                let mut interval = time::interval(refresh_interval);
+                interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
 
-                    Retry::spawn(retry_strategy, action);
+                loop {
+                    interval.tick().await;
+                    let key_store = reloadable.load_full().key_store.clone();
+                    key_store.refresh_due().await;
                 }
             }
         }
     });
 
-    let app = gen_router()
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
+    // warn about policies about to expire
+    if cli.policy_expiry_warning_secs > 0 {
+        let reloadable = state.reloadable.clone();
+        let within = Duration::from_secs(cli.policy_expiry_warning_secs);
+        supervisor.spawn("policy-expiry", move || {
+            let reloadable = reloadable.clone();
+            async move {
+                let mut interval = time::interval(Duration::from_secs(3600));
+                loop {
+                    interval.tick().await;
+                    warn_expiring(
+                        &reloadable.load().policies,
+                        std::time::SystemTime::now().into(),
+                        within,
+                    );
+                }
+            }
+        });
+    }
+
+    // setup the SLO evaluator, if any SLO is configured
+    if cli.slo_latency_ms.is_some() || cli.slo_availability_objective.is_some() {
+        let slo_config = SloConfig {
+            latency_threshold: cli.slo_latency_ms.map(Duration::from_millis),
+            latency_objective: cli.slo_latency_objective,
+            availability_objective: cli.slo_availability_objective,
+        };
+        supervisor.spawn("slo", move || slo::Evaluator::new(slo_config.clone()).run());
+    }
+
+    if let Some(tenant_usage) = &state.tenant_usage {
+        let tenant_usage = tenant_usage.clone();
+        supervisor.spawn("tenant-usage", move || tenant_usage.clone().run());
+    }
+
+    let denial_webhook = cli.denial_webhook_url.as_ref().map(|url| {
+        DenialWebhook::new(
+            url.clone(),
+            state.forwarded_for,
+            cli.denial_webhook_batch_size,
+            Duration::from_secs(cli.denial_webhook_interval_secs.max(1)),
+        )
+    });
+    if let Some(denial_webhook) = &denial_webhook {
+        let denial_webhook = denial_webhook.clone();
+        supervisor.spawn("denial-webhook", move || denial_webhook.clone().run());
+    }
+
+    if let Some(clock_check) = &state.clock_check {
+        let clock_check = clock_check.clone();
+        supervisor.spawn("clock-check", move || clock_check.clone().run());
+    }
+
+    // poll revocations, if configured
+    if let Some(url) = &cli.revocation_url {
+        let url = url.clone();
+        let period = Duration::from_secs(cli.revocation_refresh_secs.max(1));
+        let denylist = state.denylist.clone();
+        let decision_cache = state.decision_cache.clone();
+        supervisor.spawn("revocation-refresh", move || {
+            let (url, denylist, decision_cache) =
+                (url.clone(), denylist.clone(), decision_cache.clone());
+            async move {
+                let mut interval = time::interval(period);
+                interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+                let mut etag = None;
+                loop {
+                    interval.tick().await;
+                    match denylist::fetch_revocations(&url, etag.as_deref()).await {
+                        Ok(Some((entries, new_etag))) => {
+                            etag = new_etag;
+                            // decisions for revoked tokens may be cached.
+                            if denylist.set_remote(entries) {
+                                if let Some(decision_cache) = &decision_cache {
+                                    decision_cache.clear();
+                                }
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => warn!(err = %e, "failed to refresh revocations"),
+                    }
+                }
+            }
+        });
+    }
 
-    let listen_address = &cli.listen_args.listen_address.unwrap_or_else(|| {
+    let listen_address = &cli.listen_args.listen_address.clone().unwrap_or_else(|| {
         "[::]:9000"
             .parse()
             .expect("invalid fallback listen address")
     });
+    // check before binding any listener, for an error naming both.
+    listeners::check(
+        &std::iter::once(("main", listen_address))
+            .chain(cli.admin_listen_address.iter().map(|a| ("admin", a)))
+            .chain(cli.spoe_listen_address.iter().map(|a| ("SPOE", a)))
+            .collect::<Vec<_>>(),
+    )?;
+
+    if let Some(admin_listen_address) = &cli.admin_listen_address {
+        let admin_auth = match (&cli.admin_token_file, &cli.admin_policy) {
+            (Some(path), _) => Some(AdminAuth::token_from_file(path)?),
+            // the admin listener keeps using the keys from startup.
+            (None, Some(policy)) => Some(AdminAuth::policy(
+                state.reloadable.load().key_store.clone(),
+                policy,
+            )?),
+            (None, None) => None,
+        };
+        if admin_auth.is_none() {
+            warn!("admin listener is unauthenticated");
+        }
+
+        let admin_app = gen_admin_router(admin_auth).with_state(state.clone());
+        let admin_listener = tokio_listener::Listener::bind(
+            admin_listen_address,
+            &Default::default(),
+            &Default::default(),
+        )
+        .await?;
+
+        info!(%admin_listen_address, "starting admin listener");
+
+        supervisor.spawn_once("admin-listener", async move {
+            if let Err(e) = tokio_listener::axum07::serve(
+                admin_listener,
+                admin_app
+                    .into_make_service_with_connect_info::<tokio_listener::SomeSocketAddrClonable>(
+                    ),
+            )
+            .await
+            {
+                warn!(err=%e, "admin listener failed");
+            }
+        });
+    }
+
+    // empty values disable the header
+    let optional_header = |v: String| (!v.is_empty()).then(|| v.try_into()).transpose();
+    let security_headers = SecurityHeaders {
+        content_security_policy: optional_header(cli.content_security_policy)?,
+        referrer_policy: optional_header(cli.referrer_policy)?,
+        hsts_max_age: (cli.hsts_max_age_secs > 0)
+            .then(|| Duration::from_secs(cli.hsts_max_age_secs)),
+    };
+
+    let trusted_proxies = forwarded_for::TrustedProxies {
+        proxies: cli.trusted_proxies.clone().into(),
+        on_untrusted: cli.on_untrusted_proxy,
+        extra_headers: state
+            .client_cert
+            .iter()
+            .map(|source| source.header.clone())
+            .collect(),
+    };
+
+    if let Some(spoe_listen_address) = &cli.spoe_listen_address {
+        let spoe_listener = tokio_listener::Listener::bind(
+            spoe_listen_address,
+            &Default::default(),
+            &Default::default(),
+        )
+        .await?;
+
+        info!(%spoe_listen_address, "starting SPOE listener");
+        supervisor.spawn_once(
+            "spoe-listener",
+            spoe::serve(spoe_listener, state.clone(), trusted_proxies.clone()),
+        );
+    }
+
+    let error_pages = ErrorPages {
+        format: cli.error_body,
+        template: match &cli.error_page_template {
+            Some(path) => ErrorPages::load_template(path)?,
+            None => error_pages::DEFAULT_TEMPLATE.into(),
+        },
+        translations: cli
+            .error_page_translations
+            .iter()
+            .map(|s| error_pages::Translation::load(s))
+            .collect::<Result<Vec<_>, _>>()?
+            .into(),
+    };
+
+    let health_checks = HealthChecks {
+        user_agents: cli.health_check_user_agents.clone().into(),
+        paths: cli.health_check_paths.clone().into(),
+    };
+
+    let mut audit_log = match &cli.audit_log {
+        Some(path) if path.as_os_str() == "-" => Some(AuditLog::stdout()),
+        Some(path) => Some(AuditLog::open(
+            path.clone(),
+            cli.audit_log_max_bytes,
+            cli.audit_log_keep,
+        )?),
+        None => None,
+    };
+    if let Some(delivery) = cli.decision_id {
+        let key = match &cli.decision_id_key_file {
+            Some(path) => DecisionIds::load_key(path)?,
+            None => cellulose::util::random_id().into_bytes(),
+        };
+        let ttl = Duration::from_secs(cli.decision_id_ttl_secs);
+        audit_log =
+            audit_log.map(|log| log.with_decision_ids(DecisionIds::new(key, delivery, ttl)));
+    }
+    let audit_log = audit_log.map(Arc::new);
+
+    let chaos = Chaos {
+        delay: Duration::from_millis(cli.chaos_delay_ms),
+        error_percent: cli.chaos_error_percent,
+        expired_keys: cli.chaos_expired_keys,
+    };
+    if chaos.enabled() {
+        warn!(
+            ?chaos,
+            "injecting failures into /auth, not for production use"
+        );
+    }
+
+    let load_shedding = LoadShedding::new(
+        cli.max_in_flight_requests.map(NonZeroUsize::get),
+        cli.request_timeout_ms
+            .map(|ms| Duration::from_millis(ms.get())),
+    );
+    if load_shedding.enabled() {
+        info!(
+            max_in_flight_requests = ?cli.max_in_flight_requests,
+            request_timeout_ms = ?cli.request_timeout_ms,
+            "shedding load"
+        );
+    }
+
+    let app = gen_router(
+        security_headers,
+        trusted_proxies,
+        error_pages,
+        health_checks,
+        chaos,
+        audit_log,
+        denial_webhook,
+    )
+    .layer(middleware::from_fn_with_state(
+        load_shedding,
+        load_shedding::apply,
+    ))
+    .layer(TraceLayer::new_for_http())
+    // outermost, so request logs don't render credentials.
+    .layer(middleware::from_fn_with_state(
+        state.sensitive_headers.clone(),
+        sensitive_headers::apply,
+    ))
+    .with_state(state);
 
     let listener = tokio_listener::Listener::bind(
         listen_address,
@@ -103,12 +1679,25 @@ async fn main() -> eyre::Result<()> {
     .await?;
 
     info!(%listen_address, "starting daemon");
+    signals::notify_ready();
 
     tokio_listener::axum07::serve(
         listener,
         app.into_make_service_with_connect_info::<tokio_listener::SomeSocketAddrClonable>(),
     )
+    .with_graceful_shutdown(async {
+        signals::shutdown().await;
+        signals::notify_stopping();
+    })
     .await?;
 
+    supervisor.shutdown(SHUTDOWN_TIMEOUT).await;
+
+    if let Some(state_dir) = &cli.state_dir {
+        if let Err(e) = METRICS.save(state_dir) {
+            warn!(err = %e, "failed to persist metrics snapshot");
+        }
+    }
+
     Ok(())
 }
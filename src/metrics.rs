@@ -0,0 +1,956 @@
+//! Minimal in-process metrics, rendered in the Prometheus text format, or
+//! OpenMetrics if scrapers ask for it.
+//!
+//! If spans are exported (see [crate::otlp]), the latency histogram and
+//! counters of denied decisions carry the trace of a recent decision as
+//! exemplar, which are only rendered in OpenMetrics.
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    fmt::Write,
+    fs, io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use parking_lot::{Mutex, RwLock};
+
+use crate::{health_checks::HealthCheck, otlp};
+
+/// Bucket boundaries (in seconds) used for decision latencies.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// The exposition formats metrics can be rendered in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Prometheus,
+    OpenMetrics,
+}
+
+/// An observation, referencing the trace it was made in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub value: f64,
+    /// When the observation was made, in seconds since the epoch.
+    pub timestamp: f64,
+}
+
+impl Exemplar {
+    fn new(trace_id: String, value: f64) -> Self {
+        Self {
+            trace_id,
+            value,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The exemplar, to append to a sample, if rendering OpenMetrics.
+    fn render(exemplar: &Mutex<Option<Exemplar>>, format: Format) -> String {
+        match (format, &*exemplar.lock()) {
+            (Format::OpenMetrics, Some(e)) => format!(
+                " # {{trace_id=\"{}\"}} {} {}",
+                escape_label_value(&e.trace_id),
+                e.value,
+                e.timestamp
+            ),
+            _ => String::new(),
+        }
+    }
+}
+
+/// A monotonically increasing counter.
+#[derive(Default)]
+pub struct Counter {
+    value: AtomicU64,
+    exemplar: Mutex<Option<Exemplar>>,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1)
+    }
+
+    pub fn inc_by(&self, v: u64) {
+        self.value.fetch_add(v, Ordering::Relaxed);
+    }
+
+    /// Increment the counter, and keep the trace it was incremented in, if
+    /// any, as exemplar.
+    pub fn inc_with_exemplar(&self, trace_id: Option<String>) {
+        self.inc();
+        if let Some(trace_id) = trace_id {
+            *self.exemplar.lock() = Some(Exemplar::new(trace_id, 1.0));
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, v: f64) {
+        self.0.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// A histogram with fixed buckets.
+pub struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<AtomicU64>,
+    /// The last exemplar of each bucket, including +Inf.
+    exemplars: Vec<Mutex<Option<Exemplar>>>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            exemplars: (0..=buckets.len()).map(|_| Mutex::new(None)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        if let Some(i) = self.buckets.iter().position(|le| v <= *le) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        // there's no atomic f64 add, so do a CAS loop.
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + v).to_bits())
+            });
+    }
+
+    /// Observe [v], and keep the trace it was observed in, if any, as
+    /// exemplar of its bucket.
+    pub fn observe_with_exemplar(&self, v: f64, trace_id: Option<String>) {
+        self.observe(v);
+        if let Some(trace_id) = trace_id {
+            let i = (self.buckets.iter().position(|le| v <= *le)).unwrap_or(self.buckets.len());
+            *self.exemplars[i].lock() = Some(Exemplar::new(trace_id, v));
+        }
+    }
+
+    /// The bucket boundaries of this histogram.
+    pub fn buckets(&self) -> &'static [f64] {
+        self.buckets
+    }
+
+    /// Returns the number of observations <= the bucket boundary at index [i].
+    pub fn cumulative_count(&self, i: usize) -> u64 {
+        self.counts[..=i]
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns the total number of observations.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, name: &str, labels: &str, format: Format, out: &mut String) {
+        for (i, le) in self.buckets.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{}le=\"{le}\"}} {}{}",
+                label_prefix(labels),
+                self.cumulative_count(i),
+                Exemplar::render(&self.exemplars[i], format)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{}le=\"+Inf\"}} {}{}",
+            label_prefix(labels),
+            self.count(),
+            Exemplar::render(&self.exemplars[self.buckets.len()], format)
+        );
+        let _ = writeln!(
+            out,
+            "{name}_sum{} {}",
+            braced(labels),
+            f64::from_bits(self.sum.load(Ordering::Relaxed))
+        );
+        let _ = writeln!(out, "{name}_count{} {}", braced(labels), self.count());
+    }
+}
+
+/// A set of metrics of the same kind, distinguished by their label values.
+pub struct Family<M> {
+    label_names: &'static [&'static str],
+    new_metric: fn() -> M,
+    metrics: RwLock<BTreeMap<Vec<String>, Arc<M>>>,
+}
+
+impl<M> Family<M> {
+    pub fn new(label_names: &'static [&'static str], new_metric: fn() -> M) -> Self {
+        Self {
+            label_names,
+            new_metric,
+            metrics: Default::default(),
+        }
+    }
+
+    /// Returns the metric for the given label values, creating it if needed.
+    /// The label values need to be passed in the same order as the label names.
+    pub fn with_labels(&self, values: &[&str]) -> Arc<M> {
+        debug_assert_eq!(self.label_names.len(), values.len());
+
+        let key = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        if let Some(m) = self.metrics.read().get(&key) {
+            return m.clone();
+        }
+
+        self.metrics
+            .write()
+            .entry(key)
+            .or_insert_with(|| Arc::new((self.new_metric)()))
+            .clone()
+    }
+
+    /// Returns all metrics of the family, along with their label values.
+    pub(crate) fn entries(&self) -> Vec<(Vec<String>, Arc<M>)> {
+        self.metrics
+            .read()
+            .iter()
+            .map(|(values, m)| (values.clone(), m.clone()))
+            .collect()
+    }
+
+    fn for_each(&self, mut f: impl FnMut(String, &M)) {
+        for (values, m) in self.metrics.read().iter() {
+            let labels = self
+                .label_names
+                .iter()
+                .zip(values)
+                .map(|(k, v)| format!("{k}=\"{}\"", escape_label_value(v)))
+                .collect::<Vec<_>>()
+                .join(",");
+            f(labels, m)
+        }
+    }
+}
+
+impl<M: Default> Default for Family<M> {
+    fn default() -> Self {
+        Self::new(&[], M::default)
+    }
+}
+
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{labels}}}")
+    }
+}
+
+fn label_prefix(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{labels},")
+    }
+}
+
+/// All metrics exposed by cellulose.
+pub struct Metrics {
+    /// Decisions taken by the /auth endpoint, by outcome
+    /// (allowed, denied, forbidden, error).
+    pub decisions: Family<Counter>,
+
+    /// Latency of decisions taken by the /auth endpoint.
+    pub decision_duration: Histogram,
+
+    /// SLO burn rates, by slo name and window.
+    pub slo_burn_rate: Family<Gauge>,
+
+    /// Whether an SLO burn rate alert condition is met (1) or not (0), by slo
+    /// name and severity.
+    pub slo_alert: Family<Gauge>,
+
+    /// Tokens rejected for embedding key material in their header, by header
+    /// parameter.
+    pub embedded_key_rejections: Family<Counter>,
+
+    /// JWKS refreshes triggered by tokens with unknown keys, by result
+    /// (ok, error, rate_limited, in_progress).
+    pub jwks_on_demand_refreshes: Family<Counter>,
+
+    /// The current key set generation, incremented on every successful
+    /// refresh of any key source.
+    pub key_set_generation: Family<Gauge>,
+
+    /// Lookups in and evictions from the CEL program cache, by event
+    /// (hit, negative_hit, miss, eviction).
+    pub cel_program_cache: Family<Counter>,
+
+    /// The number of compiled CEL programs cached.
+    pub cel_program_cache_entries: Family<Gauge>,
+
+    /// Inserts into the CEL program cache that had to wait for another one
+    /// to finish.
+    pub cel_program_cache_contended_inserts: Family<Counter>,
+
+    /// CEL programs aborted for exceeding the evaluation timeout.
+    pub cel_evaluation_timeouts: Family<Counter>,
+
+    /// Expired tokens presented, if detection of expired token loops is
+    /// enabled.
+    pub expired_tokens: Family<Counter>,
+
+    /// Responses to clients stuck presenting the same expired token, by
+    /// response (challenge, redirect).
+    pub expired_token_loops: Family<Counter>,
+
+    /// Requests allowed, by tenant, if tenant usage accounting is enabled.
+    pub tenant_allowed_requests: Family<Counter>,
+
+    /// Seconds the local clock is ahead of the Date header of the last JWKS
+    /// response, negative if it's behind.
+    pub clock_skew: Family<Gauge>,
+
+    /// Set to 1 if counters were restored from a snapshot, labelled with
+    /// the time the snapshot was taken, to mark restarts on dashboards.
+    pub snapshot_restored: Family<Gauge>,
+
+    /// Whether a background task is running (1) or not (0), by task.
+    pub tasks_up: Family<Gauge>,
+
+    /// Restarts of background tasks after panicking, by task.
+    pub task_restarts: Family<Counter>,
+
+    /// Lookups in the decision cache, by event (hit, miss).
+    pub decision_cache: Family<Counter>,
+
+    /// Health checks sent to the /auth endpoint, by outcome, which aren't
+    /// counted as decisions.
+    pub health_checks: Family<Counter>,
+
+    /// Denials for the webhook, by result (sent, failed, dropped).
+    pub denial_webhook_events: Family<Counter>,
+
+    /// Request header values that aren't valid UTF-8.
+    pub non_utf8_headers: Family<Counter>,
+
+    /// Client addresses banned for presenting invalid tokens.
+    pub lockouts: Family<Counter>,
+
+    /// Requests answered with a 503 by load shedding, by reason
+    /// (concurrency, timeout).
+    pub shed_requests: Family<Counter>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            decisions: Family::new(&["outcome"], Counter::default),
+            decision_duration: Histogram::new(LATENCY_BUCKETS),
+            slo_burn_rate: Family::new(&["slo", "window"], Gauge::default),
+            slo_alert: Family::new(&["slo", "severity"], Gauge::default),
+            embedded_key_rejections: Family::new(&["header"], Counter::default),
+            jwks_on_demand_refreshes: Family::new(&["result"], Counter::default),
+            key_set_generation: Family::default(),
+            cel_program_cache: Family::new(&["event"], Counter::default),
+            cel_program_cache_entries: Family::default(),
+            cel_program_cache_contended_inserts: Family::default(),
+            cel_evaluation_timeouts: Family::default(),
+            expired_tokens: Family::default(),
+            expired_token_loops: Family::new(&["response"], Counter::default),
+            tenant_allowed_requests: Family::new(&["tenant"], Counter::default),
+            clock_skew: Family::default(),
+            snapshot_restored: Family::new(&["saved_at"], Gauge::default),
+            tasks_up: Family::new(&["task"], Gauge::default),
+            task_restarts: Family::new(&["task"], Counter::default),
+            decision_cache: Family::new(&["event"], Counter::default),
+            health_checks: Family::new(&["outcome"], Counter::default),
+            denial_webhook_events: Family::new(&["result"], Counter::default),
+            non_utf8_headers: Family::default(),
+            lockouts: Family::default(),
+            shed_requests: Family::new(&["reason"], Counter::default),
+        }
+    }
+}
+
+/// The file counter snapshots are persisted to, inside the state directory.
+const SNAPSHOT_FILE: &str = "metrics-snapshot.json";
+
+/// Values of all counters at a point in time.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    /// When the snapshot was taken, in seconds since the epoch.
+    pub saved_at: u64,
+    /// Counter values by metric name, along with their label values.
+    pub counters: BTreeMap<String, Vec<(Vec<String>, u64)>>,
+}
+
+impl Metrics {
+    /// The counter families, by the name they're rendered with.
+    /// Gauges and histograms describe the current process and aren't
+    /// persisted.
+    fn counters(&self) -> [(&'static str, &Family<Counter>); 16] {
+        [
+            ("cellulose_decisions_total", &self.decisions),
+            (
+                "cellulose_embedded_key_rejections_total",
+                &self.embedded_key_rejections,
+            ),
+            (
+                "cellulose_jwks_on_demand_refreshes_total",
+                &self.jwks_on_demand_refreshes,
+            ),
+            (
+                "cellulose_cel_program_cache_events_total",
+                &self.cel_program_cache,
+            ),
+            (
+                "cellulose_cel_program_cache_contended_inserts_total",
+                &self.cel_program_cache_contended_inserts,
+            ),
+            (
+                "cellulose_cel_evaluation_timeouts_total",
+                &self.cel_evaluation_timeouts,
+            ),
+            ("cellulose_expired_tokens_total", &self.expired_tokens),
+            (
+                "cellulose_expired_token_loops_total",
+                &self.expired_token_loops,
+            ),
+            (
+                "cellulose_tenant_allowed_requests_total",
+                &self.tenant_allowed_requests,
+            ),
+            ("cellulose_task_restarts_total", &self.task_restarts),
+            (
+                "cellulose_decision_cache_events_total",
+                &self.decision_cache,
+            ),
+            ("cellulose_health_checks_total", &self.health_checks),
+            (
+                "cellulose_denial_webhook_events_total",
+                &self.denial_webhook_events,
+            ),
+            ("cellulose_non_utf8_headers_total", &self.non_utf8_headers),
+            ("cellulose_lockouts_total", &self.lockouts),
+            ("cellulose_shed_requests_total", &self.shed_requests),
+        ]
+    }
+
+    /// Take a snapshot of all counters.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            counters: self
+                .counters()
+                .into_iter()
+                .map(|(name, family)| {
+                    let values = family
+                        .entries()
+                        .into_iter()
+                        .map(|(labels, c)| (labels, c.get()))
+                        .collect();
+                    (name.to_owned(), values)
+                })
+                .collect(),
+        }
+    }
+
+    /// Add the counter values from the snapshot to the current ones, and
+    /// set the restart marker.
+    /// Counters that don't exist (anymore), or have a different number of
+    /// labels, are skipped.
+    pub fn restore(&self, snapshot: &Snapshot) {
+        for (name, family) in self.counters() {
+            for (labels, value) in snapshot.counters.get(name).into_iter().flatten() {
+                if labels.len() != family.label_names.len() {
+                    continue;
+                }
+                let labels = labels.iter().map(String::as_str).collect::<Vec<_>>();
+                family.with_labels(&labels).inc_by(*value);
+            }
+        }
+        self.snapshot_restored
+            .with_labels(&[&snapshot.saved_at.to_string()])
+            .set(1.0);
+    }
+
+    /// Persist a snapshot of all counters to the state directory.
+    /// The file is written to a temporary file first and renamed, so a
+    /// crash while writing doesn't leave a truncated snapshot behind.
+    pub fn save(&self, state_dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(state_dir)?;
+        let path = state_dir.join(SNAPSHOT_FILE);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&self.snapshot())?)?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// Restore counters from the snapshot in the state directory.
+    /// Returns false if there's no snapshot.
+    /// The snapshot is removed afterwards, so a crash (which doesn't write a
+    /// new one) doesn't cause the same values to be added twice.
+    pub fn load(&self, state_dir: &Path) -> io::Result<bool> {
+        let path = state_dir.join(SNAPSHOT_FILE);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let snapshot: Snapshot = serde_json::from_slice(&data)?;
+        fs::remove_file(path)?;
+        self.restore(&snapshot);
+        Ok(true)
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        self.render_as(Format::Prometheus)
+    }
+
+    /// Render all metrics in the given exposition format.
+    pub fn render_as(&self, format: Format) -> String {
+        let mut out = String::new();
+
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_decisions_total",
+            "Decisions taken by the auth endpoint, by outcome.",
+            &self.decisions,
+        );
+
+        write_header(
+            &mut out,
+            format,
+            "cellulose_decision_duration_seconds",
+            "Latency of decisions taken by the auth endpoint.",
+            "histogram",
+        );
+        self.decision_duration
+            .render("cellulose_decision_duration_seconds", "", format, &mut out);
+
+        render_gauges(
+            &mut out,
+            format,
+            "cellulose_slo_burn_rate",
+            "SLO error budget burn rate, by window.",
+            &self.slo_burn_rate,
+        );
+        render_gauges(
+            &mut out,
+            format,
+            "cellulose_slo_alert",
+            "Whether the multi-window burn rate alert condition is met.",
+            &self.slo_alert,
+        );
+
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_embedded_key_rejections_total",
+            "Tokens rejected for embedding key material in their header.",
+            &self.embedded_key_rejections,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_jwks_on_demand_refreshes_total",
+            "JWKS refreshes triggered by tokens with unknown keys, by result.",
+            &self.jwks_on_demand_refreshes,
+        );
+        render_gauges(
+            &mut out,
+            format,
+            "cellulose_key_set_generation",
+            "The current key set generation, incremented on every refresh.",
+            &self.key_set_generation,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_cel_program_cache_events_total",
+            "Lookups in and evictions from the CEL program cache, by event.",
+            &self.cel_program_cache,
+        );
+        render_gauges(
+            &mut out,
+            format,
+            "cellulose_cel_program_cache_entries",
+            "The number of compiled CEL programs cached.",
+            &self.cel_program_cache_entries,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_cel_program_cache_contended_inserts_total",
+            "Inserts into the CEL program cache that waited for another one.",
+            &self.cel_program_cache_contended_inserts,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_cel_evaluation_timeouts_total",
+            "CEL programs aborted for exceeding the evaluation timeout.",
+            &self.cel_evaluation_timeouts,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_expired_tokens_total",
+            "Expired tokens presented.",
+            &self.expired_tokens,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_expired_token_loops_total",
+            "Responses to clients stuck presenting the same expired token.",
+            &self.expired_token_loops,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_tenant_allowed_requests_total",
+            "Requests allowed, by tenant.",
+            &self.tenant_allowed_requests,
+        );
+        render_gauges(
+            &mut out,
+            format,
+            "cellulose_clock_skew_seconds",
+            "Seconds the local clock is ahead of the last JWKS response's Date.",
+            &self.clock_skew,
+        );
+        render_gauges(
+            &mut out,
+            format,
+            "cellulose_metrics_snapshot_restored",
+            "Whether counters were restored from a snapshot taken at saved_at.",
+            &self.snapshot_restored,
+        );
+        render_gauges(
+            &mut out,
+            format,
+            "cellulose_task_up",
+            "Whether a background task is running.",
+            &self.tasks_up,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_task_restarts_total",
+            "Restarts of background tasks after panicking.",
+            &self.task_restarts,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_decision_cache_events_total",
+            "Lookups in the decision cache, by event.",
+            &self.decision_cache,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_health_checks_total",
+            "Health checks sent to the auth endpoint, by outcome.",
+            &self.health_checks,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_denial_webhook_events_total",
+            "Denials for the webhook, by result.",
+            &self.denial_webhook_events,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_non_utf8_headers_total",
+            "Request header values that aren't valid UTF-8.",
+            &self.non_utf8_headers,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_lockouts_total",
+            "Client addresses banned for presenting invalid tokens.",
+            &self.lockouts,
+        );
+        render_counters(
+            &mut out,
+            format,
+            "cellulose_shed_requests_total",
+            "Requests answered with a 503 by load shedding, by reason.",
+            &self.shed_requests,
+        );
+
+        if format == Format::OpenMetrics {
+            out.push_str("# EOF\n");
+        }
+        out
+    }
+}
+
+fn write_header(out: &mut String, format: Format, name: &str, help: &str, typ: &str) {
+    // OpenMetrics names counters without the suffix of their samples.
+    let name = match (format, typ) {
+        (Format::OpenMetrics, "counter") => name.trim_end_matches("_total"),
+        _ => name,
+    };
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {typ}");
+}
+
+fn render_counters(
+    out: &mut String,
+    format: Format,
+    name: &str,
+    help: &str,
+    family: &Family<Counter>,
+) {
+    write_header(out, format, name, help, "counter");
+    family.for_each(|labels, c| {
+        let _ = writeln!(
+            out,
+            "{name}{} {}{}",
+            braced(&labels),
+            c.get(),
+            Exemplar::render(&c.exemplar, format)
+        );
+    });
+}
+
+fn render_gauges(out: &mut String, format: Format, name: &str, help: &str, family: &Family<Gauge>) {
+    write_header(out, format, name, help, "gauge");
+    family.for_each(|labels, g| {
+        let _ = writeln!(out, "{name}{} {}", braced(&labels), g.get());
+    });
+}
+
+/// The global metrics registry.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Serve all metrics in the Prometheus text exposition format, or in
+/// OpenMetrics (with exemplars), if accepted.
+pub async fn handler(headers: HeaderMap) -> impl IntoResponse {
+    let openmetrics = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("application/openmetrics-text"));
+    if openmetrics {
+        (
+            [(
+                header::CONTENT_TYPE,
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            )],
+            METRICS.render_as(Format::OpenMetrics),
+        )
+    } else {
+        (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            METRICS.render(),
+        )
+    }
+}
+
+tokio::task_local! {
+    /// The trace of the decision being recorded, see [record_trace].
+    static TRACE_ID: Cell<Option<String>>;
+}
+
+/// Remember the trace of the current span, if exported, as the one the
+/// decision being recorded by [record_decision] is part of.
+pub(crate) fn record_trace() {
+    let _ = TRACE_ID.try_with(|trace_id| trace_id.set(otlp::current_trace_id()));
+}
+
+/// Middleware recording the latency and outcome of decisions.
+/// Health checks are only counted, see [crate::health_checks].
+pub async fn record_decision(rq: Request, next: Next) -> Response {
+    let health_check = rq.extensions().get::<HealthCheck>().is_some();
+    let start = Instant::now();
+    let (response, trace_id) = TRACE_ID
+        .scope(Cell::new(None), async {
+            let response = next.run(rq).await;
+            (response, TRACE_ID.with(Cell::take))
+        })
+        .await;
+
+    if health_check {
+        METRICS
+            .health_checks
+            .with_labels(&[outcome(response.status())])
+            .inc();
+        return response;
+    }
+    METRICS
+        .decision_duration
+        .observe_with_exemplar(start.elapsed().as_secs_f64(), trace_id.clone());
+    match outcome(response.status()) {
+        "allowed" => METRICS.decisions.with_labels(&["allowed"]).inc(),
+        outcome => METRICS
+            .decisions
+            .with_labels(&[outcome])
+            .inc_with_exemplar(trace_id),
+    }
+
+    response
+}
+
+/// Classify a response status into a decision outcome. Denials by the
+/// policy (403) are told apart from those of requests without valid
+/// identities.
+pub fn outcome(status: StatusCode) -> &'static str {
+    if status.is_success() {
+        "allowed"
+    } else if status.is_server_error() {
+        "error"
+    } else if status == StatusCode::FORBIDDEN {
+        "forbidden"
+    } else {
+        "denied"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Counter, Family, Format, Histogram, Metrics};
+
+    #[test]
+    fn histogram_cumulative() {
+        let h = Histogram::new(&[0.1, 1.0]);
+        h.observe(0.05);
+        h.observe(0.5);
+        h.observe(5.0);
+
+        assert_eq!(1, h.cumulative_count(0));
+        assert_eq!(2, h.cumulative_count(1));
+        assert_eq!(3, h.count());
+
+        let mut out = String::new();
+        h.render("h", "", Format::Prometheus, &mut out);
+        assert_eq!(
+            "h_bucket{le=\"0.1\"} 1\nh_bucket{le=\"1\"} 2\nh_bucket{le=\"+Inf\"} 3\nh_sum 5.55\nh_count 3\n",
+            out
+        );
+    }
+
+    #[test]
+    fn exemplars() {
+        let h = Histogram::new(&[0.1, 1.0]);
+        h.observe_with_exemplar(0.5, Some("abc".to_string()));
+        h.observe_with_exemplar(5.0, None);
+
+        let mut out = String::new();
+        h.render("h", "", Format::OpenMetrics, &mut out);
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!("h_bucket{le=\"0.1\"} 0", lines[0]);
+        assert!(
+            lines[1].starts_with("h_bucket{le=\"1\"} 1 # {trace_id=\"abc\"} 0.5 "),
+            "{}",
+            lines[1]
+        );
+        assert_eq!("h_bucket{le=\"+Inf\"} 2", lines[2]);
+
+        // only rendered in OpenMetrics
+        let mut out = String::new();
+        h.render("h", "", Format::Prometheus, &mut out);
+        assert!(!out.contains('#'));
+
+        let metrics = Metrics::default();
+        (metrics.decisions.with_labels(&["denied"])).inc_with_exemplar(Some("abc".to_string()));
+        let out = metrics.render_as(Format::OpenMetrics);
+        assert!(out.contains("# TYPE cellulose_decisions counter\n"));
+        assert!(
+            out.contains("cellulose_decisions_total{outcome=\"denied\"} 1 # {trace_id=\"abc\"} 1 ")
+        );
+        assert!(out.ends_with("# EOF\n"));
+        assert!(metrics
+            .render()
+            .contains("cellulose_decisions_total{outcome=\"denied\"} 1\n"));
+    }
+
+    #[test]
+    fn family_labels() {
+        let f: Family<Counter> = Family::new(&["outcome"], Counter::default);
+        f.with_labels(&["allowed"]).inc();
+        f.with_labels(&["allowed"]).inc();
+        f.with_labels(&["denied\"x"]).inc();
+
+        let mut rendered = vec![];
+        f.for_each(|labels, c| rendered.push((labels, c.get())));
+        assert_eq!(
+            vec![
+                ("outcome=\"allowed\"".to_string(), 2),
+                ("outcome=\"denied\\\"x\"".to_string(), 1)
+            ],
+            rendered
+        );
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let dir = tempfile::tempdir().expect("must create dir");
+
+        let before = Metrics::default();
+        before.decisions.with_labels(&["allowed"]).inc_by(3);
+        before.embedded_key_rejections.with_labels(&["jku"]).inc();
+        assert!(!before.load(dir.path()).expect("must load"));
+        before.save(dir.path()).expect("must save");
+
+        let after = Metrics::default();
+        after.decisions.with_labels(&["allowed"]).inc();
+        assert!(after.load(dir.path()).expect("must load"));
+        assert_eq!(4, after.decisions.with_labels(&["allowed"]).get());
+        assert_eq!(1, after.embedded_key_rejections.with_labels(&["jku"]).get());
+        assert!(after
+            .render()
+            .contains("cellulose_metrics_snapshot_restored{saved_at=\""));
+
+        // the snapshot is consumed
+        assert!(!after.load(dir.path()).expect("must load"));
+    }
+}
@@ -0,0 +1,99 @@
+//! OpenID Connect Discovery, resolving an issuer URL to its provider metadata.
+use std::fmt;
+
+use crate::util::HTTP_CLIENT;
+
+/// The subset of the OpenID Provider Metadata we care about.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub jwks_uri: String,
+    /// Where to send browsers to log in, for the login flow.
+    pub authorization_endpoint: Option<String>,
+    /// Where to exchange authorization codes, for the login flow.
+    pub token_endpoint: Option<String>,
+    /// Where to send browsers to log out at the provider (RP-Initiated
+    /// Logout), if supported.
+    pub end_session_endpoint: Option<String>,
+    /// The PKCE code challenge methods supported, if advertised.
+    pub code_challenge_methods_supported: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    Http(reqwest::Error),
+    /// The issuer in the metadata document doesn't match the one it was
+    /// retrieved for.
+    IssuerMismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::Http(e) => write!(f, "failed to fetch provider metadata: {}", e),
+            DiscoveryError::IssuerMismatch { expected, actual } => write!(
+                f,
+                "provider metadata is for issuer {}, expected {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+impl From<reqwest::Error> for DiscoveryError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Http(value)
+    }
+}
+
+/// Returns the URL of the discovery document for the given issuer.
+pub fn discovery_url(issuer: &str) -> String {
+    format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    )
+}
+
+/// Fetch the provider metadata of the given issuer.
+pub async fn discover(issuer: &str) -> Result<ProviderMetadata, DiscoveryError> {
+    let metadata: ProviderMetadata = HTTP_CLIENT
+        .get(discovery_url(issuer))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // The issuer in the document MUST be identical to the one used for
+    // discovery, see OpenID Connect Discovery 1.0, section 4.3.
+    if metadata.issuer != issuer {
+        return Err(DiscoveryError::IssuerMismatch {
+            expected: issuer.to_owned(),
+            actual: metadata.issuer,
+        });
+    }
+
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::discovery_url;
+
+    #[test]
+    fn url() {
+        assert_eq!(
+            "https://idp.example.com/realms/foo/.well-known/openid-configuration",
+            discovery_url("https://idp.example.com/realms/foo/")
+        );
+        assert_eq!(
+            "https://idp.example.com/.well-known/openid-configuration",
+            discovery_url("https://idp.example.com")
+        );
+    }
+}
@@ -0,0 +1,458 @@
+//! Export of spans to an OpenTelemetry collector, via OTLP/HTTP with JSON
+//! encoding, so the latency cellulose adds to requests shows up in traces.
+//!
+//! Spans (decisions, with the verification of the token and the evaluation
+//! of the policy within them, and key refreshes) are recorded as they close,
+//! and sent in batches. They follow the log filter, which includes them at
+//! the default `info` level. If the collector can't keep up, spans are
+//! dropped rather than slowing down decisions.
+//!
+//! Decisions continue the trace of the request they're taken for, if it
+//! carries [W3C trace context](https://www.w3.org/TR/trace-context/)
+//! headers, so they show up as a hop between the proxy and the upstream.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use axum::http::HeaderMap;
+use serde_json::json;
+use tokio::{sync::mpsc, time};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    warn, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer, Registry};
+
+use crate::util::HTTP_CLIENT;
+
+/// The most spans sent in one request.
+const MAX_BATCH: usize = 512;
+
+/// How often spans are sent, unless a batch fills up before.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many spans are queued for export at most, further ones are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq)]
+enum AttributeValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+/// A span being recorded, kept as extension of the span.
+#[derive(Debug)]
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    /// Vendor-specific trace data, passed on from the `tracestate` header.
+    trace_state: Option<String>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, AttributeValue)>,
+}
+
+struct Visitor<'a>(&'a mut Vec<(&'static str, AttributeValue)>);
+
+impl Visitor<'_> {
+    fn set(&mut self, field: &Field, value: AttributeValue) {
+        self.0.retain(|(name, _)| *name != field.name());
+        self.0.push((field.name(), value));
+    }
+}
+
+impl Visit for Visitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, AttributeValue::String(value.to_owned()));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, AttributeValue::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match i64::try_from(value) {
+            Ok(value) => self.set(field, AttributeValue::Int(value)),
+            Err(_) => self.set(field, AttributeValue::String(value.to_string())),
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, AttributeValue::Double(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, AttributeValue::Bool(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.set(field, AttributeValue::String(format!("{:?}", value)));
+    }
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Records spans, and queues them for the [Exporter] once closed.
+pub struct OtlpLayer {
+    spans: mpsc::Sender<SpanData>,
+}
+
+/// Sends the spans recorded by the [OtlpLayer] to the collector.
+pub struct Exporter {
+    /// The URL to POST spans to.
+    url: String,
+    service_name: String,
+    spans: mpsc::Receiver<SpanData>,
+}
+
+/// Setup exporting spans to the collector at [endpoint], the base URL, like
+/// `http://localhost:4318`. The service is named by `OTEL_SERVICE_NAME`, if
+/// set.
+pub fn new(endpoint: &str) -> (OtlpLayer, Exporter) {
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    let exporter = Exporter {
+        url: format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+        service_name: std::env::var("OTEL_SERVICE_NAME")
+            .unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string()),
+        spans: rx,
+    };
+    (OtlpLayer { spans: tx }, exporter)
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        // spans without a (recorded) parent start a new trace.
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            let data = extensions.get::<SpanData>()?;
+            Some((data.trace_id, data.span_id, data.trace_state.clone()))
+        });
+        let now = SystemTime::now();
+        let (trace_id, parent_span_id, trace_state) = match parent {
+            Some((trace_id, span_id, trace_state)) => (trace_id, Some(span_id), trace_state),
+            None => (random_bytes(), None, None),
+        };
+        let mut data = SpanData {
+            trace_id,
+            span_id: random_bytes(),
+            parent_span_id,
+            trace_state,
+            name: span.name(),
+            start: now,
+            end: now,
+            attributes: vec![],
+        };
+        attrs.record(&mut Visitor(&mut data.attributes));
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(&mut Visitor(&mut data.attributes));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(mut data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        data.end = SystemTime::now();
+        // dropped if the queue is full.
+        let _ = self.spans.try_send(data);
+    }
+}
+
+/// The id of the trace the current span is part of, if it's exported.
+pub fn current_trace_id() -> Option<String> {
+    tracing::Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let span = dispatch.downcast_ref::<Registry>()?.span(id)?;
+            let extensions = span.extensions();
+            extensions.get::<SpanData>().map(|data| hex(&data.trace_id))
+        })
+        .flatten()
+}
+
+/// Parse hex-encoded bytes, in lowercase as W3C trace context requires.
+fn unhex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != 2 * N || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Parse a `traceparent` header into the trace id and the id of the parent
+/// span. Later versions may append fields, which are ignored.
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = value.trim().split('-');
+    let version = unhex::<1>(parts.next()?)?;
+    let trace_id = unhex::<16>(parts.next()?)?;
+    let parent_id = unhex::<8>(parts.next()?)?;
+    unhex::<1>(parts.next()?)?;
+    if version == [0xff] || (version == [0] && parts.next().is_some()) {
+        return None;
+    }
+    (trace_id != [0; 16] && parent_id != [0; 8]).then_some((trace_id, parent_id))
+}
+
+/// Make the spans in the current scope part of the trace the request is in,
+/// as told by its `traceparent` and `tracestate` headers, with the outermost
+/// one as child of the span that sent it. Spans started within them after
+/// this inherit it.
+pub fn continue_trace(headers: &HeaderMap) {
+    let Some((trace_id, parent_id)) = headers
+        .get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_traceparent)
+    else {
+        return;
+    };
+    // multiple headers are combined, like any list.
+    let trace_state = headers
+        .get_all("tracestate")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    let trace_state = (!trace_state.is_empty()).then_some(trace_state);
+
+    tracing::Span::current().with_subscriber(|(id, dispatch)| {
+        let Some(span) = dispatch.downcast_ref::<Registry>().and_then(|r| r.span(id)) else {
+            return;
+        };
+        for span in span.scope() {
+            let mut extensions = span.extensions_mut();
+            if let Some(data) = extensions.get_mut::<SpanData>() {
+                data.trace_id = trace_id;
+                data.trace_state.clone_from(&trace_state);
+                if data.parent_span_id.is_none() {
+                    data.parent_span_id = Some(parent_id);
+                }
+            }
+        }
+    });
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn attribute(key: &str, value: &AttributeValue) -> serde_json::Value {
+    let value = match value {
+        AttributeValue::String(s) => json!({ "stringValue": s }),
+        AttributeValue::Int(i) => json!({ "intValue": i.to_string() }),
+        AttributeValue::Double(d) => json!({ "doubleValue": d }),
+        AttributeValue::Bool(b) => json!({ "boolValue": b }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+impl Exporter {
+    /// The OTLP request for the spans.
+    fn encode(&self, spans: &[SpanData]) -> serde_json::Value {
+        let spans: Vec<_> = spans
+            .iter()
+            .map(|span| {
+                json!({
+                    "traceId": hex(&span.trace_id),
+                    "spanId": hex(&span.span_id),
+                    "parentSpanId": span.parent_span_id.as_ref().map(|id| hex(id)).unwrap_or_default(),
+                    "traceState": span.trace_state.as_deref().unwrap_or_default(),
+                    "name": span.name,
+                    // SPAN_KIND_INTERNAL
+                    "kind": 1,
+                    "startTimeUnixNano": unix_nanos(span.start),
+                    "endTimeUnixNano": unix_nanos(span.end),
+                    "attributes": span
+                        .attributes
+                        .iter()
+                        .map(|(key, value)| attribute(key, value))
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        attribute("service.name", &AttributeValue::String(self.service_name.clone())),
+                        attribute("service.version", &AttributeValue::String(env!("CARGO_PKG_VERSION").to_string())),
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME") },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    /// Send spans as they're recorded, until the layer is dropped.
+    pub async fn run(mut self) {
+        let mut interval = time::interval(EXPORT_INTERVAL);
+        let mut batch = Vec::with_capacity(MAX_BATCH);
+        loop {
+            tokio::select! {
+                span = self.spans.recv() => match span {
+                    Some(span) => {
+                        batch.push(span);
+                        if batch.len() < MAX_BATCH {
+                            continue;
+                        }
+                    }
+                    None => return,
+                },
+                _ = interval.tick() => {}
+            }
+            if batch.is_empty() {
+                continue;
+            }
+            let body = self.encode(&batch);
+            batch.clear();
+            let result = HTTP_CLIENT
+                .post(&self.url)
+                .json(&body)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            if let Err(e) = result {
+                warn!(err = %e, "failed to export spans");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{continue_trace, current_trace_id, hex, new, parse_traceparent, AttributeValue};
+
+    #[test]
+    fn spans() {
+        let (layer, mut exporter) = new("http://localhost:4318/");
+        assert_eq!("http://localhost:4318/v1/traces", exporter.url);
+
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let trace_id = tracing::subscriber::with_default(subscriber, || {
+            let decision = tracing::info_span!(
+                "decision",
+                policy = "admins",
+                allowed = tracing::field::Empty
+            );
+            let _guard = decision.enter();
+            tracing::info_span!("cel").in_scope(|| {});
+            decision.record("allowed", true);
+            current_trace_id()
+        });
+
+        let cel = exporter.spans.try_recv().expect("must be recorded");
+        let decision = exporter.spans.try_recv().expect("must be recorded");
+        assert_eq!("cel", cel.name);
+        assert_eq!(decision.trace_id, cel.trace_id);
+        assert_eq!(Some(hex(&decision.trace_id)), trace_id);
+        assert_eq!(Some(decision.span_id), cel.parent_span_id);
+        assert_eq!(None, decision.parent_span_id);
+        assert_eq!(
+            vec![
+                ("policy", AttributeValue::String("admins".to_string())),
+                ("allowed", AttributeValue::Bool(true)),
+            ],
+            decision.attributes
+        );
+
+        let body = exporter.encode(&[decision]);
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!("decision", span["name"]);
+        assert_eq!(hex(&cel.trace_id), span["traceId"]);
+        assert_eq!("", span["parentSpanId"]);
+        assert_eq!(
+            serde_json::json!({ "key": "allowed", "value": { "boolValue": true } }),
+            span["attributes"][1]
+        );
+    }
+
+    #[test]
+    fn trace_context() {
+        let trace_id = [
+            0x4b, 0xf9, 0x2f, 0x35, 0x77, 0xb3, 0x4d, 0xa6, 0xa3, 0xce, 0x92, 0x9d, 0x0e, 0x0e,
+            0x47, 0x36,
+        ];
+        let parent_id = [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7];
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        assert_eq!(Some((trace_id, parent_id)), parse_traceparent(traceparent));
+        // later versions may add fields
+        assert!(parse_traceparent(&format!("01-{}-01-xyz", &traceparent[3..55])).is_some());
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-xyz",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(None, parse_traceparent(invalid), "{}", invalid);
+        }
+
+        let (layer, mut exporter) = new("http://localhost:4318");
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", HeaderValue::from_static(traceparent));
+        headers.append("tracestate", HeaderValue::from_static("a=1"));
+        headers.append("tracestate", HeaderValue::from_static("b=2"));
+
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("decision").in_scope(|| {
+                continue_trace(&headers);
+                tracing::info_span!("cel").in_scope(|| {});
+            });
+        });
+
+        let cel = exporter.spans.try_recv().expect("must be recorded");
+        let decision = exporter.spans.try_recv().expect("must be recorded");
+        assert_eq!(trace_id, decision.trace_id);
+        assert_eq!(Some(parent_id), decision.parent_span_id);
+        assert_eq!(trace_id, cel.trace_id);
+        assert_eq!(Some(decision.span_id), cel.parent_span_id);
+        assert_eq!(Some("a=1,b=2"), cel.trace_state.as_deref());
+
+        let body = exporter.encode(&[decision]);
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!("00f067aa0ba902b7", span["parentSpanId"]);
+        assert_eq!("a=1,b=2", span["traceState"]);
+    }
+}
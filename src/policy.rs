@@ -0,0 +1,689 @@
+//! Named policies configured on the server, and the rules applied to claims
+//! before evaluating them.
+//!
+//! Policies can be limited to a window of time, for temporary access grants
+//! that would otherwise be forgotten. Outside of it, the policy named as
+//! fallback applies instead, or access is denied.
+//!
+//! Named policies can extend another one, inheriting the settings they don't
+//! set themselves, so the verification options, response headers and macros
+//! shared by many routes are configured once:
+//!
+//! ```toml
+//! [policies.base]
+//! cel = "true"
+//! verification = { allowed_audiences = ["api"], max_validity = 3600 }
+//! headers = { "Cache-Control" = "no-store" }
+//! macros = { is_admin = "'admin' in jwt_claims.groups" }
+//!
+//! [policies.admin]
+//! extends = "base"
+//! cel = "is_admin"
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::http::{HeaderName, HeaderValue};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{debug, info, warn};
+
+use crate::{CustomClaims, MacroError, Macros, VerificationConfig};
+
+/// A CEL program, along with settings for evaluating it.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(try_from = "PolicyConfig")]
+pub struct Policy {
+    /// The name the policy is configured with, if any. Cheap to clone, as
+    /// policies are cloned per request.
+    pub name: Option<Arc<str>>,
+
+    /// Where the policy was loaded from, like the path of the config file,
+    /// if configured on the server.
+    pub source: Option<Arc<str>>,
+
+    /// A CEL expression that returns true if access should be granted, or
+    /// false if not.
+    pub cel: String,
+
+    /// Types to coerce claims to before evaluating the program, by claim
+    /// name.
+    pub coerce: BTreeMap<String, Coercion>,
+
+    /// How numbers in the claims are converted to CEL values.
+    pub numbers: NumberMode,
+
+    /// The claims available as `jwt_claims`, if not all of them. Others are
+    /// dropped before converting them to CEL values, which saves work with
+    /// tokens carrying lots of claims. `jwt` and `actors` are unaffected.
+    pub context_claims: Option<Vec<String>>,
+
+    /// The minimum weight of the verified identities, with the weighted
+    /// identity combiner, for routes more sensitive than the default.
+    pub min_identity_weight: Option<u32>,
+
+    /// Verification options for tokens presented for this policy. Options
+    /// sent with the request take precedence, the configured defaults fill
+    /// in the ones unset.
+    pub verification: VerificationConfig,
+
+    /// Headers added to the responses of allowed requests, like
+    /// Cache-Control. Identity headers and headers returned by the program
+    /// override them.
+    pub headers: Vec<(HeaderName, String)>,
+
+    /// Macros expanded into the program and backend expression of this
+    /// policy, before the configured ones, which they shadow.
+    pub macros: Macros,
+
+    /// When the policy comes into effect, if not right away.
+    pub valid_from: Option<DateTime<Utc>>,
+
+    /// When the policy stops being in effect, if ever.
+    pub valid_until: Option<DateTime<Utc>>,
+
+    /// The name of the policy applying outside of the validity window.
+    /// Without one, access is denied.
+    pub fallback: Option<String>,
+
+    /// A CEL expression returning the name of the upstream (pool) to route
+    /// allowed requests to, sent as X-Auth-Backend, or null for no hint.
+    pub backend: Option<String>,
+}
+
+/// Policies are configured either as CEL program only, or as table with
+/// additional settings.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum PolicyConfig {
+    Cel(String),
+    Full(Box<PolicyTable>),
+}
+
+/// The settings of a policy, as configured. Unset ones are inherited from
+/// the policy it extends, if any.
+#[derive(Clone, Default, serde::Deserialize)]
+struct PolicyTable {
+    /// The name of the policy to inherit settings from.
+    extends: Option<String>,
+    cel: Option<String>,
+    #[serde(default)]
+    coerce: BTreeMap<String, Coercion>,
+    numbers: Option<NumberMode>,
+    context_claims: Option<Vec<String>>,
+    min_identity_weight: Option<u32>,
+    verification: Option<VerificationConfig>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    macros: BTreeMap<String, String>,
+    /// RFC 3339, like "2024-12-31T23:59:59Z".
+    valid_from: Option<String>,
+    valid_until: Option<String>,
+    fallback: Option<String>,
+    backend: Option<String>,
+}
+
+impl From<PolicyConfig> for PolicyTable {
+    fn from(config: PolicyConfig) -> Self {
+        match config {
+            PolicyConfig::Cel(cel) => PolicyTable {
+                cel: Some(cel),
+                ..Default::default()
+            },
+            PolicyConfig::Full(table) => *table,
+        }
+    }
+}
+
+impl PolicyTable {
+    /// Take the settings unset in this table from [parent], and extend
+    /// whatever it extends next. Tables (coercions, verification options,
+    /// headers and macros) are merged, with the entries of this one taking
+    /// precedence.
+    fn inherit(self, parent: &PolicyTable) -> PolicyTable {
+        let mut coerce = parent.coerce.clone();
+        coerce.extend(self.coerce);
+        let mut macros = parent.macros.clone();
+        macros.extend(self.macros);
+        // header names are case-insensitive.
+        let headers = (parent.headers.iter().chain(&self.headers))
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+            .collect();
+        let verification = match (self.verification, &parent.verification) {
+            (Some(verification), Some(parent)) => Some(verification.or(parent)),
+            (verification, parent) => verification.or_else(|| parent.clone()),
+        };
+        PolicyTable {
+            extends: parent.extends.clone(),
+            cel: self.cel.or_else(|| parent.cel.clone()),
+            coerce,
+            numbers: self.numbers.or(parent.numbers),
+            context_claims: self
+                .context_claims
+                .or_else(|| parent.context_claims.clone()),
+            min_identity_weight: self.min_identity_weight.or(parent.min_identity_weight),
+            verification,
+            headers,
+            macros,
+            valid_from: self.valid_from.or_else(|| parent.valid_from.clone()),
+            valid_until: self.valid_until.or_else(|| parent.valid_until.clone()),
+            fallback: self.fallback.or_else(|| parent.fallback.clone()),
+            backend: self.backend.or_else(|| parent.backend.clone()),
+        }
+    }
+}
+
+fn parse_timestamp(
+    field: &str,
+    timestamp: Option<String>,
+) -> Result<Option<DateTime<Utc>>, String> {
+    timestamp
+        .map(|t| {
+            DateTime::parse_from_rfc3339(&t)
+                .map(|t| t.to_utc())
+                .map_err(|e| format!("invalid {} {}: {}", field, t, e))
+        })
+        .transpose()
+}
+
+impl TryFrom<PolicyConfig> for Policy {
+    type Error = String;
+
+    fn try_from(config: PolicyConfig) -> Result<Self, Self::Error> {
+        PolicyTable::from(config).try_into()
+    }
+}
+
+impl TryFrom<PolicyTable> for Policy {
+    type Error = String;
+
+    fn try_from(table: PolicyTable) -> Result<Self, Self::Error> {
+        let PolicyTable {
+            extends,
+            cel,
+            coerce,
+            numbers,
+            context_claims,
+            min_identity_weight,
+            verification,
+            headers,
+            macros,
+            valid_from,
+            valid_until,
+            fallback,
+            backend,
+        } = table;
+        if let Some(extends) = extends {
+            return Err(format!(
+                "can't extend {} outside of named policies",
+                extends
+            ));
+        }
+        let cel = cel.ok_or_else(|| "missing cel".to_string())?;
+        let valid_from = parse_timestamp("valid_from", valid_from)?;
+        let valid_until = parse_timestamp("valid_until", valid_until)?;
+        if let (Some(from), Some(until)) = (valid_from, valid_until) {
+            if from >= until {
+                return Err("valid_from must be before valid_until".to_string());
+            }
+        }
+        let headers = headers
+            .into_iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| format!("invalid header name {}: {}", name, e))?;
+                HeaderValue::from_str(&value)
+                    .map_err(|e| format!("invalid value of header {}: {}", name, e))?;
+                Ok((name, value))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            name: None,
+            source: None,
+            cel,
+            coerce,
+            numbers: numbers.unwrap_or_default(),
+            context_claims,
+            min_identity_weight,
+            verification: verification.unwrap_or_default(),
+            headers,
+            macros: macros.try_into()?,
+            valid_from,
+            valid_until,
+            fallback,
+            backend,
+        })
+    }
+}
+
+/// Resolve the parents of the named policies, each inheriting the settings
+/// it doesn't set itself from the policy it extends, and so on.
+fn resolve(tables: HashMap<String, PolicyTable>) -> Result<HashMap<String, Policy>, String> {
+    tables
+        .iter()
+        .map(|(name, table)| {
+            let mut table = table.clone();
+            let mut seen = vec![name.as_str()];
+            while let Some(extends) = table.extends.take() {
+                let (parent_name, parent) = tables
+                    .get_key_value(&extends)
+                    .ok_or_else(|| format!("policy {} extends unknown policy {}", name, extends))?;
+                if seen.contains(&parent_name.as_str()) {
+                    return Err(format!("policy {} extends itself via {}", name, extends));
+                }
+                seen.push(parent_name);
+                table = table.inherit(parent);
+            }
+            let policy = Policy::try_from(table).map_err(|e| format!("policy {}: {}", name, e))?;
+            Ok((name.clone(), policy))
+        })
+        .collect()
+}
+
+/// Deserialize named policies, resolving the policies they extend.
+pub(crate) fn deserialize_named<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, Policy>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let configs = HashMap::<String, PolicyConfig>::deserialize(deserializer)?;
+    resolve(
+        configs
+            .into_iter()
+            .map(|(name, config)| (name, config.into()))
+            .collect(),
+    )
+    .map_err(serde::de::Error::custom)
+}
+
+impl From<String> for Policy {
+    fn from(cel: String) -> Self {
+        Self {
+            name: None,
+            source: None,
+            cel,
+            coerce: BTreeMap::new(),
+            numbers: NumberMode::default(),
+            context_claims: None,
+            min_identity_weight: None,
+            verification: VerificationConfig::default(),
+            headers: Vec::new(),
+            macros: Macros::default(),
+            valid_from: None,
+            valid_until: None,
+            fallback: None,
+            backend: None,
+        }
+    }
+}
+
+/// The type a claim is coerced to.
+/// IdPs serialize some claims inconsistently (numbers as strings, lists with
+/// a single element as that element), which policies would otherwise need to
+/// check for.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Coercion {
+    /// Integers, or strings and floats representing one.
+    Int,
+    /// Numbers, or strings representing one.
+    Float,
+    /// Bools, or the strings "true" and "false".
+    Bool,
+    /// Strings, or numbers and bools rendered as string.
+    String,
+    /// Lists, or a single value wrapped into one.
+    List,
+}
+
+/// How numbers in the claims are converted to CEL values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NumberMode {
+    /// Non-negative integers as uints, negative ones as ints, and others
+    /// (with a fractional part or exponent, or beyond 64 bits) as doubles.
+    /// As uints don't mix with int literals in arithmetic, and doubles lose
+    /// precision above 2^53, this is mostly kept for compatibility.
+    #[default]
+    Auto,
+    /// Integral numbers as ints, and those not fitting one (or not exactly
+    /// representable anymore, like 1e20) as strings of their digits, so
+    /// large IDs keep their precision. Numbers beyond 64 bits are already
+    /// rounded when parsing the token, their digits may differ from the
+    /// token. Numbers with a fractional part are doubles.
+    Int,
+}
+
+impl Coercion {
+    /// Coerce a value, returning None if it can't be.
+    fn apply(self, value: Value) -> Option<Value> {
+        match (self, value) {
+            (_, Value::Null) => None,
+            (Coercion::Int, Value::Number(n)) => n
+                .as_i64()
+                .or_else(|| n.as_f64().filter(|f| f.fract() == 0.0).map(|f| f as i64))
+                .map(Value::from),
+            (Coercion::Int, Value::String(s)) => s.trim().parse::<i64>().ok().map(Value::from),
+            (Coercion::Float, Value::Number(n)) => n.as_f64().map(Value::from),
+            (Coercion::Float, Value::String(s)) => s
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .map(Value::from),
+            (Coercion::Bool, v @ Value::Bool(_)) => Some(v),
+            (Coercion::Bool, Value::String(s)) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            (Coercion::String, v @ Value::String(_)) => Some(v),
+            (Coercion::String, v @ (Value::Number(_) | Value::Bool(_))) => {
+                Some(Value::String(v.to_string()))
+            }
+            (Coercion::List, v @ Value::Array(_)) => Some(v),
+            (Coercion::List, v) => Some(Value::Array(vec![v])),
+            _ => None,
+        }
+    }
+}
+
+impl Policy {
+    /// Expand the macros of the policy, and then the configured [macros], in
+    /// [cel], its program or backend expression.
+    pub fn expand(&self, cel: &str, macros: &Macros) -> Result<String, MacroError> {
+        let cel = self.macros.expand(cel)?;
+        Ok(macros.expand(&cel)?.into_owned())
+    }
+
+    /// Drop the claims not listed in `context_claims`, if set.
+    pub fn filter_claims(&self, claims: &mut CustomClaims) {
+        if let Some(context_claims) = &self.context_claims {
+            claims.retain(|name, _| context_claims.contains(name));
+        }
+    }
+
+    /// Coerce the claims to the configured types.
+    /// Claims that can't be coerced are removed, so the policy only ever sees
+    /// the configured type, and can check for presence with `has()`.
+    pub fn coerce_claims(&self, claims: &mut CustomClaims) {
+        for (name, coercion) in &self.coerce {
+            let Some(value) = claims.remove(name) else {
+                continue;
+            };
+            match coercion.apply(value) {
+                Some(value) => {
+                    claims.insert(name.clone(), value);
+                }
+                None => debug!(claim = %name, ?coercion, "unable to coerce claim, removing"),
+            }
+        }
+    }
+
+    /// Whether [now] is within the validity window of the policy.
+    pub fn in_effect(&self, now: DateTime<Utc>) -> bool {
+        self.valid_from.is_none_or(|from| from <= now)
+            && self.valid_until.is_none_or(|until| now < until)
+    }
+
+    /// The policy applying in place of this one at [now]: itself within its
+    /// validity window, otherwise its fallback (or the fallback's, and so
+    /// on). Without one in effect, a policy denying access, keeping the name
+    /// of this one.
+    pub fn effective(&self, policies: &HashMap<String, Policy>, now: DateTime<Utc>) -> Policy {
+        let mut policy = self;
+        // bounded, in case fallbacks form a cycle.
+        for _ in 0..=policies.len() {
+            if policy.in_effect(now) {
+                return policy.clone();
+            }
+            let Some(fallback) = policy.fallback.as_ref().and_then(|f| policies.get(f)) else {
+                break;
+            };
+            debug!(policy = ?policy.name, fallback = ?fallback.name, "policy not in effect, using fallback");
+            policy = fallback;
+        }
+        debug!(policy = ?self.name, "policy not in effect, denying");
+        Policy {
+            name: self.name.clone(),
+            source: self.source.clone(),
+            ..Policy::from("false".to_string())
+        }
+    }
+}
+
+/// Warn about policies expiring within [within] from [now], so temporary
+/// grants can be extended in time, and log those that expired.
+pub fn warn_expiring(policies: &HashMap<String, Policy>, now: DateTime<Utc>, within: Duration) {
+    for (name, policy) in policies {
+        let Some(until) = policy.valid_until else {
+            continue;
+        };
+        let Ok(remaining) = (until - now).to_std() else {
+            info!(policy = %name, valid_until = %until, fallback = ?policy.fallback, "policy expired");
+            continue;
+        };
+        if remaining <= within {
+            warn!(
+                policy = %name,
+                valid_until = %until,
+                fallback = ?policy.fallback,
+                "policy expires in {}h",
+                remaining.as_secs() / 3600
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::DateTime;
+
+    use super::{deserialize_named, Coercion, NumberMode, Policy};
+    use crate::Macros;
+
+    #[test]
+    fn coerce() {
+        let policy: Policy = toml::from_str(
+            r#"
+            cel = "jwt_claims.exp_level > 2"
+            [coerce]
+            exp_level = "int"
+            flags = "list"
+            groups = "list"
+            verified = "bool"
+            org_id = "string"
+            ratio = "float"
+            broken = "int"
+            "#,
+        )
+        .expect("must parse");
+        assert_eq!(Some(&Coercion::Int), policy.coerce.get("exp_level"));
+
+        let mut claims = serde_json::json!({
+            "exp_level": "3",
+            "flags": "beta",
+            "groups": ["a", "b"],
+            "verified": "True",
+            "org_id": 42,
+            "ratio": 1,
+            "broken": "high",
+            "untouched": "3",
+        })
+        .as_object()
+        .unwrap()
+        .clone();
+        policy.coerce_claims(&mut claims);
+
+        assert_eq!(
+            serde_json::json!({
+                "exp_level": 3,
+                "flags": ["beta"],
+                "groups": ["a", "b"],
+                "verified": true,
+                "org_id": "42",
+                "ratio": 1.0,
+                "untouched": "3",
+            }),
+            serde_json::Value::Object(claims)
+        );
+
+        // only the CEL program
+        let policy: Policy = serde_json::from_str(r#""true""#).expect("must parse");
+        assert_eq!(Policy::from("true".to_string()), policy);
+        assert_eq!(NumberMode::Auto, policy.numbers);
+        let policy: Policy = toml::from_str("cel = 'true'\nnumbers = 'int'").expect("must parse");
+        assert_eq!(NumberMode::Int, policy.numbers);
+    }
+
+    #[test]
+    fn context_claims() {
+        let policy: Policy =
+            toml::from_str("cel = 'true'\ncontext_claims = ['sub', 'groups']").expect("must parse");
+        let claims = serde_json::json!({ "sub": "alice", "groups": ["a"], "picture": "..." });
+        let mut claims = claims.as_object().unwrap().clone();
+        policy.filter_claims(&mut claims);
+        assert_eq!(
+            serde_json::json!({ "sub": "alice", "groups": ["a"] }),
+            serde_json::Value::Object(claims.clone())
+        );
+
+        // all claims without the setting
+        Policy::from("true".to_string()).filter_claims(&mut claims);
+        assert_eq!(2, claims.len());
+    }
+
+    #[test]
+    fn validity_window() {
+        let config = r#"
+            [temporary]
+            cel = "true"
+            valid_from = "2024-06-01T00:00:00Z"
+            valid_until = "2024-07-01T00:00:00+02:00"
+            fallback = "regular"
+            [regular]
+            cel = "jwt_claims.sub == 'alice'"
+            valid_until = "2024-06-15T00:00:00Z"
+            [cycle]
+            cel = "true"
+            valid_until = "2024-01-01T00:00:00Z"
+            fallback = "cycle"
+            "#;
+        let mut policies: HashMap<String, Policy> = toml::from_str(config).expect("must parse");
+        for (name, policy) in policies.iter_mut() {
+            policy.name = Some(name.as_str().into());
+        }
+        let at = |t: &str| DateTime::parse_from_rfc3339(t).unwrap().to_utc();
+        let effective = |name: &str, t: &str| policies[name].effective(&policies, at(t));
+
+        assert_eq!(
+            Some(at("2024-06-30T22:00:00Z")),
+            policies["temporary"].valid_until
+        );
+        assert_eq!("true", effective("temporary", "2024-06-20T00:00:00Z").cel);
+        // before and after, the fallback, while it's in effect itself
+        assert_eq!(
+            "jwt_claims.sub == 'alice'",
+            effective("temporary", "2024-05-01T00:00:00Z").cel
+        );
+        let denied = effective("temporary", "2024-07-01T00:00:00Z");
+        assert_eq!("false", denied.cel);
+        assert_eq!(Some("temporary"), denied.name.as_deref());
+        assert_eq!("false", effective("cycle", "2024-06-01T00:00:00Z").cel);
+
+        let invalid = r#"
+            cel = "true"
+            valid_from = "2024-07-01T00:00:00Z"
+            valid_until = "2024-06-01T00:00:00Z"
+            "#;
+        assert!(toml::from_str::<Policy>(invalid).is_err());
+        assert!(toml::from_str::<Policy>("cel = 'true'\nvalid_until = 'tomorrow'").is_err());
+    }
+
+    #[test]
+    fn extends() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            #[serde(deserialize_with = "deserialize_named")]
+            policies: HashMap<String, Policy>,
+        }
+
+        let config: Config = toml::from_str(
+            r#"
+            [policies.base]
+            cel = "true"
+            verification = { allowed_audiences = ["api"], max_validity = 3600 }
+            headers = { "Cache-Control" = "no-store", "X-Frame-Options" = "DENY" }
+            macros = { is_admin = "'admin' in jwt_claims.groups" }
+            coerce = { groups = "list" }
+
+            [policies.admins]
+            extends = "base"
+            cel = "is_admin"
+            verification = { max_validity = 60 }
+            headers = { "cache-control" = "private" }
+
+            [policies.senior_admins]
+            extends = "admins"
+            cel = "is_admin && jwt_claims.level > 2"
+            coerce = { level = "int" }
+
+            [policies.standalone]
+            cel = "false"
+            "#,
+        )
+        .expect("must parse");
+        let policy = &config.policies["senior_admins"];
+
+        assert_eq!(2, policy.coerce.len());
+        assert_eq!(Some(60), policy.verification.max_validity);
+        assert_eq!(
+            Some(&["api".to_string()].into()),
+            policy.verification.allowed_audiences.as_ref()
+        );
+        assert_eq!(
+            vec![
+                ("cache-control".to_string(), "private".to_string()),
+                ("x-frame-options".to_string(), "DENY".to_string())
+            ],
+            policy
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect::<Vec<_>>()
+        );
+        let macros: Macros = toml::from_str("is_admin = 'false'").unwrap();
+        assert_eq!(
+            "('admin' in jwt_claims.groups) && jwt_claims.level > 2",
+            policy.expand(&policy.cel, &macros).expect("must expand")
+        );
+        // the configured macros apply to the others
+        assert_eq!(
+            "(false)",
+            config.policies["standalone"]
+                .expand("is_admin", &macros)
+                .expect("must expand")
+        );
+        assert_eq!(
+            Some(3600),
+            config.policies["base"].verification.max_validity
+        );
+
+        for invalid in [
+            "[policies.a]\nextends = 'b'\ncel = 'true'",
+            "[policies.a]\nextends = 'b'\n[policies.b]\nextends = 'a'\ncel = 'true'",
+            "[policies.a]\nextends = 'b'\n[policies.b]\nheaders = { 'Cache-Control' = 'no-store' }",
+        ] {
+            assert!(toml::from_str::<Config>(invalid).is_err(), "{}", invalid);
+        }
+        assert!(toml::from_str::<Policy>("extends = 'base'\ncel = 'true'").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! pprof-compatible CPU and heap profiling endpoints.
+//!
+//! CPU profiles are collected with pprof-rs, heap profiles are dumped by
+//! jemalloc, which needs to be the global allocator with profiling enabled.
+use std::{
+    ffi::CString,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use pprof::protos::Message;
+use tracing::warn;
+
+/// The default duration of a CPU profile, in seconds.
+const DEFAULT_PROFILE_SECONDS: u64 = 30;
+
+/// The maximum duration of a CPU profile, in seconds.
+const MAX_PROFILE_SECONDS: u64 = 300;
+
+#[derive(serde::Deserialize)]
+pub struct ProfileParams {
+    /// How long to collect samples for, in seconds.
+    seconds: Option<u64>,
+
+    /// Sampling frequency, in Hz.
+    frequency: Option<i32>,
+}
+
+/// Collect a CPU profile and return it in the pprof protobuf format.
+pub async fn profile(Query(params): Query<ProfileParams>) -> Result<impl IntoResponse, StatusCode> {
+    let seconds = params
+        .seconds
+        .unwrap_or(DEFAULT_PROFILE_SECONDS)
+        .min(MAX_PROFILE_SECONDS);
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(params.frequency.unwrap_or(100))
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| {
+            // only one profiler can run at a time
+            warn!(err=%e, "failed to start profiler");
+            StatusCode::CONFLICT
+        })?;
+
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+
+    let profile = guard
+        .report()
+        .build()
+        .and_then(|report| report.pprof())
+        .map_err(|e| {
+            warn!(err=%e, "failed to build profile");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut body = Vec::new();
+    profile.encode(&mut body).map_err(|e| {
+        warn!(err=%e, "failed to encode profile");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], body))
+}
+
+/// Dump a jemalloc heap profile, which can be inspected with `jeprof` or
+/// `pprof`.
+pub async fn heap() -> Result<impl IntoResponse, StatusCode> {
+    static DUMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    tokio::task::spawn_blocking(|| {
+        // SAFETY: opt.prof is a bool.
+        let enabled =
+            unsafe { tikv_jemalloc_ctl::raw::read::<bool>(b"opt.prof\0") }.map_err(|e| {
+                warn!(err=%e, "failed to query jemalloc profiling state");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        if !enabled {
+            warn!("jemalloc heap profiling is not enabled");
+            return Err(StatusCode::NOT_IMPLEMENTED);
+        }
+
+        let path = std::env::temp_dir().join(format!(
+            "cellulose-heap-{}-{}.prof",
+            std::process::id(),
+            DUMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let c_path = CString::new(path.to_string_lossy().into_owned())
+            .expect("temp path must not contain NUL bytes");
+
+        // SAFETY: prof.dump takes a pointer to a NUL-terminated filename,
+        // which outlives the call.
+        unsafe { tikv_jemalloc_ctl::raw::write(b"prof.dump\0", c_path.as_ptr()) }.map_err(|e| {
+            warn!(err=%e, "failed to dump heap profile");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        let body = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+
+        body.map(|body| ([(header::CONTENT_TYPE, "application/octet-stream")], body))
+            .map_err(|e| {
+                warn!(err=%e, "failed to read heap profile");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+}
@@ -0,0 +1,79 @@
+//! Human-friendly rendering of the token subject, for decision events.
+//!
+//! Subjects are often opaque UUIDs, which reviewers need to look up
+//! separately. If configured, the first present display claim (like
+//! `preferred_username`) is rendered along with a prefix of the subject,
+//! which stays unique enough to disambiguate, e.g. `alice (8c0a1f3e)`.
+use serde_json::Value;
+
+/// The number of characters of the subject to render after the display
+/// claim.
+const SUB_PREFIX_LEN: usize = 8;
+
+/// Render the principal from the first of the display claims set to a
+/// string, followed by the subject prefix. Falls back to the subject alone,
+/// if none are set.
+/// Returns None if neither is set.
+pub fn render(
+    claims: &serde_json::Map<String, Value>,
+    display_claims: &[String],
+) -> Option<String> {
+    let sub = claims.get("sub").and_then(Value::as_str);
+    let display = display_claims
+        .iter()
+        .find_map(|name| claims.get(name)?.as_str())
+        .filter(|display| !display.is_empty());
+
+    match (display, sub) {
+        (Some(display), Some(sub)) => {
+            let prefix = match sub.char_indices().nth(SUB_PREFIX_LEN) {
+                Some((i, _)) => &sub[..i],
+                None => sub,
+            };
+            Some(format!("{} ({})", display, prefix))
+        }
+        (Some(display), None) => Some(display.to_owned()),
+        (None, Some(sub)) => Some(sub.to_owned()),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::render;
+
+    #[test]
+    fn render_principal() {
+        let display_claims = ["preferred_username".to_string(), "email".to_string()];
+        let claims = |v: serde_json::Value| v.as_object().unwrap().clone();
+
+        assert_eq!(
+            Some("alice (8c0a1f3e)".to_string()),
+            render(
+                &claims(json!({
+                    "sub": "8c0a1f3e-5b7d-4c2a-9e61-2f0d3b4a5c6d",
+                    "preferred_username": "alice",
+                    "email": "alice@example.com",
+                })),
+                &display_claims
+            )
+        );
+        assert_eq!(
+            Some("bob@example.com (u1)".to_string()),
+            render(
+                &claims(json!({"sub": "u1", "email": "bob@example.com"})),
+                &display_claims
+            )
+        );
+        assert_eq!(
+            Some("u1".to_string()),
+            render(
+                &claims(json!({"sub": "u1", "preferred_username": 42})),
+                &display_claims
+            )
+        );
+        assert_eq!(None, render(&claims(json!({})), &display_claims));
+    }
+}
@@ -0,0 +1,169 @@
+//! Compiled CEL programs, by their source.
+//!
+//! Programs are sent as part of the request, so the cache is bounded, to not
+//! grow forever with distinct programs. The least recently used ones are
+//! evicted first.
+//!
+//! Lookups don't take any lock: the map is immutable, and swapped for a new
+//! one on insert. Recency is tracked with an atomic per entry. Only inserts
+//! are serialized, so a burst of novel programs doesn't block requests with
+//! known ones.
+//!
+//! Programs failing to compile are cached too, for [NEGATIVE_TTL], so
+//! repeated requests with the same broken program are rejected cheaply.
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use cel_interpreter::{ParseError, Program};
+use parking_lot::Mutex;
+
+use crate::metrics::METRICS;
+
+/// How long compile errors are cached.
+pub const NEGATIVE_TTL: Duration = Duration::from_secs(60);
+
+struct Entry {
+    /// The compiled program, or the error compiling it.
+    program: Result<Arc<Program>, Arc<ParseError>>,
+    /// When the entry is considered stale, for compile errors.
+    expires_at: Option<Instant>,
+    /// The value of the cache clock on the last lookup.
+    last_used: AtomicU64,
+}
+
+pub struct ProgramCache {
+    capacity: NonZeroUsize,
+    programs: ArcSwap<HashMap<String, Arc<Entry>>>,
+    /// Serializes inserts, so concurrent ones don't drop each other's
+    /// entries.
+    insert_lock: Mutex<()>,
+    /// Incremented on every lookup, to order entries by recency.
+    clock: AtomicU64,
+}
+
+impl ProgramCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            capacity,
+            programs: Default::default(),
+            insert_lock: Mutex::new(()),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns the compiled program, compiling and inserting it if not
+    /// cached yet.
+    /// Compilation happens without holding the lock, so concurrent requests
+    /// with the same new program might compile it more than once.
+    pub fn get_or_compile(&self, cel_str: &str) -> Result<Arc<Program>, Arc<ParseError>> {
+        let programs = self.programs.load();
+        let entry = programs
+            .get(cel_str)
+            .filter(|entry| entry.expires_at.is_none_or(|t| Instant::now() < t));
+        if let Some(entry) = entry {
+            let event = match entry.program {
+                Ok(_) => "hit",
+                Err(_) => "negative_hit",
+            };
+            METRICS.cel_program_cache.with_labels(&[event]).inc();
+            entry.last_used.store(self.tick(), Ordering::Relaxed);
+            return entry.program.clone();
+        }
+        METRICS.cel_program_cache.with_labels(&["miss"]).inc();
+
+        let program = Program::compile(cel_str).map(Arc::new).map_err(Arc::new);
+        self.insert_entry(cel_str.to_owned(), program.clone());
+        program
+    }
+
+    /// Insert an already compiled program, evicting the least recently used
+    /// one if full.
+    pub fn insert(&self, cel_str: String, program: Arc<Program>) {
+        self.insert_entry(cel_str, Ok(program))
+    }
+
+    fn insert_entry(&self, cel_str: String, program: Result<Arc<Program>, Arc<ParseError>>) {
+        let _guard = self.insert_lock.try_lock().unwrap_or_else(|| {
+            METRICS
+                .cel_program_cache_contended_inserts
+                .with_labels(&[])
+                .inc();
+            self.insert_lock.lock()
+        });
+
+        let mut programs = HashMap::clone(&self.programs.load());
+        programs.insert(
+            cel_str,
+            Arc::new(Entry {
+                expires_at: program.is_err().then(|| Instant::now() + NEGATIVE_TTL),
+                program,
+                last_used: AtomicU64::new(self.tick()),
+            }),
+        );
+        while programs.len() > self.capacity.get() {
+            let lru = programs
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(cel_str, _)| cel_str.clone())
+                .expect("must not be empty");
+            programs.remove(&lru);
+            METRICS.cel_program_cache.with_labels(&["eviction"]).inc();
+        }
+
+        METRICS
+            .cel_program_cache_entries
+            .with_labels(&[])
+            .set(programs.len() as f64);
+        self.programs.store(Arc::new(programs));
+    }
+
+    /// The maximum number of programs kept.
+    pub fn capacity(&self) -> usize {
+        self.capacity.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, sync::Arc};
+
+    use super::ProgramCache;
+
+    #[test]
+    fn lru() {
+        let cache = ProgramCache::new(NonZeroUsize::new(2).unwrap());
+        let a = cache.get_or_compile("1 == 1").expect("must compile");
+        cache.get_or_compile("2 == 2").expect("must compile");
+
+        // a is used more recently than b, so b is evicted.
+        assert!(Arc::ptr_eq(
+            &a,
+            &cache.get_or_compile("1 == 1").expect("must compile")
+        ));
+        cache.get_or_compile("3 == 3").expect("must compile");
+
+        let programs = cache.programs.load();
+        assert_eq!(2, programs.len());
+        assert!(programs.contains_key("1 == 1"));
+        assert!(!programs.contains_key("2 == 2"));
+
+        // compile errors are cached too
+        let err = cache.get_or_compile("1 ==").expect_err("must fail");
+        assert!(Arc::ptr_eq(
+            &err,
+            &cache.get_or_compile("1 ==").expect_err("must fail")
+        ));
+    }
+}
@@ -0,0 +1,226 @@
+//! Rate limiting of /auth requests per client address and per subject, so
+//! the endpoint can't be used to brute-force tokens cheaply.
+//!
+//! Each client address and subject gets a token bucket, refilled at the
+//! configured rate up to the burst size. Requests finding their bucket
+//! empty are answered with a 429 and a Retry-After until it refills.
+//!
+//! The client address is the one determined from X-Forwarded-For, as the
+//! peer is usually the proxy; requests without one aren't limited by
+//! address. The subject is read from the token before verifying it, as
+//! requests with invalid tokens are the ones to limit, so it's only a
+//! second line of defense: clients can make up a subject per request.
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder};
+use parking_lot::Mutex;
+
+/// The maximum number of buckets per key kind. Once reached, the least
+/// recently updated buckets are dropped to make room, rather than the memory
+/// growing without bounds. Those are usually full again already.
+const MAX_TRACKED: usize = 100_000;
+
+/// A rate, with the number of requests allowed at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Tracked<K> {
+    by_key: HashMap<K, Bucket>,
+    /// The keys ordered by when their bucket was last updated, to drop the
+    /// least recently updated ones without going through all of them.
+    by_update: BTreeSet<(Instant, K)>,
+}
+
+/// The buckets of one key kind.
+struct Buckets<K> {
+    limit: Limit,
+    buckets: Mutex<Tracked<K>>,
+    max_tracked: usize,
+}
+
+impl<K: Eq + Hash + Ord + Clone> Buckets<K> {
+    fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(Tracked {
+                by_key: HashMap::new(),
+                by_update: BTreeSet::new(),
+            }),
+            max_tracked: MAX_TRACKED,
+        }
+    }
+
+    /// Take a token from the bucket of [key] at [now], returning how long
+    /// until one is available if there's none.
+    fn take(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let Limit { per_second, burst } = self.limit;
+        let burst = f64::from(burst.max(1));
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            (bucket.tokens + elapsed.as_secs_f64() * per_second).min(burst)
+        };
+
+        let mut tracked = self.buckets.lock();
+        let Tracked { by_key, by_update } = &mut *tracked;
+        if !by_key.contains_key(&key) {
+            while by_key.len() >= self.max_tracked {
+                let Some((_, oldest)) = by_update.pop_first() else {
+                    break;
+                };
+                by_key.remove(&oldest);
+            }
+        }
+        let bucket = by_key.entry(key.clone()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        by_update.remove(&(bucket.updated, key.clone()));
+        by_update.insert((now, key));
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(
+            Duration::try_from_secs_f64((1.0 - bucket.tokens) / per_second)
+                .unwrap_or(Duration::MAX),
+        )
+    }
+}
+
+/// Limits requests per client address and per subject, see the module docs.
+pub struct RateLimiter {
+    per_ip: Option<Buckets<IpAddr>>,
+    per_subject: Option<Buckets<String>>,
+}
+
+impl RateLimiter {
+    /// Limit requests per client address and subject, if set.
+    pub fn new(per_ip: Option<Limit>, per_subject: Option<Limit>) -> Self {
+        Self {
+            per_ip: per_ip.map(Buckets::new),
+            per_subject: per_subject.map(Buckets::new),
+        }
+    }
+
+    /// Count a request from [client_ip] with [token], returning how long
+    /// until the client may retry if it exceeds a limit.
+    pub fn check(&self, client_ip: Option<IpAddr>, token: Option<&str>) -> Result<(), Duration> {
+        let now = Instant::now();
+        if let (Some(buckets), Some(ip)) = (&self.per_ip, client_ip) {
+            buckets.take(ip, now)?;
+        }
+        if let (Some(buckets), Some(sub)) = (&self.per_subject, token.and_then(unverified_subject))
+        {
+            buckets.take(sub, now)?;
+        }
+        Ok(())
+    }
+}
+
+/// Peek into the payload of a token to extract the (unverified!) subject.
+fn unverified_subject(token: &str) -> Option<String> {
+    #[derive(serde::Deserialize)]
+    struct Claims {
+        sub: Option<String>,
+    }
+
+    let payload = token.split('.').nth(1)?;
+    let payload = Base64UrlSafeNoPadding::decode_to_vec(payload, None).ok()?;
+    serde_json::from_slice::<Claims>(&payload).ok()?.sub
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Buckets, Limit, RateLimiter};
+
+    #[test]
+    fn token_bucket() {
+        let buckets = Buckets::new(Limit {
+            per_second: 2.0,
+            burst: 3,
+        });
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(Ok(()), buckets.take("a", t0));
+        }
+        assert_eq!(Err(Duration::from_millis(500)), buckets.take("a", t0));
+        // other keys have their own bucket
+        assert_eq!(Ok(()), buckets.take("b", t0));
+
+        // refilled at the rate, up to the burst
+        let t1 = t0 + Duration::from_millis(500);
+        assert_eq!(Ok(()), buckets.take("a", t1));
+        assert!(buckets.take("a", t1).is_err());
+        let t2 = t1 + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(Ok(()), buckets.take("a", t2));
+        }
+        assert!(buckets.take("a", t2).is_err());
+    }
+
+    #[test]
+    fn bounded() {
+        let buckets = Buckets {
+            max_tracked: 2,
+            ..Buckets::new(Limit {
+                per_second: 1.0,
+                burst: 1,
+            })
+        };
+        let t0 = Instant::now();
+        assert_eq!(Ok(()), buckets.take("a", t0));
+        assert_eq!(Ok(()), buckets.take("b", t0 + Duration::from_millis(1)));
+        // still limited once full
+        assert!(buckets.take("a", t0 + Duration::from_millis(2)).is_err());
+        // the least recently updated bucket made room
+        assert_eq!(Ok(()), buckets.take("c", t0 + Duration::from_millis(3)));
+        assert!(buckets.take("a", t0 + Duration::from_millis(4)).is_err());
+        assert_eq!(Ok(()), buckets.take("b", t0 + Duration::from_millis(5)));
+
+        let tracked = buckets.buckets.lock();
+        assert_eq!(2, tracked.by_key.len());
+        assert_eq!(2, tracked.by_update.len());
+    }
+
+    #[test]
+    fn keys() {
+        let limit = Limit {
+            per_second: 1.0,
+            burst: 1,
+        };
+        // {"alg":"none"}.{"iss":"https://idp.example.com","sub":"alice"}.
+        let alice =
+            "eyJhbGciOiJub25lIn0.eyJpc3MiOiJodHRwczovL2lkcC5leGFtcGxlLmNvbSIsInN1YiI6ImFsaWNlIn0.";
+        let ip = Some([192, 0, 2, 1].into());
+
+        let limiter = RateLimiter::new(None, Some(limit));
+        assert!(limiter.check(ip, Some(alice)).is_ok());
+        assert!(limiter.check(None, Some(alice)).is_err());
+        // tokens without subject, and requests without token, aren't limited
+        assert!(limiter.check(ip, Some("garbage")).is_ok());
+        assert!(limiter.check(ip, None).is_ok());
+
+        let limiter = RateLimiter::new(Some(limit), None);
+        assert!(limiter.check(ip, None).is_ok());
+        assert!(limiter.check(ip, Some(alice)).is_err());
+        assert!(limiter.check(Some([192, 0, 2, 2].into()), None).is_ok());
+        assert!(limiter.check(None, None).is_ok());
+    }
+}
@@ -0,0 +1,65 @@
+//! OAuth 2.0 scopes, and down-scoping them to the ones relevant for a policy.
+use serde_json::Value;
+
+/// The header carrying the scopes relevant for the policy on allow.
+pub const X_AUTH_SCOPES: &str = "x-auth-scopes";
+
+/// Returns the scopes granted to the token.
+/// These are read from the `scope` claim (a space-separated string, RFC 9068),
+/// falling back to `scp` (a list of strings, or a space-separated string), as
+/// used by some IdPs.
+pub fn token_scopes(claims: &serde_json::Map<String, Value>) -> Vec<String> {
+    match claims.get("scope").or_else(|| claims.get("scp")) {
+        Some(Value::String(s)) => parse_list(s),
+        Some(Value::Array(vs)) => vs
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(str::to_owned)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Parse a list of scopes separated by spaces and/or commas.
+pub fn parse_list(s: &str) -> Vec<String> {
+    s.split([' ', ','])
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Returns the token scopes that are also relevant, in the order of the
+/// relevant ones.
+pub fn intersect(token_scopes: &[String], relevant: &[String]) -> Vec<String> {
+    relevant
+        .iter()
+        .filter(|s| token_scopes.contains(s))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{intersect, parse_list, token_scopes};
+
+    #[test]
+    fn scopes() {
+        let claims = serde_json::json!({"scope": "read write  admin"});
+        let token = token_scopes(claims.as_object().unwrap());
+        assert_eq!(vec!["read", "write", "admin"], token);
+
+        let claims = serde_json::json!({"scp": ["read", "write"]});
+        assert_eq!(
+            vec!["read", "write"],
+            token_scopes(claims.as_object().unwrap())
+        );
+
+        assert!(token_scopes(&Default::default()).is_empty());
+
+        assert_eq!(
+            vec!["admin", "read"],
+            intersect(&token, &parse_list("admin,read,delete"))
+        );
+        assert!(intersect(&token, &parse_list("delete")).is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+//! Protective response headers for user-facing HTML pages (error and login
+//! pages), which browsers render directly.
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+/// Security headers added to HTML responses.
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    /// Sent as Content-Security-Policy, if set.
+    pub content_security_policy: Option<HeaderValue>,
+    /// Sent as Referrer-Policy, if set.
+    pub referrer_policy: Option<HeaderValue>,
+    /// Sent as max-age of Strict-Transport-Security, if set and the request
+    /// was received via TLS.
+    pub hsts_max_age: Option<Duration>,
+}
+
+/// Pages only use inline styles, and must not be framed.
+pub const DEFAULT_CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; style-src 'unsafe-inline'; form-action 'self'; frame-ancestors 'none'";
+
+pub const DEFAULT_REFERRER_POLICY: &str = "no-referrer";
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            content_security_policy: Some(HeaderValue::from_static(
+                DEFAULT_CONTENT_SECURITY_POLICY,
+            )),
+            referrer_policy: Some(HeaderValue::from_static(DEFAULT_REFERRER_POLICY)),
+            hsts_max_age: Some(Duration::from_secs(365 * 24 * 3600)),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// Add the headers to a response, unless already set by the handler.
+    fn insert(&self, headers: &mut HeaderMap, tls: bool) {
+        let is_html = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/html"));
+        if !is_html {
+            return;
+        }
+
+        headers
+            .entry(header::X_CONTENT_TYPE_OPTIONS)
+            .or_insert(HeaderValue::from_static("nosniff"));
+        if let Some(csp) = &self.content_security_policy {
+            headers
+                .entry(header::CONTENT_SECURITY_POLICY)
+                .or_insert(csp.clone());
+        }
+        if let Some(referrer_policy) = &self.referrer_policy {
+            headers
+                .entry(header::REFERRER_POLICY)
+                .or_insert(referrer_policy.clone());
+        }
+        if let (Some(max_age), true) = (self.hsts_max_age, tls) {
+            headers.entry(header::STRICT_TRANSPORT_SECURITY).or_insert(
+                HeaderValue::try_from(format!("max-age={}", max_age.as_secs()))
+                    .expect("must be a valid header value"),
+            );
+        }
+    }
+}
+
+/// Middleware adding the security headers to HTML responses.
+/// cellulose itself doesn't terminate TLS, so whether the request was
+/// received via TLS is determined by the X-Forwarded-Proto header set by the
+/// reverse proxy in front.
+pub async fn apply(State(config): State<SecurityHeaders>, rq: Request, next: Next) -> Response {
+    let tls = rq
+        .headers()
+        .get("x-forwarded-proto")
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"https"));
+
+    let mut response = next.run(rq).await;
+    config.insert(response.headers_mut(), tls);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap, HeaderValue};
+
+    use super::SecurityHeaders;
+
+    #[test]
+    fn html_only() {
+        let config = SecurityHeaders::default();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        config.insert(&mut headers, true);
+        assert_eq!(1, headers.len());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        headers.insert(
+            header::REFERRER_POLICY,
+            HeaderValue::from_static("same-origin"),
+        );
+        config.insert(&mut headers, false);
+        assert_eq!("nosniff", headers[header::X_CONTENT_TYPE_OPTIONS]);
+        assert!(headers.contains_key(header::CONTENT_SECURITY_POLICY));
+        // set by the handler
+        assert_eq!("same-origin", headers[header::REFERRER_POLICY]);
+        // not received via TLS
+        assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+
+        config.insert(&mut headers, true);
+        assert_eq!(
+            "max-age=31536000",
+            headers[header::STRICT_TRANSPORT_SECURITY]
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! Headers carrying credentials, which must never reach CEL programs, logs
+//! or debug endpoints.
+//!
+//! The token and API key are verified before the policy is evaluated, so
+//! programs have no need for them. These headers are always sensitive,
+//! further ones (like a custom session header) can be configured.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{self, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION},
+        HeaderMap, HeaderName,
+    },
+    middleware::Next,
+    response::Response,
+};
+
+use crate::api_keys::X_API_KEY;
+
+/// The headers that are always sensitive.
+pub const ALWAYS: [HeaderName; 4] = [
+    AUTHORIZATION,
+    COOKIE,
+    PROXY_AUTHORIZATION,
+    HeaderName::from_static(X_API_KEY),
+];
+
+#[derive(Clone, Debug)]
+pub struct SensitiveHeaders(Arc<[HeaderName]>);
+
+impl Default for SensitiveHeaders {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl SensitiveHeaders {
+    /// The headers that are always sensitive, and the [extra] ones.
+    pub fn new(extra: Vec<HeaderName>) -> Self {
+        let mut names = ALWAYS.to_vec();
+        for name in extra {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        Self(names.into())
+    }
+
+    pub fn contains(&self, name: &HeaderName) -> bool {
+        self.0.contains(name)
+    }
+
+    /// Remove the sensitive headers, before passing the others on.
+    pub fn strip(&self, headers: &mut HeaderMap) {
+        for name in self.0.iter() {
+            headers.remove(name);
+        }
+    }
+
+    /// Mark the values of sensitive headers as such, so they're not
+    /// rendered in debug output, like request logs.
+    pub fn mark(&self, headers: &mut HeaderMap) {
+        for name in self.0.iter() {
+            if let header::Entry::Occupied(mut entry) = headers.entry(name) {
+                entry.iter_mut().for_each(|v| v.set_sensitive(true));
+            }
+        }
+    }
+}
+
+/// Middleware marking sensitive headers, before any logging sees the
+/// request.
+pub async fn apply(
+    State(sensitive_headers): State<SensitiveHeaders>,
+    mut rq: Request,
+    next: Next,
+) -> Response {
+    sensitive_headers.mark(rq.headers_mut());
+    next.run(rq).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderName, HeaderValue};
+
+    use super::SensitiveHeaders;
+
+    #[test]
+    fn strip_and_mark() {
+        let sensitive = SensitiveHeaders::new(vec![HeaderName::from_static("x-session")]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer a.b.c"));
+        headers.append("cookie", HeaderValue::from_static("a=1"));
+        headers.append("cookie", HeaderValue::from_static("b=2"));
+        headers.insert("x-api-key", HeaderValue::from_static("secret"));
+        headers.insert("x-session", HeaderValue::from_static("secret"));
+        headers.insert("x-forwarded-host", HeaderValue::from_static("example.com"));
+
+        let mut marked = headers.clone();
+        sensitive.mark(&mut marked);
+        assert!(marked.get_all("cookie").iter().all(|v| v.is_sensitive()));
+        assert!(!format!("{:?}", marked).contains("secret"));
+        assert!(format!("{:?}", marked).contains("example.com"));
+
+        sensitive.strip(&mut headers);
+        assert_eq!(
+            vec!["x-forwarded-host"],
+            headers.keys().map(|k| k.as_str()).collect::<Vec<_>>()
+        );
+    }
+}
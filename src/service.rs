@@ -0,0 +1,73 @@
+//! Running as a Windows service.
+//!
+//! The service is registered with the service control manager as usual
+//! (e.g. `sc.exe create cellulose binPath= "C:\...\cellulose.exe --windows-service ..."`).
+//! Stopping the service requests a graceful shutdown.
+use std::{ffi::OsString, sync::OnceLock, time::Duration};
+
+use tracing::warn;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+};
+
+use crate::signals;
+
+pub const SERVICE_NAME: &str = "cellulose";
+
+/// The function running the server, until shutdown.
+static RUN: OnceLock<fn() -> bool> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn set_state(handle: &ServiceStatusHandle, state: ServiceState, exit_code: u32) {
+    let status = ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::from_secs(30),
+        process_id: None,
+    };
+    if let Err(e) = handle.set_service_status(status) {
+        warn!(err = %e, "failed to set service status");
+    }
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let handle = match service_control_handler::register(SERVICE_NAME, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            signals::request_shutdown();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    }) {
+        Ok(handle) => handle,
+        Err(e) => {
+            warn!(err = %e, "failed to register service control handler");
+            return;
+        }
+    };
+
+    set_state(&handle, ServiceState::Running, 0);
+    let ok = RUN.get().is_some_and(|run| run());
+    set_state(&handle, ServiceState::Stopped, if ok { 0 } else { 1 });
+}
+
+/// Run as a Windows service, calling [run] once started.
+/// [run] returns whether the server ran successfully.
+/// Blocks until the service is stopped.
+pub fn run(run: fn() -> bool) -> Result<(), windows_service::Error> {
+    let _ = RUN.set(run);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
@@ -0,0 +1,175 @@
+//! Storage of login state and sessions, see [crate::login].
+//!
+//! Sessions are kept in memory by default. Multiple instances behind a load
+//! balancer need to share them, as browsers may finish a login at, or send
+//! their session cookie to, any of them. With the `redis` feature, they can
+//! be kept in Redis instead.
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+#[derive(Debug)]
+pub struct SessionError(String);
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+fn err(msg: String) -> SessionError {
+    SessionError(msg)
+}
+
+/// Stores values (serialized sessions and the like) by key, until they
+/// expire.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Store the value under the key, for [ttl].
+    async fn put(&self, key: &str, value: String, ttl: Duration) -> Result<(), SessionError>;
+
+    /// The value stored under the key, unless it expired.
+    async fn get(&self, key: &str) -> Result<Option<String>, SessionError>;
+
+    /// Remove the value stored under the key, returning it, unless it
+    /// expired. Of concurrent calls, only one gets it.
+    async fn take(&self, key: &str) -> Result<Option<String>, SessionError>;
+}
+
+/// The maximum number of entries kept in memory, so browsers starting logins
+/// without completing them can't exhaust it.
+const MAX_ENTRIES: usize = 100_000;
+
+/// Keeps sessions in memory, only for this instance.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn put(&self, key: &str, value: String, ttl: Duration) -> Result<(), SessionError> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (_, expires)| now < *expires);
+            if entries.len() >= MAX_ENTRIES {
+                return Err(err("too many sessions".to_string()));
+            }
+        }
+        entries.insert(key.to_owned(), (value, now + ttl));
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, SessionError> {
+        let mut entries = self.entries.lock();
+        match entries.get(key) {
+            Some((value, expires)) if Instant::now() < *expires => Ok(Some(value.clone())),
+            Some(_) => {
+                entries.remove(key);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn take(&self, key: &str) -> Result<Option<String>, SessionError> {
+        Ok(self
+            .entries
+            .lock()
+            .remove(key)
+            .filter(|(_, expires)| Instant::now() < *expires)
+            .map(|(value, _)| value))
+    }
+}
+
+/// Keeps sessions in Redis, shared by all instances using it. Keys are
+/// prefixed with `cellulose:`, values expire along with them.
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    connection: redis::aio::ConnectionManager,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    /// The prefix of all keys.
+    const PREFIX: &'static str = "cellulose:";
+
+    /// Connect to the Redis server at the URL, like `redis://localhost:6379`.
+    /// The connection is re-established if lost.
+    pub async fn connect(url: &str) -> Result<Self, SessionError> {
+        let client = redis::Client::open(url).map_err(|e| err(e.to_string()))?;
+        let connection = client
+            .get_connection_manager()
+            .await
+            .map_err(|e| err(format!("connecting to Redis: {}", e)))?;
+        Ok(Self { connection })
+    }
+
+    async fn query<T: redis::FromRedisValue>(&self, cmd: redis::Cmd) -> Result<T, SessionError> {
+        cmd.query_async(&mut self.connection.clone())
+            .await
+            .map_err(|e| err(format!("Redis: {}", e)))
+    }
+}
+
+#[cfg(feature = "redis")]
+#[async_trait]
+impl SessionStore for RedisStore {
+    async fn put(&self, key: &str, value: String, ttl: Duration) -> Result<(), SessionError> {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(format!("{}{}", Self::PREFIX, key))
+            .arg(value)
+            .arg("PX")
+            .arg(ttl.as_millis().max(1) as u64);
+        self.query(cmd).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, SessionError> {
+        let mut cmd = redis::cmd("GET");
+        cmd.arg(format!("{}{}", Self::PREFIX, key));
+        self.query(cmd).await
+    }
+
+    async fn take(&self, key: &str) -> Result<Option<String>, SessionError> {
+        // needs Redis 6.2
+        let mut cmd = redis::cmd("GETDEL");
+        cmd.arg(format!("{}{}", Self::PREFIX, key));
+        self.query(cmd).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{MemoryStore, SessionStore};
+
+    #[tokio::test]
+    async fn memory_store() {
+        let store = MemoryStore::default();
+        store
+            .put("a", "1".to_string(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        store
+            .put("b", "2".to_string(), Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert_eq!(Some("1".to_string()), store.get("a").await.unwrap());
+        assert_eq!(None, store.get("b").await.unwrap());
+        assert_eq!(None, store.get("c").await.unwrap());
+
+        assert_eq!(Some("1".to_string()), store.take("a").await.unwrap());
+        assert_eq!(None, store.take("a").await.unwrap());
+        assert_eq!(None, store.get("a").await.unwrap());
+    }
+}
@@ -0,0 +1,163 @@
+//! Shutdown and reload signals, and service manager notifications,
+//! abstracted over platforms.
+//!
+//! On unix, SIGINT and SIGTERM request a graceful shutdown, SIGHUP a reload.
+//! Readiness and shutdown are reported to systemd via sd_notify, if started
+//! with NOTIFY_SOCKET set.
+//!
+//! On Windows, Ctrl+C, closing the console window and system shutdown
+//! request a graceful shutdown, Ctrl+Break a reload. When running as a
+//! Windows service, stopping the service requests a graceful shutdown.
+use std::{io, sync::LazyLock};
+
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+static SHUTDOWN_REQUESTED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Request a graceful shutdown, like a signal would.
+/// Used by service managers without signals (Windows services).
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.notify_one();
+}
+
+/// Resolves once a graceful shutdown is requested.
+pub async fn shutdown() {
+    tokio::select! {
+        _ = platform::shutdown() => {},
+        _ = SHUTDOWN_REQUESTED.notified() => {},
+    }
+    info!("shutting down");
+}
+
+/// Signals requesting to reload the configuration.
+pub struct Reload(platform::Reload);
+
+impl Reload {
+    /// Start listening for reload signals.
+    pub fn new() -> io::Result<Self> {
+        platform::Reload::new().map(Self)
+    }
+
+    /// Resolves once a reload is requested.
+    pub async fn recv(&mut self) {
+        self.0.recv().await
+    }
+}
+
+/// Tell the service manager startup has finished.
+pub fn notify_ready() {
+    platform::notify("READY=1")
+}
+
+/// Tell the service manager a graceful shutdown has started.
+pub fn notify_stopping() {
+    platform::notify("STOPPING=1")
+}
+
+/// Wait for the signal, or forever if unable to listen for it.
+async fn recv_or_pending(signal: io::Result<impl std::future::Future>, name: &str) {
+    match signal {
+        Ok(signal) => {
+            signal.await;
+        }
+        Err(e) => {
+            warn!(err = %e, "unable to listen for {}", name);
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::io;
+
+    use tokio::signal::unix::{signal, Signal, SignalKind};
+    use tracing::debug;
+
+    use super::recv_or_pending;
+
+    fn recv(kind: SignalKind) -> io::Result<impl std::future::Future> {
+        signal(kind).map(|mut s| async move { s.recv().await })
+    }
+
+    pub async fn shutdown() {
+        tokio::select! {
+            _ = recv_or_pending(recv(SignalKind::interrupt()), "SIGINT") => {},
+            _ = recv_or_pending(recv(SignalKind::terminate()), "SIGTERM") => {},
+        }
+    }
+
+    pub struct Reload(Signal);
+
+    impl Reload {
+        pub fn new() -> io::Result<Self> {
+            signal(SignalKind::hangup()).map(Self)
+        }
+
+        pub async fn recv(&mut self) {
+            self.0.recv().await;
+        }
+    }
+
+    /// Send a state update via sd_notify, if NOTIFY_SOCKET is set.
+    pub fn notify(state: &str) {
+        use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+
+        let result =
+            UnixDatagram::unbound().and_then(|socket| match path.as_bytes().strip_prefix(b"@") {
+                #[cfg(target_os = "linux")]
+                Some(name) => {
+                    use std::os::linux::net::SocketAddrExt;
+                    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                    socket.send_to_addr(state.as_bytes(), &addr)
+                }
+                #[cfg(not(target_os = "linux"))]
+                Some(_) => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "abstract sockets are only supported on Linux",
+                )),
+                None => socket.send_to(state.as_bytes(), &path),
+            });
+        if let Err(e) = result {
+            debug!(err = %e, state, "failed to notify service manager");
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+
+    use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close, ctrl_shutdown, CtrlBreak};
+
+    use super::recv_or_pending;
+
+    pub async fn shutdown() {
+        tokio::select! {
+            _ = recv_or_pending(ctrl_c().map(|mut s| async move { s.recv().await }), "Ctrl+C") => {},
+            _ = recv_or_pending(ctrl_close().map(|mut s| async move { s.recv().await }), "console close") => {},
+            _ = recv_or_pending(ctrl_shutdown().map(|mut s| async move { s.recv().await }), "system shutdown") => {},
+        }
+    }
+
+    pub struct Reload(CtrlBreak);
+
+    impl Reload {
+        pub fn new() -> io::Result<Self> {
+            ctrl_break().map(Self)
+        }
+
+        pub async fn recv(&mut self) {
+            self.0.recv().await;
+        }
+    }
+
+    /// Windows services report their state via the service control
+    /// handler instead.
+    pub fn notify(_state: &str) {}
+}
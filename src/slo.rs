@@ -0,0 +1,268 @@
+//! A small built-in SLO evaluator, computing error budget burn rates from the
+//! decision metrics and exposing them (and alert conditions) as gauges, for
+//! setups without a full Prometheus alerting stack.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use tokio::time;
+use tracing::warn;
+
+use crate::metrics::{Metrics, METRICS};
+
+/// The windows burn rates are computed over.
+const WINDOWS: &[(&str, Duration)] = &[
+    ("5m", Duration::from_secs(5 * 60)),
+    ("30m", Duration::from_secs(30 * 60)),
+    ("1h", Duration::from_secs(60 * 60)),
+    ("6h", Duration::from_secs(6 * 60 * 60)),
+];
+
+/// How often to sample the metrics and re-evaluate.
+const EVALUATION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Multi-window, multi-burn-rate alert conditions, as recommended in the Google
+/// SRE workbook: (severity, long window, short window, burn rate threshold).
+const ALERTS: &[(&str, &str, &str, f64)] =
+    &[("page", "1h", "5m", 14.4), ("ticket", "6h", "30m", 6.0)];
+
+#[derive(Clone, Debug)]
+pub struct SloConfig {
+    /// Decisions slower than this count against the latency SLO.
+    /// The latency SLO is disabled if unset.
+    pub latency_threshold: Option<Duration>,
+
+    /// Fraction of decisions that need to be faster than latency_threshold.
+    pub latency_objective: f64,
+
+    /// Fraction of decisions that need to not fail with a server error.
+    /// The availability SLO is disabled if unset.
+    pub availability_objective: Option<f64>,
+}
+
+/// Cumulative counters at a point in time.
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    at: Instant,
+    total: u64,
+    slow: u64,
+    errors: u64,
+}
+
+/// Extracts the number of bad events from a sample.
+type BadEvents = fn(&Sample) -> u64;
+
+pub struct Evaluator {
+    config: SloConfig,
+    /// Index of the histogram bucket used as latency threshold.
+    bucket: Option<usize>,
+    samples: VecDeque<Sample>,
+}
+
+impl Evaluator {
+    pub fn new(config: SloConfig) -> Self {
+        let bucket = config.latency_threshold.map(|threshold| {
+            let threshold = threshold.as_secs_f64();
+            let buckets = METRICS.decision_duration.buckets();
+
+            // Histograms only know about their bucket boundaries, so use the
+            // smallest bucket containing the threshold.
+            let i = buckets
+                .iter()
+                .position(|le| *le >= threshold)
+                .unwrap_or(buckets.len() - 1);
+            if buckets[i] != threshold {
+                warn!(
+                    threshold,
+                    bucket = buckets[i],
+                    "latency threshold is no bucket boundary, rounding"
+                );
+            }
+            i
+        });
+
+        Self {
+            config,
+            bucket,
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn sample(&self, metrics: &Metrics) -> Sample {
+        let total = metrics.decision_duration.count();
+        let fast = self
+            .bucket
+            .map(|i| metrics.decision_duration.cumulative_count(i))
+            .unwrap_or(total);
+
+        Sample {
+            at: Instant::now(),
+            total,
+            slow: total.saturating_sub(fast),
+            errors: metrics.decisions.with_labels(&["error"]).get(),
+        }
+    }
+
+    fn record(&mut self, sample: Sample) {
+        let max_window = WINDOWS.iter().map(|(_, w)| *w).max().unwrap_or_default();
+
+        self.samples.push_back(sample);
+        while let Some(front) = self.samples.front() {
+            if sample.at.duration_since(front.at) > max_window {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Calculate the burn rate over the given window, using [bad] to extract
+    /// the number of bad events from a sample.
+    fn burn_rate(&self, window: Duration, objective: f64, bad: BadEvents) -> f64 {
+        let Some(latest) = self.samples.back() else {
+            return 0.0;
+        };
+        // the oldest sample still inside the window
+        let Some(base) = self
+            .samples
+            .iter()
+            .find(|s| latest.at.duration_since(s.at) <= window)
+        else {
+            return 0.0;
+        };
+
+        let total = latest.total - base.total;
+        if total == 0 {
+            return 0.0;
+        }
+
+        let error_ratio = (bad(latest) - bad(base)) as f64 / total as f64;
+        error_ratio / (1.0 - objective)
+    }
+
+    /// Update burn rate and alert gauges.
+    fn evaluate(&self, metrics: &Metrics) {
+        let slos: [(&str, Option<f64>, BadEvents); 2] = [
+            (
+                "latency",
+                self.bucket.map(|_| self.config.latency_objective),
+                |s| s.slow,
+            ),
+            ("availability", self.config.availability_objective, |s| {
+                s.errors
+            }),
+        ];
+
+        for (slo, objective, bad) in slos {
+            let Some(objective) = objective else {
+                continue;
+            };
+
+            for (window_name, window) in WINDOWS {
+                metrics
+                    .slo_burn_rate
+                    .with_labels(&[slo, window_name])
+                    .set(self.burn_rate(*window, objective, bad));
+            }
+
+            for (severity, long, short, threshold) in ALERTS {
+                let firing = [long, short]
+                    .iter()
+                    .all(|w| metrics.slo_burn_rate.with_labels(&[slo, w]).get() > *threshold);
+                metrics
+                    .slo_alert
+                    .with_labels(&[slo, severity])
+                    .set(if firing { 1.0 } else { 0.0 });
+            }
+        }
+    }
+
+    /// Periodically sample the metrics and update the SLO gauges.
+    pub async fn run(mut self) {
+        let mut interval = time::interval(EVALUATION_INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+        loop {
+            interval.tick().await;
+            self.record(self.sample(&METRICS));
+            self.evaluate(&METRICS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Evaluator, Sample, SloConfig};
+    use crate::metrics::Metrics;
+
+    fn evaluator() -> Evaluator {
+        Evaluator::new(SloConfig {
+            latency_threshold: Some(Duration::from_millis(5)),
+            latency_objective: 0.99,
+            availability_objective: Some(0.999),
+        })
+    }
+
+    #[test]
+    fn burn_rate() {
+        let mut e = evaluator();
+        let start = Instant::now();
+
+        e.record(Sample {
+            at: start,
+            total: 0,
+            slow: 0,
+            errors: 0,
+        });
+        e.record(Sample {
+            at: start + Duration::from_secs(60),
+            total: 1000,
+            slow: 20,
+            errors: 1,
+        });
+
+        // 2% slow with a 1% budget
+        let rate = e.burn_rate(Duration::from_secs(300), 0.99, |s| s.slow);
+        assert!((rate - 2.0).abs() < 1e-9, "unexpected rate {rate}");
+        // 0.1% errors with a 0.1% budget
+        let rate = e.burn_rate(Duration::from_secs(300), 0.999, |s| s.errors);
+        assert!((rate - 1.0).abs() < 1e-9, "unexpected rate {rate}");
+    }
+
+    #[test]
+    fn alerts() {
+        let mut e = evaluator();
+        let start = Instant::now();
+
+        e.record(Sample {
+            at: start,
+            total: 0,
+            slow: 0,
+            errors: 0,
+        });
+        e.record(Sample {
+            at: start + Duration::from_secs(60),
+            total: 100,
+            slow: 50,
+            errors: 0,
+        });
+
+        let metrics = Metrics::default();
+        e.evaluate(&metrics);
+
+        assert_eq!(
+            1.0,
+            metrics.slo_alert.with_labels(&["latency", "page"]).get()
+        );
+        assert_eq!(
+            0.0,
+            metrics
+                .slo_alert
+                .with_labels(&["availability", "page"])
+                .get()
+        );
+    }
+}
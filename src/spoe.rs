@@ -0,0 +1,787 @@
+//! HAProxy Stream Processing Offload (SPOE) agent.
+//!
+//! Instead of doing a subrequest, HAProxy sends the request to the agent via
+//! the SPOP protocol, and gets the decision back as variables. Messages are
+//! expected to carry the following arguments:
+//!
+//!  - `headers`: the request headers, as `req.hdrs_bin`. The token is read
+//!    from the Authorization header, as with /auth.
+//!  - `policy` or `cel_str`: the name of a configured policy, or the CEL
+//!    program, like the query parameters of /auth.
+//!  - `scopes` (optional): scopes relevant for the policy, separated by
+//!    spaces or commas.
+//!
+//! The agent sets the following variables in the transaction scope (prefixed
+//! by the `var-prefix` of the agent):
+//!
+//!  - `allowed`: whether access should be granted.
+//!  - `status`: the status code /auth would have responded with.
+//!  - `x_auth_scopes`, `baggage`: the [DecisionMetadata] on allow, if any.
+//!  - identity headers, like `x_auth_request_user`: lowercase, with dashes
+//!    replaced by underscores.
+//!  - `reason`: why access was denied, if the policy returned a reason.
+//!
+//! An example HAProxy configuration:
+//!
+//! ```text
+//! [cellulose]
+//! spoe-agent cellulose
+//!     messages check-auth
+//!     option var-prefix auth
+//!     timeout hello 2s
+//!     timeout idle 2m
+//!     timeout processing 500ms
+//!     use-backend cellulose
+//!
+//! spoe-message check-auth
+//!     args headers=req.hdrs_bin policy=str(admin-only)
+//!     event on-frontend-http-request
+//! ```
+//!
+//! with `http-request deny unless { var(txn.auth.allowed) -m bool }` in the
+//! frontend.
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Instant,
+};
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    decide, dpop,
+    forwarded_for::{self, TrustedProxies},
+    metrics::{self, METRICS},
+    resolve_policy, scopes, AppState, DecisionMetadata, Denial, VerificationConfig,
+};
+
+/// The only protocol version supported.
+const VERSION: &str = "2.0";
+
+/// The maximum frame size accepted, HAProxy's default.
+const MAX_FRAME_SIZE: u32 = 16384;
+
+// Frame types
+const HAPROXY_HELLO: u8 = 1;
+const HAPROXY_DISCONNECT: u8 = 2;
+const NOTIFY: u8 = 3;
+const AGENT_HELLO: u8 = 101;
+const AGENT_DISCONNECT: u8 = 102;
+const ACK: u8 = 103;
+
+const FLAG_FIN: u32 = 1;
+
+// Data types
+const TYPE_NULL: u8 = 0;
+const TYPE_BOOL: u8 = 1;
+const TYPE_INT32: u8 = 2;
+const TYPE_UINT32: u8 = 3;
+const TYPE_INT64: u8 = 4;
+const TYPE_UINT64: u8 = 5;
+const TYPE_IPV4: u8 = 6;
+const TYPE_IPV6: u8 = 7;
+const TYPE_STRING: u8 = 8;
+const TYPE_BINARY: u8 = 9;
+
+const ACTION_SET_VAR: u8 = 1;
+const SCOPE_TRANSACTION: u8 = 2;
+
+// Status codes sent in disconnect frames.
+const STATUS_NORMAL: u32 = 0;
+const STATUS_IO: u32 = 1;
+const STATUS_TOO_BIG: u32 = 3;
+const STATUS_INVALID: u32 = 4;
+const STATUS_NO_VERSION: u32 = 5;
+const STATUS_NO_MAX_FRAME_SIZE: u32 = 6;
+const STATUS_NO_CAPABILITIES: u32 = 7;
+const STATUS_UNSUPPORTED_VERSION: u32 = 8;
+const STATUS_BAD_MAX_FRAME_SIZE: u32 = 9;
+const STATUS_FRAGMENTED: u32 = 10;
+
+#[derive(Debug)]
+pub struct SpoeError {
+    /// The status code sent to HAProxy in the disconnect frame.
+    status: u32,
+    message: String,
+}
+
+impl fmt::Display for SpoeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (status {})", self.message, self.status)
+    }
+}
+
+impl std::error::Error for SpoeError {}
+
+fn err(status: u32, message: impl Into<String>) -> SpoeError {
+    SpoeError {
+        status,
+        message: message.into(),
+    }
+}
+
+fn invalid() -> SpoeError {
+    err(STATUS_INVALID, "invalid frame received")
+}
+
+/// A typed value.
+#[derive(Debug, PartialEq)]
+enum Data<'a> {
+    Null,
+    Bool(bool),
+    /// Any of the integer types. Signed ones are sent as two's complement.
+    Int(u64),
+    Ip(IpAddr),
+    String(&'a [u8]),
+    Binary(&'a [u8]),
+}
+
+/// Decodes the primitives of the protocol from a buffer.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], SpoeError> {
+        if self.0.len() < n {
+            return Err(invalid());
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SpoeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, SpoeError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes(
+            bytes.try_into().expect("must be 4 bytes"),
+        ))
+    }
+
+    fn varint(&mut self) -> Result<u64, SpoeError> {
+        let b = self.u8()?;
+        if b < 240 {
+            return Ok(b as u64);
+        }
+        // accumulate in a wider type, to detect overflows.
+        let mut v = b as u128;
+        let mut shift = 4;
+        loop {
+            let b = self.u8()?;
+            if shift > 64 {
+                return Err(invalid());
+            }
+            v += (b as u128) << shift;
+            shift += 7;
+            if b < 128 {
+                return u64::try_from(v).map_err(|_| invalid());
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], SpoeError> {
+        let len = self.varint()?;
+        self.bytes(usize::try_from(len).map_err(|_| invalid())?)
+    }
+
+    fn data(&mut self) -> Result<Data<'a>, SpoeError> {
+        let b = self.u8()?;
+        Ok(match b & 0x0f {
+            TYPE_NULL => Data::Null,
+            TYPE_BOOL => Data::Bool(b & 0x10 != 0),
+            TYPE_INT32 | TYPE_UINT32 | TYPE_INT64 | TYPE_UINT64 => Data::Int(self.varint()?),
+            TYPE_IPV4 => {
+                let bytes: [u8; 4] = self.bytes(4)?.try_into().expect("must be 4 bytes");
+                Data::Ip(Ipv4Addr::from(bytes).into())
+            }
+            TYPE_IPV6 => {
+                let bytes: [u8; 16] = self.bytes(16)?.try_into().expect("must be 16 bytes");
+                Data::Ip(Ipv6Addr::from(bytes).into())
+            }
+            TYPE_STRING => Data::String(self.string()?),
+            TYPE_BINARY => Data::Binary(self.string()?),
+            _ => return Err(invalid()),
+        })
+    }
+
+    /// Decode key-value pairs until the end of the buffer.
+    fn kv_list(&mut self) -> Result<Vec<(&'a [u8], Data<'a>)>, SpoeError> {
+        let mut list = Vec::new();
+        while !self.is_empty() {
+            list.push((self.string()?, self.data()?));
+        }
+        Ok(list)
+    }
+}
+
+/// Encodes the primitives of the protocol.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn varint(&mut self, mut v: u64) -> &mut Self {
+        if v < 240 {
+            self.0.push(v as u8);
+            return self;
+        }
+        self.0.push(v as u8 | 240);
+        v = (v - 240) >> 4;
+        while v >= 128 {
+            self.0.push(v as u8 | 128);
+            v = (v - 128) >> 7;
+        }
+        self.0.push(v as u8);
+        self
+    }
+
+    fn string(&mut self, s: &[u8]) -> &mut Self {
+        self.varint(s.len() as u64);
+        self.0.extend_from_slice(s);
+        self
+    }
+
+    fn data(&mut self, data: &Data) -> &mut Self {
+        match data {
+            Data::Null => self.0.push(TYPE_NULL),
+            Data::Bool(b) => self.0.push(TYPE_BOOL | if *b { 0x10 } else { 0 }),
+            Data::Int(v) => {
+                self.0.push(TYPE_UINT64);
+                self.varint(*v);
+            }
+            Data::Ip(IpAddr::V4(ip)) => {
+                self.0.push(TYPE_IPV4);
+                self.0.extend_from_slice(&ip.octets());
+            }
+            Data::Ip(IpAddr::V6(ip)) => {
+                self.0.push(TYPE_IPV6);
+                self.0.extend_from_slice(&ip.octets());
+            }
+            Data::String(s) => {
+                self.0.push(TYPE_STRING);
+                self.string(s);
+            }
+            Data::Binary(s) => {
+                self.0.push(TYPE_BINARY);
+                self.string(s);
+            }
+        }
+        self
+    }
+
+    fn kv(&mut self, key: &str, data: &Data) -> &mut Self {
+        self.string(key.as_bytes()).data(data)
+    }
+
+    fn set_var(&mut self, name: &str, data: &Data) -> &mut Self {
+        self.0
+            .extend_from_slice(&[ACTION_SET_VAR, 3, SCOPE_TRANSACTION]);
+        self.string(name.as_bytes()).data(data)
+    }
+}
+
+/// A decoded frame.
+struct Frame {
+    typ: u8,
+    flags: u32,
+    stream_id: u64,
+    frame_id: u64,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(typ: u8, stream_id: u64, frame_id: u64, payload: Vec<u8>) -> Self {
+        Self {
+            typ,
+            flags: FLAG_FIN,
+            stream_id,
+            frame_id,
+            payload,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Encoder(vec![self.typ]);
+        body.0.extend_from_slice(&self.flags.to_be_bytes());
+        body.varint(self.stream_id).varint(self.frame_id);
+        body.0.extend_from_slice(&self.payload);
+
+        let mut frame = (body.0.len() as u32).to_be_bytes().to_vec();
+        frame.extend(body.0);
+        frame
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, SpoeError> {
+        let mut decoder = Decoder(buf);
+        let typ = decoder.u8()?;
+        let flags = decoder.u32()?;
+        let stream_id = decoder.varint()?;
+        let frame_id = decoder.varint()?;
+        Ok(Self {
+            typ,
+            flags,
+            stream_id,
+            frame_id,
+            payload: decoder.0.to_vec(),
+        })
+    }
+}
+
+/// Read a frame, or None if the connection was closed.
+async fn read_frame(
+    rd: &mut (impl AsyncRead + Unpin),
+    max_frame_size: u32,
+) -> Result<Option<Frame>, SpoeError> {
+    let io_err = |e: std::io::Error| err(STATUS_IO, e.to_string());
+
+    let mut len = [0; 4];
+    match rd.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(io_err(e)),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > max_frame_size {
+        return Err(err(STATUS_TOO_BIG, "frame is too big"));
+    }
+
+    let mut buf = vec![0; len as usize];
+    rd.read_exact(&mut buf).await.map_err(io_err)?;
+    Frame::decode(&buf).map(Some)
+}
+
+/// The settings negotiated in the handshake.
+#[derive(Debug, PartialEq)]
+struct Hello {
+    max_frame_size: u32,
+    pipelining: bool,
+    healthcheck: bool,
+}
+
+/// Handle the HAPROXY-HELLO frame, returning the negotiated settings and
+/// the payload of the AGENT-HELLO frame.
+fn hello(payload: &[u8]) -> Result<(Hello, Vec<u8>), SpoeError> {
+    let mut versions = None;
+    let mut max_frame_size = None;
+    let mut capabilities = None;
+    let mut healthcheck = false;
+    for (key, value) in Decoder(payload).kv_list()? {
+        match (key, value) {
+            (b"supported-versions", Data::String(v)) => versions = Some(v),
+            (b"max-frame-size", Data::Int(v)) => max_frame_size = Some(v),
+            (b"capabilities", Data::String(v)) => capabilities = Some(v),
+            (b"healthcheck", Data::Bool(v)) => healthcheck = v,
+            _ => {}
+        }
+    }
+
+    let split = |v: &[u8]| {
+        String::from_utf8_lossy(v)
+            .split(',')
+            .map(|s| s.trim().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    let versions = versions.ok_or_else(|| err(STATUS_NO_VERSION, "version value not found"))?;
+    if !split(versions).iter().any(|v| v == VERSION) {
+        return Err(err(STATUS_UNSUPPORTED_VERSION, "unsupported version"));
+    }
+    let max_frame_size = max_frame_size
+        .ok_or_else(|| err(STATUS_NO_MAX_FRAME_SIZE, "max-frame-size value not found"))?;
+    // HAProxy requires at least 256 bytes.
+    if max_frame_size < 256 {
+        return Err(err(
+            STATUS_BAD_MAX_FRAME_SIZE,
+            "max-frame-size too big or too small",
+        ));
+    }
+    let max_frame_size = max_frame_size.min(MAX_FRAME_SIZE as u64) as u32;
+    let capabilities =
+        capabilities.ok_or_else(|| err(STATUS_NO_CAPABILITIES, "capabilities value not found"))?;
+    let pipelining = split(capabilities).iter().any(|c| c == "pipelining");
+
+    let mut payload = Encoder::default();
+    payload
+        .kv("version", &Data::String(VERSION.as_bytes()))
+        .kv("max-frame-size", &Data::Int(max_frame_size as u64))
+        .kv(
+            "capabilities",
+            &Data::String(if pipelining { b"pipelining" } else { b"" }),
+        );
+
+    Ok((
+        Hello {
+            max_frame_size,
+            pipelining,
+            healthcheck,
+        },
+        payload.0,
+    ))
+}
+
+fn disconnect(e: &SpoeError) -> Frame {
+    let mut payload = Encoder::default();
+    payload
+        .kv("status-code", &Data::Int(e.status as u64))
+        .kv("message", &Data::String(e.message.as_bytes()));
+    Frame::new(AGENT_DISCONNECT, 0, 0, payload.0)
+}
+
+/// Parse headers in the format of `req.hdrs_bin`: pairs of length-prefixed
+/// names and values, terminated by an empty pair.
+fn parse_headers(buf: &[u8]) -> Result<HeaderMap, SpoeError> {
+    let mut decoder = Decoder(buf);
+    let mut headers = HeaderMap::new();
+    loop {
+        let name = decoder.string()?;
+        let value = decoder.string()?;
+        if name.is_empty() {
+            return Ok(headers);
+        }
+        let name = HeaderName::from_bytes(name).map_err(|_| invalid())?;
+        let value = HeaderValue::from_bytes(value).map_err(|_| invalid())?;
+        headers.append(name, value);
+    }
+}
+
+/// Returns the token from the Authorization header, sent either as bearer or
+/// DPoP-bound token.
+fn authorization_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers
+        .get(axum::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    match value.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => {
+            Some(token.trim()).filter(|token| !token.is_empty())
+        }
+        _ => dpop::authorization_token(headers),
+    }
+}
+
+/// Decide on a single message, returning the metadata on allow.
+async fn decide_message(
+    state: &AppState,
+    args: &[(&[u8], Data<'_>)],
+) -> Result<DecisionMetadata, Denial> {
+    let arg = |name: &str| {
+        args.iter()
+            .find(|(key, _)| *key == name.as_bytes())
+            .map(|(_, value)| value)
+    };
+    let string_arg = |name: &str| match arg(name) {
+        Some(Data::String(s) | Data::Binary(s)) => Some(String::from_utf8_lossy(s).into_owned()),
+        _ => None,
+    };
+
+    let headers = match arg("headers") {
+        Some(Data::Binary(buf) | Data::String(buf)) => parse_headers(buf).map_err(|e| {
+            debug!(err = %e, "invalid headers");
+            StatusCode::BAD_REQUEST
+        })?,
+        _ => HeaderMap::new(),
+    };
+    let token = authorization_token(&headers).map(str::to_owned);
+
+    let policy = resolve_policy(state, string_arg("cel_str"), string_arg("policy"))?;
+    let relevant_scopes = string_arg("scopes").map(|s| scopes::parse_list(&s));
+
+    let decision = decide(
+        state,
+        token.as_deref(),
+        policy,
+        &VerificationConfig::default(),
+        None,
+        headers,
+    )
+    .await?;
+
+    Ok(decision.metadata(relevant_scopes.as_deref()))
+}
+
+/// Handle a NOTIFY frame, returning the ACK frame.
+async fn notify(state: &AppState, frame: Frame, max_frame_size: u32) -> Result<Frame, SpoeError> {
+    let mut decoder = Decoder(&frame.payload);
+    let mut actions = Encoder::default();
+    let mut fallback = Encoder::default();
+    while !decoder.is_empty() {
+        let _name = decoder.string()?;
+        let nb_args = decoder.u8()?;
+        let args = (0..nb_args)
+            .map(|_| Ok((decoder.string()?, decoder.data()?)))
+            .collect::<Result<Vec<_>, SpoeError>>()?;
+
+        let start = Instant::now();
+        let result = decide_message(state, &args).await;
+        let status = match &result {
+            Ok(_) => StatusCode::OK,
+            Err(denial) => denial.status,
+        };
+        METRICS
+            .decision_duration
+            .observe(start.elapsed().as_secs_f64());
+        METRICS
+            .decisions
+            .with_labels(&[metrics::outcome(status)])
+            .inc();
+
+        for encoder in [&mut actions, &mut fallback] {
+            encoder
+                .set_var("allowed", &Data::Bool(result.is_ok()))
+                .set_var("status", &Data::Int(status.as_u16() as u64));
+        }
+        match result {
+            Ok(metadata) => {
+                for (name, value) in metadata.entries() {
+                    actions.set_var(&name.replace('-', "_"), &Data::String(value.as_bytes()));
+                }
+            }
+            Err(Denial {
+                reason: Some(reason),
+                ..
+            }) => {
+                actions.set_var("reason", &Data::String(reason.as_bytes()));
+            }
+            Err(_) => {}
+        }
+    }
+
+    let mut ack = Frame::new(ACK, frame.stream_id, frame.frame_id, actions.0);
+    if ack.encode().len() - 4 > max_frame_size as usize {
+        warn!("decision metadata exceeds the max frame size, omitting it");
+        ack.payload = fallback.0;
+    }
+    Ok(ack)
+}
+
+/// Handle a connection from HAProxy.
+async fn handle<S: AsyncRead + AsyncWrite + Send + 'static>(
+    conn: S,
+    state: AppState,
+) -> Result<(), SpoeError> {
+    let (mut rd, mut wr) = tokio::io::split(conn);
+
+    let result = async {
+        let frame = read_frame(&mut rd, MAX_FRAME_SIZE)
+            .await?
+            .ok_or_else(|| err(STATUS_IO, "connection closed before hello"))?;
+        if frame.typ != HAPROXY_HELLO {
+            return Err(invalid());
+        }
+        let (hello, payload) = hello(&frame.payload)?;
+        wr.write_all(&Frame::new(AGENT_HELLO, 0, 0, payload).encode())
+            .await
+            .map_err(|e| err(STATUS_IO, e.to_string()))?;
+        if hello.healthcheck {
+            return Ok(());
+        }
+
+        // ACK frames are written by a separate task, so with pipelining,
+        // notifications can be handled concurrently.
+        let (tx, mut rx) = mpsc::channel::<Frame>(64);
+        let writer = tokio::spawn(async move {
+            while let Some(frame) = rx.recv().await {
+                wr.write_all(&frame.encode()).await?;
+            }
+            wr.shutdown().await
+        });
+
+        let result = async {
+            while let Some(frame) = read_frame(&mut rd, hello.max_frame_size).await? {
+                match frame.typ {
+                    NOTIFY if frame.flags & FLAG_FIN == 0 => {
+                        return Err(err(
+                            STATUS_FRAGMENTED,
+                            "payload fragmentation is not supported",
+                        ))
+                    }
+                    NOTIFY => {
+                        let (state, tx) = (state.clone(), tx.clone());
+                        let task = async move {
+                            let frame = match notify(&state, frame, hello.max_frame_size).await {
+                                Ok(ack) => ack,
+                                Err(e) => disconnect(&e),
+                            };
+                            let _ = tx.send(frame).await;
+                        };
+                        if hello.pipelining {
+                            tokio::spawn(task);
+                        } else {
+                            task.await;
+                        }
+                    }
+                    HAPROXY_DISCONNECT => return Err(err(STATUS_NORMAL, "normal")),
+                    _ => return Err(invalid()),
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        // let pending notifications finish, and the disconnect frame be sent
+        // last.
+        if let Err(e) = &result {
+            let _ = tx.send(disconnect(e)).await;
+        }
+        drop(tx);
+        let _ = writer.await;
+        result
+    }
+    .await;
+
+    match result {
+        Err(e) if e.status == STATUS_NORMAL => Ok(()),
+        result => result,
+    }
+}
+
+/// Serve SPOE connections from HAProxy.
+pub async fn serve(
+    mut listener: tokio_listener::Listener,
+    state: AppState,
+    trusted_proxies: TrustedProxies,
+) {
+    loop {
+        let conn = match listener.accept().await {
+            Ok((conn, addr)) => {
+                // only proxies speak SPOE, and all the headers come from them.
+                let peer = forwarded_for::peer_ip(&addr.clonable());
+                if !trusted_proxies.trusts(peer) {
+                    debug!(?peer, "closing SPOE connection from untrusted peer");
+                    continue;
+                }
+                conn
+            }
+            Err(e) => {
+                warn!(err = %e, "failed to accept SPOE connection");
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(conn, state).await {
+                debug!(err = %e, "SPOE connection failed");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hello, parse_headers, Data, Decoder, Encoder, Frame, Hello};
+
+    #[test]
+    fn varint() {
+        for v in [
+            0,
+            1,
+            239,
+            240,
+            241,
+            2287,
+            2288,
+            264431,
+            264432,
+            u32::MAX as u64,
+            u64::MAX,
+        ] {
+            let mut encoder = Encoder::default();
+            encoder.varint(v);
+            assert_eq!(v, Decoder(&encoder.0).varint().expect("must decode"));
+        }
+
+        let mut encoder = Encoder::default();
+        encoder.varint(240);
+        assert_eq!(vec![0xf0, 0x00], encoder.0);
+
+        assert!(Decoder(&[0xf0, 0x80]).varint().is_err());
+    }
+
+    #[test]
+    fn handshake() {
+        let mut payload = Encoder::default();
+        payload
+            .kv("supported-versions", &Data::String(b"2.0"))
+            .kv("max-frame-size", &Data::Int(65536))
+            .kv("capabilities", &Data::String(b"pipelining,async"))
+            .kv("engine-id", &Data::String(b"abc"));
+        let (negotiated, reply) = hello(&payload.0).expect("must succeed");
+        assert_eq!(
+            Hello {
+                max_frame_size: 16384,
+                pipelining: true,
+                healthcheck: false
+            },
+            negotiated
+        );
+
+        let reply = Decoder(&reply).kv_list().expect("must decode");
+        assert_eq!(
+            vec![
+                (&b"version"[..], Data::String(b"2.0")),
+                (&b"max-frame-size"[..], Data::Int(16384)),
+                (&b"capabilities"[..], Data::String(b"pipelining")),
+            ],
+            reply
+        );
+
+        let mut payload = Encoder::default();
+        payload
+            .kv("supported-versions", &Data::String(b"1.0"))
+            .kv("max-frame-size", &Data::Int(16380))
+            .kv("capabilities", &Data::String(b""));
+        assert_eq!(8, hello(&payload.0).unwrap_err().status);
+    }
+
+    #[test]
+    fn frames() {
+        let frame = Frame::new(101, 1, 300, vec![1, 2, 3]);
+        let encoded = frame.encode();
+        assert_eq!(
+            (encoded.len() - 4) as u32,
+            u32::from_be_bytes(encoded[..4].try_into().unwrap())
+        );
+
+        let decoded = Frame::decode(&encoded[4..]).expect("must decode");
+        assert_eq!(
+            (101, 1, 1, 300, vec![1, 2, 3]),
+            (
+                decoded.typ,
+                decoded.flags,
+                decoded.stream_id,
+                decoded.frame_id,
+                decoded.payload
+            )
+        );
+    }
+
+    #[test]
+    fn headers() {
+        let mut buf = Encoder::default();
+        buf.string(b"authorization")
+            .string(b"Bearer abc")
+            .string(b"x-foo")
+            .string(b"a")
+            .string(b"x-foo")
+            .string(b"b")
+            .string(b"")
+            .string(b"");
+        let headers = parse_headers(&buf.0).expect("must parse");
+        assert_eq!("Bearer abc", headers["authorization"]);
+        assert_eq!(2, headers.get_all("x-foo").iter().count());
+        assert_eq!(Some("abc"), super::authorization_token(&headers));
+
+        // missing terminator
+        assert!(parse_headers(&buf.0[..buf.0.len() - 2]).is_err());
+    }
+}
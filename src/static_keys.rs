@@ -0,0 +1,251 @@
+//! A key source backed by local PEM or JWK(S) files, for environments that
+//! can't reach the JWKS endpoint of their IdP.
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use parking_lot::{Mutex, RwLock};
+use tracing::{info, warn};
+
+use crate::{
+    key_set::{KeySet, LoadError},
+    key_store,
+};
+
+#[derive(Clone)]
+pub struct StaticSource {
+    /// The issuer this source is responsible for.
+    /// If None, it is used for tokens of any issuer not explicitly routed.
+    issuer: Option<String>,
+    paths: Arc<Vec<PathBuf>>,
+    keys: Arc<RwLock<Arc<KeySet>>>,
+    /// Keeps the file watcher alive, if reloading on change is enabled.
+    watcher: Arc<Mutex<Option<RecommendedWatcher>>>,
+}
+
+/// Load keys from a single file. JSON files are parsed as JWK(S), everything
+/// else as PEM.
+fn load_file(path: &Path) -> Result<KeySet, LoadError> {
+    let contents = std::fs::read(path)
+        .map_err(|e| LoadError(format!("unable to read {}: {}", path.display(), e)))?;
+
+    let key_set = if contents.trim_ascii_start().starts_with(b"{") {
+        KeySet::from_jwk_json(&contents)
+    } else {
+        let pem = String::from_utf8(contents)
+            .map_err(|_| LoadError("PEM file is not valid UTF-8".to_string()))?;
+        // use the file name (without extension) as key id
+        let kid = path.file_stem().map(|s| s.to_string_lossy().into_owned());
+        KeySet::from_pem(&pem, kid)
+    }
+    .map_err(|e| LoadError(format!("{}: {}", path.display(), e)))?;
+
+    if key_set.is_empty() {
+        return Err(LoadError(format!("{}: no usable keys", path.display())));
+    }
+
+    Ok(key_set)
+}
+
+fn load_files(paths: &[PathBuf]) -> Result<KeySet, LoadError> {
+    let mut key_set = KeySet::default();
+    for path in paths {
+        key_set.extend(load_file(path)?);
+    }
+    Ok(key_set)
+}
+
+fn reload(paths: &[PathBuf], keys: &RwLock<Arc<KeySet>>) -> Result<(), LoadError> {
+    let key_set = load_files(paths)?;
+    *keys.write() = Arc::new(key_set);
+    key_store::next_generation();
+    Ok(())
+}
+
+/// The symlink Kubernetes swaps to update all files of a mounted secret,
+/// which themselves are symlinks through it.
+const KUBERNETES_DATA_DIR: &str = "..data";
+
+/// Whether an event for [path] concerns one of the [key_files], by their
+/// (canonicalized) paths: it's one of them, or the symlink swapped to update
+/// the Kubernetes secret next to it.
+fn is_key_file(key_files: &[PathBuf], path: &Path) -> bool {
+    key_files.iter().any(|key_file| {
+        key_file == path
+            || (key_file.parent() == path.parent()
+                && path.file_name() == Some(OsStr::new(KUBERNETES_DATA_DIR)))
+    })
+}
+
+impl StaticSource {
+    /// Load keys from the given files.
+    pub fn load(issuer: Option<String>, paths: Vec<PathBuf>) -> Result<Self, LoadError> {
+        let key_set = load_files(&paths)?;
+
+        Ok(Self {
+            issuer,
+            paths: Arc::new(paths),
+            keys: Arc::new(RwLock::new(Arc::new(key_set))),
+            watcher: Default::default(),
+        })
+    }
+
+    /// The issuer this source is responsible for, if any.
+    pub fn issuer(&self) -> Option<&str> {
+        self.issuer.as_deref()
+    }
+
+    /// Reload keys from disk. On failure, the previous keys are kept.
+    pub fn reload(&self) -> Result<(), LoadError> {
+        reload(&self.paths, &self.keys)
+    }
+
+    /// Watch the key files for changes, and reload them.
+    /// This watches the containing directories, so files replaced by a
+    /// rename (or symlink swap, as done for Kubernetes secrets) are picked up,
+    /// but only events for the key files themselves (or the symlink swapped
+    /// for Kubernetes secrets) trigger a reload, see [is_key_file].
+    pub fn watch(&self) -> notify::Result<()> {
+        let mut watched = Vec::with_capacity(self.paths.len());
+        for path in self.paths.iter() {
+            let dir = path
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            // events are reported relative to the path watched.
+            let dir = dir.canonicalize()?;
+            if let Some(name) = path.file_name() {
+                watched.push(dir.join(name));
+            }
+        }
+
+        let (paths, keys) = (self.paths.clone(), self.keys.clone());
+        let key_files = watched.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+                Ok(event)
+                    if (event.kind.is_create()
+                        || event.kind.is_modify()
+                        || event.kind.is_remove())
+                        && event.paths.iter().any(|p| is_key_file(&key_files, p)) =>
+                {
+                    match reload(&paths, &keys) {
+                        Ok(()) => {
+                            info!(generation = key_store::generation(), "reloaded key files")
+                        }
+                        Err(e) => {
+                            warn!(err=%e, "failed to reload key files, keeping previous keys")
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!(err=%e, "error watching key files"),
+            })?;
+
+        for path in &watched {
+            if let Some(dir) = path.parent() {
+                watcher.watch(dir, RecursiveMode::NonRecursive)?;
+            }
+        }
+
+        *self.watcher.lock() = Some(watcher);
+        Ok(())
+    }
+
+    /// Returns the currently loaded keys.
+    pub fn key_set(&self) -> Arc<KeySet> {
+        self.keys.read().clone()
+    }
+
+    /// The files keys are loaded from.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+
+    use jwt_simple::prelude::*;
+
+    use super::{is_key_file, StaticSource};
+
+    fn pem() -> String {
+        ES256KeyPair::generate().public_key().to_pem().unwrap()
+    }
+
+    #[test]
+    fn load() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.pem"), dir.path().join("b.json"));
+        std::fs::write(&a, pem()).unwrap();
+        let x = Base64UrlSafeNoPadding::encode_to_string(
+            Ed25519KeyPair::generate().public_key().to_bytes(),
+        )
+        .unwrap();
+        let jwks =
+            serde_json::json!({"keys": [{"kty": "OKP", "crv": "Ed25519", "kid": "b1", "x": x}]});
+        std::fs::write(&b, jwks.to_string()).unwrap();
+
+        let source = StaticSource::load(None, vec![a.clone(), b.clone()]).expect("must load");
+        // PEM keys are named by the file
+        let kids = source
+            .key_set()
+            .keys()
+            .iter()
+            .map(|k| k.kid.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![Some("a".to_string()), Some("b1".to_string())], kids);
+
+        std::fs::write(&b, "").unwrap();
+        assert!(StaticSource::load(None, vec![a.clone(), b]).is_err());
+        assert!(StaticSource::load(None, vec![dir.path().join("missing.pem")]).is_err());
+
+        // failed reloads keep the previous keys
+        let source = StaticSource::load(None, vec![a.clone()]).unwrap();
+        std::fs::write(&a, "garbage").unwrap();
+        assert!(source.reload().is_err());
+        assert_eq!(1, source.key_set().keys().len());
+    }
+
+    #[test]
+    fn key_files() {
+        let key_files = [PathBuf::from("/keys/jwks.json")];
+        assert!(is_key_file(&key_files, "/keys/jwks.json".as_ref()));
+        assert!(is_key_file(&key_files, "/keys/..data".as_ref()));
+        assert!(!is_key_file(&key_files, "/keys/other.json".as_ref()));
+        assert!(!is_key_file(&key_files, "/other/..data".as_ref()));
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.pem");
+        std::fs::write(&path, pem()).unwrap();
+        let source = StaticSource::load(None, vec![path.clone()]).unwrap();
+        source.watch().expect("must watch");
+        let keys = source.key_set();
+
+        // other files in the directory are ignored
+        std::fs::write(dir.path().join("other.pem"), pem()).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(Arc::ptr_eq(&keys, &source.key_set()));
+
+        // replaced by a rename, like editors and secret mounts do
+        let tmp = dir.path().join("key.pem.tmp");
+        std::fs::write(&tmp, pem()).unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while Arc::ptr_eq(&keys, &source.key_set()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("must reload");
+        assert_ne!(keys.keys(), source.key_set().keys());
+    }
+}
@@ -0,0 +1,263 @@
+//! Supervision of background tasks, like key refreshes, config reloads and
+//! exporters.
+//!
+//! Tasks that panic are restarted, with an exponential backoff, so a bug
+//! triggered once doesn't silently stop, say, key refreshes for the lifetime
+//! of the process. The state of each task is reported at `/-/tasks` on the
+//! admin listener, and as metrics. On shutdown, all tasks are stopped
+//! together, waiting a bit for them to finish.
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, Json};
+use parking_lot::Mutex;
+use tokio::{sync::watch, task::JoinSet, time};
+use tracing::{debug, error, warn};
+
+use crate::{metrics::METRICS, AppState};
+
+/// The delay before the first restart of a task, doubled for every further
+/// one, up to [MAX_BACKOFF].
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum delay between restarts. Tasks that ran for longer than this
+/// before panicking start over with [MIN_BACKOFF].
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long to wait for tasks to stop on shutdown.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// Panicked, waiting to be restarted.
+    Restarting,
+    /// Returned, tasks aren't restarted after that.
+    Finished,
+    /// Panicked, and isn't restarted.
+    Failed,
+    /// Stopped on shutdown.
+    Stopped,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct TaskStatus {
+    pub state: TaskState,
+    /// How often the task was restarted after panicking.
+    pub restarts: u64,
+    /// The message of the last panic, if any.
+    pub last_panic: Option<String>,
+}
+
+/// Runs background tasks, see the module docs. Cheap to clone.
+#[derive(Clone)]
+pub struct Supervisor {
+    statuses: Arc<Mutex<BTreeMap<&'static str, TaskStatus>>>,
+    tasks: Arc<Mutex<JoinSet<()>>>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self {
+            statuses: Default::default(),
+            tasks: Default::default(),
+            shutdown: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+/// The message a task panicked with.
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(msg) => msg.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+impl Supervisor {
+    /// Run the task returned by [task], restarting it with a new one
+    /// returned by [task] if it panics, until it returns, or on shutdown.
+    pub fn spawn<F, Fut>(&self, name: &'static str, task: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.supervise(name, true, task)
+    }
+
+    /// Run the task without restarting it, like listeners taking ownership
+    /// of their socket. It's still reported on, and stopped on shutdown.
+    pub fn spawn_once<Fut>(&self, name: &'static str, task: Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let task = Mutex::new(Some(task));
+        self.supervise(name, false, move || {
+            task.lock().take().expect("only started once")
+        })
+    }
+
+    fn supervise<F, Fut>(&self, name: &'static str, restart: bool, task: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let statuses = self.statuses.clone();
+        let update = move |state: TaskState, panic: Option<String>| {
+            let mut statuses = statuses.lock();
+            let status = statuses.entry(name).or_insert(TaskStatus {
+                state,
+                restarts: 0,
+                last_panic: None,
+            });
+            status.state = state;
+            if panic.is_some() {
+                status.last_panic = panic;
+                if state == TaskState::Restarting {
+                    status.restarts += 1;
+                    METRICS.task_restarts.with_labels(&[name]).inc();
+                }
+            }
+            let up = if state == TaskState::Running {
+                1.0
+            } else {
+                0.0
+            };
+            METRICS.tasks_up.with_labels(&[name]).set(up);
+        };
+        update(TaskState::Running, None);
+
+        let mut shutdown = self.shutdown.subscribe();
+        self.tasks.lock().spawn(async move {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                let started = Instant::now();
+                let mut handle = tokio::spawn(task());
+                let result = tokio::select! {
+                    result = &mut handle => result,
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => {
+                        handle.abort();
+                        update(TaskState::Stopped, None);
+                        return;
+                    }
+                };
+                let panic = match result {
+                    Ok(()) => {
+                        debug!(task = name, "task finished");
+                        update(TaskState::Finished, None);
+                        return;
+                    }
+                    Err(e) if e.is_panic() => panic_message(e.into_panic()),
+                    Err(_) => {
+                        update(TaskState::Stopped, None);
+                        return;
+                    }
+                };
+                if !restart {
+                    error!(task = name, panic, "task panicked");
+                    update(TaskState::Failed, Some(panic));
+                    return;
+                }
+
+                if started.elapsed() > MAX_BACKOFF {
+                    backoff = MIN_BACKOFF;
+                }
+                error!(task = name, panic, ?backoff, "task panicked, restarting");
+                update(TaskState::Restarting, Some(panic));
+                tokio::select! {
+                    _ = time::sleep(backoff) => {}
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => {
+                        update(TaskState::Stopped, None);
+                        return;
+                    }
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                update(TaskState::Running, None);
+            }
+        });
+    }
+
+    /// The status of all tasks, by name.
+    pub fn statuses(&self) -> BTreeMap<&'static str, TaskStatus> {
+        self.statuses.lock().clone()
+    }
+
+    /// Stop all tasks, waiting for up to [timeout] for them to do so.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.shutdown.send_replace(true);
+        let mut tasks = std::mem::take(&mut *self.tasks.lock());
+        let stopped = time::timeout(timeout, async {
+            while tasks.join_next().await.is_some() {}
+        })
+        .await;
+        if stopped.is_err() {
+            warn!(
+                ?timeout,
+                "background tasks didn't stop in time, aborting them"
+            );
+            tasks.abort_all();
+        }
+    }
+}
+
+/// Handler reporting the status of all tasks.
+pub async fn handler(State(state): State<AppState>) -> Json<BTreeMap<&'static str, TaskStatus>> {
+    Json(state.supervisor.statuses())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::{Supervisor, TaskState};
+
+    #[tokio::test(start_paused = true)]
+    async fn restart_and_shutdown() {
+        let supervisor = Supervisor::default();
+        let runs = Arc::new(AtomicU32::new(0));
+        supervisor.spawn("flaky", {
+            let runs = runs.clone();
+            move || {
+                let run = runs.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if run < 2 {
+                        panic!("run {}", run);
+                    }
+                    std::future::pending::<()>().await
+                }
+            }
+        });
+        supervisor.spawn_once("once", async { panic!("boom") });
+        supervisor.spawn_once("done", async {});
+
+        // restarted after 1s, then 2s
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        assert_eq!(3, runs.load(Ordering::SeqCst));
+        let statuses = supervisor.statuses();
+        assert_eq!(TaskState::Running, statuses["flaky"].state);
+        assert_eq!(2, statuses["flaky"].restarts);
+        assert_eq!(Some("run 1"), statuses["flaky"].last_panic.as_deref());
+        assert_eq!(TaskState::Failed, statuses["once"].state);
+        assert_eq!(Some("boom"), statuses["once"].last_panic.as_deref());
+        assert_eq!(TaskState::Finished, statuses["done"].state);
+
+        supervisor.shutdown(Duration::from_secs(5)).await;
+        assert_eq!(TaskState::Stopped, supervisor.statuses()["flaky"].state);
+    }
+}
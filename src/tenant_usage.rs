@@ -0,0 +1,321 @@
+//! Accounting of allowed requests per tenant, for chargeback or showback
+//! reporting.
+//!
+//! The tenant is read from a claim of the token. Requests are counted over
+//! fixed windows (aligned to multiples of the window length since the
+//! epoch), exposed as `cellulose_tenant_allowed_requests_total`, and, if an
+//! export directory is configured, written to one file per window once it
+//! ends.
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::{metrics::METRICS, CustomClaims};
+
+/// The label requests without (a string or number) tenant claim are counted
+/// with.
+pub const NO_TENANT: &str = "_none";
+
+/// The label requests of tenants beyond [MAX_TENANTS] are counted with.
+pub const OTHER_TENANTS: &str = "_other";
+
+/// The maximum number of tenants tracked individually, to bound the
+/// cardinality of the metric and the memory used. The tenant claim is set by
+/// the IdP, but still shouldn't be able to blow up the process.
+const MAX_TENANTS: usize = 10_000;
+
+/// The prefix of export files, followed by the window start.
+const FILE_PREFIX: &str = "tenant-usage-";
+
+/// The format of export files.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// A JSON object with the window and the counts by tenant.
+    Json,
+    /// Rows of window start, window end, tenant and count, with a header.
+    Csv,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+/// Where to export the counts of each window to.
+#[derive(Clone, Debug)]
+pub struct Export {
+    pub dir: PathBuf,
+    pub format: ExportFormat,
+    /// How many files to keep, older ones are removed.
+    pub keep: usize,
+}
+
+/// The counts of a single window.
+#[derive(Debug, Default, PartialEq)]
+pub struct Window {
+    /// The start of the window, in seconds since the epoch.
+    pub start: u64,
+    pub counts: BTreeMap<String, u64>,
+}
+
+struct State {
+    current: Window,
+    /// The tenants tracked individually.
+    known: HashSet<String>,
+}
+
+/// Counts allowed requests by tenant.
+pub struct TenantUsage {
+    /// The claim holding the tenant.
+    pub claim: String,
+    pub window: Duration,
+    pub export: Option<Export>,
+    state: Mutex<State>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl TenantUsage {
+    pub fn new(claim: String, window: Duration, export: Option<Export>) -> Self {
+        let window = window.max(Duration::from_secs(1));
+        Self {
+            claim,
+            state: Mutex::new(State {
+                current: Window {
+                    start: now() / window.as_secs() * window.as_secs(),
+                    counts: BTreeMap::new(),
+                },
+                known: HashSet::new(),
+            }),
+            window,
+            export,
+        }
+    }
+
+    /// The tenant of the token, to be passed to [record].
+    pub fn tenant(&self, claims: &CustomClaims) -> String {
+        match claims.get(&self.claim) {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(serde_json::Value::Number(n)) => n.to_string(),
+            _ => NO_TENANT.to_string(),
+        }
+    }
+
+    /// Count an allowed request of the tenant.
+    pub fn record(&self, tenant: String) {
+        let mut state = self.state.lock();
+        let tenant = if state.known.contains(&tenant) {
+            tenant
+        } else if state.known.len() < MAX_TENANTS {
+            state.known.insert(tenant.clone());
+            tenant
+        } else {
+            OTHER_TENANTS.to_string()
+        };
+
+        METRICS
+            .tenant_allowed_requests
+            .with_labels(&[&tenant])
+            .inc();
+        *state.current.counts.entry(tenant).or_default() += 1;
+    }
+
+    /// Start a new window, if the current one ended at [now], returning the
+    /// finished one.
+    fn rotate(&self, now: u64) -> Option<Window> {
+        let secs = self.window.as_secs();
+        let start = now / secs * secs;
+        let mut state = self.state.lock();
+        if state.current.start == start {
+            return None;
+        }
+        Some(std::mem::replace(
+            &mut state.current,
+            Window {
+                start,
+                counts: BTreeMap::new(),
+            },
+        ))
+    }
+
+    /// Write the window to the export directory, removing the oldest files
+    /// beyond the ones to keep.
+    fn write(&self, export: &Export, window: &Window) -> io::Result<()> {
+        let end = window.start + self.window.as_secs();
+        let contents = match export.format {
+            ExportFormat::Json => serde_json::to_string(&serde_json::json!({
+                "start": window.start,
+                "end": end,
+                "tenants": window.counts,
+            }))?,
+            ExportFormat::Csv => render_csv(window, end),
+        };
+
+        fs::create_dir_all(&export.dir)?;
+        let path = export.dir.join(format!(
+            "{}{}.{}",
+            FILE_PREFIX,
+            window.start,
+            export.format.extension()
+        ));
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(tmp_path, &path)?;
+        debug!(path = %path.display(), "exported tenant usage");
+
+        remove_old_files(&export.dir, export.keep)
+    }
+
+    /// Export finished windows, at the end of each window.
+    pub async fn run(self: Arc<Self>) {
+        let secs = self.window.as_secs();
+        loop {
+            time::sleep(Duration::from_secs(secs - now() % secs)).await;
+
+            let Some(window) = self.rotate(now()) else {
+                continue;
+            };
+            if let Some(export) = &self.export {
+                if let Err(e) = self.write(export, &window) {
+                    warn!(err = %e, dir = %export.dir.display(), "failed to export tenant usage");
+                }
+            }
+        }
+    }
+}
+
+/// Quote a CSV field, if needed.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn render_csv(window: &Window, end: u64) -> String {
+    let mut out = String::from("window_start,window_end,tenant,allowed_requests\n");
+    for (tenant, count) in &window.counts {
+        let _ = writeln!(
+            out,
+            "{},{},{},{}",
+            window.start,
+            end,
+            csv_field(tenant),
+            count
+        );
+    }
+    out
+}
+
+/// Remove the oldest export files, keeping the [keep] most recent ones.
+/// Files are named by the window start, which orders them by age.
+fn remove_old_files(dir: &Path, keep: usize) -> io::Result<()> {
+    let mut files = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(FILE_PREFIX) && !n.ends_with(".tmp"))
+        })
+        .collect::<Vec<_>>();
+    files.sort_by_key(|path| {
+        path.file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s[FILE_PREFIX.len()..].parse::<u64>().ok())
+    });
+
+    let excess = files.len().saturating_sub(keep);
+    for path in &files[..excess] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Export, ExportFormat, TenantUsage, Window, NO_TENANT};
+
+    #[test]
+    fn windows() {
+        let dir = tempfile::tempdir().expect("must create tempdir");
+        let usage = TenantUsage::new(
+            "tenant".to_string(),
+            Duration::from_secs(60),
+            Some(Export {
+                dir: dir.path().to_owned(),
+                format: ExportFormat::Csv,
+                keep: 2,
+            }),
+        );
+
+        let claims = serde_json::json!({ "tenant": "acme, inc" });
+        usage.record(usage.tenant(claims.as_object().unwrap()));
+        usage.record(usage.tenant(claims.as_object().unwrap()));
+        usage.record(usage.tenant(&Default::default()));
+
+        let start = usage.state.lock().current.start;
+        assert_eq!(None, usage.rotate(start + 59));
+        let window = usage.rotate(start + 60).expect("must rotate");
+        assert_eq!(start, window.start);
+        assert_eq!(2, window.counts["acme, inc"]);
+        assert_eq!(1, window.counts[NO_TENANT]);
+        assert!(usage.state.lock().current.counts.is_empty());
+
+        let export = usage.export.as_ref().unwrap();
+        usage.write(export, &window).expect("must write");
+        let path = dir.path().join(format!("tenant-usage-{}.csv", start));
+        assert_eq!(
+            format!(
+                "window_start,window_end,tenant,allowed_requests\n\
+                 {start},{end},_none,1\n\
+                 {start},{end},\"acme, inc\",2\n",
+                start = start,
+                end = start + 60
+            ),
+            std::fs::read_to_string(path).unwrap()
+        );
+
+        // only the most recent files are kept
+        for i in 1..=2 {
+            let window = Window {
+                start: start + i * 60,
+                ..Default::default()
+            };
+            usage.write(export, &window).expect("must write");
+        }
+        let mut files = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            vec![
+                format!("tenant-usage-{}.csv", start + 60),
+                format!("tenant-usage-{}.csv", start + 120),
+            ],
+            files
+        );
+    }
+}
@@ -1,18 +1,90 @@
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use std::{sync::LazyLock, time::Duration};
+
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter};
+
+use crate::{log_levels, otlp};
+
+/// How long outgoing requests may take to connect.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long outgoing requests may take in total, so a hanging IdP (or
+/// webhook) can't stall refreshes or requests waiting for them.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// HTTP client shared for all outgoing requests.
+pub static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("failed to build HTTP client")
+});
+
+/// A random identifier with 256 bits of entropy, URL-safe, for session ids
+/// and the like.
+pub fn random_id() -> String {
+    use aes_gcm::aead::{rand_core::RngCore, OsRng};
+    use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Encoder};
+
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    Base64UrlSafeNoPadding::encode_to_string(bytes).expect("must encode")
+}
+
+/// The format of log lines.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable, with the fields of the event and its spans.
+    Compact,
+    /// One JSON object per line, with the timestamp, level, target, the
+    /// fields of the event (including the message), and the current span
+    /// along with all spans it's in.
+    Json,
+}
+
+/// Setup logging to stderr, in the given [format], using the given filter
+/// (in RUST_LOG syntax), or RUST_LOG if unset.
+/// The filter can be extended per policy at runtime, see [log_levels].
+/// With an [otlp_endpoint], spans are exported there, by the returned
+/// [otlp::Exporter], which needs to be run.
+pub fn setup_tracing(
+    format: LogFormat,
+    filter: Option<&str>,
+    otlp_endpoint: Option<&str>,
+) -> Option<otlp::Exporter> {
+    let base = match filter {
+        Some(filter) => filter.to_owned(),
+        None => std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default(),
+    };
+    let (filter, handle) =
+        reload::Layer::new(log_levels::build_filter(&base).expect("Invalid log filter"));
+
+    let (otlp_layer, exporter) = otlp_endpoint.map(otlp::new).unzip();
+
+    let compact = (format == LogFormat::Compact).then(|| {
+        tracing_subscriber::fmt::Layer::new()
+            .with_writer(std::io::stderr)
+            .compact()
+    });
+    let json = (format == LogFormat::Json).then(|| {
+        tracing_subscriber::fmt::Layer::new()
+            .with_writer(std::io::stderr)
+            .json()
+            .flatten_event(true)
+    });
 
-pub fn setup_tracing() {
     let subscriber = tracing_subscriber::registry()
-        .with(
-            EnvFilter::builder()
-                .with_default_directive(tracing::Level::INFO.into())
-                .from_env()
-                .expect("Invalid RUST_LOG"),
-        )
-        .with(
-            tracing_subscriber::fmt::Layer::new()
-                .with_writer(std::io::stderr)
-                .compact(),
-        );
+        .with(filter)
+        .with(compact)
+        .with(json)
+        .with(otlp_layer);
 
     subscriber.try_init().expect("failed to setup tracing");
+    log_levels::LogLevels::init(base, handle);
+    exporter
 }
@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+
+use jwt_simple::prelude::{Duration, UnixTimeStamp, VerificationOptions};
+use serde::Deserialize;
+
+/// The maximum clock drift that can be tolerated, in seconds.
+pub const MAX_TIME_TOLERANCE: u64 = 24 * 3600;
+
+/// The maximum age of tokens that can be required, in seconds. Longer
+/// durations make no difference for tokens anyway, and could overflow the
+/// time math of jwt_simple.
+pub const MAX_VALIDITY: u64 = 10 * 365 * 24 * 3600;
+
+/// Deserialize a number of seconds, rejecting more than [max], so requests
+/// get a 400 rather than overflowing the time math.
+fn at_most<'de, D>(deserializer: D, max: u64) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<u64>::deserialize(deserializer)? {
+        Some(secs) if secs > max => Err(serde::de::Error::custom(format!(
+            "{} exceeds the maximum of {} seconds",
+            secs, max
+        ))),
+        secs => Ok(secs),
+    }
+}
+
+fn time_tolerance<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    at_most(d, MAX_TIME_TOLERANCE)
+}
+
+fn max_validity<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    at_most(d, MAX_VALIDITY)
+}
+
+/// Knobs controlling JWT verification, mirroring the fields of
+/// [VerificationOptions].
+///
+/// All fields are optional, unset fields keep the jwt_simple defaults.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct VerificationConfig {
+    /// Allowed audiences of the JWT
+    pub allowed_audiences: Option<HashSet<String>>,
+
+    /// Allowed issuers of the JWT
+    pub allowed_issuers: Option<HashSet<String>>,
+
+    /// Require a specific subject to be present
+    pub required_subject: Option<String>,
+
+    /// Require a specific nonce to be present
+    pub required_nonce: Option<String>,
+
+    /// Require a specific key identifier to be present
+    pub required_key_id: Option<String>,
+
+    /// Reject tokens issued before the given unix timestamp (in seconds)
+    pub reject_before: Option<u64>,
+
+    /// Accept tokens issued with a date in the future
+    pub accept_future: Option<bool>,
+
+    /// How much clock drift to tolerate when verifying timestamps, in seconds,
+    /// at most [MAX_TIME_TOLERANCE]
+    #[serde(default, deserialize_with = "time_tolerance")]
+    pub time_tolerance: Option<u64>,
+
+    /// Reject tokens issued more than this many seconds ago, at most
+    /// [MAX_VALIDITY]
+    #[serde(default, deserialize_with = "max_validity")]
+    pub max_validity: Option<u64>,
+}
+
+impl VerificationConfig {
+    /// Returns the config with unset fields taken from [defaults].
+    pub fn or(&self, defaults: &VerificationConfig) -> VerificationConfig {
+        VerificationConfig {
+            allowed_audiences: self
+                .allowed_audiences
+                .clone()
+                .or_else(|| defaults.allowed_audiences.clone()),
+            allowed_issuers: self
+                .allowed_issuers
+                .clone()
+                .or_else(|| defaults.allowed_issuers.clone()),
+            required_subject: self
+                .required_subject
+                .clone()
+                .or_else(|| defaults.required_subject.clone()),
+            required_nonce: self
+                .required_nonce
+                .clone()
+                .or_else(|| defaults.required_nonce.clone()),
+            required_key_id: self
+                .required_key_id
+                .clone()
+                .or_else(|| defaults.required_key_id.clone()),
+            reject_before: self.reject_before.or(defaults.reject_before),
+            accept_future: self.accept_future.or(defaults.accept_future),
+            time_tolerance: self.time_tolerance.or(defaults.time_tolerance),
+            max_validity: self.max_validity.or(defaults.max_validity),
+        }
+    }
+
+    /// A representation of the config for hashing, with sets sorted, so the
+    /// same options always yield the same string.
+    pub(crate) fn canonical(&self) -> String {
+        fn sorted(set: &Option<HashSet<String>>) -> Option<Vec<&String>> {
+            set.as_ref().map(|set| {
+                let mut set = set.iter().collect::<Vec<_>>();
+                set.sort();
+                set
+            })
+        }
+        format!(
+            "{:?}",
+            (
+                sorted(&self.allowed_audiences),
+                sorted(&self.allowed_issuers),
+                &self.required_subject,
+                &self.required_nonce,
+                &self.required_key_id,
+                self.reject_before,
+                self.accept_future,
+                self.time_tolerance,
+                self.max_validity,
+            )
+        )
+    }
+
+    /// Construct the [VerificationOptions] to pass to the key store.
+    pub fn to_options(&self) -> VerificationOptions {
+        let defaults = VerificationOptions::default();
+
+        VerificationOptions {
+            allowed_audiences: self.allowed_audiences.clone(),
+            allowed_issuers: self.allowed_issuers.clone(),
+            required_subject: self.required_subject.clone(),
+            required_nonce: self.required_nonce.clone(),
+            required_key_id: self.required_key_id.clone(),
+            reject_before: self.reject_before.map(UnixTimeStamp::from_secs),
+            accept_future: self.accept_future.unwrap_or(defaults.accept_future),
+            time_tolerance: self
+                .time_tolerance
+                .map(Duration::from_secs)
+                .or(defaults.time_tolerance),
+            max_validity: self.max_validity.map(Duration::from_secs),
+            ..defaults
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jwt_simple::prelude::{Duration, UnixTimeStamp, VerificationOptions};
+
+    use axum::{extract::Query, http::StatusCode};
+
+    use super::VerificationConfig;
+
+    #[test]
+    fn defaults() {
+        let defaults = VerificationOptions::default();
+        let options = VerificationConfig::default().to_options();
+
+        assert_eq!(defaults.accept_future, options.accept_future);
+        assert_eq!(defaults.time_tolerance, options.time_tolerance);
+        assert_eq!(None, options.reject_before);
+        assert_eq!(None, options.required_subject);
+    }
+
+    #[test]
+    fn overrides() {
+        let options = VerificationConfig {
+            required_subject: Some("alice".to_string()),
+            required_nonce: Some("n0nce".to_string()),
+            reject_before: Some(1_700_000_000),
+            accept_future: Some(true),
+            time_tolerance: Some(5),
+            ..Default::default()
+        }
+        .to_options();
+
+        assert_eq!(Some("alice".to_string()), options.required_subject);
+        assert_eq!(Some("n0nce".to_string()), options.required_nonce);
+        assert_eq!(
+            Some(UnixTimeStamp::from_secs(1_700_000_000)),
+            options.reject_before
+        );
+        assert!(options.accept_future);
+        assert_eq!(Some(Duration::from_secs(5)), options.time_tolerance);
+    }
+
+    #[test]
+    fn with_defaults() {
+        let defaults = VerificationConfig {
+            required_subject: Some("alice".to_string()),
+            max_validity: Some(3600),
+            ..Default::default()
+        };
+        let config = VerificationConfig {
+            required_subject: Some("bob".to_string()),
+            ..Default::default()
+        }
+        .or(&defaults);
+
+        assert_eq!(Some("bob".to_string()), config.required_subject);
+        assert_eq!(Some(3600), config.max_validity);
+    }
+
+    #[test]
+    fn bounded() {
+        let query = |query: &str| {
+            let uri = format!("/auth?{}", query).parse().unwrap();
+            Query::<VerificationConfig>::try_from_uri(&uri).map(|Query(config)| config)
+        };
+        let config = query("time_tolerance=60&max_validity=3600").expect("must parse");
+        assert_eq!(Some(60), config.time_tolerance);
+        assert_eq!(Some(3600), config.max_validity);
+        assert_eq!(None, query("").unwrap().time_tolerance);
+
+        for query_str in ["time_tolerance=86401", "max_validity=18446744073709551615"] {
+            let rejection = query(query_str).unwrap_err();
+            assert_eq!(StatusCode::BAD_REQUEST, rejection.status(), "{}", query_str);
+        }
+        // in config files too
+        assert!(toml::from_str::<VerificationConfig>("time_tolerance = 100000").is_err());
+    }
+}
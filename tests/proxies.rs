@@ -0,0 +1,196 @@
+//! End-to-end tests of the proxy configurations in examples/, against the
+//! binary built from this tree, see examples/README.md.
+//! They need Docker on Linux (for host networking) and the ports of the
+//! examples free, so they only run with CELLULOSE_E2E=1.
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use reqwest::StatusCode;
+
+const CELLULOSE: &str = env!("CARGO_BIN_EXE_cellulose");
+
+/// The proxies, and where they listen.
+const PROXIES: &[(&str, &str)] = &[
+    ("traefik", "http://127.0.0.1:8081"),
+    ("caddy", "http://127.0.0.1:8082"),
+    ("nginx", "http://127.0.0.1:8083"),
+];
+
+/// Stops the processes and containers started by the test, also if it fails.
+#[derive(Default)]
+struct Cleanup {
+    children: Vec<Child>,
+    containers: Vec<String>,
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        if !self.containers.is_empty() {
+            let _ = Command::new("docker")
+                .args(["rm", "-f"])
+                .args(&self.containers)
+                .stdout(Stdio::null())
+                .status();
+        }
+    }
+}
+
+impl Cleanup {
+    /// Start a container with host networking, the arguments go before the
+    /// image and after it, respectively.
+    fn docker_run(&mut self, options: &[String], image: &str, args: &[&str]) {
+        let output = Command::new("docker")
+            .args(["run", "--rm", "-d", "--network", "host"])
+            .args(options)
+            .arg(image)
+            .args(args)
+            .output()
+            .expect("docker must be installed");
+        assert!(
+            output.status.success(),
+            "failed to start {}: {}",
+            image,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        self.containers
+            .push(String::from_utf8_lossy(&output.stdout).trim().to_owned());
+    }
+}
+
+fn examples() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("examples")
+}
+
+fn mount(from: PathBuf, to: &str) -> [String; 2] {
+    ["-v".to_string(), format!("{}:{}:ro", from.display(), to)]
+}
+
+/// Wait for something to answer HTTP requests at the URL.
+async fn wait_for(client: &reqwest::Client, url: &str) {
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while client.get(url).send().await.is_err() {
+        assert!(Instant::now() < deadline, "{} didn't come up", url);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
+
+#[tokio::test]
+async fn proxies() {
+    if std::env::var("CELLULOSE_E2E").as_deref() != Ok("1") {
+        eprintln!("skipping, set CELLULOSE_E2E=1 to run");
+        return;
+    }
+
+    let fixture = tempfile::tempdir().unwrap();
+    let status = Command::new(CELLULOSE)
+        .arg("make-fixture")
+        .arg("--out")
+        .arg(fixture.path())
+        .args(["--subject", "alice"])
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let token = std::fs::read_to_string(fixture.path().join("token.jwt")).unwrap();
+    let token = token.trim();
+
+    let mut cleanup = Cleanup::default();
+    cleanup.children.push(
+        Command::new(CELLULOSE)
+            .arg("--key-file")
+            .arg(fixture.path().join("jwks.json"))
+            .args([
+                "--listen-address",
+                "127.0.0.1:9000",
+                "--identity-header",
+                "X-Auth-Request-User=sub",
+                "--default-cel",
+                r#"jwt.sub == "alice" && !url.path.startsWith("/admin")"#,
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    cleanup.docker_run(&[], "traefik/whoami:v1.10", &["--port", "8080"]);
+    cleanup.docker_run(
+        &mount(examples().join("traefik"), "/etc/traefik"),
+        "traefik:v3.1",
+        &[
+            "--entryPoints.web.address=:8081",
+            "--providers.file.filename=/etc/traefik/dynamic.yml",
+        ],
+    );
+    cleanup.docker_run(
+        &mount(examples().join("caddy/Caddyfile"), "/etc/caddy/Caddyfile"),
+        "caddy:2.8",
+        &[],
+    );
+    cleanup.docker_run(
+        &mount(examples().join("nginx/nginx.conf"), "/etc/nginx/nginx.conf"),
+        "nginx:1.27-alpine",
+        &[],
+    );
+
+    let client = reqwest::Client::new();
+    wait_for(&client, "http://127.0.0.1:9000/").await;
+    wait_for(&client, "http://127.0.0.1:8080/").await;
+
+    for (proxy, base) in PROXIES {
+        wait_for(&client, base).await;
+
+        // without token, with the challenge
+        let response = client.get(format!("{}/", base)).send().await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{}", proxy);
+        assert_eq!(
+            Some(r#"Bearer realm="cellulose""#),
+            response
+                .headers()
+                .get("www-authenticate")
+                .and_then(|v| v.to_str().ok()),
+            "{}",
+            proxy
+        );
+
+        // allowed, with the identity passed on, not the one sent by the client
+        let response = client
+            .get(format!("{}/app?x=1", base))
+            .bearer_auth(token)
+            .header("X-Auth-Request-User", "mallory")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status(), "{}", proxy);
+        let echo = response.text().await.unwrap();
+        assert!(
+            echo.lines().any(|l| l == "X-Auth-Request-User: alice"),
+            "{}: {}",
+            proxy,
+            echo
+        );
+        assert!(!echo.contains("mallory"), "{}: {}", proxy, echo);
+
+        // denied by the policy, based on the original URL
+        let response = client
+            .get(format!("{}/admin", base))
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status(), "{}", proxy);
+
+        // an invalid token
+        let response = client
+            .get(format!("{}/", base))
+            .bearer_auth(format!("{}x", token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{}", proxy);
+    }
+}